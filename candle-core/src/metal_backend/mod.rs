@@ -743,34 +743,50 @@ impl BackendStorage for MetalStorage {
                 f.dtype()
             );
         }
-        let name = match (self.dtype, t.dtype()) {
-            (DType::U8, DType::F32) => "where_u8_f32",
-            (DType::U32, DType::F32) => "where_u32_f32",
-            (DType::U8, DType::BF16) => "where_u8_bf16",
-            (DType::U8, DType::F16) => "where_u8_f16",
-            (DType::U8, DType::I64) => "where_u8_i64",
-            (DType::U8, DType::U32) => "where_u8_u32",
-            (DType::U8, DType::U8) => "where_u8_u8",
+        // The strided kernel and its contiguous variant.
+        let (name, contiguous_name) = match (self.dtype, t.dtype()) {
+            (DType::U8, DType::F32) => ("where_u8_f32", "where_u8_f32_contiguous"),
+            (DType::U32, DType::F32) => ("where_u32_f32", "where_u32_f32_contiguous"),
+            (DType::U8, DType::BF16) => ("where_u8_bf16", "where_u8_bf16_contiguous"),
+            (DType::U8, DType::F16) => ("where_u8_f16", "where_u8_f16_contiguous"),
+            (DType::U8, DType::I64) => ("where_u8_i64", "where_u8_i64_contiguous"),
+            (DType::U8, DType::U32) => ("where_u8_u32", "where_u8_u32_contiguous"),
+            (DType::U8, DType::U8) => ("where_u8_u8", "where_u8_u8_contiguous"),
             (left, right) => crate::bail!("Metal where_cond {left:?} {right:?} not implemented"),
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         let t = buffer_o(&t.buffer, t_l, t.dtype);
         let f = buffer_o(&f.buffer, f_l, f.dtype);
-        candle_metal_kernels::call_where_cond_strided(
-            &device.device,
-            &command_buffer,
-            &device.kernels,
-            name,
-            dims,
-            src,
-            layout.stride(),
-            t,
-            t_l.stride(),
-            f,
-            f_l.stride(),
-            &buffer,
-        )
-        .map_err(MetalError::from)?;
+        if layout.is_contiguous() && t_l.is_contiguous() && f_l.is_contiguous() {
+            candle_metal_kernels::call_where_cond_contiguous(
+                &device.device,
+                &command_buffer,
+                &device.kernels,
+                contiguous_name,
+                el,
+                src,
+                t,
+                f,
+                &buffer,
+            )
+            .map_err(MetalError::from)?;
+        } else {
+            candle_metal_kernels::call_where_cond_strided(
+                &device.device,
+                &command_buffer,
+                &device.kernels,
+                name,
+                dims,
+                src,
+                layout.stride(),
+                t,
+                t_l.stride(),
+                f,
+                f_l.stride(),
+                &buffer,
+            )
+            .map_err(MetalError::from)?;
+        }
         Ok(Self::new(buffer, device, el, dtype))
     }
 
@@ -1323,18 +1339,19 @@ impl BackendStorage for MetalStorage {
         let dtype = self.dtype;
         let device = self.device();
         let buffer = device.new_buffer(dst_el, dtype, "index_select")?;
-        let name = match (ids.dtype, self.dtype) {
-            (DType::U8, DType::BF16) => "is_u8_bf16",
-            (DType::U8, DType::F32) => "is_u8_f32",
-            (DType::U8, DType::F16) => "is_u8_f16",
+        // The kernel for contiguous sources and its strided variant.
+        let (name, strided_name) = match (ids.dtype, self.dtype) {
+            (DType::U8, DType::BF16) => ("is_u8_bf16", "is_u8_bf16_strided"),
+            (DType::U8, DType::F32) => ("is_u8_f32", "is_u8_f32_strided"),
+            (DType::U8, DType::F16) => ("is_u8_f16", "is_u8_f16_strided"),
 
-            (DType::U32, DType::F32) => "is_u32_f32",
-            (DType::U32, DType::F16) => "is_u32_f16",
-            (DType::U32, DType::BF16) => "is_u32_bf16",
+            (DType::U32, DType::F32) => ("is_u32_f32", "is_u32_f32_strided"),
+            (DType::U32, DType::F16) => ("is_u32_f16", "is_u32_f16_strided"),
+            (DType::U32, DType::BF16) => ("is_u32_bf16", "is_u32_bf16_strided"),
 
-            (DType::I64, DType::F32) => "is_i64_f32",
-            (DType::I64, DType::F16) => "is_i64_f16",
-            (DType::I64, DType::BF16) => "is_i64_bf16",
+            (DType::I64, DType::F32) => ("is_i64_f32", "is_i64_f32_strided"),
+            (DType::I64, DType::F16) => ("is_i64_f16", "is_i64_f16_strided"),
+            (DType::I64, DType::BF16) => ("is_i64_bf16", "is_i64_bf16_strided"),
 
             (left, right) => {
                 crate::bail!("Metal contiguous index_select {left:?} {right:?} not implemented")
@@ -1343,6 +1360,23 @@ impl BackendStorage for MetalStorage {
         let command_buffer = self.device.command_buffer()?;
         let src = buffer_o(&self.buffer, src_l, dtype);
         let ids = buffer_o(&ids.buffer, ids_l, ids.dtype);
+        if !src_l.is_contiguous() {
+            candle_metal_kernels::call_index_select_strided(
+                &device.device,
+                &command_buffer,
+                &self.device.kernels,
+                strided_name,
+                src_l.dims(),
+                src_l.stride(),
+                dim,
+                ids_el,
+                src,
+                ids,
+                &buffer,
+            )
+            .map_err(MetalError::from)?;
+            return Ok(Self::new(buffer, device.clone(), dst_el, dtype));
+        }
         candle_metal_kernels::call_index_select(
             &device.device,
             &command_buffer,
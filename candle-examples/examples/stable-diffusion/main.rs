@@ -9,6 +9,8 @@ use candle_transformers::models::stable_diffusion;
 use anyhow::{Error as E, Result};
 use candle::{DType, Device, IndexOp, Module, Tensor, D};
 use clap::Parser;
+use stable_diffusion::placement::DevicePlacement;
+use stable_diffusion::schedulers::ModelInputBuffer;
 use stable_diffusion::vae::AutoEncoderKL;
 use tokenizers::Tokenizer;
 
@@ -29,6 +31,10 @@ struct Args {
     #[arg(long)]
     cpu: bool,
 
+    /// Keep the autoencoder on CPU, only the unet runs on the GPU.
+    #[arg(long)]
+    vae_on_cpu: bool,
+
     /// Enable tracing (generates a trace-timestamp.json file).
     #[arg(long)]
     tracing: bool,
@@ -57,7 +63,8 @@ struct Args {
     /// The file specifying the tokenizer to used for tokenization.
     tokenizer: Option<String>,
 
-    /// The size of the sliced attention or 0 for automatic slicing (disabled by default)
+    /// The number of queries per slice when computing the attention scores in slices or 0 for
+    /// automatic slicing (disabled by default)
     #[arg(long)]
     sliced_attention_size: Option<usize>,
 
@@ -87,6 +94,10 @@ struct Args {
     #[arg(long)]
     use_flash_attn: bool,
 
+    /// Run the unet resnet blocks in channels-last layout, this is faster on metal.
+    #[arg(long)]
+    channels_last: bool,
+
     #[arg(long)]
     use_f16: bool,
 
@@ -269,6 +280,7 @@ fn output_filename(
 #[allow(clippy::too_many_arguments)]
 fn save_image(
     vae: &AutoEncoderKL,
+    placement: &DevicePlacement,
     latents: &Tensor,
     vae_scale: f64,
     bsize: usize,
@@ -277,7 +289,7 @@ fn save_image(
     num_samples: usize,
     timestep_ids: Option<usize>,
 ) -> Result<()> {
-    let images = vae.decode(&(latents / vae_scale)?)?;
+    let images = placement.decode(&(latents / vae_scale)?, |xs| vae.decode(xs))?;
     let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
     let images = (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?;
     for batch in 0..bsize {
@@ -349,7 +361,7 @@ fn text_embeddings(
         sd_config.clip2.as_ref().unwrap()
     };
     let text_model =
-        stable_diffusion::build_clip_transformer(clip_config, clip_weights, device, DType::F32)?;
+        stable_diffusion::build_clip_transformer(clip_config, &[clip_weights], device, DType::F32)?;
     let text_embeddings = text_model.forward(&tokens)?;
 
     let text_embeddings = if use_guide_scale {
@@ -407,6 +419,7 @@ fn run(args: Args) -> Result<()> {
         prompt,
         uncond_prompt,
         cpu,
+        vae_on_cpu,
         height,
         width,
         n_steps,
@@ -423,6 +436,7 @@ fn run(args: Args) -> Result<()> {
         use_f16,
         guidance_scale,
         use_flash_attn,
+        channels_last,
         img2img,
         img2img_strength,
         seed,
@@ -468,7 +482,12 @@ fn run(args: Args) -> Result<()> {
             stable_diffusion::StableDiffusionConfig::v2_1(sliced_attention_size, height, width)
         }
         StableDiffusionVersion::Xl => {
-            stable_diffusion::StableDiffusionConfig::sdxl(sliced_attention_size, height, width)
+            stable_diffusion::StableDiffusionConfig::sdxl_with(stable_diffusion::SdxlOptions {
+                height,
+                width,
+                sliced_attention_size,
+                ..Default::default()
+            })
         }
         StableDiffusionVersion::Turbo => stable_diffusion::StableDiffusionConfig::sdxl_turbo(
             sliced_attention_size,
@@ -513,17 +532,34 @@ fn run(args: Args) -> Result<()> {
 
     println!("Building the autoencoder.");
     let vae_weights = ModelFile::Vae.get(vae_weights, sd_version, use_f16)?;
-    let vae = sd_config.build_vae(vae_weights, &device, dtype)?;
+    let placement = if vae_on_cpu {
+        DevicePlacement::new(Device::Cpu, device.clone())
+    } else {
+        DevicePlacement::single(&device)
+    };
+    let vae = sd_config.build_vae(&[vae_weights], &placement.vae, dtype)?;
     let init_latent_dist = match &img2img {
         None => None,
         Some(image) => {
-            let image = image_preprocess(image)?.to_device(&device)?;
+            let image = image_preprocess(image)?.to_device(&placement.vae)?;
             Some(vae.encode(&image)?)
         }
     };
     println!("Building the unet.");
     let unet_weights = ModelFile::Unet.get(unet_weights, sd_version, use_f16)?;
-    let unet = sd_config.build_unet(unet_weights, &device, 4, use_flash_attn, dtype)?;
+    let memory_format = if channels_last {
+        stable_diffusion::resnet::MemoryFormat::ChannelsLast
+    } else {
+        stable_diffusion::resnet::MemoryFormat::ChannelsFirst
+    };
+    let unet = sd_config.build_unet(
+        &[unet_weights],
+        &device,
+        4,
+        use_flash_attn,
+        memory_format,
+        dtype,
+    )?;
 
     let t_start = if img2img.is_some() {
         n_steps - (n_steps as f64 * img2img_strength) as usize
@@ -542,10 +578,11 @@ fn run(args: Args) -> Result<()> {
         let timesteps = scheduler.timesteps();
         let latents = match &init_latent_dist {
             Some(init_latent_dist) => {
-                let latents = (init_latent_dist.sample()? * vae_scale)?.to_device(&device)?;
+                let latents =
+                    (init_latent_dist.sample()? * vae_scale)?.to_device(&placement.unet)?;
                 if t_start < timesteps.len() {
                     let noise = latents.randn_like(0f64, 1f64)?;
-                    scheduler.add_noise(&latents, noise, timesteps[t_start])?
+                    scheduler.add_noise(&latents, &noise, timesteps[t_start])?
                 } else {
                     latents
                 }
@@ -562,6 +599,12 @@ fn run(args: Args) -> Result<()> {
             }
         };
         let mut latents = latents.to_dtype(dtype)?;
+        // With guidance the unet input is written in place at each step rather than concatenated.
+        let mut input_buffer = if use_guide_scale {
+            Some(ModelInputBuffer::new(&latents, 2)?)
+        } else {
+            None
+        };
 
         println!("starting sampling");
         for (timestep_index, &timestep) in timesteps.iter().enumerate() {
@@ -569,10 +612,9 @@ fn run(args: Args) -> Result<()> {
                 continue;
             }
             let start_time = std::time::Instant::now();
-            let latent_model_input = if use_guide_scale {
-                Tensor::cat(&[&latents, &latents], 0)?
-            } else {
-                latents.clone()
+            let latent_model_input = match input_buffer.as_mut() {
+                Some(input_buffer) => input_buffer.fill(&latents)?.clone(),
+                None => latents.clone(),
             };
 
             let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep)?;
@@ -595,6 +637,7 @@ fn run(args: Args) -> Result<()> {
             if args.intermediary_images {
                 save_image(
                     &vae,
+                    &placement,
                     &latents,
                     vae_scale,
                     bsize,
@@ -613,6 +656,7 @@ fn run(args: Args) -> Result<()> {
         );
         save_image(
             &vae,
+            &placement,
             &latents,
             vae_scale,
             bsize,
@@ -48,6 +48,26 @@ kernel void FN_NAME##_strided( \
     output[id] = T(fma(float(input[get_strided_index(id, num_dims, dims, strides)]), mul, add)); \
 }
 
+// Per channel affine, `channels` is the size of the channel axis and `inner` the number of
+// elements after it, the scale and shift vectors have one value per channel.
+#define AFFINE_CHANNELWISE(FN_NAME, T) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    constant size_t &channels, \
+    constant size_t &inner, \
+    device const T *scale, \
+    device const T *shift, \
+    device const T *input,  \
+    device T *output, \
+    uint id [[ thread_position_in_grid ]] \
+) { \
+    if (id >= dim) { \
+        return; \
+    } \
+    const size_t c = (id / inner) % channels; \
+    output[id] = T(fma(float(input[id]), float(scale[c]), float(shift[c]))); \
+}
+
 #define POWF(FN_NAME, TYPENAME) \
 kernel void FN_NAME( \
     constant size_t &dim, \
@@ -108,19 +128,61 @@ kernel void FN_NAME##_strided( \
     output[id] = TYPENAME((x > 0)?x: mul * (exp(x) - 1)); \
 } \
 
+// Exponential moving average of parameters, ema = decay * ema + (1 - decay) * param, updated in
+// place. The update is done in float whatever T.
+#define EMA_UPDATE(FN_NAME, T) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    constant float &decay, \
+    device T *ema, \
+    device const T *param, \
+    uint id [[ thread_position_in_grid ]] \
+) { \
+    if (id >= dim) { \
+        return; \
+    } \
+    ema[id] = T(fma(float(ema[id]), decay, (1.0f - decay) * float(param[id]))); \
+} \
+
+// Dequantizes int8 weights stored as uint8 offset by 128, `inner` consecutive elements share the
+// scale of their output channel.
+#define DEQUANTIZE_INT8(FN_NAME, T) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    constant size_t &inner, \
+    device const uint8_t *weight, \
+    device const float *scale, \
+    device T *output, \
+    uint id [[ thread_position_in_grid ]] \
+) { \
+    if (id >= dim) { \
+        return; \
+    } \
+    output[id] = T((float(weight[id]) - 128.0f) * scale[id / inner]); \
+} \
+
 
 AFFINE(affine_u8, uint8_t)
 AFFINE(affine_u32, uint32_t)
 AFFINE(affine_f32, float)
 AFFINE(affine_f16, half)
+AFFINE_CHANNELWISE(affine_channelwise_f32, float)
+AFFINE_CHANNELWISE(affine_channelwise_f16, half)
 POWF(powf_f32, float)
 POWF(powf_f16, half)
 ELU(elu_f32, float)
 ELU(elu_f16, half)
+EMA_UPDATE(ema_update_f32, float)
+EMA_UPDATE(ema_update_f16, half)
+DEQUANTIZE_INT8(dequantize_int8_f32, float)
+DEQUANTIZE_INT8(dequantize_int8_f16, half)
 
 
 #if defined(__HAVE_BFLOAT__)
 AFFINE(affine_bf16, bfloat);
+AFFINE_CHANNELWISE(affine_channelwise_bf16, bfloat);
 POWF(powf_bf16, bfloat);
 ELU(elu_bf16, bfloat);
+EMA_UPDATE(ema_update_bf16, bfloat);
+DEQUANTIZE_INT8(dequantize_int8_bf16, bfloat);
 #endif
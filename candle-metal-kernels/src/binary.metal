@@ -54,6 +54,31 @@ kernel void FN_NAME_STRIDED( \
     output[tid] = OUT_TYPENAME(FN); \
 }
 
+// The scalar is passed as a float and converted to TYPENAME, reverse swaps the operands.
+#define BINARY_SCALAR(FN, TYPENAME, FN_NAME) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    constant float &scalar, \
+    constant bool &reverse, \
+    device const TYPENAME *input,  \
+    device TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    TYPENAME x = reverse ? TYPENAME(scalar) : input[tid]; \
+    TYPENAME y = reverse ? input[tid] : TYPENAME(scalar); \
+    output[tid] = TYPENAME(FN); \
+}
+
+#define BINARY_SCALAR_OP(FN, NAME) \
+BINARY_SCALAR(FN, float, NAME##_scalar_f32); \
+BINARY_SCALAR(FN, half, NAME##_scalar_f16);
+
+#define BFLOAT_BINARY_SCALAR_OP(FN, NAME) \
+BINARY_SCALAR(FN, bfloat, NAME##_scalar_bf16);
+
 #define BINARY_OP(FN, NAME) \
 BINARY(FN, float, float, NAME##_f32, NAME##_f32_strided); \
 BINARY(FN, half, half, NAME##_f16, NAME##_f16_strided); \
@@ -85,6 +110,13 @@ BINARY_OP(x / y, div)
 BINARY_OP(MIN(x, y), min)
 BINARY_OP(MAX(x, y), max)
 
+BINARY_SCALAR_OP(x + y, add)
+BINARY_SCALAR_OP(x - y, sub)
+BINARY_SCALAR_OP(x * y, mul)
+BINARY_SCALAR_OP(x / y, div)
+BINARY_SCALAR_OP(MIN(x, y), min)
+BINARY_SCALAR_OP(MAX(x, y), max)
+
 BINARY_OP_OUT(eq, x == y)
 BINARY_OP_OUT(ne, x != y)
 BINARY_OP_OUT(le, x <= y)
@@ -116,6 +148,13 @@ BFLOAT_BINARY_OP(x / y, div)
 BFLOAT_BINARY_OP(MIN(x, y), min)
 BFLOAT_BINARY_OP(MAX(x, y), max)
 
+BFLOAT_BINARY_SCALAR_OP(x + y, add)
+BFLOAT_BINARY_SCALAR_OP(x - y, sub)
+BFLOAT_BINARY_SCALAR_OP(x * y, mul)
+BFLOAT_BINARY_SCALAR_OP(x / y, div)
+BFLOAT_BINARY_SCALAR_OP(MIN(x, y), min)
+BFLOAT_BINARY_SCALAR_OP(MAX(x, y), max)
+
 BFLOAT_BINARY_OP_OUT(eq, x == y)
 BFLOAT_BINARY_OP_OUT(ne, x != y)
 BFLOAT_BINARY_OP_OUT(le, x <= y)
@@ -18,6 +18,51 @@ METAL_FUNC uint get_strided_index(
 
 using namespace metal;
 
+// Converts a float to half rounding to nearest even, this matches `half::f16::from_f32` as used
+// by the cpu backend. Values above the half max (65504), once rounded, overflow to infinity,
+// values below the smallest half normal become subnormals and the ones that are too small for a
+// subnormal become signed zeros. NaNs stay NaNs.
+METAL_FUNC half f32_to_f16(float value) {
+    uint x = as_type<uint>(value);
+    uint sign = (x >> 16) & 0x8000;
+    uint exp = (x >> 23) & 0xff;
+    uint man = x & 0x7fffff;
+
+    // Infinities and NaNs, make sure that NaNs remain NaNs.
+    if (exp == 0xff) {
+        uint nan_bit = man == 0 ? 0 : 0x0200;
+        return as_type<half>(ushort(sign | 0x7c00 | nan_bit | (man >> 13)));
+    }
+
+    int half_exp = int(exp) - 127 + 15;
+    if (half_exp >= 0x1f) {
+        return as_type<half>(ushort(sign | 0x7c00));
+    }
+
+    // In both branches below, the result is rounded up when the first discarded bit is set and
+    // either another discarded bit or the last kept bit is set.
+    if (half_exp <= 0) {
+        if (14 - half_exp > 24) {
+            return as_type<half>(ushort(sign));
+        }
+        man = man | 0x800000;
+        uint half_man = man >> (14 - half_exp);
+        uint round_bit = 1u << (13 - half_exp);
+        if ((man & round_bit) != 0 && (man & (3 * round_bit - 1)) != 0) {
+            half_man += 1;
+        }
+        return as_type<half>(ushort(sign | half_man));
+    }
+
+    // A carry out of the mantissa increments the exponent, up to infinity.
+    uint bits = sign | (uint(half_exp) << 10) | (man >> 13);
+    uint round_bit = 0x1000;
+    if ((man & round_bit) != 0 && (man & (3 * round_bit - 1)) != 0) {
+        bits += 1;
+    }
+    return as_type<half>(ushort(bits));
+}
+
 #define CAST(FN_NAME, FN_NAME_STRIDED, LEFT_TYPENAME, RIGHT_TYPENAME) \
 kernel void FN_NAME( \
     constant size_t &dim, \
@@ -72,6 +117,71 @@ kernel void FN_NAME_STRIDED( \
     output[tid] = static_cast<RIGHT_TYPENAME>(static_cast<IR_TYPENAME>(input[get_strided_index(tid, num_dims, dims, strides)])); \
 } \
 
+#define CAST_FN(FN_NAME, FN_NAME_STRIDED, LEFT_TYPENAME, RIGHT_TYPENAME, CAST_FUNC) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    device const LEFT_TYPENAME *input,  \
+    device RIGHT_TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = CAST_FUNC(input[tid]); \
+} \
+kernel void FN_NAME_STRIDED( \
+    constant size_t &dim, \
+    constant size_t &num_dims, \
+    constant size_t *dims, \
+    constant size_t *strides, \
+    device const LEFT_TYPENAME *input,  \
+    device RIGHT_TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = CAST_FUNC(input[get_strided_index(tid, num_dims, dims, strides)]); \
+} \
+
+// Converts a decoded image in [-1, 1], a contiguous (channels, height, width) tensor, to u8 in
+// (height, width, channels) order. The values are truncated after scaling like a cast would.
+template<typename T>
+METAL_FUNC void latent_to_rgb8(
+    constant size_t &channels,
+    constant size_t &height,
+    constant size_t &width,
+    device const T *input,
+    device uint8_t *output,
+    uint tid
+) {
+    if (tid >= channels * height * width) {
+        return;
+    }
+    const size_t c = tid % channels;
+    const size_t hw = tid / channels;
+    const float v = clamp(float(input[c * height * width + hw]) / 2.0f + 0.5f, 0.0f, 1.0f);
+    output[tid] = uint8_t(v * 255.0f);
+}
+
+#define LATENT_TO_RGB8(FN_NAME, T) \
+kernel void FN_NAME( \
+    constant size_t &channels, \
+    constant size_t &height, \
+    constant size_t &width, \
+    device const T *input, \
+    device uint8_t *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    latent_to_rgb8<T>(channels, height, width, input, output, tid); \
+} \
+
+LATENT_TO_RGB8(latent_to_rgb8_f32, float)
+LATENT_TO_RGB8(latent_to_rgb8_f16, half)
+#if defined(__HAVE_BFLOAT__)
+LATENT_TO_RGB8(latent_to_rgb8_bf16, bfloat)
+#endif
+
 // u32
 CAST(cast_u32_f32, cast_u32_f32_strided, uint32_t, float)
 CAST(cast_u32_u8, cast_u32_u8_strided, uint32_t, uint8_t)
@@ -113,7 +223,7 @@ CAST_THROUGH(cast_i64_bf16, cast_i64_bf16_strided, int64_t, bfloat, float)
 #endif
 
 // f32
-CAST(cast_f32_f16, cast_f32_f16_strided, float, half)
+CAST_FN(cast_f32_f16, cast_f32_f16_strided, float, half, f32_to_f16)
 CAST(cast_f32_u32, cast_f32_u32_strided, float, uint32_t)
 CAST(cast_f32_u8, cast_f32_u8_strided, float, uint8_t)
 CAST(cast_f32_i64, cast_f32_i64_strided, float, int64_t)
@@ -199,6 +199,70 @@ METAL_FUNC void upsample_nearest2d(
   dst[tid] = src[src_i];
 }
 
+// Depth to space: src (b_size, c * r * r, h, w) -> dst (b_size, c, h * r, w * r), the channel
+// c * r * r + i * r + j goes to the pixel (i, j) of each r x r block.
+template <typename T>
+METAL_FUNC void pixel_shuffle(
+    constant size_t &dst_numel,
+    constant size_t *src_dims,
+    constant size_t &r,
+    device const T *src,
+    device T *dst,
+    uint tid [[ thread_position_in_grid ]]
+) {
+  if (tid >= dst_numel) {
+    return;
+  }
+  const size_t c_in = src_dims[1];
+  const size_t h_in = src_dims[2];
+  const size_t w_in = src_dims[3];
+  const size_t c_out = c_in / (r * r);
+  const size_t h_out = h_in * r;
+  const size_t w_out = w_in * r;
+
+  const size_t b_idx = tid / (c_out * h_out * w_out);
+  const size_t c_idx = (tid / (h_out * w_out)) % c_out;
+  const size_t dst_h = (tid / w_out) % h_out;
+  const size_t dst_w = tid % w_out;
+
+  const size_t src_c = c_idx * r * r + (dst_h % r) * r + dst_w % r;
+  const size_t src_i = ((b_idx * c_in + src_c) * h_in + dst_h / r) * w_in + dst_w / r;
+  dst[tid] = src[src_i];
+}
+
+// Space to depth, the inverse of pixel_shuffle: src (b_size, c, h * r, w * r) -> dst
+// (b_size, c * r * r, h, w).
+template <typename T>
+METAL_FUNC void pixel_unshuffle(
+    constant size_t &dst_numel,
+    constant size_t *src_dims,
+    constant size_t &r,
+    device const T *src,
+    device T *dst,
+    uint tid [[ thread_position_in_grid ]]
+) {
+  if (tid >= dst_numel) {
+    return;
+  }
+  const size_t c_in = src_dims[1];
+  const size_t h_in = src_dims[2];
+  const size_t w_in = src_dims[3];
+  const size_t c_out = c_in * r * r;
+  const size_t h_out = h_in / r;
+  const size_t w_out = w_in / r;
+
+  const size_t b_idx = tid / (c_out * h_out * w_out);
+  const size_t c_idx = (tid / (h_out * w_out)) % c_out;
+  const size_t dst_h = (tid / w_out) % h_out;
+  const size_t dst_w = tid % w_out;
+
+  const size_t src_c = c_idx / (r * r);
+  const size_t src_h = dst_h * r + (c_idx / r) % r;
+  const size_t src_w = dst_w * r + c_idx % r;
+  const size_t src_i = ((b_idx * c_in + src_c) * h_in + src_h) * w_in + src_w;
+  dst[tid] = src[src_i];
+}
+
 #define IM2COL_OP(T, FN_NAME) \
 kernel void FN_NAME(  \
     constant size_t &dst_numel, \
@@ -250,6 +314,28 @@ kernel void FN_NAME(  \
   col2im1d<T>(dst_el, l_out, l_in, c_out, k_size, stride, src, dst, tid); \
 } \
  
+#define PIXEL_SHUFFLE_OP(TYPENAME, FN_NAME, FN_NAME_INV) \
+kernel void FN_NAME(  \
+    constant size_t &dst_numel, \
+    constant size_t *src_dims, \
+    constant size_t &r, \
+    device const TYPENAME *src, \
+    device TYPENAME *dst, \
+    uint tid [[ thread_position_in_grid ]] \
+) {  \
+  pixel_shuffle<TYPENAME>(dst_numel, src_dims, r, src, dst, tid); \
+} \
+kernel void FN_NAME_INV(  \
+    constant size_t &dst_numel, \
+    constant size_t *src_dims, \
+    constant size_t &r, \
+    device const TYPENAME *src, \
+    device TYPENAME *dst, \
+    uint tid [[ thread_position_in_grid ]] \
+) {  \
+  pixel_unshuffle<TYPENAME>(dst_numel, src_dims, r, src, dst, tid); \
+} \
+
 #define UPSAMPLE_NEAREST2D_OP(TYPENAME, FN_NAME) \
 kernel void FN_NAME(  \
     constant size_t &w_out, \
@@ -544,6 +630,95 @@ kernel void FN_NAME(  \
   conv_transpose2d<TYPENAME, TYPEACC>(w_out, h_out, stride, padding, out_padding, dilation, input_dims, input_stride, k_dims, k_stride, src, k, dst, tid); \
 } \
 
+// Writes the output of the direct convolution as (b, h_out, w_out, c_out) when set.
+constant bool conv2d_channels_last_ [[function_constant(0)]];
+constant bool conv2d_channels_last = is_function_constant_defined(conv2d_channels_last_) && conv2d_channels_last_;
+
+// Direct convolution, each thread computes one output element by accumulating over its
+// receptive field. Input is (b, c_in, h_in, w_in), kernel is (c_out, c_in, h_k, w_k). A
+// channels-last input is passed with the matching strides, the output is (b, c_out, h_out, w_out)
+// or (b, h_out, w_out, c_out) depending on conv2d_channels_last.
+template <typename T, typename A>
+METAL_FUNC void conv2d_direct(
+  constant size_t &w_out,
+  constant size_t &h_out,
+  constant size_t &stride,
+  constant size_t &padding,
+  constant size_t &dilation,
+  constant size_t *input_dims,
+  constant size_t *input_stride,
+  constant size_t *k_dims,
+  constant size_t *k_stride,
+  constant bool &has_bias,
+  device const T *src,
+  device const T *k,
+  device const T *bias,
+  device T *dst,
+  uint tid [[ thread_position_in_grid ]]
+) {
+  const size_t c_out = k_dims[0];
+  const size_t h_k = k_dims[2];
+  const size_t w_k = k_dims[3];
+  const size_t c_in = input_dims[1];
+  const size_t h_in = input_dims[2];
+  const size_t w_in = input_dims[3];
+
+  if (tid >= input_dims[0] * c_out * w_out * h_out) {
+    return;
+  }
+
+  const size_t b_idx = tid / (w_out * h_out * c_out);
+  size_t dst_c_idx, out_y, out_x;
+  if (conv2d_channels_last) {
+    dst_c_idx = tid % c_out;
+    out_x = (tid / c_out) % w_out;
+    out_y = (tid / (c_out * w_out)) % h_out;
+  } else {
+    dst_c_idx = (tid / (w_out * h_out)) % c_out;
+    out_y = (tid / w_out) % h_out;
+    out_x = tid % w_out;
+  }
+
+  const size_t src_idx0 = b_idx * input_stride[0];
+
+  A d = has_bias ? static_cast<A>(bias[dst_c_idx]) : 0;
+  for (size_t k_y = 0; k_y < h_k; ++k_y) {
+      const int inp_y = (int)(out_y * stride + k_y * dilation) - (int)padding;
+      if (inp_y < 0 || inp_y >= (int)h_in) continue;
+      for (size_t k_x = 0; k_x < w_k; ++k_x) {
+          const int inp_x = (int)(out_x * stride + k_x * dilation) - (int)padding;
+          if (inp_x < 0 || inp_x >= (int)w_in) continue;
+          for (size_t src_c_idx = 0; src_c_idx < c_in; ++src_c_idx) {
+              const size_t src_idx = src_idx0 + src_c_idx * input_stride[1] + inp_y * input_stride[2] + inp_x * input_stride[3];
+              const size_t k_idx = dst_c_idx * k_stride[0] + src_c_idx * k_stride[1] + k_y * k_stride[2] + k_x * k_stride[3];
+              d += static_cast<A>(src[src_idx]) * static_cast<A>(k[k_idx]);
+          }
+      }
+  }
+  dst[tid] = static_cast<T>(d);
+}
+
+#define CONV2D_DIRECT_OP(TYPENAME, TYPEACC, FN_NAME) \
+kernel void FN_NAME(  \
+    constant size_t &w_out, \
+    constant size_t &h_out, \
+    constant size_t &stride, \
+    constant size_t &padding, \
+    constant size_t &dilation, \
+    constant size_t *input_dims, \
+    constant size_t *input_stride, \
+    constant size_t *k_dims, \
+    constant size_t *k_stride, \
+    constant bool &has_bias, \
+    device const TYPENAME *src, \
+    device const TYPENAME *k, \
+    device const TYPENAME *bias, \
+    device TYPENAME *dst, \
+    uint tid [[ thread_position_in_grid ]] \
+) {  \
+  conv2d_direct<TYPENAME, TYPEACC>(w_out, h_out, stride, padding, dilation, input_dims, input_stride, k_dims, k_stride, has_bias, src, k, bias, dst, tid); \
+} \
+
 IM2COL_OP(float, im2col_f32)
 IM2COL_OP(half, im2col_f16)
 IM2COL_OP(uint8_t, im2col_u8)
@@ -568,6 +743,14 @@ UPSAMPLE_NEAREST2D_OP(uint32_t, upsample_nearest2d_u32)
 UPSAMPLE_NEAREST2D_OP(bfloat, upsample_nearest2d_bf16)
 #endif
 
+PIXEL_SHUFFLE_OP(float, pixel_shuffle_f32, pixel_unshuffle_f32)
+PIXEL_SHUFFLE_OP(half, pixel_shuffle_f16, pixel_unshuffle_f16)
+PIXEL_SHUFFLE_OP(uint8_t, pixel_shuffle_u8, pixel_unshuffle_u8)
+PIXEL_SHUFFLE_OP(uint32_t, pixel_shuffle_u32, pixel_unshuffle_u32)
+#if defined(__HAVE_BFLOAT__)
+PIXEL_SHUFFLE_OP(bfloat, pixel_shuffle_bf16, pixel_unshuffle_bf16)
+#endif
+
 MAXPOOL2D_OP(float, max_pool2d_f32)
 MAXPOOL2D_OP(half, max_pool2d_f16)
 MAXPOOL2D_OP(uint32_t, max_pool2d_u32)
@@ -595,5 +778,11 @@ CONVT1D_OP(bfloat, float, conv_transpose1d_bf16)
 CONVT2D_OP(float, float, conv_transpose2d_f32)
 CONVT2D_OP(half, float, conv_transpose2d_f16)
 #if defined(__HAVE_BFLOAT__)
-CONVT1D_OP(bfloat, float, conv_transpose2d_bf16)
+CONVT2D_OP(bfloat, float, conv_transpose2d_bf16)
+#endif
+
+CONV2D_DIRECT_OP(float, float, conv2d_direct_f32)
+CONV2D_DIRECT_OP(half, float, conv2d_direct_f16)
+#if defined(__HAVE_BFLOAT__)
+CONV2D_DIRECT_OP(bfloat, float, conv2d_direct_bf16)
 #endif
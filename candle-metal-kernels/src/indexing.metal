@@ -48,6 +48,41 @@ METAL_FUNC void index(
     output[tid] = input[strided_src_i];
 }
 
+// Same as `index` but walks the source with its actual strides, so the input can be any view
+// (transposed, sliced, ...). The destination is contiguous with `src_dims[dim]` replaced by
+// `ids_size`.
+template<typename TYPENAME, typename INDEX_TYPENAME>
+METAL_FUNC void index_strided(
+    constant size_t &dst_size,
+    constant size_t &num_dims,
+    constant size_t &dim,
+    constant size_t &ids_size,
+    constant size_t *src_dims,
+    constant size_t *src_strides,
+    const device TYPENAME *input,
+    const device INDEX_TYPENAME *input_ids,
+    device TYPENAME *output,
+    uint tid [[ thread_position_in_grid ]]
+) {
+    if (tid >= dst_size) {
+        return;
+    }
+    size_t idx = tid;
+    size_t strided_src_i = 0;
+    for (uint d = 0; d < num_dims; d++) {
+        const uint dim_idx = num_dims - 1 - d;
+        const size_t dim_size = dim_idx == dim ? ids_size : src_dims[dim_idx];
+        size_t coord = idx % dim_size;
+        idx /= dim_size;
+        if (dim_idx == dim) {
+            // Same out of bounds clamping as the contiguous version.
+            coord = min((size_t)input_ids[coord], src_dims[dim_idx] - 1);
+        }
+        strided_src_i += coord * src_strides[dim_idx];
+    }
+    output[tid] = input[strided_src_i];
+}
+
 # define INDEX_OP(NAME, INDEX_TYPENAME, TYPENAME) \
 kernel void NAME( \
     constant size_t &dst_size, \
@@ -64,6 +99,58 @@ kernel void NAME( \
     uint tid [[ thread_position_in_grid ]] \
 ) { \
     index<TYPENAME, INDEX_TYPENAME>(dst_size, left_size, src_dim_size, right_size, ids_size, contiguous, src_dims, src_strides, input, input_ids, output, tid); \
+} \
+kernel void NAME##_strided( \
+    constant size_t &dst_size, \
+    constant size_t &num_dims, \
+    constant size_t &dim, \
+    constant size_t &ids_size, \
+    constant size_t *src_dims, \
+    constant size_t *src_strides, \
+    const device TYPENAME *input, \
+    const device INDEX_TYPENAME *input_ids, \
+    device TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    index_strided<TYPENAME, INDEX_TYPENAME>(dst_size, num_dims, dim, ids_size, src_dims, src_strides, input, input_ids, output, tid); \
+}
+
+
+// Fused embedding lookup: copies the `ids` rows of a contiguous `[vocab_size, hidden_size]`
+// weight and multiplies them by `scale`.
+template<typename TYPENAME, typename INDEX_TYPENAME>
+METAL_FUNC void embedding(
+    constant size_t &dst_size,
+    constant size_t &vocab_size,
+    constant size_t &hidden_size,
+    constant float &scale,
+    const device TYPENAME *weight,
+    const device INDEX_TYPENAME *input_ids,
+    device TYPENAME *output,
+    uint tid [[ thread_position_in_grid ]]
+) {
+    if (tid >= dst_size) {
+        return;
+    }
+    const size_t id_i = tid / hidden_size;
+    const size_t hidden_i = tid % hidden_size;
+    // Force prevent out of bounds indexing, same as index_select.
+    const size_t row = min((size_t)input_ids[id_i], vocab_size - 1);
+    output[tid] = TYPENAME(float(weight[row * hidden_size + hidden_i]) * scale);
+}
+
+# define EMBEDDING_OP(NAME, INDEX_TYPENAME, TYPENAME) \
+kernel void NAME( \
+    constant size_t &dst_size, \
+    constant size_t &vocab_size, \
+    constant size_t &hidden_size, \
+    constant float &scale, \
+    const device TYPENAME *weight, \
+    const device INDEX_TYPENAME *input_ids, \
+    device TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    embedding<TYPENAME, INDEX_TYPENAME>(dst_size, vocab_size, hidden_size, scale, weight, input_ids, output, tid); \
 }
 
 
@@ -186,6 +273,85 @@ kernel void NAME( \
     index_add<TYPENAME, INDEX_TYPENAME>(dst_size, left_size, src_dim_size, right_size, dst_dim_size, ids_dim_size, input, input_ids, output, tid); \
 }
 
+template<typename TYPENAME>
+METAL_FUNC void repeat(
+    constant size_t &dst_size,
+    constant size_t &num_dims,
+    constant size_t *src_dims,
+    constant size_t *src_strides,
+    constant size_t *repeats,
+    const device TYPENAME *input,
+    device TYPENAME *output,
+    uint tid [[ thread_position_in_grid ]]
+) {
+    if (tid >= dst_size) {
+        return;
+    }
+    // Walk the output dims from the innermost one, each output coordinate maps back to the
+    // input coordinate modulo the input dim.
+    size_t idx = tid;
+    size_t src_i = 0;
+    for (uint d = 0; d < num_dims; d++) {
+        const uint dim_idx = num_dims - 1 - d;
+        const size_t dst_dim = src_dims[dim_idx] * repeats[dim_idx];
+        src_i += ((idx % dst_dim) % src_dims[dim_idx]) * src_strides[dim_idx];
+        idx /= dst_dim;
+    }
+    output[tid] = input[src_i];
+}
+
+# define REPEAT_OP(NAME, TYPENAME) \
+kernel void NAME( \
+    constant size_t &dst_size, \
+    constant size_t &num_dims, \
+    constant size_t *src_dims, \
+    constant size_t *src_strides, \
+    constant size_t *repeats, \
+    const device TYPENAME *input, \
+    device TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    repeat<TYPENAME>(dst_size, num_dims, src_dims, src_strides, repeats, input, output, tid); \
+}
+
+template<typename TYPENAME>
+METAL_FUNC void narrow(
+    constant size_t &dst_size,
+    constant size_t &num_dims,
+    constant size_t *dst_dims,
+    constant size_t *src_strides,
+    constant size_t *starts,
+    const device TYPENAME *input,
+    device TYPENAME *output,
+    uint tid [[ thread_position_in_grid ]]
+) {
+    if (tid >= dst_size) {
+        return;
+    }
+    size_t idx = tid;
+    size_t src_i = 0;
+    for (uint d = 0; d < num_dims; d++) {
+        const uint dim_idx = num_dims - 1 - d;
+        src_i += (idx % dst_dims[dim_idx] + starts[dim_idx]) * src_strides[dim_idx];
+        idx /= dst_dims[dim_idx];
+    }
+    output[tid] = input[src_i];
+}
+
+# define NARROW_OP(NAME, TYPENAME) \
+kernel void NAME( \
+    constant size_t &dst_size, \
+    constant size_t &num_dims, \
+    constant size_t *dst_dims, \
+    constant size_t *src_strides, \
+    constant size_t *starts, \
+    const device TYPENAME *input, \
+    device TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    narrow<TYPENAME>(dst_size, num_dims, dst_dims, src_strides, starts, input, output, tid); \
+}
+
 
 INDEX_OP(is_i64_f32, int64_t, float)
 INDEX_OP(is_i64_f16, int64_t, half)
@@ -205,6 +371,15 @@ INDEX_OP(is_u8_f16, uint8_t, half)
 INDEX_OP(is_u8_bf16, uint8_t, bfloat)
 #endif
 
+EMBEDDING_OP(embedding_u32_f32, uint32_t, float)
+EMBEDDING_OP(embedding_u32_f16, uint32_t, half)
+EMBEDDING_OP(embedding_i64_f32, int64_t, float)
+EMBEDDING_OP(embedding_i64_f16, int64_t, half)
+#if defined(__HAVE_BFLOAT__)
+EMBEDDING_OP(embedding_u32_bf16, uint32_t, bfloat)
+EMBEDDING_OP(embedding_i64_bf16, int64_t, bfloat)
+#endif
+
 GATHER_OP(gather_u32_f32, uint, float)
 GATHER_OP(gather_u32_f16, uint, half)
 #if defined(__HAVE_BFLOAT__)
@@ -252,3 +427,25 @@ INDEX_ADD_OP(ia_u8_u8, uint8_t, uint8_t)
 #if defined(__HAVE_BFLOAT__)
 INDEX_ADD_OP(ia_u8_bf16, uint8_t, bfloat)
 #endif
+
+REPEAT_OP(repeat_f32, float)
+REPEAT_OP(repeat_f16, half)
+REPEAT_OP(repeat_u8, uint8_t)
+REPEAT_OP(repeat_u32, uint32_t)
+#if __METAL_VERSION__ >= 220
+REPEAT_OP(repeat_i64, int64_t)
+#endif
+#if defined(__HAVE_BFLOAT__)
+REPEAT_OP(repeat_bf16, bfloat)
+#endif
+
+NARROW_OP(narrow_f32, float)
+NARROW_OP(narrow_f16, half)
+NARROW_OP(narrow_u8, uint8_t)
+NARROW_OP(narrow_u32, uint32_t)
+#if __METAL_VERSION__ >= 220
+NARROW_OP(narrow_i64, int64_t)
+#endif
+#if defined(__HAVE_BFLOAT__)
+NARROW_OP(narrow_bf16, bfloat)
+#endif
@@ -1,14 +1,15 @@
 use metal::{
     Buffer, CompileOptions, ComputeCommandEncoderRef, ComputePipelineState, Device, Function,
-    FunctionConstantValues, Library, MTLDataType, MTLSize, NSUInteger,
+    FunctionConstantValues, Library, MTLDataType, MTLLanguageVersion, MTLSize, NSUInteger,
 };
 use std::collections::HashMap;
 use std::ffi::c_void;
 use std::sync::RwLock;
 
+pub mod reference;
 mod utils;
-pub use utils::BufferOffset;
 use utils::{get_block_dims, linear_split, EncoderProvider};
+pub use utils::{BufferOffset, BufferView};
 
 const AFFINE: &str = include_str!("affine.metal");
 const BINARY: &str = include_str!("binary.metal");
@@ -17,11 +18,14 @@ const CONV: &str = include_str!("conv.metal");
 const FILL: &str = include_str!("fill.metal");
 const INDEXING: &str = include_str!("indexing.metal");
 // Current source: https://github.com/ivarflakstad/metal-flash-attention/tree/candle
+#[cfg(feature = "metal-flash-attention")]
 const MFA: &[u8] = include_bytes!("libMetalFlashAttention.metallib");
 const MLX_GEMM: &str = include_str!("mlx_gemm.metal");
 const QUANTIZED: &str = include_str!("quantized.metal");
 const RANDOM: &str = include_str!("random.metal");
 const REDUCE: &str = include_str!("reduce.metal");
+const SAMPLING: &str = include_str!("sampling.metal");
+const SDPA: &str = include_str!("sdpa.metal");
 const SORT: &str = include_str!("sort.metal");
 const TERNARY: &str = include_str!("ternary.metal");
 const UNARY: &str = include_str!("unary.metal");
@@ -35,15 +39,47 @@ pub enum Source {
     Fill,
     Gemm,
     Indexing,
+    /// The precompiled metal flash attention library, this requires the `metal-flash-attention`
+    /// feature.
+    #[cfg(feature = "metal-flash-attention")]
     Mfa,
     Quantized,
     Random,
     Reduce,
+    Sampling,
+    Sdpa,
     Sort,
     Ternary,
     Unary,
 }
 
+impl Source {
+    /// Every source, e.g. to load all the libraries upfront with [`Kernels::load_library`].
+    /// `Mfa`, only available with the `metal-flash-attention` feature, is a precompiled library,
+    /// the other sources are compiled from their MSL code.
+    pub fn all() -> &'static [Source] {
+        &[
+            Source::Affine,
+            Source::Binary,
+            Source::Cast,
+            Source::Conv,
+            Source::Fill,
+            Source::Gemm,
+            Source::Indexing,
+            #[cfg(feature = "metal-flash-attention")]
+            Source::Mfa,
+            Source::Quantized,
+            Source::Random,
+            Source::Reduce,
+            Source::Sampling,
+            Source::Sdpa,
+            Source::Sort,
+            Source::Ternary,
+            Source::Unary,
+        ]
+    }
+}
+
 pub mod copy2d {
     pub struct Kernel(pub &'static str);
     pub const FLOAT: Kernel = Kernel("copy2d_f32");
@@ -131,14 +167,69 @@ macro_rules! ops{
     };
 }
 
+macro_rules! cast_ops{
+    ($($name:ident),+) => {
+        pub mod cast {
+        pub struct Kernel(pub &'static str);
+        $(
+        pub mod $name {
+            use super::Kernel;
+            pub const HALF_FLOAT: Kernel = Kernel(concat!(stringify!($name), "_f16_f32"));
+            pub const FLOAT_HALF: Kernel = Kernel(concat!(stringify!($name), "_f32_f16"));
+            pub const BFLOAT_FLOAT: Kernel = Kernel(concat!(stringify!($name), "_bf16_f32"));
+            pub const FLOAT_BFLOAT: Kernel = Kernel(concat!(stringify!($name), "_f32_bf16"));
+        }
+        )+
+        }
+    };
+}
+
+macro_rules! scalar_ops{
+    ($($name:ident),+) => {
+        pub mod scalar {
+        pub struct Kernel(pub &'static str);
+        $(
+        pub mod $name {
+            use super::Kernel;
+            pub const FLOAT: Kernel = Kernel(concat!(stringify!($name), "_scalar_f32"));
+            pub const HALF: Kernel = Kernel(concat!(stringify!($name), "_scalar_f16"));
+            pub const BFLOAT: Kernel = Kernel(concat!(stringify!($name), "_scalar_bf16"));
+        }
+        )+
+        }
+    };
+}
+
 pub mod unary {
     ops!(
         cos, sin, exp, sqr, sqrt, neg, log, gelu, abs, ceil, floor, relu, round, erf, gelu_erf,
-        tanh, recip, silu, sign, sigmoid
+        tanh, recip, silu, sign, sigmoid, rsqrt, sqrt_clamp
+    );
+    // The input and output dtypes of these kernels differ, e.g. `exp::HALF_FLOAT` reads f16
+    // values and writes f32 ones.
+    cast_ops!(
+        cos, sin, exp, sqr, sqrt, neg, log, gelu, abs, ceil, floor, relu, round, erf, gelu_erf,
+        tanh, recip, silu, sign, sigmoid, rsqrt, sqrt_clamp
     );
+    // f16 and bf16 kernels with a fast path selected by an `ErfPrecision`.
+    pub mod precision {
+        pub struct Kernel(pub &'static str);
+        pub mod erf {
+            use super::Kernel;
+            pub const HALF: Kernel = Kernel("erf_precision_f16");
+            pub const BFLOAT: Kernel = Kernel("erf_precision_bf16");
+        }
+        pub mod gelu_erf {
+            use super::Kernel;
+            pub const HALF: Kernel = Kernel("gelu_erf_precision_f16");
+            pub const BFLOAT: Kernel = Kernel("gelu_erf_precision_bf16");
+        }
+    }
 }
 pub mod binary {
     ops!(add, sub, mul, div, min, max, eq, ne, le, lt, ge, gt);
+    // Kernels applying the op between each element and a scalar, see `call_binary_scalar`.
+    scalar_ops!(add, sub, mul, div, min, max);
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -159,6 +250,47 @@ pub enum MetalKernelError {
         rhs_stride: Vec<usize>,
         mnk: (usize, usize, usize),
     },
+    #[error("dtype {0:?} is not supported on this device, bfloat requires Metal 3.1 (macOS 14 or iOS 17), consider using f16 or f32 instead")]
+    DTypeUnsupported(DType),
+    #[error("{kernel} is not available for dtype {dtype:?}")]
+    UnsupportedDTypeForOp { kernel: &'static str, dtype: DType },
+    #[error("{kernel}: length {length} is not a multiple of {divisor}")]
+    IndivisibleLength {
+        kernel: &'static str,
+        length: usize,
+        divisor: usize,
+    },
+    #[error("Could not dump the kernel source to {path:?}: {error}")]
+    SourceDumpError {
+        path: std::path::PathBuf,
+        error: String,
+    },
+    #[error("Invalid softmax temperature {0}, it has to be positive and finite")]
+    InvalidTemperature(f32),
+    #[error("Invalid reduction axes {axes:?} for a tensor of rank {rank}")]
+    InvalidReduceAxes { axes: Vec<usize>, rank: usize },
+    #[error("Invalid attention scale {0}, it has to be positive and finite")]
+    InvalidAttentionScale(f32),
+    #[error("Causal attention requires at least as many keys as queries, got {kv_len} keys for {q_len} queries")]
+    InvalidCausalAttention { q_len: usize, kv_len: usize },
+    #[error("Invalid narrow range {start}..{end} for dim {dim} of size {dim_size}")]
+    NarrowOutOfBounds {
+        dim: usize,
+        start: usize,
+        end: usize,
+        dim_size: usize,
+    },
+}
+
+/// The element types supported by the kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DType {
+    BF16,
+    F16,
+    F32,
+    I64,
+    U32,
+    U8,
 }
 
 impl<T> From<std::sync::PoisonError<T>> for MetalKernelError {
@@ -167,13 +299,115 @@ impl<T> From<std::sync::PoisonError<T>> for MetalKernelError {
     }
 }
 
-type Libraries = HashMap<Source, Library>;
-type Pipelines = HashMap<(&'static str, Option<ConstantValues>), ComputePipelineState>;
+/// Kernels commonly dispatched during a stable diffusion denoising step in f16, these can be
+/// passed to [`Kernels::warmup`] to avoid compiling them lazily on the first step.
+pub const STABLE_DIFFUSION_KERNELS: &[(Source, &str)] = &[
+    (Source::Affine, "affine_f16"),
+    (Source::Binary, "add_f16"),
+    (Source::Binary, "add_f16_strided"),
+    (Source::Binary, "div_f16_strided"),
+    (Source::Binary, "mul_f16"),
+    (Source::Binary, "mul_f16_strided"),
+    (Source::Binary, "sub_f16_strided"),
+    (Source::Cast, "cast_f16_f32"),
+    (Source::Cast, "cast_f32_f16"),
+    (Source::Conv, "im2col_f16"),
+    (Source::Conv, "upsample_nearest2d_f16"),
+    (Source::Reduce, "fast_sum_f16_strided"),
+    (Source::Reduce, "softmax_f16"),
+    (Source::Reduce, "layernorm_f16"),
+    (Source::Unary, "copy2d_f16"),
+    (Source::Unary, "copy_f16_strided"),
+    (Source::Unary, "gelu_f16"),
+    (Source::Unary, "silu_f16"),
+    (Source::Unary, "sqrt_f16"),
+];
+
+/// Options used when compiling the libraries that are built from source. These are part of the
+/// library cache key so that the same source compiled with different options is cached twice.
+#[derive(Debug, Clone, Copy)]
+pub struct LibraryCompileOptions {
+    /// Let the compiler use fast-math optimizations, this is the Metal default.
+    pub fast_math: bool,
+    /// The MSL version to target, the latest version supported by the device if not set.
+    pub language_version: Option<MTLLanguageVersion>,
+}
+
+impl Default for LibraryCompileOptions {
+    fn default() -> Self {
+        Self {
+            fast_math: true,
+            language_version: None,
+        }
+    }
+}
+
+impl LibraryCompileOptions {
+    fn compile_options(&self) -> CompileOptions {
+        let options = CompileOptions::new();
+        options.set_fast_math_enabled(self.fast_math);
+        if let Some(language_version) = self.language_version {
+            options.set_language_version(language_version);
+        }
+        options
+    }
+}
+
+impl PartialEq for LibraryCompileOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.fast_math == other.fast_math
+            && self.language_version.map(|v| v as u64) == other.language_version.map(|v| v as u64)
+    }
+}
+
+impl Eq for LibraryCompileOptions {}
+
+impl std::hash::Hash for LibraryCompileOptions {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.fast_math.hash(state);
+        self.language_version.map(|v| v as u64).hash(state);
+    }
+}
+
+// A minimal library that only compiles when the device supports the bfloat type.
+const BFLOAT_PROBE: &str = r#"
+#include <metal_stdlib>
+using namespace metal;
+#if !defined(__HAVE_BFLOAT__)
+#error "bfloat is not supported"
+#endif
+kernel void bfloat_probe(device bfloat *output [[buffer(0)]]) {
+    output[0] = bfloat(0.0f);
+}
+"#;
+
+fn is_bfloat_kernel(name: &str) -> bool {
+    name.contains("bf16") || name == "bgemm"
+}
 
+// Libraries and pipelines belong to the device they were created on, the caches are keyed by the
+// device registry id so that a `Kernels` shared by several devices never returns an object of
+// another device. The autotuned gemm tiles are keyed the same way as they were benchmarked on a
+// single device.
+type Libraries = HashMap<(u64, Source, LibraryCompileOptions), Library>;
+type Pipelines = HashMap<(u64, Source, &'static str, Option<ConstantValues>), ComputePipelineState>;
+#[cfg(feature = "metal-flash-attention")]
+type GemmTiles = HashMap<(u64, &'static str, usize, usize, usize), GemmTile>;
+
+/// Compiles and caches the kernel libraries and pipelines, a single instance can be used with
+/// multiple devices.
 #[derive(Debug)]
 pub struct Kernels {
     libraries: RwLock<Libraries>,
     pipelines: RwLock<Pipelines>,
+    compile_options: LibraryCompileOptions,
+    source_compile_options: HashMap<Source, LibraryCompileOptions>,
+    bfloat_supported: RwLock<HashMap<u64, bool>>,
+    source_dump_dir: Option<std::path::PathBuf>,
+    #[cfg(feature = "metal-flash-attention")]
+    gemm_tiles: RwLock<GemmTiles>,
+    #[cfg(feature = "metal-flash-attention")]
+    gemm_autotune: bool,
 }
 
 impl Default for Kernels {
@@ -184,12 +418,175 @@ impl Default for Kernels {
 
 impl Kernels {
     pub fn new() -> Self {
+        Self::new_with_compile_options(LibraryCompileOptions::default())
+    }
+
+    /// Creates the kernels using the given options to compile all the libraries.
+    pub fn new_with_compile_options(compile_options: LibraryCompileOptions) -> Self {
         let libraries = RwLock::new(Libraries::new());
         let pipelines = RwLock::new(Pipelines::new());
         Self {
             libraries,
             pipelines,
+            compile_options,
+            source_compile_options: HashMap::new(),
+            bfloat_supported: RwLock::new(HashMap::new()),
+            source_dump_dir: None,
+            #[cfg(feature = "metal-flash-attention")]
+            gemm_tiles: RwLock::new(GemmTiles::new()),
+            #[cfg(feature = "metal-flash-attention")]
+            gemm_autotune: false,
+        }
+    }
+
+    /// Whether the device supports the bfloat type, this is probed once per device by compiling
+    /// a small library and cached afterwards.
+    pub fn supports_bfloat(&self, device: &Device) -> bool {
+        let id = device.registry_id();
+        let cached = self
+            .bfloat_supported
+            .read()
+            .ok()
+            .and_then(|s| s.get(&id).copied());
+        if let Some(supported) = cached {
+            return supported;
+        }
+        let supported = device
+            .new_library_with_source(BFLOAT_PROBE, &CompileOptions::new())
+            .is_ok();
+        if let Ok(mut bfloat_supported) = self.bfloat_supported.write() {
+            bfloat_supported.insert(id, supported);
+        }
+        supported
+    }
+
+    /// Overrides the compile options for a single source, e.g. to disable fast-math for
+    /// numerically sensitive kernels.
+    pub fn with_source_compile_options(
+        mut self,
+        source: Source,
+        compile_options: LibraryCompileOptions,
+    ) -> Self {
+        self.source_compile_options.insert(source, compile_options);
+        self
+    }
+
+    /// Writes the MSL source of each library to `dir` before it gets compiled for the first
+    /// time, e.g. `affine.metal` for [`Source::Affine`], so that compile failures can be
+    /// investigated. The function constants of specialized pipelines are written along, in
+    /// `<kernel name>.constants.txt`, and precompiled libraries only get a note in
+    /// `<source>.txt`. The directory has to exist.
+    pub fn with_source_dump(mut self, dir: impl Into<std::path::PathBuf>) -> Self {
+        self.source_dump_dir = Some(dir.into());
+        self
+    }
+
+    #[cfg(feature = "metal-flash-attention")]
+    /// Makes [`call_gemm`] benchmark the [`GemmTile::CANDIDATES`] the first time it sees a
+    /// `(m, n, k)` problem size for a given kernel on a device and use the fastest tile
+    /// afterwards, see [`Kernels::autotune_gemm`]. This blocks the first call on running the
+    /// benchmarks.
+    pub fn with_gemm_autotune(mut self) -> Self {
+        self.gemm_autotune = true;
+        self
+    }
+
+    #[cfg(feature = "metal-flash-attention")]
+    /// The tile selected by the autotuner on this device for this gemm kernel and problem size,
+    /// if any.
+    pub fn gemm_tile(
+        &self,
+        device: &Device,
+        name: &'static str,
+        (m, n, k): (usize, usize, usize),
+    ) -> Option<GemmTile> {
+        let tiles = self.gemm_tiles.read().ok()?;
+        tiles.get(&(device.registry_id(), name, m, n, k)).copied()
+    }
+
+    #[cfg(feature = "metal-flash-attention")]
+    /// Returns the fastest of the [`GemmTile::CANDIDATES`] for multiplying a `(m, k)` matrix by
+    /// a `(k, n)` one with the `name` MFA kernel. The candidates are timed on their own command
+    /// queue on first use and the winner is cached for the device and problem size.
+    pub fn autotune_gemm(
+        &self,
+        device: &Device,
+        name: &'static str,
+        (m, n, k): (usize, usize, usize),
+    ) -> Result<GemmTile, MetalKernelError> {
+        if let Some(tile) = self.gemm_tile(device, name, (m, n, k)) {
+            return Ok(tile);
+        }
+        const REPEATS: usize = 3;
+        let bytes = gemm_dtype_size(name)?;
+        let options = metal::MTLResourceOptions::StorageModePrivate;
+        let lhs = device.new_buffer((m * k * bytes).max(1) as u64, options);
+        let rhs = device.new_buffer((k * n * bytes).max(1) as u64, options);
+        let output = device.new_buffer((m * n * bytes).max(1) as u64, options);
+        let command_queue = device.new_command_queue();
+        let mut best: Option<(std::time::Duration, GemmTile)> = None;
+        for tile in GemmTile::CANDIDATES {
+            let run = |repeats: usize| -> Result<std::time::Duration, MetalKernelError> {
+                let command_buffer = command_queue.new_command_buffer();
+                for _ in 0..repeats {
+                    call_gemm_with_tile(
+                        device,
+                        command_buffer,
+                        self,
+                        name,
+                        (1, m, n, k),
+                        &[m * k, k, 1],
+                        0,
+                        &lhs,
+                        &[k * n, n, 1],
+                        0,
+                        &rhs,
+                        (1., 0.),
+                        &output,
+                        tile,
+                    )?;
+                }
+                let start = std::time::Instant::now();
+                command_buffer.commit();
+                command_buffer.wait_until_completed();
+                Ok(start.elapsed())
+            };
+            // The first run compiles the pipeline, a tile that cannot be built is skipped.
+            if run(1).is_err() {
+                continue;
+            }
+            let elapsed = run(REPEATS)?;
+            match best {
+                Some((best_elapsed, _)) if best_elapsed <= elapsed => {}
+                _ => best = Some((elapsed, tile)),
+            }
+        }
+        let tile = match best {
+            Some((_, tile)) => tile,
+            None => GemmTile::default_for(m),
+        };
+        let mut tiles = self.gemm_tiles.write()?;
+        let key = (device.registry_id(), name, m, n, k);
+        Ok(*tiles.entry(key).or_insert(tile))
+    }
+
+    fn dump(&self, file_name: &str, content: &str) -> Result<(), MetalKernelError> {
+        if let Some(dir) = self.source_dump_dir.as_ref() {
+            let path = dir.join(file_name);
+            std::fs::write(&path, content).map_err(|e| MetalKernelError::SourceDumpError {
+                path,
+                error: e.to_string(),
+            })?
         }
+        Ok(())
+    }
+
+    /// The options used to compile the library for [`source`].
+    pub fn compile_options(&self, source: Source) -> LibraryCompileOptions {
+        self.source_compile_options
+            .get(&source)
+            .copied()
+            .unwrap_or(self.compile_options)
     }
 
     fn get_library_source(&self, source: Source) -> &'static str {
@@ -204,9 +601,12 @@ impl Kernels {
             Source::Quantized => QUANTIZED,
             Source::Random => RANDOM,
             Source::Reduce => REDUCE,
+            Source::Sampling => SAMPLING,
+            Source::Sdpa => SDPA,
             Source::Sort => SORT,
             Source::Ternary => TERNARY,
             Source::Unary => UNARY,
+            #[cfg(feature = "metal-flash-attention")]
             Source::Mfa => panic!("Invalid lib"),
         }
     }
@@ -218,12 +618,33 @@ impl Kernels {
         device: &Device,
         source: Source,
     ) -> Result<Library, MetalKernelError> {
+        self.load_library_with_options(device, source, self.compile_options(source))
+    }
+
+    /// Load the give library from its [`source`] compiled with the given options.
+    /// If this has been previously loaded with the same options it will just fetch it from cache.
+    pub fn load_library_with_options(
+        &self,
+        device: &Device,
+        source: Source,
+        compile_options: LibraryCompileOptions,
+    ) -> Result<Library, MetalKernelError> {
+        let key = (device.registry_id(), source, compile_options);
+        // Cache hits only take the read lock, the write lock is only taken on a miss and the
+        // cache is checked again as another thread may have loaded the library in between.
+        if let Some(lib) = self.libraries.read()?.get(&key) {
+            return Ok(lib.clone());
+        }
         let mut libraries = self.libraries.write()?;
-        if let Some(lib) = libraries.get(&source) {
+        if let Some(lib) = libraries.get(&key) {
             Ok(lib.clone())
         } else {
+            let source_name = format!("{source:?}").to_lowercase();
             let lib = match source {
+                #[cfg(feature = "metal-flash-attention")]
                 Source::Mfa => {
+                    let note = "precompiled metallib, the MSL source is not available\n";
+                    self.dump(&format!("{source_name}.txt"), note)?;
                     let source_data = MFA;
                     device.new_library_with_data(source_data).map_err(|e| {
                         MetalKernelError::LoadLibraryError(format!(
@@ -233,12 +654,13 @@ impl Kernels {
                 }
                 source => {
                     let source_content = self.get_library_source(source);
+                    self.dump(&format!("{source_name}.metal"), source_content)?;
                     device
-                        .new_library_with_source(source_content, &CompileOptions::new())
+                        .new_library_with_source(source_content, &compile_options.compile_options())
                         .map_err(|e| MetalKernelError::LoadLibraryError(e.to_string()))?
                 }
             };
-            libraries.insert(source, lib.clone());
+            libraries.insert(key, lib.clone());
             Ok(lib)
         }
     }
@@ -267,12 +689,26 @@ impl Kernels {
         name: &'static str,
         constants: Option<ConstantValues>,
     ) -> Result<ComputePipelineState, MetalKernelError> {
+        if is_bfloat_kernel(name) && !self.supports_bfloat(device) {
+            return Err(MetalKernelError::DTypeUnsupported(DType::BF16));
+        }
+        let key = (device.registry_id(), source, name, constants);
+        // Same double-checked locking as in `load_library`, so that dispatches from multiple
+        // threads do not get serialized on cache hits.
+        if let Some(pipeline) = self.pipelines.read()?.get(&key) {
+            return Ok(pipeline.clone());
+        }
         let mut pipelines = self.pipelines.write()?;
-        let key = (name, constants);
         if let Some(pipeline) = pipelines.get(&key) {
             Ok(pipeline.clone())
         } else {
-            let (name, constants) = key;
+            let (device_id, source, name, constants) = key;
+            if let Some(constants) = constants.as_ref() {
+                self.dump(
+                    &format!("{name}.constants.txt"),
+                    &format!("{constants:#?}\n"),
+                )?;
+            }
             let func = self.load_function(
                 device,
                 source,
@@ -282,7 +718,7 @@ impl Kernels {
             let pipeline = device
                 .new_compute_pipeline_state_with_function(&func)
                 .map_err(|e| MetalKernelError::FailedToCreatePipeline(e.to_string()))?;
-            pipelines.insert((name, constants), pipeline.clone());
+            pipelines.insert((device_id, source, name, constants), pipeline.clone());
 
             Ok(pipeline)
         }
@@ -299,6 +735,47 @@ impl Kernels {
     ) -> Result<ComputePipelineState, MetalKernelError> {
         self.load_pipeline_with_constants(device, source, name, None)
     }
+
+    /// Eagerly compiles the libraries and pipelines for the given kernels so that the first
+    /// dispatch using them does not pay for the compilation.
+    /// Kernels that have already been loaded are skipped.
+    pub fn warmup(
+        &self,
+        device: &Device,
+        kernels: &[(Source, &'static str)],
+    ) -> Result<(), MetalKernelError> {
+        for &(source, name) in kernels {
+            self.load_pipeline(device, source, name)?;
+        }
+        Ok(())
+    }
+
+    /// The sources which library has been compiled, each source is only reported once even if
+    /// it has been compiled with different options. This is meant for diagnostics.
+    pub fn loaded_sources(&self) -> Result<Vec<Source>, MetalKernelError> {
+        let mut sources: Vec<Source> = vec![];
+        for &(_, source, _) in self.libraries.read()?.keys() {
+            if !sources.contains(&source) {
+                sources.push(source)
+            }
+        }
+        Ok(sources)
+    }
+
+    /// The source and name of the compiled pipelines sorted by name, kernels compiled with
+    /// different function constants or for different devices are reported once per set of
+    /// constants and device. This is meant for
+    /// diagnostics, e.g. checking that a warmup covered the kernels used afterwards.
+    pub fn compiled_pipelines(&self) -> Result<Vec<(Source, String)>, MetalKernelError> {
+        let mut pipelines: Vec<(Source, String)> = self
+            .pipelines
+            .read()?
+            .keys()
+            .map(|&(_, source, name, _)| (source, name.to_string()))
+            .collect();
+        pipelines.sort_by(|(_, n1), (_, n2)| n1.cmp(n2));
+        Ok(pipelines)
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -317,7 +794,7 @@ pub fn call_copy2d(
     dst_o_in_bytes: usize,
 ) -> Result<(), MetalKernelError> {
     let pipeline = kernels.load_pipeline(device, Source::Unary, name.0)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     set_params!(
@@ -355,7 +832,7 @@ pub fn call_unary_contiguous_tiled(
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
     let pipeline = kernels.load_pipeline(device, Source::Unary, kernel_name.0)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(kernel_name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     let tile_size = 2;
     let tiles = (length + tile_size - 1) / tile_size;
@@ -381,423 +858,459 @@ pub fn call_unary_contiguous(
     input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Unary, kernel_name.0)?;
-    let encoder = ep.encoder();
-    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(encoder, (length, &input, output));
-
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
-    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
-    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
-    Ok(())
+    call_unary_contiguous_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        length,
+        input,
+        BufferOffset::zero_offset(output),
+    )
 }
 
+/// Same as [`call_unary_contiguous`] but writes the result at `output.offset_in_bytes`.
 #[allow(clippy::too_many_arguments)]
-pub fn call_unary_strided(
+pub fn call_unary_contiguous_with_offset(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    name: unary::strided::Kernel,
-    shape: &[usize],
+    kernel_name: unary::contiguous::Kernel,
+    length: usize,
     input: BufferOffset,
-    strides: &[usize],
     output: BufferOffset,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Unary, name.0)?;
-
-    let length: usize = shape.iter().product();
-    let num_dims: usize = shape.len();
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Unary, kernel_name.0)?;
+    let encoder = ep.encoder_with_label(kernel_name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
 
     encoder.set_compute_pipeline_state(&pipeline);
-    set_params!(encoder, (length, num_dims, shape, strides, &input, &output));
+
+    set_params!(encoder, (length, &input, &output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn call_binary_contiguous(
+/// Applies a unary op while converting between dtypes, e.g. `unary::cast::exp::HALF_FLOAT` reads
+/// f16 values, computes the exponential in f32 and writes f32 values in a single dispatch rather
+/// than a cast followed by the op. The same-dtype kernels of [`unary::contiguous`] already
+/// compute in f32 for f16 and bf16 inputs, e.g. `exp::HALF` is the fused f16 to f32 to f16 chain.
+pub fn call_cast_unary(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: binary::contiguous::Kernel,
+    kernel_name: unary::cast::Kernel,
     length: usize,
-    left: BufferOffset,
-    right: BufferOffset,
+    input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Binary, kernel_name.0)?;
-
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Unary, kernel_name.0)?;
+    let encoder = ep.encoder_with_label(kernel_name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(encoder, (length, &left, &right, output));
+    set_params!(encoder, (length, &input, output));
 
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
-
-    encoder.use_resource(left.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(right.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Encodes the unary kernel `iterations` times back to back on the same encoder, the pipeline
+/// and arguments are only set once. This is meant for benchmarking the amortized cost of a kernel
+/// without host overhead between dispatches. `input` and `output` can be the same buffer to apply
+/// the op `iterations` times.
 #[allow(clippy::too_many_arguments)]
-pub fn call_binary_strided(
+pub fn call_unary_contiguous_repeated(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    name: binary::strided::Kernel,
-    shape: &[usize],
-    left_input: BufferOffset,
-    left_strides: &[usize],
-    right_input: BufferOffset,
-    right_strides: &[usize],
-    output: &Buffer,
+    kernel_name: unary::contiguous::Kernel,
+    length: usize,
+    input: BufferOffset,
+    output: BufferOffset,
+    iterations: usize,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Binary, name.0)?;
-
-    let num_dims: usize = shape.len();
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Unary, kernel_name.0)?;
+    let encoder = ep.encoder_with_label(kernel_name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    let width: usize = shape.iter().product();
-    let length: usize = shape.iter().product();
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, width);
 
     encoder.set_compute_pipeline_state(&pipeline);
-    set_params!(
-        encoder,
-        (
-            length,
-            num_dims,
-            shape,
-            left_strides,
-            right_strides,
-            &left_input,
-            &right_input,
-            output
-        )
-    );
-    encoder.use_resource(left_input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(right_input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
-    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
 
+    set_params!(encoder, (length, &input, &output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    for _ in 0..iterations {
+        encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    }
+    Ok(())
+}
+
+/// Calls `f` `iterations` times with a single compute encoder, `f` can then call any of the
+/// `call_*` functions with this encoder as the encoder provider. This is the generic version
+/// of [`call_unary_contiguous_repeated`], the pipeline and arguments are set on each iteration.
+pub fn call_repeated<F>(
+    ep: impl EncoderProvider,
+    iterations: usize,
+    mut f: F,
+) -> Result<(), MetalKernelError>
+where
+    F: FnMut(&ComputeCommandEncoderRef) -> Result<(), MetalKernelError>,
+{
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    for _ in 0..iterations {
+        f(encoder)?
+    }
     Ok(())
 }
 
+/// The GELU formulation, models are trained with a specific one and using the other one
+/// subtly degrades their outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeluVariant {
+    /// `0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))`, this is the `gelu` kernel.
+    Tanh,
+    /// `0.5 * x * (1 + erf(x / sqrt(2)))`, this is the `gelu_erf` kernel.
+    Erf,
+}
+
+/// Applies the GELU activation using the kernel for the given [`GeluVariant`].
 #[allow(clippy::too_many_arguments)]
-pub fn call_cast_contiguous(
+pub fn call_gelu(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
+    variant: GeluVariant,
+    dtype: DType,
     length: usize,
     input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Cast, kernel_name)?;
-
-    let encoder = ep.encoder();
-    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(encoder, (length, &input, output));
+    use unary::contiguous::{gelu, gelu_erf};
+    let kernel_name = match (variant, dtype) {
+        (GeluVariant::Tanh, DType::F32) => gelu::FLOAT,
+        (GeluVariant::Tanh, DType::F16) => gelu::HALF,
+        (GeluVariant::Tanh, DType::BF16) => gelu::BFLOAT,
+        (GeluVariant::Erf, DType::F32) => gelu_erf::FLOAT,
+        (GeluVariant::Erf, DType::F16) => gelu_erf::HALF,
+        (GeluVariant::Erf, DType::BF16) => gelu_erf::BFLOAT,
+        (_, dtype) => {
+            return Err(MetalKernelError::UnsupportedDTypeForOp {
+                kernel: "gelu",
+                dtype,
+            })
+        }
+    };
+    call_unary_contiguous(device, ep, kernels, kernel_name, length, input, output)
+}
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
-    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
-    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
-    Ok(())
+/// How `erf` is evaluated by the [`unary::precision`] kernels.
+///
+/// The accurate path converts the input to f32 and uses the same formula as the `erf` and
+/// `gelu_erf` kernels. The fast path evaluates a lower degree polynomial (A&S formula 7.1.27)
+/// directly in f16 or bf16, avoiding the conversions and the exponential at the cost of
+/// precision. Compared to the accurate path over `[-6, 6]`, the absolute error of `erf` is
+/// below `4e-3` for f16 and `2.5e-2` for bf16, the error of `gelu_erf` is below the same bound
+/// times `max(1, |x|)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ErfPrecision {
+    #[default]
+    Accurate,
+    Fast,
 }
 
+/// Applies `erf` or `gelu_erf` on f16 or bf16 values with the given [`ErfPrecision`], the fast
+/// path is selected with a function constant so each precision gets its own pipeline.
 #[allow(clippy::too_many_arguments)]
-pub fn call_cast_strided(
+pub fn call_unary_with_precision(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
-    shape: &[usize],
+    kernel_name: unary::precision::Kernel,
+    precision: ErfPrecision,
+    length: usize,
     input: BufferOffset,
-    input_strides: &[usize],
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Cast, kernel_name)?;
-
-    let encoder = ep.encoder();
+    let constants = Some(ConstantValues::new(vec![(
+        0,
+        Value::Bool(precision == ErfPrecision::Fast),
+    )]));
+    let pipeline =
+        kernels.load_pipeline_with_constants(device, Source::Unary, kernel_name.0, constants)?;
+    let encoder = ep.encoder_with_label(kernel_name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
 
-    let length: usize = shape.iter().product();
+    encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(
-        encoder,
-        (length, shape.len(), shape, input_strides, &input, output)
-    );
+    set_params!(encoder, (length, &input, output));
 
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
-
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Whether a layout with the given shape and strides is contiguous in row major order, the
+/// strides of dims of size 1 do not matter.
+pub fn is_contiguous(shape: &[usize], strides: &[usize]) -> bool {
+    if shape.len() != strides.len() {
+        return false;
+    }
+    let mut expected = 1;
+    for (&dim, &stride) in shape.iter().zip(strides.iter()).rev() {
+        if dim != 1 && stride != expected {
+            return false;
+        }
+        expected *= dim;
+    }
+    true
+}
+
+/// Applies a unary op to a possibly strided input, the contiguous kernel is used when the
+/// layout [`is_contiguous`] as it is faster than the strided one. The output is contiguous.
 #[allow(clippy::too_many_arguments)]
-pub fn call_reduce_contiguous(
+pub fn call_unary(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
-    length: usize,
-    out_length: usize,
+    name: unary::strided::Kernel,
+    shape: &[usize],
     input: BufferOffset,
-    output: &Buffer,
+    strides: &[usize],
+    output: BufferOffset,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let elements_to_sum = length / out_length;
-
-    let encoder = ep.encoder();
-    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(encoder, (length, elements_to_sum, &input, output));
+    match name.0.strip_suffix("_strided") {
+        Some(contiguous) if is_contiguous(shape, strides) => call_unary_contiguous_with_offset(
+            device,
+            ep,
+            kernels,
+            unary::contiguous::Kernel(contiguous),
+            shape.iter().product(),
+            input,
+            output,
+        ),
+        _ => call_unary_strided(device, ep, kernels, name, shape, input, strides, output),
+    }
+}
 
-    let thread_group_count = MTLSize {
-        width: out_length as u64,
-        height: 1,
-        depth: 1,
+#[allow(clippy::too_many_arguments)]
+pub fn call_unary_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: unary::strided::Kernel,
+    shape: &[usize],
+    input: BufferOffset,
+    strides: &[usize],
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let args = UnaryStridedArgs {
+        shape,
+        input: BufferView::new(input.buffer, strides).with_offset(input.offset_in_bytes),
+        output,
     };
+    call_unary_strided_args(device, ep, kernels, name, args)
+}
 
-    let width = std::cmp::min(
-        pipeline.max_total_threads_per_threadgroup(),
-        (elements_to_sum as u64 + 2 - 1) / 2,
-    )
-    .next_power_of_two();
+/// Arguments of a strided unary op, the output is contiguous with shape `shape`.
+pub struct UnaryStridedArgs<'a> {
+    pub shape: &'a [usize],
+    pub input: BufferView<'a>,
+    pub output: BufferOffset<'a>,
+}
 
-    let thread_group_size = MTLSize {
-        width,
-        height: 1,
-        depth: 1,
-    };
+pub fn call_unary_strided_args(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: unary::strided::Kernel,
+    args: UnaryStridedArgs,
+) -> Result<(), MetalKernelError> {
+    let UnaryStridedArgs {
+        shape,
+        input,
+        output,
+    } = args;
+    let pipeline = kernels.load_pipeline(device, Source::Unary, name.0)?;
+
+    let length: usize = shape.iter().product();
+    let num_dims: usize = shape.len();
+    let encoder = ep.encoder_with_label(name.0);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
 
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(
+        encoder,
+        (
+            length,
+            num_dims,
+            shape,
+            input.strides,
+            &input.buffer_offset(),
+            &output
+        )
+    );
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Same as [`call_unary_strided`] but with signed strides, e.g. to apply an op to a reversed
+/// view without copying it first. `input.offset_in_bytes` is the offset of the first element of
+/// the view, negative strides go backwards from there.
 #[allow(clippy::too_many_arguments)]
-pub fn call_reduce_strided(
+pub fn call_unary_strided_signed(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
+    name: unary::strided::Kernel,
     shape: &[usize],
-    strides: &[usize],
-    out_length: usize,
     input: BufferOffset,
-    output: &Buffer,
+    strides: &[i64],
+    output: BufferOffset,
 ) -> Result<(), MetalKernelError> {
-    let length: usize = shape.iter().product();
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let elements_to_sum = length / out_length;
+    let pipeline = kernels.load_pipeline(device, Source::Unary, name.0)?;
 
-    let encoder = ep.encoder();
+    let length: usize = shape.iter().product();
+    let num_dims: usize = shape.len();
+    let encoder = ep.encoder_with_label(name.0);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(
-        encoder,
-        (shape.len(), shape, strides, elements_to_sum, &input, output)
-    );
-
-    let thread_group_count = MTLSize {
-        width: out_length as u64,
-        height: 1,
-        depth: 1,
-    };
-
-    let width = std::cmp::min(
-        pipeline.max_total_threads_per_threadgroup(),
-        elements_to_sum as u64,
-    )
-    .next_power_of_two();
-
-    let thread_group_size = MTLSize {
-        width,
-        height: 1,
-        depth: 1,
-    };
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
 
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (length, num_dims, shape, strides, &input, &output));
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn call_last_softmax(
+fn call_unary_mask(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     kernel_name: &'static str,
     length: usize,
-    elements_to_sum: usize,
-    input: &Buffer,
-    input_offset: usize,
+    input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Unary, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(
-        encoder,
-        (length, elements_to_sum, (input, input_offset), output)
-    );
-
-    let out_length = length / elements_to_sum;
-
-    let thread_group_count = MTLSize {
-        width: out_length as u64,
-        height: 1,
-        depth: 1,
-    };
 
-    let width = std::cmp::min(
-        pipeline.max_total_threads_per_threadgroup(),
-        elements_to_sum as u64,
-    )
-    .next_power_of_two();
+    encoder.set_compute_pipeline_state(&pipeline);
 
-    let thread_group_size = MTLSize {
-        width,
-        height: 1,
-        depth: 1,
-    };
+    set_params!(encoder, (length, &input, output));
 
-    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn call_rms_norm(
+/// Writes a u8 mask to `output` that is 1 where the input is NaN and 0 elsewhere.
+pub fn call_isnan(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
+    dtype: DType,
     length: usize,
-    elements_to_sum: usize,
-    eps: f32,
-    input: &Buffer,
-    input_offset: usize,
-    alpha: &Buffer,
-    alpha_offset: usize,
+    input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let encoder = ep.encoder();
-    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(
-        encoder,
-        (
-            length,
-            elements_to_sum,
-            (input, input_offset),
-            output,
-            (alpha, alpha_offset),
-            eps
-        )
-    );
-
-    let out_length = length / elements_to_sum;
-
-    let thread_group_count = MTLSize {
-        width: out_length as u64,
-        height: 1,
-        depth: 1,
+    let kernel_name = match dtype {
+        DType::F32 => "isnan_f32",
+        DType::F16 => "isnan_f16",
+        DType::BF16 => "isnan_bf16",
+        dtype => {
+            return Err(MetalKernelError::UnsupportedDTypeForOp {
+                kernel: "isnan",
+                dtype,
+            })
+        }
     };
+    call_unary_mask(device, ep, kernels, kernel_name, length, input, output)
+}
 
-    let width = std::cmp::min(
-        pipeline.max_total_threads_per_threadgroup(),
-        elements_to_sum as u64,
-    )
-    .next_power_of_two();
-
-    let thread_group_size = MTLSize {
-        width,
-        height: 1,
-        depth: 1,
+/// Writes a u8 mask to `output` that is 1 where the input is neither NaN nor infinite and 0
+/// elsewhere.
+pub fn call_isfinite(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    dtype: DType,
+    length: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let kernel_name = match dtype {
+        DType::F32 => "isfinite_f32",
+        DType::F16 => "isfinite_f16",
+        DType::BF16 => "isfinite_bf16",
+        dtype => {
+            return Err(MetalKernelError::UnsupportedDTypeForOp {
+                kernel: "isfinite",
+                dtype,
+            })
+        }
     };
-
-    encoder.use_resource(input, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
-    encoder.set_threadgroup_memory_length(0, (width * 4).max(16) as u64);
-    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
-    Ok(())
+    call_unary_mask(device, ep, kernels, kernel_name, length, input, output)
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn call_layer_norm(
+/// Writes a single u8 to `output` which is 1 if any element of the input is NaN or infinite and
+/// 0 otherwise, e.g. to detect overflows when using dynamic loss scaling.
+/// The whole buffer is reduced by a single threadgroup.
+pub fn call_any_nonfinite(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
+    dtype: DType,
     length: usize,
-    elements_to_sum: usize,
-    eps: f32,
-    input: &Buffer,
-    input_offset: usize,
-    alpha: &Buffer,
-    alpha_offset: usize,
-    beta: &Buffer,
-    beta_offset: usize,
+    input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
+    let kernel_name = match dtype {
+        DType::F32 => "any_nonfinite_f32",
+        DType::F16 => "any_nonfinite_f16",
+        DType::BF16 => "any_nonfinite_bf16",
+        dtype => {
+            return Err(MetalKernelError::UnsupportedDTypeForOp {
+                kernel: "any_nonfinite",
+                dtype,
+            })
+        }
+    };
     let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(kernel_name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(
-        encoder,
-        (
-            length,
-            elements_to_sum,
-            (input, input_offset),
-            output,
-            (alpha, alpha_offset),
-            (beta, beta_offset),
-            eps
-        )
-    );
-
-    let out_length = length / elements_to_sum;
+    set_params!(encoder, (length, &input, output));
 
     let thread_group_count = MTLSize {
-        width: out_length as u64,
+        width: 1,
         height: 1,
         depth: 1,
     };
 
     let width = std::cmp::min(
         pipeline.max_total_threads_per_threadgroup(),
-        elements_to_sum as u64,
+        length.max(1) as u64,
     )
     .next_power_of_two();
 
@@ -807,398 +1320,1984 @@ pub fn call_layer_norm(
         depth: 1,
     };
 
-    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
-    encoder.set_threadgroup_memory_length(0, (width * 8).max(32) as u64);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// The inputs are read from their `offset_in_bytes`, use [`call_binary_contiguous_with_offset`]
+/// to also write to a sub-region of the output, e.g. when all the buffers live in an arena.
 #[allow(clippy::too_many_arguments)]
-pub fn call_rope_i(
+pub fn call_binary_contiguous(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
-    bh: usize,
-    td: usize,
-    src: &Buffer,
-    src_offset: usize,
-    cos: &Buffer,
-    cos_offset: usize,
-    sin: &Buffer,
-    sin_offset: usize,
+    kernel_name: binary::contiguous::Kernel,
+    length: usize,
+    left: BufferOffset,
+    right: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let encoder = ep.encoder();
-    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    call_binary_contiguous_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        length,
+        left,
+        right,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_binary_contiguous`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_binary_contiguous_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: binary::contiguous::Kernel,
+    length: usize,
+    left: BufferOffset,
+    right: BufferOffset,
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Binary, kernel_name.0)?;
+
+    let encoder = ep.encoder_with_label(kernel_name.0);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, &left, &right, &output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+
+    encoder.use_resource(left.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(right.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Applies a binary op between each of the `length` elements of `input` and `scalar`, this avoids
+/// materializing a broadcast buffer for the scalar operand. The op computes `x op scalar` or, when
+/// `reverse` is set, `scalar op x`, e.g. `scalar - x` or `scalar / x` for the non-commutative ops.
+/// The scalar is converted to the dtype of the kernel before applying the op.
+#[allow(clippy::too_many_arguments)]
+pub fn call_binary_scalar(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: binary::scalar::Kernel,
+    length: usize,
+    input: BufferOffset,
+    scalar: f32,
+    output: &Buffer,
+    reverse: bool,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Binary, kernel_name.0)?;
+
+    let encoder = ep.encoder_with_label(kernel_name.0);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, scalar, reverse, &input, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_binary_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: binary::strided::Kernel,
+    shape: &[usize],
+    left_input: BufferOffset,
+    left_strides: &[usize],
+    right_input: BufferOffset,
+    right_strides: &[usize],
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_binary_strided_with_offset(
+        device,
+        ep,
+        kernels,
+        name,
+        shape,
+        left_input,
+        left_strides,
+        right_input,
+        right_strides,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_binary_strided`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_binary_strided_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: binary::strided::Kernel,
+    shape: &[usize],
+    left_input: BufferOffset,
+    left_strides: &[usize],
+    right_input: BufferOffset,
+    right_strides: &[usize],
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let args = BinaryStridedArgs {
+        shape,
+        left: BufferView::new(left_input.buffer, left_strides)
+            .with_offset(left_input.offset_in_bytes),
+        right: BufferView::new(right_input.buffer, right_strides)
+            .with_offset(right_input.offset_in_bytes),
+        output,
+    };
+    call_binary_strided_args(device, ep, kernels, name, args)
+}
+
+/// Arguments of a strided binary op, the output is contiguous with shape `shape`.
+pub struct BinaryStridedArgs<'a> {
+    pub shape: &'a [usize],
+    pub left: BufferView<'a>,
+    pub right: BufferView<'a>,
+    pub output: BufferOffset<'a>,
+}
+
+pub fn call_binary_strided_args(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: binary::strided::Kernel,
+    args: BinaryStridedArgs,
+) -> Result<(), MetalKernelError> {
+    let BinaryStridedArgs {
+        shape,
+        left,
+        right,
+        output,
+    } = args;
+    let pipeline = kernels.load_pipeline(device, Source::Binary, name.0)?;
+
+    let num_dims: usize = shape.len();
+    let encoder = ep.encoder_with_label(name.0);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    let length: usize = shape.iter().product();
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(
+        encoder,
+        (
+            length,
+            num_dims,
+            shape,
+            left.strides,
+            right.strides,
+            &left.buffer_offset(),
+            &right.buffer_offset(),
+            &output
+        )
+    );
+    encoder.use_resource(left.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(right.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+
+    Ok(())
+}
+
+/// Casts `length` elements of `input` using the given cast kernel, e.g. `cast_f32_f16`.
+///
+/// The `f32` to `f16` narrowing rounds to nearest even like the cpu backend, values that are
+/// too large for a `f16` become infinities and values that are too small for a normal `f16` are
+/// rounded to subnormals or to zero.
+#[allow(clippy::too_many_arguments)]
+pub fn call_cast_contiguous(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_cast_contiguous_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        length,
+        input,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_cast_contiguous`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_cast_contiguous_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    input: BufferOffset,
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Cast, kernel_name)?;
+
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, &input, &output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Converts the output of a VAE decoder, a contiguous `(channels, height, width)` image with
+/// values in `[-1, 1]`, to a `(height, width, channels)` `u8` buffer ready to be encoded: the
+/// values are mapped to `[0, 1]`, clamped, scaled to 255 and truncated. `name` is one of
+/// `latent_to_rgb8_{f32,f16,bf16}`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_latent_to_rgb8(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    (channels, height, width): (usize, usize, usize),
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Cast, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (channels, height, width, &input, output));
+
+    let length = channels * height * width;
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_cast_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    shape: &[usize],
+    input: BufferOffset,
+    input_strides: &[usize],
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_cast_strided_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        shape,
+        input,
+        input_strides,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_cast_strided`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_cast_strided_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    shape: &[usize],
+    input: BufferOffset,
+    input_strides: &[usize],
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Cast, kernel_name)?;
+
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let length: usize = shape.iter().product();
+
+    set_params!(
+        encoder,
+        (length, shape.len(), shape, input_strides, &input, &output)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_reduce_contiguous(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    out_length: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_reduce_contiguous_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        length,
+        out_length,
+        input,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_reduce_contiguous`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_reduce_contiguous_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    out_length: usize,
+    input: BufferOffset,
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    if out_length == 0 || length % out_length != 0 {
+        return Err(MetalKernelError::IndivisibleLength {
+            kernel: kernel_name,
+            length,
+            divisor: out_length,
+        });
+    }
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let elements_to_sum = length / out_length;
+
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, elements_to_sum, &input, &output));
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        (elements_to_sum as u64 + 2 - 1) / 2,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_reduce_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    shape: &[usize],
+    strides: &[usize],
+    out_length: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_reduce_strided_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        shape,
+        strides,
+        out_length,
+        input,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_reduce_strided`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_reduce_strided_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    shape: &[usize],
+    strides: &[usize],
+    out_length: usize,
+    input: BufferOffset,
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let length: usize = shape.iter().product();
+    if out_length == 0 || length % out_length != 0 {
+        return Err(MetalKernelError::IndivisibleLength {
+            kernel: kernel_name,
+            length,
+            divisor: out_length,
+        });
+    }
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let elements_to_sum = length / out_length;
+
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            shape.len(),
+            shape,
+            strides,
+            elements_to_sum,
+            &input,
+            &output
+        )
+    );
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Reduces the strided input over all the given `axes` in a single dispatch, e.g. the spatial
+/// dims of a `(b, c, h, w)` tensor with `axes` set to `[2, 3]`.
+///
+/// The reduced axes are moved last in the layout passed to the strided kernel so that each
+/// output reduces a consecutive run of elements, no copy of the input is made. The axes have to
+/// be distinct and in range. The output is contiguous and has the returned shape, this is
+/// `shape` without the reduced axes.
+#[allow(clippy::too_many_arguments)]
+pub fn call_reduce_multi_axis(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    shape: &[usize],
+    strides: &[usize],
+    axes: &[usize],
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<Vec<usize>, MetalKernelError> {
+    let rank = shape.len();
+    let invalid = || MetalKernelError::InvalidReduceAxes {
+        axes: axes.to_vec(),
+        rank,
+    };
+    if axes.is_empty() {
+        return Err(invalid());
+    }
+    let mut reduced = vec![false; rank];
+    for &axis in axes {
+        if axis >= rank || reduced[axis] {
+            return Err(invalid());
+        }
+        reduced[axis] = true;
+    }
+    // The reduced axes keep their relative order, so that the index of an element within its
+    // run does not depend on the order of `axes`.
+    let kept = (0..rank).filter(|&d| !reduced[d]);
+    let order: Vec<usize> = kept
+        .clone()
+        .chain((0..rank).filter(|&d| reduced[d]))
+        .collect();
+    let permuted_shape: Vec<usize> = order.iter().map(|&d| shape[d]).collect();
+    let permuted_strides: Vec<usize> = order.iter().map(|&d| strides[d]).collect();
+    let out_shape: Vec<usize> = kept.map(|d| shape[d]).collect();
+    call_reduce_strided(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        &permuted_shape,
+        &permuted_strides,
+        out_shape.iter().product(),
+        input,
+        output,
+    )?;
+    Ok(out_shape)
+}
+
+/// Reduces all the `length` contiguous elements of `input` to the single element of `output`
+/// with a value reduction kernel such as `fast_sum_f32_strided`.
+///
+/// Reducing a large buffer with a single threadgroup is slow, so this first reduces the input
+/// to partial results, one threadgroup each, and then reduces the partials. The number of
+/// partials has to divide `length`, the largest divisor up to 1024 is used and a length without
+/// a small enough divisor, e.g. a large prime, falls back to a single pass.
+pub fn call_reduce_all(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    const MAX_PARTIALS: usize = 1024;
+    // There is no point in a second pass when each partial would only get a few elements.
+    let num_partials = if length < 4 * MAX_PARTIALS {
+        1
+    } else {
+        (1..=MAX_PARTIALS)
+            .rev()
+            .find(|p| length % p == 0)
+            .unwrap_or(1)
+    };
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    if num_partials == 1 {
+        return call_reduce_strided(
+            device,
+            encoder,
+            kernels,
+            kernel_name,
+            &[length],
+            &[1],
+            1,
+            input,
+            output,
+        );
+    }
+    // Large enough for the widest dtype, the buffer is retained by the command buffer until the
+    // second pass has run.
+    let partials = device.new_buffer(
+        (num_partials * std::mem::size_of::<i64>()) as u64,
+        metal::MTLResourceOptions::StorageModePrivate,
+    );
+    call_reduce_strided(
+        device,
+        encoder,
+        kernels,
+        kernel_name,
+        &[length],
+        &[1],
+        num_partials,
+        input,
+        &partials,
+    )?;
+    call_reduce_strided(
+        device,
+        encoder,
+        kernels,
+        kernel_name,
+        &[num_partials],
+        &[1],
+        1,
+        BufferOffset::zero_offset(&partials),
+        output,
+    )
+}
+
+/// Sums `shape.iter().product() / out_length` consecutive elements into each output.
+///
+/// With `deterministic` set, each output is summed sequentially by a single thread so the
+/// result is bit-identical across runs, this is much slower than the default tree
+/// reduction.
+#[allow(clippy::too_many_arguments)]
+pub fn call_sum_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    dtype: DType,
+    shape: &[usize],
+    strides: &[usize],
+    out_length: usize,
+    input: BufferOffset,
+    output: &Buffer,
+    deterministic: bool,
+) -> Result<(), MetalKernelError> {
+    if !deterministic {
+        let kernel_name = match dtype {
+            DType::F32 => "fast_sum_f32_strided",
+            DType::F16 => "fast_sum_f16_strided",
+            DType::BF16 => "fast_sum_bf16_strided",
+            DType::I64 => "fast_sum_i64_strided",
+            DType::U32 => "fast_sum_u32_strided",
+            DType::U8 => "fast_sum_u8_strided",
+        };
+        return call_reduce_strided(
+            device,
+            ep,
+            kernels,
+            kernel_name,
+            shape,
+            strides,
+            out_length,
+            input,
+            output,
+        );
+    }
+    let kernel_name = match dtype {
+        DType::F32 => "sequential_sum_f32_strided",
+        DType::F16 => "sequential_sum_f16_strided",
+        DType::BF16 => "sequential_sum_bf16_strided",
+        dtype => {
+            return Err(MetalKernelError::UnsupportedDTypeForOp {
+                kernel: "sequential_sum",
+                dtype,
+            })
+        }
+    };
+    let length: usize = shape.iter().product();
+    if out_length == 0 || length % out_length != 0 {
+        return Err(MetalKernelError::IndivisibleLength {
+            kernel: kernel_name,
+            length,
+            divisor: out_length,
+        });
+    }
+    let elements_to_sum = length / out_length;
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            shape.len(),
+            shape,
+            strides,
+            elements_to_sum,
+            out_length,
+            &input,
+            output
+        )
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, out_length);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Computes `max(|x|)` along the last dimension of `shape`, e.g. to get the per row scales when
+/// quantizing weights. The output has one element per row, `kernel_name` is one of
+/// `fast_absmax_{f32,f16,bf16}_strided`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_reduce_absmax(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    shape: &[usize],
+    strides: &[usize],
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let out_length = shape[..shape.len().saturating_sub(1)].iter().product();
+    call_reduce_strided(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        shape,
+        strides,
+        out_length,
+        input,
+        output,
+    )
+}
+
+/// Softmax over each contiguous group of `elements_to_sum` elements.
+///
+/// With a `temperature`, this computes `softmax(input / temperature)` in the same dispatch, the
+/// scaling is applied before subtracting the max. The temperature has to be positive, `None` is
+/// the same as a temperature of 1.
+#[allow(clippy::too_many_arguments)]
+pub fn call_last_softmax(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    input: &Buffer,
+    input_offset: usize,
+    output: &Buffer,
+    temperature: Option<f32>,
+) -> Result<(), MetalKernelError> {
+    call_last_softmax_with_offset(
+        device,
+        ep,
+        kernels,
+        kernel_name,
+        length,
+        elements_to_sum,
+        input,
+        input_offset,
+        BufferOffset::zero_offset(output),
+        temperature,
+    )
+}
+
+/// Same as [`call_last_softmax`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_last_softmax_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    input: &Buffer,
+    input_offset: usize,
+    output: BufferOffset,
+    temperature: Option<f32>,
+) -> Result<(), MetalKernelError> {
+    if elements_to_sum == 0 || length % elements_to_sum != 0 {
+        return Err(MetalKernelError::IndivisibleLength {
+            kernel: kernel_name,
+            length,
+            divisor: elements_to_sum,
+        });
+    }
+    let temperature = temperature.unwrap_or(1.);
+    if !(temperature > 0. && temperature.is_finite()) {
+        return Err(MetalKernelError::InvalidTemperature(temperature));
+    }
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            length,
+            elements_to_sum,
+            1. / temperature,
+            (input, input_offset),
+            &output
+        )
+    );
+
+    let out_length = length / elements_to_sum;
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Computes `logsumexp` over each contiguous group of `elements_to_sum` elements of `input`, e.g.
+/// along the last axis, and writes `length / elements_to_sum` values. The max of each group is
+/// subtracted before the exponentials so that large values do not overflow, the accumulation is
+/// done in f32. `kernel_name` is one of `logsumexp_f32`, `logsumexp_f16` or `logsumexp_bf16`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_logsumexp(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    if elements_to_sum == 0 || length % elements_to_sum != 0 {
+        return Err(MetalKernelError::IndivisibleLength {
+            kernel: kernel_name,
+            length,
+            divisor: elements_to_sum,
+        });
+    }
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, elements_to_sum, &input, output));
+
+    let thread_group_count = MTLSize {
+        width: (length / elements_to_sum) as u64,
+        height: 1,
+        depth: 1,
+    };
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_rms_norm(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    eps: f32,
+    input: &Buffer,
+    input_offset: usize,
+    alpha: &Buffer,
+    alpha_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            length,
+            elements_to_sum,
+            (input, input_offset),
+            output,
+            (alpha, alpha_offset),
+            eps
+        )
+    );
+
+    let out_length = length / elements_to_sum;
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.set_threadgroup_memory_length(0, (width * 4).max(16) as u64);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_layer_norm(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    eps: f32,
+    input: &Buffer,
+    input_offset: usize,
+    alpha: &Buffer,
+    alpha_offset: usize,
+    beta: &Buffer,
+    beta_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            length,
+            elements_to_sum,
+            (input, input_offset),
+            output,
+            (alpha, alpha_offset),
+            (beta, beta_offset),
+            eps
+        )
+    );
+
+    let out_length = length / elements_to_sum;
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.set_threadgroup_memory_length(0, (width * 8).max(32) as u64);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Computes the mean and the variance of each contiguous group of `elements_to_sum` elements in
+/// a single pass, the variance is divided by `elements_to_sum - 1` when `unbiased` is set. Both
+/// outputs hold `length / elements_to_sum` values of the input dtype.
+#[allow(clippy::too_many_arguments)]
+pub fn call_mean_var(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    unbiased: bool,
+    input: BufferOffset,
+    mean: &Buffer,
+    var: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (elements_to_sum, unbiased, &input, mean, var));
+
+    let out_length = length / elements_to_sum;
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    // The largest power of two that fits, the tree reduction needs one.
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum.max(1) as u64,
+    );
+    let width = (width + 1).next_power_of_two() / 2;
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(mean, metal::MTLResourceUsage::Write);
+    encoder.use_resource(var, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_rope_i(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    bh: usize,
+    td: usize,
+    src: &Buffer,
+    src_offset: usize,
+    cos: &Buffer,
+    cos_offset: usize,
+    sin: &Buffer,
+    sin_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            bh,
+            td,
+            (src, src_offset),
+            (cos, cos_offset),
+            (sin, sin_offset),
+            output
+        )
+    );
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, (bh * td) / 2);
+    encoder.use_resource(src, metal::MTLResourceUsage::Read);
+    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
+    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_rope_thd(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    b: usize,
+    t: usize,
+    h: usize,
+    d: usize,
+    src: &Buffer,
+    src_offset: usize,
+    cos: &Buffer,
+    cos_offset: usize,
+    sin: &Buffer,
+    sin_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            b,
+            t,
+            h,
+            d,
+            (src, src_offset),
+            (cos, cos_offset),
+            (sin, sin_offset),
+            output
+        )
+    );
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, (b * t * h * d) / 2);
+    encoder.use_resource(src, metal::MTLResourceUsage::Read);
+    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
+    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_rope(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    bh: usize,
+    td: usize,
+    d: usize,
+    src: &Buffer,
+    src_offset: usize,
+    cos: &Buffer,
+    cos_offset: usize,
+    sin: &Buffer,
+    sin_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder_with_label(kernel_name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            bh,
+            td,
+            d,
+            (src, src_offset),
+            (cos, cos_offset),
+            (sin, sin_offset),
+            output
+        )
+    );
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, (bh * td) / 2);
+    encoder.use_resource(src, metal::MTLResourceUsage::Read);
+    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
+    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// The input is read from its `offset_in_bytes`, use [`call_affine_with_offset`] to also write
+/// to a sub-region of the output.
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    size: usize,
+    input: BufferOffset,
+    output: &Buffer,
+    mul: f32,
+    add: f32,
+) -> Result<(), MetalKernelError> {
+    call_affine_with_offset(
+        device,
+        ep,
+        kernels,
+        name,
+        size,
+        input,
+        BufferOffset::zero_offset(output),
+        mul,
+        add,
+    )
+}
+
+/// Same as [`call_affine`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    size: usize,
+    input: BufferOffset,
+    output: BufferOffset,
+    mul: f32,
+    add: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, mul, add, &input, &output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Same as [`call_affine`] but overwrites the `size` elements of `buffer` with the result. Each
+/// thread reads and writes a single element so the kernel is safe to run in place.
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine_inplace(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    size: usize,
+    buffer: BufferOffset,
+    mul: f32,
+    add: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, mul, add, &buffer, &buffer));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(
+        buffer.buffer,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Updates the exponential moving average of a parameter in place, the `length` elements of
+/// `ema` become `decay * ema + (1 - decay) * param`. This takes one dispatch per parameter
+/// buffer, `name` is one of `ema_update_f32`, `ema_update_f16` or `ema_update_bf16`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_ema_update(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    length: usize,
+    ema: BufferOffset,
+    param: BufferOffset,
+    decay: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, decay, &ema, &param));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+    encoder.use_resource(
+        ema.buffer,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.use_resource(param.buffer, metal::MTLResourceUsage::Read);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Dequantizes `length` int8 weights stored as `u8` values offset by 128, each run of `inner`
+/// consecutive weights is multiplied by the next `f32` value of `scale`. `name` is one of
+/// `dequantize_int8_f32`, `dequantize_int8_f16` or `dequantize_int8_bf16` and sets the dtype of
+/// `output`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_dequantize_int8(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    length: usize,
+    inner: usize,
+    weight: BufferOffset,
+    scale: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (length, inner, &weight, &scale, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+    encoder.use_resource(weight.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(scale.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    input: BufferOffset,
+    input_stride: &[usize],
+    output: &Buffer,
+    mul: f32,
+    add: f32,
+) -> Result<(), MetalKernelError> {
+    call_affine_strided_with_offset(
+        device,
+        ep,
+        kernels,
+        name,
+        shape,
+        input,
+        input_stride,
+        BufferOffset::zero_offset(output),
+        mul,
+        add,
+    )
+}
+
+/// Same as [`call_affine_strided`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine_strided_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    input: BufferOffset,
+    input_stride: &[usize],
+    output: BufferOffset,
+    mul: f32,
+    add: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    let size: usize = shape.iter().product();
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
     set_params!(
         encoder,
         (
-            bh,
-            td,
-            (src, src_offset),
-            (cos, cos_offset),
-            (sin, sin_offset),
-            output
+            size,
+            shape.len(),
+            shape,
+            input_stride,
+            mul,
+            add,
+            &input,
+            &output
         )
     );
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, (bh * td) / 2);
-    encoder.use_resource(src, metal::MTLResourceUsage::Read);
-    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
-    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Computes `input * scale[c] + shift[c]` where `c` is the index along `channel_axis` of a
+/// contiguous tensor with the given shape, e.g. axis 1 for the affine part of a group norm on
+/// `(batch, channels, height, width)`. `scale` and `shift` hold one value per channel in the
+/// input dtype, `name` is one of `affine_channelwise_{f32,f16,bf16}`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_affine_channelwise(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    channel_axis: usize,
+    input: BufferOffset,
+    scale: &Buffer,
+    shift: &Buffer,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    let size: usize = shape.iter().product();
+    let channels = shape[channel_axis];
+    let inner: usize = shape[channel_axis + 1..].iter().product();
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (size, channels, inner, scale, shift, &input, output)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(scale, metal::MTLResourceUsage::Read);
+    encoder.use_resource(shift, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn call_rope_thd(
+pub fn call_powf(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
-    b: usize,
-    t: usize,
-    h: usize,
-    d: usize,
-    src: &Buffer,
-    src_offset: usize,
-    cos: &Buffer,
-    cos_offset: usize,
-    sin: &Buffer,
-    sin_offset: usize,
+    name: &'static str,
+    size: usize,
+    input: BufferOffset,
     output: &Buffer,
+    mul: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, mul, &input, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_powf_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    input: BufferOffset,
+    input_stride: &[usize],
+    output: &Buffer,
+    mul: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    let size: usize = shape.iter().product();
+
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
     set_params!(
         encoder,
-        (
-            b,
-            t,
-            h,
-            d,
-            (src, src_offset),
-            (cos, cos_offset),
-            (sin, sin_offset),
-            output
-        )
+        (size, shape.len(), shape, input_stride, mul, &input, output)
     );
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, (b * t * h * d) / 2);
-    encoder.use_resource(src, metal::MTLResourceUsage::Read);
-    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
-    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn call_rope(
+pub fn call_elu(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
-    kernel_name: &'static str,
-    bh: usize,
-    td: usize,
-    d: usize,
-    src: &Buffer,
-    src_offset: usize,
-    cos: &Buffer,
-    cos_offset: usize,
-    sin: &Buffer,
-    sin_offset: usize,
+    name: &'static str,
+    size: usize,
+    input: BufferOffset,
+    output: &Buffer,
+    mul: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (size, mul, &input, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_elu_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    input: BufferOffset,
+    input_stride: &[usize],
     output: &Buffer,
+    mul: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    let size: usize = shape.iter().product();
+
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (size, shape.len(), shape, input_stride, mul, &input, output)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn call_where_cond_strided(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    cond: BufferOffset,
+    cond_stride: &[usize],
+    left: BufferOffset,
+    left_stride: &[usize],
+    right: BufferOffset,
+    right_stride: &[usize],
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_where_cond_strided_with_offset(
+        device,
+        ep,
+        kernels,
+        name,
+        shape,
+        cond,
+        cond_stride,
+        left,
+        left_stride,
+        right,
+        right_stride,
+        BufferOffset::zero_offset(output),
+    )
+}
+
+/// Same as [`call_where_cond_strided`] but writes the result at `output.offset_in_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_where_cond_strided_with_offset(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    cond: BufferOffset,
+    cond_stride: &[usize],
+    left: BufferOffset,
+    left_stride: &[usize],
+    right: BufferOffset,
+    right_stride: &[usize],
+    output: BufferOffset,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
+    let size: usize = shape.iter().product();
+    let rank = shape.len();
+
     set_params!(
         encoder,
         (
-            bh,
-            td,
-            d,
-            (src, src_offset),
-            (cos, cos_offset),
-            (sin, sin_offset),
-            output
+            size,
+            rank,
+            shape,
+            cond_stride,
+            left_stride,
+            right_stride,
+            &cond,
+            &left,
+            &right,
+            &output
         )
     );
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, (bh * td) / 2);
-    encoder.use_resource(src, metal::MTLResourceUsage::Read);
-    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
-    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(cond.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(left.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(right.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output.buffer, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Selects between `left` and `right` depending on `cond` when the three of them are contiguous
+/// with the same number of elements, this skips the strided index computations. `name` is the
+/// name of the strided kernel followed by `_contiguous`, e.g. `where_u8_f32_contiguous`.
 #[allow(clippy::too_many_arguments)]
-pub fn call_affine(
+pub fn call_where_cond_contiguous(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
-    size: usize,
-    input: BufferOffset,
+    length: usize,
+    cond: BufferOffset,
+    left: BufferOffset,
+    right: BufferOffset,
     output: &Buffer,
-    mul: f32,
-    add: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(encoder, (size, mul, add, &input, output));
+    set_params!(encoder, (length, &cond, &left, &right, output));
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
-    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
+
+    encoder.use_resource(cond.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(left.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(right.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn call_affine_strided(
+pub fn call_masked_fill(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
-    shape: &[usize],
+    length: usize,
     input: BufferOffset,
-    input_stride: &[usize],
+    mask: BufferOffset,
+    value: f32,
     output: &Buffer,
-    mul: f32,
-    add: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
-    let size: usize = shape.iter().product();
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(
-        encoder,
-        (
-            size,
-            shape.len(),
-            shape,
-            input_stride,
-            mul,
-            add,
-            &input,
-            output
-        )
-    );
+    set_params!(encoder, (length, value, &input, &mask, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length);
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(mask.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Same as [`call_masked_fill`] but the input and the mask can be non-contiguous, a zero stride
+/// on the mask can be used to broadcast it over the leading dimensions.
 #[allow(clippy::too_many_arguments)]
-pub fn call_powf(
+pub fn call_masked_fill_strided(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
-    size: usize,
+    shape: &[usize],
     input: BufferOffset,
+    input_stride: &[usize],
+    mask: BufferOffset,
+    mask_stride: &[usize],
+    value: f32,
     output: &Buffer,
-    mul: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(encoder, (size, mul, &input, output));
+    let size: usize = shape.iter().product();
+    let rank = shape.len();
+
+    set_params!(
+        encoder,
+        (
+            size,
+            rank,
+            shape,
+            input_stride,
+            mask_stride,
+            value,
+            &input,
+            &mask,
+            output
+        )
+    );
 
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(mask.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn call_powf_strided(
+pub fn call_index_select(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
     shape: &[usize],
+    ids_size: usize,
+    dim: usize,
+    contiguous: bool,
+    src_dims: &[usize],
+    src_strides: &[usize],
     input: BufferOffset,
-    input_stride: &[usize],
+    ids: BufferOffset,
     output: &Buffer,
-    mul: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
-    let size: usize = shape.iter().product();
+    let left_size: usize = shape[..dim].iter().product();
+    let right_size: usize = shape[dim + 1..].iter().product();
+    let src_dim_size = shape[dim];
+    let dst_el = ids_size * left_size * right_size;
 
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
+
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+
     encoder.set_compute_pipeline_state(&pipeline);
 
     set_params!(
         encoder,
-        (size, shape.len(), shape, input_stride, mul, &input, output)
+        (
+            dst_el,
+            left_size,
+            src_dim_size,
+            right_size,
+            ids_size,
+            contiguous,
+            src_dims,
+            src_strides,
+            &input,
+            &ids,
+            output
+        )
     );
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
+
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(ids.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Selects `ids` along `dim` from an arbitrarily strided input, `name` is one of the
+/// `is_*_strided` kernels. The input offset is taken from `input.offset_in_bytes` and the output
+/// is contiguous with shape `shape` where `shape[dim]` is replaced by `ids_size`.
 #[allow(clippy::too_many_arguments)]
-pub fn call_elu(
+pub fn call_index_select_strided(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
-    size: usize,
+    shape: &[usize],
+    strides: &[usize],
+    dim: usize,
+    ids_size: usize,
     input: BufferOffset,
+    ids: BufferOffset,
     output: &Buffer,
-    mul: f32,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
+    assert_eq!(shape.len(), strides.len());
+    assert!(dim < shape.len());
+    let num_dims = shape.len();
+    let left_size: usize = shape[..dim].iter().product();
+    let right_size: usize = shape[dim + 1..].iter().product();
+    let dst_el = ids_size * left_size * right_size;
 
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
+
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(encoder, (size, mul, &input, output));
+    set_params!(
+        encoder,
+        (dst_el, num_dims, dim, ids_size, shape, strides, &input, &ids, output)
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(ids.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Embedding lookup fused with an optional scaling of the result (e.g. by `sqrt(d_model)`).
+/// `weight` is a contiguous `[vocab_size, hidden_size]` matrix, `name` is one of the
+/// `embedding_*` kernels and the output has shape `[num_ids, hidden_size]`.
 #[allow(clippy::too_many_arguments)]
-pub fn call_elu_strided(
+pub fn call_embedding(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
-    shape: &[usize],
-    input: BufferOffset,
-    input_stride: &[usize],
+    vocab_size: usize,
+    hidden_size: usize,
+    num_ids: usize,
+    weight: BufferOffset,
+    ids: BufferOffset,
     output: &Buffer,
-    mul: f32,
+    scale: Option<f32>,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Affine, name)?;
-    let size: usize = shape.iter().product();
+    let dst_el = num_ids * hidden_size;
+    let scale = scale.unwrap_or(1.0);
 
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
+
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+
     encoder.set_compute_pipeline_state(&pipeline);
 
     set_params!(
         encoder,
-        (size, shape.len(), shape, input_stride, mul, &input, output)
+        (
+            dst_el,
+            vocab_size,
+            hidden_size,
+            scale,
+            &weight,
+            &ids,
+            output
+        )
     );
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
-    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
+
+    encoder.use_resource(weight.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(ids.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Materializes a tensor tiled `repeats[d]` times along each dimension `d`, the output has
+/// shape `shape[d] * repeats[d]` and is contiguous.
 #[allow(clippy::too_many_arguments)]
-pub fn call_where_cond_strided(
+pub fn call_repeat(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
     shape: &[usize],
-    cond: BufferOffset,
-    cond_stride: &[usize],
-    left: BufferOffset,
-    left_stride: &[usize],
-    right: BufferOffset,
-    right_stride: &[usize],
+    strides: &[usize],
+    repeats: &[usize],
+    input: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+    assert_eq!(shape.len(), strides.len());
+    assert_eq!(shape.len(), repeats.len());
+    let dst_el: usize = shape
+        .iter()
+        .zip(repeats.iter())
+        .map(|(s, r)| s * r)
+        .product();
 
-    let encoder = ep.encoder();
+    let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
+
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-    encoder.set_compute_pipeline_state(&pipeline);
 
-    let size: usize = shape.iter().product();
-    let rank = shape.len();
+    encoder.set_compute_pipeline_state(&pipeline);
 
     set_params!(
         encoder,
-        (
-            size,
-            rank,
-            shape,
-            cond_stride,
-            left_stride,
-            right_stride,
-            &cond,
-            &left,
-            &right,
-            output
-        )
+        (dst_el, shape.len(), shape, strides, repeats, &input, output)
     );
 
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
 
-    encoder.use_resource(cond.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(left.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(right.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+/// Copies the sub-tensor selected by the per-dimension `[start, end)` ranges into a contiguous
+/// output buffer.
 #[allow(clippy::too_many_arguments)]
-pub fn call_index_select(
+pub fn call_narrow(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
     shape: &[usize],
-    ids_size: usize,
-    dim: usize,
-    contiguous: bool,
-    src_dims: &[usize],
-    src_strides: &[usize],
+    strides: &[usize],
+    ranges: &[(usize, usize)],
     input: BufferOffset,
-    ids: BufferOffset,
     output: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let left_size: usize = shape[..dim].iter().product();
-    let right_size: usize = shape[dim + 1..].iter().product();
-    let src_dim_size = shape[dim];
-    let dst_el = ids_size * left_size * right_size;
+    assert_eq!(shape.len(), strides.len());
+    assert_eq!(shape.len(), ranges.len());
+    for (dim, (&(start, end), &dim_size)) in ranges.iter().zip(shape.iter()).enumerate() {
+        if start > end || end > dim_size {
+            return Err(MetalKernelError::NarrowOutOfBounds {
+                dim,
+                start,
+                end,
+                dim_size,
+            });
+        }
+    }
+    let dst_dims: Vec<usize> = ranges.iter().map(|(start, end)| end - start).collect();
+    let starts: Vec<usize> = ranges.iter().map(|(start, _)| *start).collect();
+    let dst_el: usize = dst_dims.iter().product();
 
     let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1207,15 +3306,11 @@ pub fn call_index_select(
         encoder,
         (
             dst_el,
-            left_size,
-            src_dim_size,
-            right_size,
-            ids_size,
-            contiguous,
-            src_dims,
-            src_strides,
+            shape.len(),
+            dst_dims.as_slice(),
+            strides,
+            starts.as_slice(),
             &input,
-            &ids,
             output
         )
     );
@@ -1223,7 +3318,6 @@ pub fn call_index_select(
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
 
     encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(ids.buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(output, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
@@ -1249,7 +3343,7 @@ pub fn call_gather(
 
     let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1298,7 +3392,7 @@ pub fn call_scatter_add(
 
     let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1326,6 +3420,10 @@ pub fn call_scatter_add(
     Ok(())
 }
 
+/// Adds the slices of `input` along `dim` to the slices of `output` selected by `ids`. Each thread
+/// handles one position outside of `dim` and accumulates the source slices in the order of `ids`,
+/// so the result does not depend on the scheduling of the threads and is reproducible across runs
+/// without atomics.
 #[allow(clippy::too_many_arguments)]
 pub fn call_index_add(
     device: &Device,
@@ -1348,7 +3446,7 @@ pub fn call_index_add(
     let ids_dim_size = ids_shape[0];
 
     let pipeline = kernels.load_pipeline(device, Source::Indexing, name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1457,8 +3555,139 @@ impl ConstantValues {
     }
 }
 
+#[cfg(feature = "metal-flash-attention")]
+/// The tiling of the MFA gemm kernels: each simdgroup computes a `m_simd x n_simd` block of the
+/// output stepping by `k_simd` along the inner dimension, and a threadgroup has
+/// `m_splits x n_splits` simdgroups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GemmTile {
+    pub m_simd: u16,
+    pub n_simd: u16,
+    pub k_simd: u16,
+    pub m_splits: u16,
+    pub n_splits: u16,
+}
+
+#[cfg(feature = "metal-flash-attention")]
+impl GemmTile {
+    /// The tiles benchmarked by [`Kernels::autotune_gemm`].
+    pub const CANDIDATES: [GemmTile; 4] = [
+        GemmTile::new(8, 8, 64, 1, 1),
+        GemmTile::new(40, 40, 32, 1, 1),
+        GemmTile::new(48, 48, 24, 1, 1),
+        GemmTile::new(32, 32, 32, 2, 2),
+    ];
+
+    pub const fn new(m_simd: u16, n_simd: u16, k_simd: u16, m_splits: u16, n_splits: u16) -> Self {
+        Self {
+            m_simd,
+            n_simd,
+            k_simd,
+            m_splits,
+            n_splits,
+        }
+    }
+
+    /// The tile used when the problem size has not been autotuned.
+    pub fn default_for(m: usize) -> Self {
+        if m == 1 {
+            Self::new(8, 8, 64, 1, 1)
+        } else {
+            Self::new(40, 40, 32, 1, 1)
+        }
+    }
+}
+
+#[cfg(feature = "metal-flash-attention")]
+fn gemm_dtype_size(name: &str) -> Result<usize, MetalKernelError> {
+    match name {
+        "sgemm" => Ok(4),
+        "hgemm" => Ok(2),
+        "bgemm" => Ok(2),
+        other => Err(MetalKernelError::LoadLibraryError(format!(
+            "{other} is not a valid kernel for gemm"
+        ))),
+    }
+}
+
+#[cfg(feature = "metal-flash-attention")]
+#[allow(clippy::too_many_arguments)]
+pub fn call_gemm(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    (b, m, n, k): (usize, usize, usize, usize),
+    lhs_stride: &[usize],
+    lhs_offset: usize,
+    lhs_buffer: &Buffer,
+    rhs_stride: &[usize],
+    rhs_offset: usize,
+    rhs_buffer: &Buffer,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    call_gemm_with_alpha_beta(
+        device,
+        ep,
+        kernels,
+        name,
+        (b, m, n, k),
+        lhs_stride,
+        lhs_offset,
+        lhs_buffer,
+        rhs_stride,
+        rhs_offset,
+        rhs_buffer,
+        (1., 0.),
+        output,
+    )
+}
+
+#[cfg(feature = "metal-flash-attention")]
+/// Same as [`call_gemm`] but computes `output = alpha * lhs @ rhs + beta * output`, the existing
+/// content of `output` is only read when `beta` is not zero, e.g. to add a residual in place.
 #[allow(clippy::too_many_arguments)]
-pub fn call_gemm(
+pub fn call_gemm_with_alpha_beta(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    (b, m, n, k): (usize, usize, usize, usize),
+    lhs_stride: &[usize],
+    lhs_offset: usize,
+    lhs_buffer: &Buffer,
+    rhs_stride: &[usize],
+    rhs_offset: usize,
+    rhs_buffer: &Buffer,
+    (alpha, beta): (f32, f32),
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let tile = match kernels.gemm_tile(device, name, (m, n, k)) {
+        Some(tile) => tile,
+        None if kernels.gemm_autotune => kernels.autotune_gemm(device, name, (m, n, k))?,
+        None => GemmTile::default_for(m),
+    };
+    call_gemm_with_tile(
+        device,
+        ep,
+        kernels,
+        name,
+        (b, m, n, k),
+        lhs_stride,
+        lhs_offset,
+        lhs_buffer,
+        rhs_stride,
+        rhs_offset,
+        rhs_buffer,
+        (alpha, beta),
+        output,
+        tile,
+    )
+}
+
+#[cfg(feature = "metal-flash-attention")]
+#[allow(clippy::too_many_arguments)]
+fn call_gemm_with_tile(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
@@ -1470,7 +3699,9 @@ pub fn call_gemm(
     rhs_stride: &[usize],
     rhs_offset: usize,
     rhs_buffer: &Buffer,
+    (alpha, beta): (f32, f32),
     output: &Buffer,
+    tile: GemmTile,
 ) -> Result<(), MetalKernelError> {
     assert!(rhs_stride.len() >= 2);
     assert!(lhs_stride.len() >= 2);
@@ -1505,26 +3736,16 @@ pub fn call_gemm(
         })?;
     };
     let d_trans = false;
-    let alpha = 1.0f32;
-    let beta = 0.0f32;
     let batched = b > 1;
     let fused_activation = false;
     let fused_bias = false;
-    let (m_simd, n_simd, k_simd, m_splits, n_splits) = if m == 1 {
-        let m_simd = 8;
-        let n_simd = 8;
-        let k_simd = 64;
-        let m_splits = 1;
-        let n_splits = 1;
-        (m_simd, n_simd, k_simd, m_splits, n_splits)
-    } else {
-        let m_simd = 40;
-        let n_simd = 40;
-        let k_simd = 32;
-        let m_splits = 1;
-        let n_splits = 1;
-        (m_simd, n_simd, k_simd, m_splits, n_splits)
-    };
+    let GemmTile {
+        m_simd,
+        n_simd,
+        k_simd,
+        m_splits,
+        n_splits,
+    } = tile;
     let constants = Some(ConstantValues::new(vec![
         (0, Value::USize(m)),
         (1, Value::USize(n)),
@@ -1568,19 +3789,10 @@ pub fn call_gemm(
             block_elements = std::cmp::max(block_elements, n_group);
         }
     }
-    let bytes = match name {
-        "sgemm" => 4,
-        "hgemm" => 2,
-        "bgemm" => 2,
-        other => {
-            return Err(MetalKernelError::LoadLibraryError(format!(
-                "{other} is not a valid kernel for gemm"
-            )));
-        }
-    };
+    let bytes = gemm_dtype_size(name)? as u16;
     let block_bytes = block_elements * bytes;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     encoder.set_threadgroup_memory_length(0, block_bytes.into());
@@ -1622,7 +3834,12 @@ pub fn call_gemm(
     };
     encoder.use_resource(lhs_buffer, metal::MTLResourceUsage::Read);
     encoder.use_resource(rhs_buffer, metal::MTLResourceUsage::Read);
-    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    let output_usage = if beta == 0. {
+        metal::MTLResourceUsage::Write
+    } else {
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write
+    };
+    encoder.use_resource(output, output_usage);
     encoder.dispatch_thread_groups(grid_size, group_size);
     Ok(())
 }
@@ -1643,7 +3860,7 @@ pub fn call_im2col1d_strided(
     let l_out = (shape[2] + 2 * padding - dilation * (k_size - 1) - 1) / stride + 1;
     let dst_el = shape[0] * l_out * shape[1] * k_size;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1675,7 +3892,7 @@ pub fn call_col2im1d(
     let l_out = (l_in - 1) * stride + k_size;
     let dst_el = shape[0] * c_out * l_out;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1710,7 +3927,7 @@ pub fn call_im2col_strided(
 
     let dst_el = shape[0] * h_out * w_out * shape[1] * h_k * w_k;
 
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
     encoder.set_compute_pipeline_state(&pipeline);
@@ -1745,7 +3962,7 @@ pub fn call_upsample_nearest_2d(
     let scale_w = shape[2] as f32 / out_w as f32;
     let scale_h = shape[3] as f32 / out_h as f32;
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     set_params!(
@@ -1758,6 +3975,79 @@ pub fn call_upsample_nearest_2d(
     Ok(())
 }
 
+/// Depth to space: rearranges a contiguous `(b, c * r * r, h, w)` input of the given `shape` to a
+/// `(b, c, h * r, w * r)` output, `name` is one of the `pixel_shuffle_*` kernels.
+#[allow(clippy::too_many_arguments)]
+pub fn call_pixel_shuffle(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    r: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    if shape[1] % (r * r) != 0 {
+        return Err(MetalKernelError::IndivisibleLength {
+            kernel: "pixel_shuffle",
+            length: shape[1],
+            divisor: r * r,
+        });
+    }
+    call_pixel_rearrange(device, ep, kernels, name, shape, r, input, output)
+}
+
+/// Space to depth, the inverse of [`call_pixel_shuffle`]: rearranges a contiguous
+/// `(b, c, h * r, w * r)` input to a `(b, c * r * r, h, w)` output, `name` is one of the
+/// `pixel_unshuffle_*` kernels.
+#[allow(clippy::too_many_arguments)]
+pub fn call_pixel_unshuffle(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    r: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    for &length in &shape[2..] {
+        if length % r != 0 {
+            return Err(MetalKernelError::IndivisibleLength {
+                kernel: "pixel_unshuffle",
+                length,
+                divisor: r,
+            });
+        }
+    }
+    call_pixel_rearrange(device, ep, kernels, name, shape, r, input, output)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn call_pixel_rearrange(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    shape: &[usize],
+    r: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Conv, name)?;
+    let dst_el: usize = shape.iter().product();
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (dst_el, shape, r, &input, output));
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_random_uniform(
     device: &Device,
@@ -1776,7 +4066,7 @@ pub fn call_random_uniform(
         ));
     }
     let pipeline = kernels.load_pipeline(device, Source::Random, name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
     let odd = (length % 2 != 0) as usize;
@@ -1808,7 +4098,7 @@ pub fn call_random_normal(
     buffer: &Buffer,
 ) -> Result<(), MetalKernelError> {
     let pipeline = kernels.load_pipeline(device, Source::Random, name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
     let odd = (length % 2 != 0) as usize;
@@ -1962,7 +4252,7 @@ pub fn call_quantized_matmul_mv_t(
     };
 
     let pipeline = kernels.load_pipeline(device, Source::Quantized, name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
@@ -2022,7 +4312,7 @@ pub fn call_pool2d(
     let dst_el = out_w * out_h * shape[0] * shape[1];
     let pipeline: ComputePipelineState = kernels.load_pipeline(device, Source::Conv, name)?;
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     set_params!(
@@ -2061,7 +4351,7 @@ pub fn call_conv_transpose1d(
     let dst_el = c_out * l_out * b_size;
     let pipeline: ComputePipelineState = kernels.load_pipeline(device, Source::Conv, name)?;
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     set_params!(
@@ -2088,6 +4378,10 @@ pub fn call_conv_transpose1d(
     Ok(())
 }
 
+/// Configuration of a conv_transpose2d, the input has shape `(b, c_in, h_in, w_in)` and the
+/// kernel `(c_in, c_out, h_k, w_k)`. The output height is
+/// `(h_in - 1) * stride - 2 * padding + dilation * (h_k - 1) + output_padding + 1`, same for the
+/// width.
 pub struct CallConvTranspose2dCfg<'a> {
     pub dilation: usize,
     pub stride: usize,
@@ -2105,6 +4399,8 @@ pub struct CallConvTranspose2dCfg<'a> {
     pub kernel_offset: usize,
 }
 
+/// Each thread gathers the input elements that contribute to one output element rather than
+/// scattering the inputs, so no atomics are needed.
 #[allow(clippy::too_many_arguments)]
 pub fn call_conv_transpose2d(
     device: &Device,
@@ -2119,7 +4415,7 @@ pub fn call_conv_transpose2d(
     let dst_el = cfg.c_out * cfg.out_w * cfg.out_h * cfg.b_size;
     let pipeline: ComputePipelineState = kernels.load_pipeline(device, Source::Conv, name)?;
     let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     set_params!(
@@ -2147,6 +4443,78 @@ pub fn call_conv_transpose2d(
     Ok(())
 }
 
+/// Configuration of a direct conv2d, the input has shape `(b, c_in, h_in, w_in)` and the kernel
+/// `(c_out, c_in, h_k, w_k)`. A channels-last input is described by its strides.
+pub struct CallConv2dDirectCfg<'a> {
+    pub stride: usize,
+    pub padding: usize,
+    pub dilation: usize,
+    pub out_w: usize,
+    pub out_h: usize,
+    pub input_dims: &'a [usize],
+    pub input_stride: &'a [usize],
+    pub kernel_dims: &'a [usize],
+    pub kernel_stride: &'a [usize],
+    pub input_offset: usize,
+    pub kernel_offset: usize,
+    /// Writes the output channels-last, with shape `(b, out_h, out_w, c_out)`.
+    pub channels_last: bool,
+}
+
+/// Computes a conv2d without materializing the im2col matrix, this uses less memory than
+/// im2col + gemm and is usually faster for small channel counts. The contiguous output has shape
+/// `(b, c_out, out_h, out_w)`, or `(b, out_h, out_w, c_out)` with `cfg.channels_last`, `bias` if
+/// any has `c_out` elements.
+#[allow(clippy::too_many_arguments)]
+pub fn call_conv2d_direct(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    cfg: CallConv2dDirectCfg,
+    input: &Buffer,
+    kernel: &Buffer,
+    bias: Option<&Buffer>,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let dst_el = cfg.input_dims[0] * cfg.kernel_dims[0] * cfg.out_h * cfg.out_w;
+    let channels_last = Value::Bool(cfg.channels_last);
+    let constants = Some(ConstantValues::new(vec![(0, channels_last)]));
+    let pipeline = kernels.load_pipeline_with_constants(device, Source::Conv, name, constants)?;
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, dst_el);
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    // The bias argument has to be bound even when unused.
+    let has_bias = bias.is_some();
+    let bias = bias.unwrap_or(kernel);
+    set_params!(
+        encoder,
+        (
+            cfg.out_w,
+            cfg.out_h,
+            cfg.stride,
+            cfg.padding,
+            cfg.dilation,
+            cfg.input_dims,
+            cfg.input_stride,
+            cfg.kernel_dims,
+            cfg.kernel_stride,
+            has_bias,
+            (input, cfg.input_offset),
+            (kernel, cfg.kernel_offset),
+            bias,
+            output
+        )
+    );
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(kernel, metal::MTLResourceUsage::Read);
+    encoder.use_resource(bias, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_arg_sort(
     device: &Device,
@@ -2160,7 +4528,7 @@ pub fn call_arg_sort(
     dst: &Buffer,
 ) -> Result<(), MetalKernelError> {
     let pipeline = kernels.load_pipeline(device, Source::Sort, name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
 
@@ -2184,6 +4552,122 @@ pub fn call_arg_sort(
     Ok(())
 }
 
+/// One threadgroup per row, the reductions in the sampling kernels require a power of two
+/// threadgroup size.
+fn sampling_split(
+    pipeline: &ComputePipelineState,
+    nrows: usize,
+    ncols: usize,
+) -> (MTLSize, MTLSize) {
+    let width = std::cmp::min(pipeline.max_total_threads_per_threadgroup(), ncols as u64).max(1);
+    let thread_group_count = MTLSize {
+        width: nrows as u64,
+        height: 1,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width: (width + 1).next_power_of_two() / 2,
+        height: 1,
+        depth: 1,
+    };
+    (thread_group_count, thread_group_size)
+}
+
+/// Top-k filtering of the logits of `nrows` contiguous rows of `ncols` elements: the `k` largest
+/// logits of each row are kept and the other ones are set to `-inf`, so that they get a zero
+/// probability after a softmax. Logits equal to the k-th largest one are all kept. `name` is one
+/// of `top_k_{f32,f16,bf16}`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_top_k(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    nrows: usize,
+    ncols: usize,
+    k: usize,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Sampling, name)?;
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (ncols, k, &input, output));
+
+    let (thread_group_count, thread_group_size) = sampling_split(&pipeline, nrows, ncols);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Top-p, or nucleus, filtering of the logits of `nrows` contiguous rows of `ncols` elements:
+/// the smallest set of largest logits which cumulative softmax probability is at least `p` is
+/// kept and the other logits are set to `-inf`. The largest logit is always kept. `name` is one
+/// of `top_p_{f32,f16,bf16}`.
+///
+/// The rows are not sorted, the kernel searches for the smallest kept logit instead so rows of
+/// any length are supported.
+#[allow(clippy::too_many_arguments)]
+pub fn call_top_p(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    nrows: usize,
+    ncols: usize,
+    p: f32,
+    input: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Sampling, name)?;
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (ncols, p, &input, output));
+
+    let (thread_group_count, thread_group_size) = sampling_split(&pipeline, nrows, ncols);
+    encoder.use_resource(input.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Draws one index per row of `probs`, `num_rows` contiguous rows of `row_len` probabilities, by
+/// inverting the cumulative distribution of the row. The probabilities do not have to be
+/// normalized, e.g. the output of [`call_top_p`] followed by a softmax can be used directly. The
+/// random numbers come from a counter-based generator keyed by `seed`, the results only depend
+/// on the seed and on the probabilities. `output` holds one `u32` per row, `name` is one of
+/// `multinomial_{f32,f16,bf16}`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_multinomial(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    num_rows: usize,
+    row_len: usize,
+    seed: u64,
+    probs: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Sampling, name)?;
+    let encoder = ep.encoder_with_label(name);
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(encoder, (num_rows, row_len, seed, &probs, output));
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, num_rows);
+    encoder.use_resource(probs.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum GemmDType {
     BF16,
@@ -2321,7 +4805,7 @@ pub fn call_mlx_gemm(
         (GemmDType::F16, true, true) => "gemm_tt_f16_f16_32_32_16_2_2",
     };
     let pipeline = kernels.load_pipeline_with_constants(device, Source::Gemm, name, constants)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     encoder.set_buffer(0, Some(lhs_buffer), lhs_offset as NSUInteger);
@@ -2360,6 +4844,179 @@ pub fn call_mlx_gemm(
     Ok(())
 }
 
+/// The head dims supported by the fused [`call_sdpa`] kernel, other head dims use the unfused
+/// fallback.
+pub const SDPA_HEAD_DIMS: &[usize] = &[32, 64, 96, 128];
+
+fn sdpa_kernel_name(dtype: GemmDType, head_dim: usize) -> Option<&'static str> {
+    let name = match (dtype, head_dim) {
+        (GemmDType::F32, 32) => "sdpa_f32_32",
+        (GemmDType::F32, 64) => "sdpa_f32_64",
+        (GemmDType::F32, 96) => "sdpa_f32_96",
+        (GemmDType::F32, 128) => "sdpa_f32_128",
+        (GemmDType::F16, 32) => "sdpa_f16_32",
+        (GemmDType::F16, 64) => "sdpa_f16_64",
+        (GemmDType::F16, 96) => "sdpa_f16_96",
+        (GemmDType::F16, 128) => "sdpa_f16_128",
+        (GemmDType::BF16, 32) => "sdpa_bf16_32",
+        (GemmDType::BF16, 64) => "sdpa_bf16_64",
+        (GemmDType::BF16, 96) => "sdpa_bf16_96",
+        (GemmDType::BF16, 128) => "sdpa_bf16_128",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Scaled dot-product attention, `softmax(q k^T * scale + mask) v`, where `q` has the contiguous
+/// shape `(batch, heads, q_len, head_dim)`, `k` and `v` the contiguous shape
+/// `(batch, heads, kv_len, head_dim)` and `output` gets the shape of `q`.
+///
+/// When `causal` is set, query `i` only attends to the keys `j <= i + kv_len - q_len`, the mask is
+/// aligned on the last key so that it also applies when decoding with a kv cache, and `kv_len`
+/// cannot be smaller than `q_len`.
+///
+/// The fused kernel computes the softmax online over tiles of keys and never materializes the
+/// attention scores, it is used for the head dims in [`SDPA_HEAD_DIMS`]. Other head dims fall back
+/// to a gemm for the scores, a softmax and a second gemm with the values, this allocates a scores
+/// buffer of `batch * heads * q_len * kv_len` elements.
+#[allow(clippy::too_many_arguments)]
+pub fn call_sdpa(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    dtype: GemmDType,
+    (b, h, q_len, kv_len, head_dim): (usize, usize, usize, usize, usize),
+    q: BufferOffset,
+    k: BufferOffset,
+    v: BufferOffset,
+    output: &Buffer,
+    scale: f32,
+    causal: bool,
+) -> Result<(), MetalKernelError> {
+    if !(scale > 0. && scale.is_finite()) {
+        return Err(MetalKernelError::InvalidAttentionScale(scale));
+    }
+    if causal && kv_len < q_len {
+        return Err(MetalKernelError::InvalidCausalAttention { q_len, kv_len });
+    }
+    let encoder = ep.encoder_with_label("sdpa");
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    let Some(name) = sdpa_kernel_name(dtype, head_dim) else {
+        return call_sdpa_unfused(
+            device,
+            encoder,
+            kernels,
+            dtype,
+            (b, h, q_len, kv_len, head_dim),
+            (q, k, v),
+            output,
+            scale,
+            causal,
+        );
+    };
+
+    let constants = Some(ConstantValues::new(vec![(0, Value::Bool(causal))]));
+    let pipeline = kernels.load_pipeline_with_constants(device, Source::Sdpa, name, constants)?;
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (q_len, kv_len, scale, &q, &k, &v, output));
+
+    // One thread per query row, this has to match `SDPA_BLOCK_Q` in the kernel.
+    let block_q = 32;
+    let thread_group_count = MTLSize {
+        width: q_len.div_ceil(block_q) as u64,
+        height: (b * h) as u64,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width: block_q as u64,
+        height: 1,
+        depth: 1,
+    };
+    encoder.use_resource(q.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(k.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(v.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn call_sdpa_unfused(
+    device: &Device,
+    encoder: &ComputeCommandEncoderRef,
+    kernels: &Kernels,
+    dtype: GemmDType,
+    (b, h, q_len, kv_len, head_dim): (usize, usize, usize, usize, usize),
+    (q, k, v): (BufferOffset, BufferOffset, BufferOffset),
+    output: &Buffer,
+    scale: f32,
+    causal: bool,
+) -> Result<(), MetalKernelError> {
+    let (dtype_size, mask, softmax) = match dtype {
+        GemmDType::F32 => (4, "causal_mask_f32", "softmax_f32"),
+        GemmDType::F16 => (2, "causal_mask_f16", "softmax_f16"),
+        GemmDType::BF16 => (2, "causal_mask_bf16", "softmax_bf16"),
+    };
+    let bh = b * h;
+    let scores_el = bh * q_len * kv_len;
+    let scores = device.new_buffer(
+        (scores_el * dtype_size) as u64,
+        metal::MTLResourceOptions::StorageModePrivate,
+    );
+    // q (bh, q_len, head_dim) @ k^T (bh, head_dim, kv_len)
+    call_mlx_gemm(
+        device,
+        encoder,
+        kernels,
+        dtype,
+        (bh, q_len, kv_len, head_dim),
+        &[q_len * head_dim, head_dim, 1],
+        q.offset_in_bytes,
+        q.buffer,
+        &[kv_len * head_dim, 1, head_dim],
+        k.offset_in_bytes,
+        k.buffer,
+        &scores,
+    )?;
+    if causal {
+        let pipeline = kernels.load_pipeline(device, Source::Sdpa, mask)?;
+        encoder.set_compute_pipeline_state(&pipeline);
+        set_params!(encoder, (scores_el, q_len, kv_len, &scores));
+        let (thread_group_count, thread_group_size) = linear_split(&pipeline, scores_el);
+        encoder.use_resource(&scores, metal::MTLResourceUsage::Read);
+        encoder.use_resource(&scores, metal::MTLResourceUsage::Write);
+        encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    }
+    // softmax(x * scale) is the softmax with a temperature of 1 / scale.
+    call_last_softmax(
+        device,
+        encoder,
+        kernels,
+        softmax,
+        scores_el,
+        kv_len,
+        &scores,
+        0,
+        &scores,
+        Some(1. / scale),
+    )?;
+    // scores (bh, q_len, kv_len) @ v (bh, kv_len, head_dim)
+    call_mlx_gemm(
+        device,
+        encoder,
+        kernels,
+        dtype,
+        (bh, q_len, head_dim, kv_len),
+        &[q_len * kv_len, kv_len, 1],
+        0,
+        &scores,
+        &[kv_len * head_dim, head_dim, 1],
+        v.offset_in_bytes,
+        v.buffer,
+        output,
+    )
+}
+
 pub fn call_const_fill(
     device: &Device,
     ep: impl EncoderProvider,
@@ -2370,7 +5027,7 @@ pub fn call_const_fill(
     v: f32,
 ) -> Result<(), MetalKernelError> {
     let pipeline = kernels.load_pipeline(device, Source::Fill, name)?;
-    let encoder = ep.encoder();
+    let encoder = ep.encoder_with_label(name);
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
     encoder.set_compute_pipeline_state(&pipeline);
     set_params!(encoder, (output, v, length));
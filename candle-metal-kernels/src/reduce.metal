@@ -227,6 +227,7 @@ template<typename T>
 METAL_FUNC void softmax(
     constant size_t & src_numel,
     constant size_t & el_to_sum_per_block,
+    constant float & inv_temperature,
     device const T * src,
     device T * dst,
     uint id,
@@ -241,7 +242,7 @@ METAL_FUNC void softmax(
 
     float tmp = -INFINITY;
     while (idx < stop_idx) {
-        tmp = MAX(tmp, float(src[idx]));
+        tmp = MAX(tmp, float(src[idx]) * inv_temperature);
         idx += block_dim;
     }
     shared_memory[tid] = tmp;
@@ -266,7 +267,7 @@ METAL_FUNC void softmax(
 
     idx = start_idx + tid;
     while (idx < stop_idx) {
-        const float val = exp(float(src[idx]) - _max);
+        const float val = exp(float(src[idx]) * inv_temperature - _max);
         dst[idx] = T(val);
         shared_memory[tid] += val;
         idx += block_dim;
@@ -291,6 +292,7 @@ METAL_FUNC void softmax(
 kernel void NAME( \
     constant size_t &src_numel, \
     constant size_t &el_to_sum_per_block, \
+    constant float &inv_temperature, \
     device const T *src, \
     device T *dst, \
     uint id [[ thread_position_in_grid ]], \
@@ -300,7 +302,156 @@ kernel void NAME( \
 ) { \
     threadgroup float shared_memory[THREADGROUP_SIZE]; \
     shared_memory[tid] = -INFINITY; \
-    softmax<T>(src_numel, el_to_sum_per_block, src, dst, id, tid, dst_id, block_dim, shared_memory); \
+    softmax<T>(src_numel, el_to_sum_per_block, inv_temperature, src, dst, id, tid, dst_id, block_dim, shared_memory); \
+} \
+
+// `m + log(sum(exp(x - m)))` over each block of el_to_sum_per_block elements, where m is the max
+// of the block, so that large values do not overflow. The accumulation is done in float.
+template<typename T>
+METAL_FUNC void logsumexp(
+    constant size_t & src_numel,
+    constant size_t & el_to_sum_per_block,
+    device const T * src,
+    device T * dst,
+    uint tid,
+    uint dst_id,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    size_t start_idx = dst_id * el_to_sum_per_block;
+    size_t stop_idx = min(start_idx + el_to_sum_per_block, src_numel);
+
+    float tmp = -INFINITY;
+    for (size_t idx = start_idx + tid; idx < stop_idx; idx += block_dim) {
+        tmp = MAX(tmp, float(src[idx]));
+    }
+    shared_memory[tid] = tmp;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = MAX(shared_memory[tid], shared_memory[tid + s]);
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+    const float _max = shared_memory[0];
+
+    /* prevent tid=0 from overwriting _max before other threads have read it */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    tmp = 0;
+    for (size_t idx = start_idx + tid; idx < stop_idx; idx += block_dim) {
+        tmp += exp(float(src[idx]) - _max);
+    }
+    shared_memory[tid] = tmp;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    if (tid == 0) {
+        // A block of -inf, or one with a +inf, would give inf - inf in the shift.
+        dst[dst_id] = T(isinf(_max) ? _max : _max + log(shared_memory[0]));
+    }
+}
+
+#define LOGSUMEXP(NAME, T) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    constant size_t &el_to_sum_per_block, \
+    device const T *src, \
+    device T *dst, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    logsumexp<T>(src_numel, el_to_sum_per_block, src, dst, tid, dst_id, block_dim, shared_memory); \
+} \
+
+// One thread per output summing its elements sequentially in float, the summation order does not
+// depend on the threadgroup size or scheduling so the results are reproducible.
+template<typename T>
+METAL_FUNC void sequential_sum(
+    constant size_t & num_dims,
+    constant size_t * dims,
+    constant size_t * strides,
+    constant size_t & el_to_sum_per_block,
+    constant size_t & dst_numel,
+    device const T * src,
+    device T * dst,
+    uint dst_id
+) {
+    if (dst_id >= dst_numel) {
+        return;
+    }
+    size_t start_idx = dst_id * el_to_sum_per_block;
+    size_t stop_idx = start_idx + el_to_sum_per_block;
+    float sum = 0;
+    for (size_t idx = start_idx; idx < stop_idx; idx++) {
+        sum += float(src[get_strided_index(idx, num_dims, dims, strides)]);
+    }
+    dst[dst_id] = T(sum);
+}
+
+#define SEQUENTIAL_SUM(NAME, T) \
+kernel void NAME( \
+    constant size_t &num_dims, \
+    constant size_t *dims, \
+    constant size_t *strides, \
+    constant size_t &el_to_sum_per_block, \
+    constant size_t &dst_numel, \
+    device const T *src, \
+    device T *dst, \
+    uint dst_id [[ thread_position_in_grid ]] \
+) { \
+    sequential_sum<T>(num_dims, dims, strides, el_to_sum_per_block, dst_numel, src, dst, dst_id); \
+} \
+
+// Sets dst[0] to 1 if any element of src is NaN or infinite and to 0 otherwise.
+// This runs on a single threadgroup so that the result can be written without atomics.
+template<typename T>
+METAL_FUNC void any_nonfinite(
+    constant size_t & src_numel,
+    device const T * src,
+    device uint8_t * dst,
+    uint tid,
+    uint block_dim,
+    threadgroup bool * shared_memory
+) {
+    bool found = false;
+    size_t idx = tid;
+    while (idx < src_numel) {
+        found = found || !isfinite(float(src[idx]));
+        idx += block_dim;
+    }
+    shared_memory[tid] = found;
+
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = shared_memory[tid] || shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    if (tid == 0) {
+        dst[0] = shared_memory[0] ? 1 : 0;
+    }
+}
+
+#define ANY_NONFINITE(NAME, T) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    device const T *src, \
+    device uint8_t *dst, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup bool shared_memory[THREADGROUP_SIZE]; \
+    any_nonfinite<T>(src_numel, src, dst, tid, block_dim, shared_memory); \
 } \
 
 template<typename T>
@@ -412,6 +563,94 @@ METAL_FUNC void layernorm(
     }
 }
 
+// Merges the Welford state (count, mean, m2) of b into a, Chan et al. parallel update.
+METAL_FUNC void welford_combine(
+    thread float & count,
+    thread float & mean,
+    thread float & m2,
+    float count_b,
+    float mean_b,
+    float m2_b
+) {
+    float n = count + count_b;
+    if (n == 0) {
+        return;
+    }
+    float delta = mean_b - mean;
+    mean += delta * count_b / n;
+    m2 += m2_b + delta * delta * count * count_b / n;
+    count = n;
+}
+
+// Computes the mean and the variance of each block of el_to_sum_per_block contiguous elements in
+// a single pass, the variance is unbiased (divided by n - 1) when requested. One threadgroup per
+// block, block_dim has to be a power of two.
+template<typename T>
+METAL_FUNC void mean_var(
+    constant size_t & el_to_sum_per_block,
+    constant bool & unbiased,
+    device const T * src,
+    device T * mean_dst,
+    device T * var_dst,
+    uint tid,
+    uint dst_id,
+    uint block_dim,
+    threadgroup float * shared_count,
+    threadgroup float * shared_mean,
+    threadgroup float * shared_m2
+) {
+    device const T * x = src + dst_id * el_to_sum_per_block;
+
+    float count = 0;
+    float mean = 0;
+    float m2 = 0;
+    for (size_t idx = tid; idx < el_to_sum_per_block; idx += block_dim) {
+        float v = float(x[idx]);
+        count += 1;
+        float delta = v - mean;
+        mean += delta / count;
+        m2 += delta * (v - mean);
+    }
+    shared_count[tid] = count;
+    shared_mean[tid] = mean;
+    shared_m2[tid] = m2;
+
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            welford_combine(count, mean, m2, shared_count[tid + s], shared_mean[tid + s], shared_m2[tid + s]);
+            shared_count[tid] = count;
+            shared_mean[tid] = mean;
+            shared_m2[tid] = m2;
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    if (tid == 0) {
+        float n = float(el_to_sum_per_block);
+        mean_dst[dst_id] = T(mean);
+        var_dst[dst_id] = T(m2 / (unbiased ? n - 1 : n));
+    }
+}
+
+#define MEAN_VAR(NAME, T) \
+kernel void NAME( \
+    constant size_t &el_to_sum_per_block, \
+    constant bool &unbiased, \
+    device const T *src, \
+    device T *mean_dst, \
+    device T *var_dst, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_count[THREADGROUP_SIZE]; \
+    threadgroup float shared_mean[THREADGROUP_SIZE]; \
+    threadgroup float shared_m2[THREADGROUP_SIZE]; \
+    mean_var<T>(el_to_sum_per_block, unbiased, src, mean_dst, var_dst, tid, dst_id, block_dim, shared_count, shared_mean, shared_m2); \
+} \
+
 #define RMSNORM(NAME, T) \
 kernel void NAME( \
     constant size_t &src_numel, \
@@ -572,6 +811,9 @@ REDUCE(MAX(x, y), fast_max_f32_strided, float, -HUGE_VALF)
 REDUCE(MAX(x, y), fast_max_u32_strided, uint, 0)
 REDUCE(MAX(x, y), fast_max_f16_strided, half, -HUGE_VALH)
 REDUCE(MAX(x, y), fast_max_u8_strided, uint8_t, 0)
+// The absolute value is applied to the accumulated values too, these are non-negative already.
+REDUCE(MAX(x, fabs(y)), fast_absmax_f32_strided, float, 0)
+REDUCE(MAX(x, fabs(y)), fast_absmax_f16_strided, half, 0)
 REDUCE(MIN(x, y), fast_min_f32_strided, float, HUGE_VALF)
 REDUCE(MIN(x, y), fast_min_u32_strided, uint, 0xFFFFFFFF)
 REDUCE(MIN(x, y), fast_min_f16_strided, half, HUGE_VALH)
@@ -585,12 +827,20 @@ ARGMAX(fast_argmax_f16_strided, half, -HUGE_VALH)
 ARGMAX(fast_argmax_u32_strided, uint, 0)
 ARGMAX(fast_argmax_u8_strided, uint8_t, 0)
 
+SEQUENTIAL_SUM(sequential_sum_f32_strided, float)
+SEQUENTIAL_SUM(sequential_sum_f16_strided, half)
 SOFTMAX(softmax_f32, float)
 SOFTMAX(softmax_f16, half)
+LOGSUMEXP(logsumexp_f32, float)
+LOGSUMEXP(logsumexp_f16, half)
+ANY_NONFINITE(any_nonfinite_f32, float)
+ANY_NONFINITE(any_nonfinite_f16, half)
 RMSNORM(rmsnorm_f32, float)
 RMSNORM(rmsnorm_f16, half)
 LAYERNORM(layernorm_f32, float)
 LAYERNORM(layernorm_f16, half)
+MEAN_VAR(mean_var_f32, float)
+MEAN_VAR(mean_var_f16, half)
 ROPE(rope_f32, rope_i_f32, rope_thd_f32, float)
 ROPE(rope_f16, rope_i_f16, rope_thd_f16, half)
 
@@ -604,17 +854,22 @@ ARGMAX(fast_argmax_i64_strided, int64_t, INT_MIN)
 
 #if defined(__HAVE_BFLOAT__)
 REDUCE(x + y, fast_sum_bf16, bfloat, 0)
+SEQUENTIAL_SUM(sequential_sum_bf16_strided, bfloat)
 REDUCE(x + y, fast_sum_bf16_strided, half, 0)
 REDUCE(x * y, fast_mul_bf16, bfloat, 1)
 REDUCE(x * y, fast_mul_bf16_strided, bfloat, 1)
 REDUCE(MAX(x, y), fast_max_bf16, bfloat, -HUGE_VALBF)
 REDUCE(MAX(x, y), fast_max_bf16_strided, bfloat, -HUGE_VALBF)
+REDUCE(MAX(x, (y < 0 ? -y : y)), fast_absmax_bf16_strided, bfloat, 0)
 REDUCE(MIN(x, y), fast_min_bf16, bfloat, HUGE_VALBF)
 REDUCE(MIN(x, y), fast_min_bf16_strided, bfloat, HUGE_VALBF)
 ARGMIN(fast_argmin_bf16, bfloat, HUGE_VALBF)
 ARGMAX(fast_argmax_bf16, bfloat, -HUGE_VALBF)
 SOFTMAX(softmax_bf16, bfloat)
+LOGSUMEXP(logsumexp_bf16, bfloat)
+ANY_NONFINITE(any_nonfinite_bf16, bfloat)
 RMSNORM(rmsnorm_bf16, bfloat)
 LAYERNORM(layernorm_bf16, bfloat)
+MEAN_VAR(mean_var_bf16, bfloat)
 ROPE(rope_bf16, rope_i_bf16, rope_thd_bf16, bfloat)
 #endif
@@ -0,0 +1,320 @@
+//! Pure Rust implementations of the kernels on `f32` values.
+//!
+//! These follow the same numerical contract as the Metal kernels, up to floating point rounding,
+//! and are used as the ground truth in the tests. They can also be used to verify the outputs of
+//! the kernels on a given device. Ops are named after the kernels, e.g. `"gelu"` for `gelu_f32`.
+
+/// Maps the index of an element in a contiguous layout to its index in a strided layout, this
+/// matches `get_strided_index` in the kernels.
+pub fn strided_index(idx: usize, shape: &[usize], strides: &[usize]) -> usize {
+    let mut idx = idx;
+    let mut strided_i = 0;
+    for (&dim, &stride) in shape.iter().zip(strides.iter()).rev() {
+        strided_i += (idx % dim) * stride;
+        idx /= dim;
+    }
+    strided_i
+}
+
+/// Gathers the elements of a strided view, `offset` is in elements.
+pub fn strided<T: Copy>(xs: &[T], shape: &[usize], strides: &[usize], offset: usize) -> Vec<T> {
+    let numel: usize = shape.iter().product();
+    (0..numel)
+        .map(|i| xs[offset + strided_index(i, shape, strides)])
+        .collect()
+}
+
+fn erf(x: f32) -> f32 {
+    // A&S formula 7.1.26, as in the kernels.
+    let (a1, a2, a3, a4, a5) = (
+        0.254829592f32,
+        -0.284496736,
+        1.421413741,
+        -1.453152027,
+        1.061405429,
+    );
+    let p = 0.3275911f32;
+    let sign = if x < 0. { -1. } else { 1. };
+    let x = x.abs();
+    let t = 1.0 / (1.0 + p * x);
+    let y = 1.0 - (((((a5 * t + a4) * t) + a3) * t + a2) * t + a1) * t * (-x * x).exp();
+    sign * y
+}
+
+fn gelu(x: f32) -> f32 {
+    if x > 5. {
+        return x;
+    }
+    let alpha = x + 0.044715 * x * x * x;
+    let beta = std::f32::consts::FRAC_2_SQRT_PI * std::f32::consts::FRAC_1_SQRT_2 * alpha;
+    0.5 * x * (1.0 + beta.tanh())
+}
+
+fn sign(x: f32) -> f32 {
+    if x > 0. {
+        1.
+    } else if x < 0. {
+        -1.
+    } else {
+        0.
+    }
+}
+
+/// Returns the element-wise function of the unary kernel with the given name.
+pub fn unary_fn(name: &str) -> Option<fn(f32) -> f32> {
+    let f: fn(f32) -> f32 = match name {
+        "cos" => f32::cos,
+        "sin" => f32::sin,
+        "exp" => f32::exp,
+        "sqr" => |x| x * x,
+        "sqrt" => f32::sqrt,
+        "rsqrt" => |x| 1. / x.sqrt(),
+        "sqrt_clamp" => |x| x.max(0.).sqrt(),
+        "neg" => |x| -x,
+        "log" => f32::ln,
+        "gelu" => gelu,
+        "gelu_erf" => |x| x * (1. + erf(x * std::f32::consts::FRAC_1_SQRT_2)) / 2.,
+        "erf" => erf,
+        "abs" => f32::abs,
+        "ceil" => f32::ceil,
+        "floor" => f32::floor,
+        "round" => f32::round,
+        "relu" => |x| if x < 0. { 0. } else { x },
+        "tanh" => f32::tanh,
+        "recip" => f32::recip,
+        "silu" => |x| x / (1. + (-x).exp()),
+        "sigmoid" => |x| 1. / (1. + (-x).exp()),
+        "sign" => sign,
+        "copy" => |x| x,
+        _ => return None,
+    };
+    Some(f)
+}
+
+pub fn unary(name: &str, xs: &[f32]) -> Option<Vec<f32>> {
+    let f = unary_fn(name)?;
+    Some(xs.iter().map(|&x| f(x)).collect())
+}
+
+/// Returns the element-wise function of the binary kernel with the given name, comparisons
+/// return 1 or 0.
+pub fn binary_fn(name: &str) -> Option<fn(f32, f32) -> f32> {
+    let f: fn(f32, f32) -> f32 = match name {
+        "add" => |x, y| x + y,
+        "sub" => |x, y| x - y,
+        "mul" => |x, y| x * y,
+        "div" => |x, y| x / y,
+        "min" => |x, y| if x < y { x } else { y },
+        "max" => |x, y| if x > y { x } else { y },
+        "eq" => |x, y| f32::from(u8::from(x == y)),
+        "ne" => |x, y| f32::from(u8::from(x != y)),
+        "le" => |x, y| f32::from(u8::from(x <= y)),
+        "lt" => |x, y| f32::from(u8::from(x < y)),
+        "ge" => |x, y| f32::from(u8::from(x >= y)),
+        "gt" => |x, y| f32::from(u8::from(x > y)),
+        _ => return None,
+    };
+    Some(f)
+}
+
+pub fn binary(name: &str, lhs: &[f32], rhs: &[f32]) -> Option<Vec<f32>> {
+    let f = binary_fn(name)?;
+    Some(lhs.iter().zip(rhs.iter()).map(|(&x, &y)| f(x, y)).collect())
+}
+
+/// Reduces each of the `out_length` contiguous groups of `xs`, `name` is one of `sum`, `min`,
+/// `max` or `absmax`.
+pub fn reduce(name: &str, xs: &[f32], out_length: usize) -> Option<Vec<f32>> {
+    if name == "absmax" {
+        let xs: Vec<f32> = xs.iter().map(|x| x.abs()).collect();
+        return reduce("max", &xs, out_length);
+    }
+    let f: fn(f32, f32) -> f32 = match name {
+        "sum" => |x, y| x + y,
+        "min" => f32::min,
+        "max" => f32::max,
+        _ => return None,
+    };
+    let group = xs.len() / out_length;
+    Some(
+        xs.chunks(group)
+            .map(|c| c.iter().copied().reduce(f).unwrap_or(0.))
+            .collect(),
+    )
+}
+
+/// The index of the smallest, `argmin`, or largest, `argmax`, element of each of the
+/// `out_length` contiguous groups of `xs`. Ties resolve to the first index.
+pub fn arg_reduce(name: &str, xs: &[f32], out_length: usize) -> Option<Vec<u32>> {
+    let better: fn(f32, f32) -> bool = match name {
+        "argmin" => |x, best| x < best,
+        "argmax" => |x, best| x > best,
+        _ => return None,
+    };
+    let group = xs.len() / out_length;
+    let arg = |c: &[f32]| {
+        let mut best = 0;
+        for (i, &x) in c.iter().enumerate() {
+            if better(x, c[best]) {
+                best = i
+            }
+        }
+        best as u32
+    };
+    Some(xs.chunks(group).map(arg).collect())
+}
+
+/// Softmax over each contiguous group of `last_dim` elements.
+pub fn softmax(xs: &[f32], last_dim: usize) -> Vec<f32> {
+    let mut ys = Vec::with_capacity(xs.len());
+    for c in xs.chunks(last_dim) {
+        let max = c.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exps: Vec<f32> = c.iter().map(|&x| (x - max).exp()).collect();
+        let sum: f32 = exps.iter().sum();
+        ys.extend(exps.iter().map(|&e| e / sum))
+    }
+    ys
+}
+
+/// `logsumexp` over each contiguous group of `last_dim` elements, shifted by the max of the group
+/// and accumulated in `f64`.
+pub fn logsumexp(xs: &[f32], last_dim: usize) -> Vec<f32> {
+    xs.chunks(last_dim)
+        .map(|c| {
+            let max = c.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+            let sum: f64 = c.iter().map(|&x| (x as f64 - max).exp()).sum();
+            (max + sum.ln()) as f32
+        })
+        .collect()
+}
+
+/// Scaled dot-product attention on contiguous `(batch * heads, len, head_dim)` inputs. With
+/// `causal`, query `i` attends to the keys `j <= i + kv_len - q_len`.
+pub fn sdpa(
+    q: &[f32],
+    k: &[f32],
+    v: &[f32],
+    (q_len, kv_len, head_dim): (usize, usize, usize),
+    scale: f32,
+    causal: bool,
+) -> Vec<f32> {
+    let bh = q.len() / (q_len * head_dim);
+    let mut out = Vec::with_capacity(q.len());
+    for b in 0..bh {
+        let k = &k[b * kv_len * head_dim..(b + 1) * kv_len * head_dim];
+        let v = &v[b * kv_len * head_dim..(b + 1) * kv_len * head_dim];
+        for i in 0..q_len {
+            let q = &q[(b * q_len + i) * head_dim..(b * q_len + i + 1) * head_dim];
+            let scores: Vec<f32> = (0..kv_len)
+                .map(|j| {
+                    if causal && j + q_len > i + kv_len {
+                        return f32::NEG_INFINITY;
+                    }
+                    let k = &k[j * head_dim..(j + 1) * head_dim];
+                    q.iter().zip(k.iter()).map(|(x, y)| x * y).sum::<f32>() * scale
+                })
+                .collect();
+            let probs = softmax(&scores, kv_len);
+            for d in 0..head_dim {
+                out.push((0..kv_len).map(|j| probs[j] * v[j * head_dim + d]).sum())
+            }
+        }
+    }
+    out
+}
+
+/// The mean and the variance of each contiguous group of `last_dim` elements, computed in `f64`
+/// with two passes. The variance is divided by `last_dim - 1` when `unbiased` is set.
+pub fn mean_var(xs: &[f32], last_dim: usize, unbiased: bool) -> (Vec<f32>, Vec<f32>) {
+    let n = last_dim as f64;
+    let ddof = if unbiased { 1. } else { 0. };
+    xs.chunks(last_dim)
+        .map(|c| {
+            let mean = c.iter().map(|&x| x as f64).sum::<f64>() / n;
+            let m2: f64 = c.iter().map(|&x| (x as f64 - mean).powi(2)).sum();
+            (mean as f32, (m2 / (n - ddof)) as f32)
+        })
+        .unzip()
+}
+
+/// `x * mul + add`, computed as a fused multiply-add in `f32`.
+pub fn affine(xs: &[f32], mul: f32, add: f32) -> Vec<f32> {
+    xs.iter().map(|&x| x.mul_add(mul, add)).collect()
+}
+
+/// Selects `on_true` where `cond` is non-zero and `on_false` elsewhere.
+pub fn where_cond<C: Copy + Default + PartialEq, T: Copy>(
+    cond: &[C],
+    on_true: &[T],
+    on_false: &[T],
+) -> Vec<T> {
+    cond.iter()
+        .zip(on_true.iter().zip(on_false.iter()))
+        .map(|(&c, (&t, &f))| if c != C::default() { t } else { f })
+        .collect()
+}
+
+/// Selects the `ids` along `dim` of a contiguous tensor with the given shape.
+pub fn index_select<T: Copy>(xs: &[T], shape: &[usize], dim: usize, ids: &[u32]) -> Vec<T> {
+    let left_size: usize = shape[..dim].iter().product();
+    let right_size: usize = shape[dim + 1..].iter().product();
+    let src_dim_size = shape[dim];
+    let mut ys = Vec::with_capacity(left_size * ids.len() * right_size);
+    for left in 0..left_size {
+        for &id in ids.iter() {
+            let start = (left * src_dim_size + id as usize) * right_size;
+            ys.extend_from_slice(&xs[start..start + right_size])
+        }
+    }
+    ys
+}
+
+/// Depth to space on a contiguous `(b, c * r * r, h, w)` tensor, returns `(b, c, h * r, w * r)`.
+pub fn pixel_shuffle<T: Copy>(xs: &[T], shape: &[usize], r: usize) -> Vec<T> {
+    let (b_size, c_in, h_in, w_in) = (shape[0], shape[1], shape[2], shape[3]);
+    let mut ys = Vec::with_capacity(xs.len());
+    for b_idx in 0..b_size {
+        for c_idx in 0..c_in / (r * r) {
+            for dst_h in 0..h_in * r {
+                for dst_w in 0..w_in * r {
+                    let src_c = c_idx * r * r + (dst_h % r) * r + dst_w % r;
+                    let src_i = ((b_idx * c_in + src_c) * h_in + dst_h / r) * w_in + dst_w / r;
+                    ys.push(xs[src_i])
+                }
+            }
+        }
+    }
+    ys
+}
+
+/// Space to depth on a contiguous `(b, c, h * r, w * r)` tensor, returns `(b, c * r * r, h, w)`.
+pub fn pixel_unshuffle<T: Copy>(xs: &[T], shape: &[usize], r: usize) -> Vec<T> {
+    let (b_size, c_in, h_in, w_in) = (shape[0], shape[1], shape[2], shape[3]);
+    let mut ys = Vec::with_capacity(xs.len());
+    for b_idx in 0..b_size {
+        for c_idx in 0..c_in * r * r {
+            for dst_h in 0..h_in / r {
+                for dst_w in 0..w_in / r {
+                    let src_c = c_idx / (r * r);
+                    let src_h = dst_h * r + (c_idx / r) % r;
+                    let src_w = dst_w * r + c_idx % r;
+                    ys.push(xs[((b_idx * c_in + src_c) * h_in + src_h) * w_in + src_w])
+                }
+            }
+        }
+    }
+    ys
+}
+
+/// Converts a decoded image in `[-1, 1]` with the contiguous shape `(channels, height, width)`
+/// to `u8` values in `(height, width, channels)` order, as done before saving images.
+pub fn latent_to_rgb8(xs: &[f32], channels: usize, height: usize, width: usize) -> Vec<u8> {
+    let mut ys = Vec::with_capacity(xs.len());
+    for hw in 0..height * width {
+        for c in 0..channels {
+            let v = (xs[c * height * width + hw] / 2. + 0.5).clamp(0., 1.);
+            ys.push((v * 255.) as u8)
+        }
+    }
+    ys
+}
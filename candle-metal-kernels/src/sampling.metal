@@ -0,0 +1,243 @@
+#include <metal_stdlib>
+using namespace metal;
+
+#define THREADGROUP_SIZE 1024
+
+// Maps a float to an unsigned key with the same ordering, NaNs excepted.
+METAL_FUNC uint ordered_key(float x) {
+    uint bits = as_type<uint>(x);
+    return (bits & 0x80000000) ? ~bits : (bits | 0x80000000);
+}
+
+// The block dimension has to be a power of two, all the threads of the threadgroup get the result.
+METAL_FUNC float threadgroup_sum(float value, threadgroup float * shared_memory, uint tid, uint block_dim) {
+    /* wait for the previous result to be read before overwriting it */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    shared_memory[tid] = value;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+    return shared_memory[0];
+}
+
+METAL_FUNC float threadgroup_max(float value, threadgroup float * shared_memory, uint tid, uint block_dim) {
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    shared_memory[tid] = value;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = max(shared_memory[tid], shared_memory[tid + s]);
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+    return shared_memory[0];
+}
+
+// Both filters avoid sorting the rows: the threshold is the key of the last kept element and it
+// is found bit by bit, from the most significant one, as the largest key for which the kept
+// elements still satisfy the constraint. This takes 32 passes over the row whatever its length,
+// whereas a sort in threadgroup memory would limit the row length. Elements equal to the
+// threshold are all kept.
+
+// Keeps the k largest logits of each row and sets the other ones to -inf.
+template<typename T>
+METAL_FUNC void top_k(
+    constant size_t & ncols,
+    constant size_t & k,
+    device const T * src,
+    device T * dst,
+    uint tid,
+    uint row,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    device const T * x = src + row * ncols;
+    device T * y = dst + row * ncols;
+
+    uint threshold = 0;
+    for (int bit = 31; bit >= 0; bit--) {
+        uint candidate = threshold | (1u << bit);
+        float count = 0;
+        for (size_t idx = tid; idx < ncols; idx += block_dim) {
+            count += ordered_key(float(x[idx])) >= candidate ? 1 : 0;
+        }
+        if (threadgroup_sum(count, shared_memory, tid, block_dim) >= float(k)) {
+            threshold = candidate;
+        }
+    }
+
+    for (size_t idx = tid; idx < ncols; idx += block_dim) {
+        y[idx] = ordered_key(float(x[idx])) >= threshold ? x[idx] : T(-INFINITY);
+    }
+}
+
+// Keeps the smallest set of largest logits of each row which softmax mass is at least p and sets
+// the other ones to -inf. The largest logit is always kept.
+template<typename T>
+METAL_FUNC void top_p(
+    constant size_t & ncols,
+    constant float & p,
+    device const T * src,
+    device T * dst,
+    uint tid,
+    uint row,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    device const T * x = src + row * ncols;
+    device T * y = dst + row * ncols;
+
+    float tmp = -INFINITY;
+    for (size_t idx = tid; idx < ncols; idx += block_dim) {
+        tmp = max(tmp, float(x[idx]));
+    }
+    const float _max = threadgroup_max(tmp, shared_memory, tid, block_dim);
+
+    tmp = 0;
+    for (size_t idx = tid; idx < ncols; idx += block_dim) {
+        tmp += exp(float(x[idx]) - _max);
+    }
+    const float target = p * threadgroup_sum(tmp, shared_memory, tid, block_dim);
+
+    uint threshold = 0;
+    for (int bit = 31; bit >= 0; bit--) {
+        uint candidate = threshold | (1u << bit);
+        float mass = 0;
+        for (size_t idx = tid; idx < ncols; idx += block_dim) {
+            const float v = float(x[idx]);
+            mass += ordered_key(v) >= candidate ? exp(v - _max) : 0;
+        }
+        mass = threadgroup_sum(mass, shared_memory, tid, block_dim);
+        if (mass > 0 && mass >= target) {
+            threshold = candidate;
+        }
+    }
+
+    for (size_t idx = tid; idx < ncols; idx += block_dim) {
+        y[idx] = ordered_key(float(x[idx])) >= threshold ? x[idx] : T(-INFINITY);
+    }
+}
+
+#define TOP_K(NAME, T) \
+kernel void NAME( \
+    constant size_t &ncols, \
+    constant size_t &k, \
+    device const T *src, \
+    device T *dst, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint row [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    top_k<T>(ncols, k, src, dst, tid, row, block_dim, shared_memory); \
+} \
+
+#define TOP_P(NAME, T) \
+kernel void NAME( \
+    constant size_t &ncols, \
+    constant float &p, \
+    device const T *src, \
+    device T *dst, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint row [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    top_p<T>(ncols, p, src, dst, tid, row, block_dim, shared_memory); \
+} \
+
+// Counter-based random numbers: the value for a given counter only depends on the key so the
+// results do not depend on the scheduling of the threads.
+// Squares: A Fast Counter-Based RNG, B. Widynski, 2020. https://arxiv.org/abs/2004.06278
+METAL_FUNC uint squares32(ulong ctr, ulong key) {
+    ulong x = ctr * key;
+    ulong y = x;
+    ulong z = y + key;
+    x = x * x + y;
+    x = (x >> 32) | (x << 32);
+    x = x * x + z;
+    x = (x >> 32) | (x << 32);
+    x = x * x + y;
+    x = (x >> 32) | (x << 32);
+    return uint((x * x + z) >> 32);
+}
+
+// The squares keys need to be well mixed, the seed is hashed with splitmix64. The key is made
+// odd as a zero key would only generate zeros.
+METAL_FUNC ulong squares_key(ulong seed) {
+    ulong x = seed + 0x9e3779b97f4a7c15UL;
+    x = (x ^ (x >> 30)) * 0xbf58476d1ce4e5b9UL;
+    x = (x ^ (x >> 27)) * 0x94d049bb133111ebUL;
+    return (x ^ (x >> 31)) | 1;
+}
+
+// Draws one index per row by inverting the cumulative distribution of the row, the
+// probabilities do not have to be normalized. One thread per row.
+template<typename T>
+METAL_FUNC void multinomial(
+    constant size_t & num_rows,
+    constant size_t & row_len,
+    constant ulong & seed,
+    device const T * probs,
+    device uint * dst,
+    uint row
+) {
+    if (row >= num_rows) {
+        return;
+    }
+    device const T * x = probs + row * row_len;
+
+    float total = 0;
+    for (size_t idx = 0; idx < row_len; idx++) {
+        total += float(x[idx]);
+    }
+    // 24 random bits so that the uniform value is exact in float and strictly below 1.
+    const float u = float(squares32(row, squares_key(seed)) >> 8) / 16777216.0f;
+    const float target = u * total;
+
+    // Rounding can leave the target above the last partial sum, in that case the last index
+    // with a non-zero probability is returned.
+    uint sampled = 0;
+    float cumsum = 0;
+    for (size_t idx = 0; idx < row_len; idx++) {
+        const float p = float(x[idx]);
+        if (p <= 0) {
+            continue;
+        }
+        sampled = idx;
+        cumsum += p;
+        if (cumsum > target) {
+            break;
+        }
+    }
+    dst[row] = sampled;
+}
+
+#define MULTINOMIAL(NAME, T) \
+kernel void NAME( \
+    constant size_t &num_rows, \
+    constant size_t &row_len, \
+    constant ulong &seed, \
+    device const T *probs, \
+    device uint *dst, \
+    uint row [[ thread_position_in_grid ]] \
+) { \
+    multinomial<T>(num_rows, row_len, seed, probs, dst, row); \
+} \
+
+TOP_K(top_k_f32, float)
+TOP_K(top_k_f16, half)
+TOP_P(top_p_f32, float)
+TOP_P(top_p_f16, half)
+MULTINOMIAL(multinomial_f32, float)
+MULTINOMIAL(multinomial_f16, half)
+
+#if defined(__HAVE_BFLOAT__)
+TOP_K(top_k_bf16, bfloat)
+TOP_P(top_p_bf16, bfloat)
+MULTINOMIAL(multinomial_bf16, bfloat)
+#endif
@@ -0,0 +1,158 @@
+#include <metal_stdlib>
+using namespace metal;
+
+// Applies the causal mask in the fused kernel, the mask is skipped when the constant is not set.
+constant bool sdpa_causal_ [[function_constant(0)]];
+constant bool sdpa_causal = is_function_constant_defined(sdpa_causal_) && sdpa_causal_;
+
+// Each threadgroup handles SDPA_BLOCK_Q query rows of one (batch, head) pair, one row per thread,
+// and iterates over the keys and values in tiles of SDPA_BLOCK_K rows that are staged in
+// threadgroup memory.
+#define SDPA_BLOCK_Q 32
+#define SDPA_BLOCK_K 16
+
+// Flash attention style: the softmax is computed online, the running max and sum are updated for
+// each key and the accumulated output is rescaled accordingly, so the (q_len, kv_len) scores are
+// never materialized. The accumulation is done in float whatever T.
+// With the causal mask, query i attends to the keys j <= i + kv_len - q_len, i.e. the mask is
+// aligned on the last key as when decoding with a kv cache.
+template<typename T, int D>
+METAL_FUNC void sdpa(
+    constant size_t & q_len,
+    constant size_t & kv_len,
+    constant float & scale,
+    device const T * q,
+    device const T * k,
+    device const T * v,
+    device T * o,
+    threadgroup float * k_tile,
+    threadgroup float * v_tile,
+    uint tid,
+    uint2 tgid,
+    uint block_dim
+) {
+    const size_t bh = tgid.y;
+    const size_t row = tgid.x * SDPA_BLOCK_Q + tid;
+    const bool active = row < q_len;
+    q += bh * q_len * D;
+    k += bh * kv_len * D;
+    v += bh * kv_len * D;
+    o += bh * q_len * D;
+
+    float q_row[D];
+    float acc[D];
+    for (int d = 0; d < D; d++) {
+        q_row[d] = active ? float(q[row * D + d]) * scale : 0;
+        acc[d] = 0;
+    }
+    float m = -INFINITY;
+    float l = 0;
+
+    // The tiles past the last key visible by the rows of the threadgroup are skipped.
+    size_t kv_end = kv_len;
+    if (sdpa_causal) {
+        const size_t last_row = min(size_t(tgid.x + 1) * SDPA_BLOCK_Q, q_len) - 1;
+        kv_end = min(kv_len, last_row + kv_len - q_len + 1);
+    }
+    for (size_t start = 0; start < kv_end; start += SDPA_BLOCK_K) {
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        for (uint i = tid; i < SDPA_BLOCK_K * D; i += block_dim) {
+            const bool in_bounds = start + i / D < kv_len;
+            k_tile[i] = in_bounds ? float(k[start * D + i]) : 0;
+            v_tile[i] = in_bounds ? float(v[start * D + i]) : 0;
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        if (!active) {
+            continue;
+        }
+        for (uint jj = 0; jj < SDPA_BLOCK_K; jj++) {
+            const size_t j = start + jj;
+            if (j >= kv_len || (sdpa_causal && j > row + kv_len - q_len)) {
+                break;
+            }
+            float s = 0;
+            for (int d = 0; d < D; d++) {
+                s += q_row[d] * k_tile[jj * D + d];
+            }
+            const float m_new = max(m, s);
+            const float correction = exp(m - m_new);
+            const float p = exp(s - m_new);
+            l = l * correction + p;
+            for (int d = 0; d < D; d++) {
+                acc[d] = acc[d] * correction + p * v_tile[jj * D + d];
+            }
+            m = m_new;
+        }
+    }
+
+    if (active) {
+        for (int d = 0; d < D; d++) {
+            o[row * D + d] = T(acc[d] / l);
+        }
+    }
+}
+
+#define SDPA(NAME, T, D) \
+kernel void NAME( \
+    constant size_t &q_len, \
+    constant size_t &kv_len, \
+    constant float &scale, \
+    device const T *q, \
+    device const T *k, \
+    device const T *v, \
+    device T *o, \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint2 tgid [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float k_tile[SDPA_BLOCK_K * D]; \
+    threadgroup float v_tile[SDPA_BLOCK_K * D]; \
+    sdpa<T, D>(q_len, kv_len, scale, q, k, v, o, k_tile, v_tile, tid, tgid, block_dim); \
+} \
+
+// Sets the scores of the keys masked for each query to -inf, in place, using the same alignment
+// as the fused kernel. This is used when the fused kernel is not available for the head dim.
+template<typename T>
+METAL_FUNC void causal_mask(
+    constant size_t & dst_size,
+    constant size_t & q_len,
+    constant size_t & kv_len,
+    device T * scores,
+    uint tid
+) {
+    if (tid >= dst_size) {
+        return;
+    }
+    const size_t i = (tid / kv_len) % q_len;
+    const size_t j = tid % kv_len;
+    if (j > i + kv_len - q_len) {
+        scores[tid] = T(-INFINITY);
+    }
+}
+
+#define CAUSAL_MASK(NAME, T) \
+kernel void NAME( \
+    constant size_t &dst_size, \
+    constant size_t &q_len, \
+    constant size_t &kv_len, \
+    device T *scores, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    causal_mask<T>(dst_size, q_len, kv_len, scores, tid); \
+} \
+
+#define SDPA_HEAD_DIMS(NAME, T) \
+SDPA(NAME##_32, T, 32) \
+SDPA(NAME##_64, T, 64) \
+SDPA(NAME##_96, T, 96) \
+SDPA(NAME##_128, T, 128) \
+
+SDPA_HEAD_DIMS(sdpa_f32, float)
+SDPA_HEAD_DIMS(sdpa_f16, half)
+CAUSAL_MASK(causal_mask_f32, float)
+CAUSAL_MASK(causal_mask_f16, half)
+
+#if defined(__HAVE_BFLOAT__)
+SDPA_HEAD_DIMS(sdpa_bf16, bfloat)
+CAUSAL_MASK(causal_mask_bf16, bfloat)
+#endif
@@ -39,6 +39,22 @@ METAL_FUNC void where_cond(
     out[i] = ids[strided_i] ? t[strided_i_t] : f[strided_i_f];
 }
 
+// Fast path when the condition and both values are contiguous with the same shape.
+template<typename T, typename ID>
+METAL_FUNC void where_cond_contiguous(
+    constant size_t &numel,
+    device const ID *ids,
+    device const T *t,
+    device const T *f,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    out[i] = ids[i] ? t[i] : f[i];
+}
+
 #define WHERE_OP(T, ID, FN_NAME)                                                                \
 kernel void FN_NAME(                                                                            \
     constant size_t &numel,                                                                     \
@@ -55,6 +71,81 @@ kernel void FN_NAME(
 ) {                                                                                             \
    where_cond<T, ID>(numel, num_dims, dims, strides, strides_t, strides_f, ids, t, f, out, i);  \
 }                                                                                               \
+kernel void FN_NAME##_contiguous(                                                               \
+    constant size_t &numel,                                                                     \
+    device const ID *ids,                                                                       \
+    device const T *t,                                                                          \
+    device const T *f,                                                                          \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   where_cond_contiguous<T, ID>(numel, ids, t, f, out, i);                                      \
+}                                                                                               \
+
+template<typename T>
+METAL_FUNC void masked_fill(
+    constant size_t &numel,
+    constant float &value,
+    device const T *input,
+    device const uint8_t *mask,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    out[i] = mask[i] ? T(value) : input[i];
+}
+
+template<typename T>
+METAL_FUNC void masked_fill_strided(
+    constant size_t &numel,
+    constant size_t &num_dims,
+    constant size_t *dims,
+    constant size_t *strides,
+    constant size_t *strides_mask,
+    constant float &value,
+    device const T *input,
+    device const uint8_t *mask,
+    device T *out,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    uint strided_i = get_strided_index(i, num_dims, dims, strides);
+    uint strided_i_mask = get_strided_index(i, num_dims, dims, strides_mask);
+    out[i] = mask[strided_i_mask] ? T(value) : input[strided_i];
+}
+
+#define MASKED_FILL_OP(T, FN_NAME)                                                              \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant float &value,                                                                      \
+    device const T *input,                                                                      \
+    device const uint8_t *mask,                                                                 \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   masked_fill<T>(numel, value, input, mask, out, i);                                           \
+}                                                                                               \
+kernel void FN_NAME##_strided(                                                                  \
+    constant size_t &numel,                                                                     \
+    constant size_t &num_dims,                                                                  \
+    constant size_t *dims,                                                                      \
+    constant size_t *strides,                                                                   \
+    constant size_t *strides_mask,                                                              \
+    constant float &value,                                                                      \
+    device const T *input,                                                                      \
+    device const uint8_t *mask,                                                                 \
+    device T *out,                                                                              \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   masked_fill_strided<T>(numel, num_dims, dims, strides, strides_mask, value, input, mask, out, i); \
+}                                                                                               \
+
+MASKED_FILL_OP(half, masked_fill_f16)
+MASKED_FILL_OP(float, masked_fill_f32)
 
 WHERE_OP(half, uint32_t, where_u32_f16)
 WHERE_OP(float, uint32_t, where_u32_f32)
@@ -81,6 +172,7 @@ WHERE_OP(bfloat, int64_t, where_i64_bf16)
 #endif
 
 #if defined(__HAVE_BFLOAT__)
+MASKED_FILL_OP(bfloat, masked_fill_bf16)
 WHERE_OP(bfloat, uint8_t, where_u8_bf16)
 WHERE_OP(bfloat, uint32_t, where_u32_bf16)
 #endif
@@ -124,6 +124,114 @@ fn run_strided<T: Clone>(
     read_to_vec(&output_b, v.len())
 }
 
+#[test]
+fn contiguous_layouts() {
+    assert!(is_contiguous(&[2, 3, 4], &[12, 4, 1]));
+    assert!(is_contiguous(&[], &[]));
+    // The strides of the dims of size 1 are not used.
+    assert!(is_contiguous(&[2, 1, 4], &[4, 7, 1]));
+    assert!(!is_contiguous(&[2, 3], &[1, 2]));
+    assert!(!is_contiguous(&[2, 3], &[6, 2]));
+    assert!(!is_contiguous(&[2, 3], &[3]));
+}
+
+#[test]
+fn unary_routing() {
+    let device = device();
+    let command_queue = device.new_command_queue();
+    let v: Vec<f32> = (0..6).map(|i| i as f32).collect();
+    let input = new_buffer(&device, &v);
+    let output = new_buffer(&device, &v);
+    let run = |strides: &[usize]| {
+        let kernels = Kernels::new();
+        let command_buffer = command_queue.new_command_buffer();
+        call_unary(
+            &device,
+            command_buffer,
+            &kernels,
+            unary::strided::neg::FLOAT,
+            &[2, 3],
+            BufferOffset::zero_offset(&input),
+            strides,
+            BufferOffset::zero_offset(&output),
+        )
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+        let pipelines = kernels.pipelines.read().unwrap();
+        let names: Vec<&str> = pipelines.keys().map(|(_, _, name, _)| *name).collect();
+        (names, read_to_vec::<f32>(&output, v.len()))
+    };
+
+    let (names, results) = run(&[3, 1]);
+    assert_eq!(names, ["neg_f32"]);
+    assert_eq!(results, [-0., -1., -2., -3., -4., -5.]);
+
+    // The transpose of a contiguous (3, 2).
+    let (names, results) = run(&[1, 2]);
+    assert_eq!(names, ["neg_f32_strided"]);
+    assert_eq!(results, [-0., -2., -4., -1., -3., -5.]);
+}
+
+#[test]
+fn strided_args() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+
+    // left is a contiguous [2, 3], right is the transpose of a contiguous [3, 2] starting at
+    // element 1 of its buffer.
+    let left = new_buffer(&device, &[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    let right = new_buffer(&device, &[0.0f32, 10.0, 40.0, 20.0, 50.0, 30.0, 60.0]);
+    let output = new_buffer(&device, &[0.0f32; 6]);
+    let cos_output = new_buffer(&device, &[0.0f32; 6]);
+    let shape = [2, 3];
+    let left_strides = [3, 1];
+    let right_strides = [1, 2];
+    let transposed_strides = [1, 2];
+
+    let args = BinaryStridedArgs {
+        shape: &shape,
+        left: BufferView::new(&left, &left_strides),
+        right: BufferView::new(&right, &right_strides).with_offset(std::mem::size_of::<f32>()),
+        output: BufferOffset::zero_offset(&output),
+    };
+    call_binary_strided_args(
+        &device,
+        command_buffer,
+        &kernels,
+        binary::strided::add::FLOAT,
+        args,
+    )
+    .unwrap();
+
+    let args = UnaryStridedArgs {
+        shape: &shape,
+        input: BufferView::new(&left, &transposed_strides),
+        output: BufferOffset::zero_offset(&cos_output),
+    };
+    call_unary_strided_args(
+        &device,
+        command_buffer,
+        &kernels,
+        unary::strided::cos::FLOAT,
+        args,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let results: Vec<f32> = read_to_vec(&output, 6);
+    assert_eq!(results, vec![11.0, 22.0, 33.0, 44.0, 55.0, 66.0]);
+    let results: Vec<f32> = read_to_vec(&cos_output, 6);
+    let expected: Vec<_> = [1.0f32, 3.0, 5.0, 2.0, 4.0, 6.0]
+        .iter()
+        .map(|v| v.cos())
+        .collect();
+    assert_eq!(approx(results, 4), approx(expected, 4));
+}
+
 #[test]
 fn cos_f32() {
     let v = vec![1.0f32, 2.0, 3.0];
@@ -242,6 +350,110 @@ fn gelu_f32() {
     assert_eq!(approx(results, 3), expected);
 }
 
+fn run_gelu(v: &[f32], variant: GeluVariant) -> Vec<f32> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, v);
+    call_gelu(
+        &device,
+        command_buffer,
+        &kernels,
+        variant,
+        DType::F32,
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len())
+}
+
+#[test]
+fn gelu_variants() {
+    let v: Vec<f32> = vec![-2.0, -1.0, 0., 1., 2.];
+
+    let results = run_gelu(&v, GeluVariant::Tanh);
+    let expected: Vec<f32> = v
+        .iter()
+        .map(|&x| {
+            let c = (2.0f32 / std::f32::consts::PI).sqrt();
+            0.5 * x * (1.0 + (c * (x + 0.044715 * x * x * x)).tanh())
+        })
+        .collect();
+    assert_eq!(approx(results.clone(), 4), approx(expected, 4));
+    assert_eq!(approx(vec![results[3]], 4), vec![0.8412]);
+
+    // Reference values for 0.5 * x * (1 + erf(x / sqrt(2))).
+    let results = run_gelu(&v, GeluVariant::Erf);
+    let expected = vec![-0.0455, -0.1587, 0.0, 0.8413, 1.9545];
+    assert_eq!(approx(results, 4), expected);
+}
+
+#[test]
+fn nonfinite() {
+    fn run_mask(v: &[f32], op: &str) -> Vec<u8> {
+        let device = device();
+        let kernels = Kernels::new();
+        let command_queue = device.new_command_queue();
+        let command_buffer = command_queue.new_command_buffer();
+        let input = new_buffer(&device, v);
+        let input = BufferOffset::zero_offset(&input);
+        let out_len = if op == "any_nonfinite" { 1 } else { v.len() };
+        let output = new_buffer(&device, &vec![2u8; out_len]);
+        let (device, kernels, len) = (&device, &kernels, v.len());
+        match op {
+            "isnan" => call_isnan(
+                device,
+                command_buffer,
+                kernels,
+                DType::F32,
+                len,
+                input,
+                &output,
+            ),
+            "isfinite" => call_isfinite(
+                device,
+                command_buffer,
+                kernels,
+                DType::F32,
+                len,
+                input,
+                &output,
+            ),
+            "any_nonfinite" => call_any_nonfinite(
+                device,
+                command_buffer,
+                kernels,
+                DType::F32,
+                len,
+                input,
+                &output,
+            ),
+            _ => unreachable!(),
+        }
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+        read_to_vec(&output, out_len)
+    }
+
+    let v = vec![1.0f32, f32::NAN, f32::INFINITY, -2.0, f32::NEG_INFINITY];
+    assert_eq!(run_mask(&v, "isnan"), vec![0, 1, 0, 0, 0]);
+    assert_eq!(run_mask(&v, "isfinite"), vec![1, 0, 0, 1, 0]);
+
+    let mut v = vec![1.0f32; 10_000];
+    assert_eq!(run_mask(&v, "any_nonfinite"), vec![0]);
+    v[7_777] = f32::NAN;
+    assert_eq!(run_mask(&v, "any_nonfinite"), vec![1]);
+    v[7_777] = f32::INFINITY;
+    assert_eq!(run_mask(&v, "any_nonfinite"), vec![1]);
+}
+
 #[test]
 fn silu_f16() {
     let v: Vec<f16> = [-10f32, -1.0, 0., 1., 2., 3., 10.0, 20.0]
@@ -261,6 +473,42 @@ fn silu_f32() {
     assert_eq!(approx(results, 3), expected);
 }
 
+#[test]
+fn rsqrt() {
+    let v: Vec<f32> = vec![1e-6, 0.01, 0.25, 1., 2., 3., 100., 1e6];
+    let expected: Vec<f32> = v.iter().map(|x| 1. / x.sqrt()).collect();
+    let results = run(&v, unary::contiguous::rsqrt::FLOAT);
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r - e).abs() <= 1e-5 * e, "{r} {e}");
+    }
+
+    let v: Vec<f16> = v.iter().map(|&x| f16::from_f32(x)).collect();
+    let expected: Vec<f32> = v.iter().map(|x| 1. / x.to_f32().sqrt()).collect();
+    let results = run(&v, unary::contiguous::rsqrt::HALF);
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r.to_f32() - e).abs() <= 1e-3 * e, "{r} {e}");
+    }
+
+    let v: Vec<bf16> = [0.01f32, 0.25, 1., 2., 100.]
+        .iter()
+        .map(|&x| bf16::from_f32(x))
+        .collect();
+    let expected: Vec<f32> = v.iter().map(|x| 1. / x.to_f32().sqrt()).collect();
+    let results = run(&v, unary::contiguous::rsqrt::BFLOAT);
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r.to_f32() - e).abs() <= 8e-3 * e, "{r} {e}");
+    }
+}
+
+#[test]
+fn sqrt_clamp() {
+    let v: Vec<f32> = vec![-1e-7, -0., 0., 0.25, 4.];
+    let results = run(&v, unary::contiguous::sqrt_clamp::FLOAT);
+    assert_eq!(results, [0., 0., 0., 0.5, 2.]);
+    let results = run(&v, unary::contiguous::sqrt::FLOAT);
+    assert!(results[0].is_nan());
+}
+
 #[test]
 fn binary_add_f32() {
     let left = vec![1.0f32, 2.0, 3.0];
@@ -275,6 +523,59 @@ fn binary_add_f32() {
     assert_eq!(approx(expected, 4), vec![3.0f32, 5.1, 7.2]);
 }
 
+fn run_binary_scalar<T: Clone>(
+    v: &[T],
+    scalar: f32,
+    name: binary::scalar::Kernel,
+    reverse: bool,
+) -> Vec<T> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, v);
+    call_binary_scalar(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        scalar,
+        &output,
+        reverse,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len())
+}
+
+#[test]
+fn binary_scalar() {
+    let v = vec![1.0f32, 2.0, 4.0, -8.0];
+    let results = run_binary_scalar(&v, 3., binary::scalar::sub::FLOAT, false);
+    assert_eq!(results, [-2.0, -1.0, 1.0, -11.0]);
+    // scalar - tensor
+    let results = run_binary_scalar(&v, 3., binary::scalar::sub::FLOAT, true);
+    assert_eq!(results, [2.0, 1.0, -1.0, 11.0]);
+    // scalar / tensor
+    let results = run_binary_scalar(&v, 2., binary::scalar::div::FLOAT, true);
+    assert_eq!(results, [2.0, 1.0, 0.5, -0.25]);
+    let results = run_binary_scalar(&v, 2., binary::scalar::div::FLOAT, false);
+    assert_eq!(results, [0.5, 1.0, 2.0, -4.0]);
+    let results = run_binary_scalar(&v, 1.5, binary::scalar::max::FLOAT, true);
+    assert_eq!(results, [1.5, 2.0, 4.0, 1.5]);
+
+    let v: Vec<f16> = v.iter().map(|&x| f16::from_f32(x)).collect();
+    let results = run_binary_scalar(&v, 3., binary::scalar::sub::HALF, true);
+    assert_eq!(approx_f16(results, 4), [2.0, 1.0, -1.0, 11.0]);
+    let v: Vec<bf16> = v.iter().map(|&x| bf16::from_f32(x.to_f32())).collect();
+    let results = run_binary_scalar(&v, 2., binary::scalar::div::BFLOAT, true);
+    assert_eq!(approx_bf16(results, 4), [2.0, 1.0, 0.5, -0.25]);
+}
+
 #[test]
 fn binary_ops_bf16() {
     let lhs: Vec<bf16> = [1.1f32, 2.2, 3.3].into_iter().map(bf16::from_f32).collect();
@@ -359,6 +660,39 @@ fn cast_f32() {
     assert_eq!(results, v_i64);
 }
 
+#[test]
+fn cast_f32_f16_rounding() {
+    let v_f32 = [
+        // The largest f16, a value that rounds down to it and values that overflow.
+        65504f32,
+        65519.,
+        65520.,
+        -65520.,
+        1e6,
+        // A subnormal, the smallest subnormal and a value rounding to zero.
+        1e-7,
+        5.9604645e-8,
+        2e-8,
+        // Halfway between two f16 values, these round to the even one.
+        1.000_488_3,
+        1.001_464_8,
+        2049.,
+        2051.,
+    ];
+    let expected: Vec<f16> = v_f32.iter().map(|&v| f16::from_f32(v)).collect();
+    let results: Vec<f16> = run_cast(&v_f32, "cast_f32_f16");
+    assert_eq!(results, expected);
+
+    let bits: Vec<u16> = results.iter().map(|v| v.to_bits()).collect();
+    assert_eq!(
+        bits,
+        [
+            0x7bff, 0x7bff, 0x7c00, 0xfc00, 0x7c00, 0x0002, 0x0001, 0x0000, 0x3c00, 0x3c02, 0x6800,
+            0x6802
+        ]
+    );
+}
+
 #[test]
 fn cast_f16() {
     let v_f64 = [1.0f64, 2.0, 3.0];
@@ -514,6 +848,120 @@ fn cast_i64() {
     assert_eq!(results, v_u8);
 }
 
+fn run_cast_unary<T: Clone, U: Clone>(v: &[T], name: unary::cast::Kernel) -> Vec<U> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let options = MTLResourceOptions::StorageModeManaged;
+    let size = (v.len() * std::mem::size_of::<U>()) as u64;
+    let output = device.new_buffer(size, options);
+
+    call_cast_unary(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len())
+}
+
+#[test]
+fn cast_unary() {
+    let v: Vec<f16> = (0..1000)
+        .map(|i| f16::from_f32((i as f32 - 500.) / 100.))
+        .collect();
+
+    // f16 -> (exp in f32) -> f16, the two-step version casts around the f32 kernel.
+    let v_f32: Vec<f32> = run_cast(&v, "cast_f16_f32");
+    let exp_f32 = run(&v_f32, unary::contiguous::exp::FLOAT);
+    let expected: Vec<f16> = run_cast(&exp_f32, "cast_f32_f16");
+    assert_eq!(run(&v, unary::contiguous::exp::HALF), expected);
+
+    let results: Vec<f32> = run_cast_unary(&v, unary::cast::exp::HALF_FLOAT);
+    assert_eq!(results, exp_f32);
+    let results: Vec<f16> = run_cast_unary(&v_f32, unary::cast::exp::FLOAT_HALF);
+    assert_eq!(results, expected);
+}
+
+fn run_with_precision<T: Clone>(
+    v: &[T],
+    name: unary::precision::Kernel,
+    precision: ErfPrecision,
+) -> Vec<T> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, v);
+    call_unary_with_precision(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        precision,
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len())
+}
+
+/// The largest difference between the fast and accurate paths, the `gelu_erf` one is relative
+/// to `max(1, |x|)`.
+fn max_precision_error<T: Clone + Copy>(
+    v: &[T],
+    name: unary::precision::Kernel,
+    to_f32: fn(T) -> f32,
+    relative: bool,
+) -> f32 {
+    let accurate = run_with_precision(v, unary::precision::Kernel(name.0), ErfPrecision::Accurate);
+    let fast = run_with_precision(v, name, ErfPrecision::Fast);
+    v.iter()
+        .zip(accurate.iter().zip(fast.iter()))
+        .map(|(&x, (&a, &f))| {
+            let scale = if relative {
+                to_f32(x).abs().max(1.)
+            } else {
+                1.
+            };
+            (to_f32(a) - to_f32(f)).abs() / scale
+        })
+        .fold(0., f32::max)
+}
+
+#[test]
+fn erf_precision() {
+    use unary::precision::{erf, gelu_erf};
+    let xs: Vec<f32> = (0..=1200).map(|i| (i as f32 - 600.) / 100.).collect();
+
+    let v: Vec<f16> = xs.iter().map(|&x| f16::from_f32(x)).collect();
+    // The accurate path matches the default kernels.
+    let results = run_with_precision(&v, erf::HALF, ErfPrecision::Accurate);
+    assert_eq!(results, run(&v, unary::contiguous::erf::HALF));
+    let results = run_with_precision(&v, gelu_erf::HALF, ErfPrecision::Accurate);
+    assert_eq!(results, run(&v, unary::contiguous::gelu_erf::HALF));
+    assert!(max_precision_error(&v, erf::HALF, f16::to_f32, false) < 4e-3);
+    assert!(max_precision_error(&v, gelu_erf::HALF, f16::to_f32, true) < 4e-3);
+
+    if Kernels::new().supports_bfloat(&device()) {
+        let v: Vec<bf16> = xs.iter().map(|&x| bf16::from_f32(x)).collect();
+        assert!(max_precision_error(&v, erf::BFLOAT, bf16::to_f32, false) < 2.5e-2);
+        assert!(max_precision_error(&v, gelu_erf::BFLOAT, bf16::to_f32, true) < 2.5e-2);
+    }
+}
+
 fn run_affine<T: Clone>(v: &[T], mul: f64, add: f64) -> Vec<T> {
     let device = device();
     let kernels = Kernels::new();
@@ -594,43 +1042,240 @@ fn affine() {
 }
 
 #[test]
-fn affine_strided() {
-    let input = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
-    let mul = 1.5;
-    let add = 1.1;
-    let shape = [4];
-    let strides = [2];
-    let result = run_affine_strided(&input, &shape, &strides, mul, add);
-    // 1 on 2
-    assert_eq!(result, vec![2.6, 5.6, 8.6, 11.6]);
+fn affine_inplace() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let v: Vec<f32> = (0..1000).map(|i| (i as f32 - 500.) / 7.).collect();
+    let expected = run_affine(&v, 1.5, -0.3);
+
+    // The second half of the buffer is updated in place, the first half is left unchanged.
+    let buffer = new_buffer(&device, &v);
+    let command_buffer = command_queue.new_command_buffer();
+    call_affine_inplace(
+        &device,
+        command_buffer,
+        &kernels,
+        "affine_f32",
+        500,
+        BufferOffset {
+            buffer: &buffer,
+            offset_in_bytes: 500 * std::mem::size_of::<f32>(),
+        },
+        1.5,
+        -0.3,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let results: Vec<f32> = read_to_vec(&buffer, v.len());
+    assert_eq!(results[..500], v[..500]);
+    assert_eq!(results[500..], expected[500..]);
 }
 
 #[test]
-fn index_select() {
-    let embedding = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
-    let shape = [5, 2];
-    let stride = [2, 1];
-    let ids = [0u32, 4, 2];
-    let dim = 0;
-    let result = run_index_select(&embedding, &shape, &stride, &ids, dim, "is_u32_f32");
-    assert_eq!(result, vec![1.0f32, 2.0, 9.0, 10.0, 5.0, 6.0]);
+fn ema_update() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let ema: Vec<f32> = (0..1000).map(|i| i as f32 / 10.).collect();
+    let param: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.3).sin() * 50.).collect();
+    let ema_buffer = new_buffer(&device, &ema);
+    let param_buffer = new_buffer(&device, &param);
 
-    let embedding = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
-    let shape = [2, 5];
-    let stride = [1, 2];
-    let ids = [0u32, 1, 0];
-    let dim = 0;
-    let result = run_index_select(&embedding, &shape, &stride, &ids, dim, "is_u32_f32");
-    assert_eq!(
-        result,
-        vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 1.0f32, 2.0, 3.0, 4.0, 5.0]
-    );
+    let command_buffer = command_queue.new_command_buffer();
+    call_ema_update(
+        &device,
+        command_buffer,
+        &kernels,
+        "ema_update_f32",
+        ema.len(),
+        BufferOffset::zero_offset(&ema_buffer),
+        BufferOffset::zero_offset(&param_buffer),
+        0.9,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    // One step with a decay of 0.9 moves the average 10% of the way toward the parameter.
+    let results: Vec<f32> = read_to_vec(&ema_buffer, ema.len());
+    for ((r, e), p) in results.iter().zip(ema.iter()).zip(param.iter()) {
+        let expected = e + 0.1 * (p - e);
+        assert!((r - expected).abs() < 1e-4, "{r} {expected}");
+    }
+    let ema_f16: Vec<f16> = ema.iter().map(|v| f16::from_f32(*v)).collect();
+    let param_f16: Vec<f16> = param.iter().map(|v| f16::from_f32(*v)).collect();
+    let ema_buffer = new_buffer(&device, &ema_f16);
+    let param_buffer = new_buffer(&device, &param_f16);
+    let command_buffer = command_queue.new_command_buffer();
+    call_ema_update(
+        &device,
+        command_buffer,
+        &kernels,
+        "ema_update_f16",
+        ema.len(),
+        BufferOffset::zero_offset(&ema_buffer),
+        BufferOffset::zero_offset(&param_buffer),
+        0.9,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results_f16: Vec<f16> = read_to_vec(&ema_buffer, ema.len());
+    for (r, e) in results_f16.iter().zip(results.iter()) {
+        assert!((r.to_f32() - e).abs() < 0.1, "{r} {e}");
+    }
 }
 
 #[test]
-fn index_select_strided() {
-    let embedding = (0..16).map(|x| x as f32).collect::<Vec<_>>();
-    let shape = [2, 2];
+fn dequantize_int8() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let (out_dim, inner) = (5, 7);
+    let weight: Vec<u8> = (0..out_dim * inner).map(|i| (i * 37 % 255) as u8).collect();
+    let scale: Vec<f32> = (0..out_dim).map(|i| 0.01 * (i + 1) as f32).collect();
+    let weight_buffer = new_buffer(&device, &weight);
+    let scale_buffer = new_buffer(&device, &scale);
+    let expected: Vec<f32> = weight
+        .iter()
+        .enumerate()
+        .map(|(i, &w)| (w as f32 - 128.) * scale[i / inner])
+        .collect();
+
+    let output = device.new_buffer(
+        (weight.len() * std::mem::size_of::<f32>()) as u64,
+        MTLResourceOptions::StorageModeManaged,
+    );
+    let command_buffer = command_queue.new_command_buffer();
+    call_dequantize_int8(
+        &device,
+        command_buffer,
+        &kernels,
+        "dequantize_int8_f32",
+        weight.len(),
+        inner,
+        BufferOffset::zero_offset(&weight_buffer),
+        BufferOffset::zero_offset(&scale_buffer),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<f32> = read_to_vec(&output, weight.len());
+    assert_eq!(approx(results, 6), approx(expected.clone(), 6));
+
+    let output = device.new_buffer(
+        (weight.len() * std::mem::size_of::<f16>()) as u64,
+        MTLResourceOptions::StorageModeManaged,
+    );
+    let command_buffer = command_queue.new_command_buffer();
+    call_dequantize_int8(
+        &device,
+        command_buffer,
+        &kernels,
+        "dequantize_int8_f16",
+        weight.len(),
+        inner,
+        BufferOffset::zero_offset(&weight_buffer),
+        BufferOffset::zero_offset(&scale_buffer),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<f16> = read_to_vec(&output, weight.len());
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r.to_f32() - e).abs() < 1e-2, "{r} {e}");
+    }
+}
+
+#[test]
+fn affine_strided() {
+    let input = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+    let mul = 1.5;
+    let add = 1.1;
+    let shape = [4];
+    let strides = [2];
+    let result = run_affine_strided(&input, &shape, &strides, mul, add);
+    // 1 on 2
+    assert_eq!(result, vec![2.6, 5.6, 8.6, 11.6]);
+}
+
+#[test]
+fn affine_channelwise() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+
+    let shape = [1, 3, 2, 2];
+    let input: Vec<f32> = (0..12).map(|v| v as f32).collect();
+    let scale = [0.5f32, 2.0, -1.0];
+    let shift = [1.0f32, 0.0, 0.25];
+    let input_buffer = new_buffer(&device, &input);
+    let scale_buffer = new_buffer(&device, &scale);
+    let shift_buffer = new_buffer(&device, &shift);
+    let output = new_buffer(&device, &input);
+
+    call_affine_channelwise(
+        &device,
+        command_buffer,
+        &kernels,
+        "affine_channelwise_f32",
+        &shape,
+        1,
+        BufferOffset::zero_offset(&input_buffer),
+        &scale_buffer,
+        &shift_buffer,
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let result: Vec<f32> = read_to_vec(&output, input.len());
+
+    // Broadcast the scale and shift over the 2x2 spatial dims of each channel.
+    let expected: Vec<f32> = input
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| v * scale[i / 4] + shift[i / 4])
+        .collect();
+    assert_eq!(result, expected);
+    assert_eq!(
+        result,
+        [1.0, 1.5, 2.0, 2.5, 8.0, 10.0, 12.0, 14.0, -7.75, -8.75, -9.75, -10.75]
+    );
+}
+
+#[test]
+fn index_select() {
+    let embedding = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+    let shape = [5, 2];
+    let stride = [2, 1];
+    let ids = [0u32, 4, 2];
+    let dim = 0;
+    let result = run_index_select(&embedding, &shape, &stride, &ids, dim, "is_u32_f32");
+    assert_eq!(result, vec![1.0f32, 2.0, 9.0, 10.0, 5.0, 6.0]);
+
+    let embedding = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+    let shape = [2, 5];
+    let stride = [1, 2];
+    let ids = [0u32, 1, 0];
+    let dim = 0;
+    let result = run_index_select(&embedding, &shape, &stride, &ids, dim, "is_u32_f32");
+    assert_eq!(
+        result,
+        vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 1.0f32, 2.0, 3.0, 4.0, 5.0]
+    );
+}
+
+#[test]
+fn index_select_strided() {
+    let embedding = (0..16).map(|x| x as f32).collect::<Vec<_>>();
+    let shape = [2, 2];
     let stride = [2, 4];
     let ids = [0u32];
     let dim = 0;
@@ -638,6 +1283,123 @@ fn index_select_strided() {
     assert_eq!(result, vec![0.0, 4.0]);
 }
 
+#[test]
+fn index_select_strided_view() {
+    // A contiguous [3, 4] tensor viewed as its [4, 3] transpose.
+    let v = (0..12).map(|x| x as f32).collect::<Vec<_>>();
+    let transposed = (0..4)
+        .flat_map(|i| (0..3).map(move |j| (j * 4 + i) as f32))
+        .collect::<Vec<_>>();
+    let ids = [2u32, 0, 3];
+
+    let expected = run_index_select(&transposed, &[4, 3], &[3, 1], &ids, 0, "is_u32_f32");
+    let result = run_index_select_view(&v, &[4, 3], &[1, 4], 0, &ids, 0, "is_u32_f32_strided");
+    assert_eq!(result, expected);
+    assert_eq!(result, vec![2.0, 6.0, 10.0, 0.0, 4.0, 8.0, 3.0, 7.0, 11.0]);
+
+    let expected = run_index_select(&transposed, &[4, 3], &[3, 1], &[1u32, 1], 1, "is_u32_f32");
+    let result =
+        run_index_select_view(&v, &[4, 3], &[1, 4], 0, &[1u32, 1], 1, "is_u32_f32_strided");
+    assert_eq!(result, expected);
+
+    // Same view but starting at the second row of the original tensor.
+    let result = run_index_select_view(&v, &[4, 2], &[1, 4], 4, &[3u32], 0, "is_u32_f32_strided");
+    assert_eq!(result, vec![7.0, 11.0]);
+}
+
+fn run_index_select_view<I: Clone>(
+    v: &[f32],
+    shape: &[usize],
+    strides: &[usize],
+    offset: usize,
+    ids: &[I],
+    dim: usize,
+    name: &'static str,
+) -> Vec<f32> {
+    let device = Device::system_default().expect("no device found");
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let ids_buffer = new_buffer(&device, ids);
+
+    let left_size: usize = shape[..dim].iter().product();
+    let right_size: usize = shape[dim + 1..].iter().product();
+    let dst_el = ids.len() * left_size * right_size;
+    let output = new_buffer(&device, &vec![0.0f32; dst_el]);
+
+    let kernels = Kernels::new();
+    call_index_select_strided(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        shape,
+        strides,
+        dim,
+        ids.len(),
+        BufferOffset {
+            buffer: &input,
+            offset_in_bytes: offset * std::mem::size_of::<f32>(),
+        },
+        BufferOffset::zero_offset(&ids_buffer),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, dst_el)
+}
+
+#[test]
+fn embedding() {
+    let weight = (0..15).map(|x| x as f32 * 0.5).collect::<Vec<_>>();
+    let ids = [4u32, 0, 2, 2];
+
+    let selected = run_index_select(&weight, &[5, 3], &[3, 1], &ids, 0, "is_u32_f32");
+    let result = run_embedding(&weight, 5, 3, &ids, None);
+    assert_eq!(result, selected);
+
+    let scale = 8f32.sqrt();
+    let expected = run_affine(&selected, scale as f64, 0.0);
+    let result = run_embedding(&weight, 5, 3, &ids, Some(scale));
+    assert_eq!(approx(result, 4), approx(expected, 4));
+}
+
+fn run_embedding(
+    weight: &[f32],
+    vocab_size: usize,
+    hidden_size: usize,
+    ids: &[u32],
+    scale: Option<f32>,
+) -> Vec<f32> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let weight = new_buffer(&device, weight);
+    let ids_buffer = new_buffer(&device, ids);
+    let dst_el = ids.len() * hidden_size;
+    let output = new_buffer(&device, &vec![0f32; dst_el]);
+
+    call_embedding(
+        &device,
+        command_buffer,
+        &kernels,
+        "embedding_u32_f32",
+        vocab_size,
+        hidden_size,
+        ids.len(),
+        BufferOffset::zero_offset(&weight),
+        BufferOffset::zero_offset(&ids_buffer),
+        &output,
+        scale,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, dst_el)
+}
+
 #[test]
 fn index_select_f16() {
     let embedding: Vec<_> = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0]
@@ -785,6 +1547,122 @@ fn run_index_select_strided<T: Clone, I: Clone + std::fmt::Debug>(
     read_to_vec(&dst_buffer, dst_el)
 }
 
+fn run_repeat<T: Clone>(
+    v: &[T],
+    shape: &[usize],
+    strides: &[usize],
+    repeats: &[usize],
+    name: &'static str,
+) -> Vec<T> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let options = MTLResourceOptions::StorageModeManaged;
+
+    let dst_el: usize = shape
+        .iter()
+        .zip(repeats.iter())
+        .map(|(s, r)| s * r)
+        .product();
+    let input = new_buffer(&device, v);
+    let output = device.new_buffer((dst_el * core::mem::size_of::<T>()) as u64, options);
+    call_repeat(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        shape,
+        strides,
+        repeats,
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, dst_el)
+}
+
+#[test]
+fn repeat() {
+    let v = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0];
+    let results = run_repeat(&v, &[2, 3], &[3, 1], &[2, 1], "repeat_f32");
+    assert_eq!(
+        results,
+        vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]
+    );
+
+    let results = run_repeat(&v, &[2, 3], &[3, 1], &[1, 2], "repeat_f32");
+    assert_eq!(
+        results,
+        vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 4.0, 5.0, 6.0]
+    );
+
+    // Transposed input, [3, 2] view over the [2, 3] buffer.
+    let results = run_repeat(&v, &[3, 2], &[1, 3], &[2, 1], "repeat_f32");
+    assert_eq!(
+        results,
+        vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0, 1.0, 4.0, 2.0, 5.0, 3.0, 6.0]
+    );
+
+    let v = [1u32, 2, 3, 4, 5, 6];
+    let results = run_repeat(&v, &[2, 3], &[3, 1], &[2, 1], "repeat_u32");
+    assert_eq!(results, vec![1, 2, 3, 4, 5, 6, 1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn narrow() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let options = MTLResourceOptions::StorageModeManaged;
+
+    let v: Vec<f32> = (0..16).map(|v| v as f32).collect();
+    let input = new_buffer(&device, &v);
+    let output = device.new_buffer((4 * core::mem::size_of::<f32>()) as u64, options);
+    let command_buffer = command_queue.new_command_buffer();
+    call_narrow(
+        &device,
+        command_buffer,
+        &kernels,
+        "narrow_f32",
+        &[4, 4],
+        &[4, 1],
+        &[(1, 3), (1, 3)],
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<f32> = read_to_vec(&output, 4);
+    assert_eq!(results, vec![5.0, 6.0, 9.0, 10.0]);
+
+    let command_buffer = command_queue.new_command_buffer();
+    let err = call_narrow(
+        &device,
+        command_buffer,
+        &kernels,
+        "narrow_f32",
+        &[4, 4],
+        &[4, 1],
+        &[(1, 3), (2, 5)],
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        MetalKernelError::NarrowOutOfBounds {
+            dim: 1,
+            start: 2,
+            end: 5,
+            dim_size: 4
+        }
+    ));
+}
+
 #[test]
 fn cos_f16() {
     let v: Vec<f16> = [1.0f32, 2.0, 3.0]
@@ -827,6 +1705,15 @@ fn run_reduce<T: Clone>(v: &[T], out_length: usize, name: &'static str) -> Vec<T
 }
 
 fn run_softmax<T: Clone + std::fmt::Debug>(v: &[T], last_dim: usize, name: &'static str) -> Vec<T> {
+    run_softmax_with_temperature(v, last_dim, name, None)
+}
+
+fn run_softmax_with_temperature<T: Clone + std::fmt::Debug>(
+    v: &[T],
+    last_dim: usize,
+    name: &'static str,
+    temperature: Option<f32>,
+) -> Vec<T> {
     let device = device();
     let kernels = Kernels::new();
     let command_queue = device.new_command_queue();
@@ -843,6 +1730,7 @@ fn run_softmax<T: Clone + std::fmt::Debug>(v: &[T], last_dim: usize, name: &'sta
         &input,
         0,
         &output,
+        temperature,
     )
     .unwrap();
     command_buffer.commit();
@@ -870,29 +1758,270 @@ fn reduce_sum2() {
 }
 
 #[test]
-fn softmax() {
+fn reduce_sum_with_offset() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
     let v = vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0];
-    let last_dim = 6;
-    let results = run_softmax(&v, last_dim, "softmax_f32");
-    assert_eq!(
-        approx(results, 4),
-        vec![0.0043, 0.0116, 0.0315, 0.0858, 0.2331, 0.6337]
-    );
+    let input = new_buffer(&device, &v);
+    let output = new_buffer(&device, &[-1.0f32; 6]);
 
-    let last_dim = 4096;
-    let n = 200;
-    let mut v = vec![0.0; n * last_dim];
-    for i in 0..n {
-        v[i * last_dim] = 20.0;
-    }
-    let results = run_softmax(&v, last_dim, "softmax_f32");
-    let results = approx(results, 4);
-    assert_eq!(
-        results.iter().map(|&s| s.round() as usize).sum::<usize>(),
-        n
-    );
-    assert_eq!(results[0], 1.0);
-    assert_eq!(results[1], 0.0);
+    call_reduce_strided_with_offset(
+        &device,
+        command_buffer,
+        &kernels,
+        "fast_sum_f32_strided",
+        &[6],
+        &[1],
+        2,
+        BufferOffset::zero_offset(&input),
+        BufferOffset {
+            buffer: &output,
+            offset_in_bytes: 2 * std::mem::size_of::<f32>(),
+        },
+    )
+    .unwrap();
+    call_affine_with_offset(
+        &device,
+        command_buffer,
+        &kernels,
+        "affine_f32",
+        1,
+        BufferOffset::zero_offset(&input),
+        BufferOffset {
+            buffer: &output,
+            offset_in_bytes: 5 * std::mem::size_of::<f32>(),
+        },
+        2.0,
+        0.0,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let results: Vec<f32> = read_to_vec(&output, 6);
+    assert_eq!(approx(results, 4), vec![-1.0, -1.0, 6.0, 15.0, -1.0, 2.0]);
+}
+
+fn run_reduce_multi_axis(
+    v: &[f32],
+    shape: &[usize],
+    axes: &[usize],
+    name: &'static str,
+) -> Result<(Vec<usize>, Vec<f32>), MetalKernelError> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, v);
+    let mut strides = vec![1; shape.len()];
+    for d in (0..shape.len().saturating_sub(1)).rev() {
+        strides[d] = strides[d + 1] * shape[d + 1]
+    }
+    let out_shape = call_reduce_multi_axis(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        shape,
+        &strides,
+        axes,
+        BufferOffset::zero_offset(&input),
+        &output,
+    )?;
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let out_length = out_shape.iter().product();
+    Ok((out_shape, read_to_vec(&output, out_length)))
+}
+
+#[test]
+fn reduce_multi_axis() {
+    let v: Vec<f32> = (0..96).map(|i| (i % 7) as f32 - 3.).collect();
+    let shape = [2, 3, 4, 4];
+
+    for name in ["fast_sum_f32_strided", "fast_max_f32_strided"] {
+        // Same as reducing the last dim of the `(6, 16)` reshape.
+        let expected = run_reduce(&v, 6, name);
+        let (out_shape, results) = run_reduce_multi_axis(&v, &shape, &[2, 3], name).unwrap();
+        assert_eq!(out_shape, vec![2, 3]);
+        assert_eq!(results, expected);
+        let (_, results) = run_reduce_multi_axis(&v, &shape, &[3, 2], name).unwrap();
+        assert_eq!(results, expected);
+    }
+
+    for axes in [&[][..], &[4], &[2, 2]] {
+        let err = run_reduce_multi_axis(&v, &shape, axes, "fast_sum_f32_strided").unwrap_err();
+        assert!(matches!(
+            err,
+            MetalKernelError::InvalidReduceAxes { rank: 4, .. }
+        ));
+    }
+}
+
+fn run_reduce_all(v: &[f32], name: &'static str) -> f32 {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, &[0f32]);
+    call_reduce_all(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, 1)[0]
+}
+
+#[test]
+fn reduce_all() {
+    // 100_000 is reduced with two passes, the prime 100_003 with a single one.
+    for length in [100_000, 100_003, 7] {
+        let v: Vec<f32> = (0..length)
+            .map(|i| ((i * 37) % 101) as f32 / 101.)
+            .collect();
+        let expected: f64 = v.iter().map(|&x| x as f64).sum();
+        let result = run_reduce_all(&v, "fast_sum_f32_strided");
+        assert!(
+            (result as f64 - expected).abs() <= 1e-5 * expected,
+            "{length} {result} {expected}"
+        );
+        let expected = v.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        assert_eq!(run_reduce_all(&v, "fast_max_f32_strided"), expected);
+    }
+}
+
+#[test]
+fn reduce_indivisible_length() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, &[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+    let output = new_buffer(&device, &[0.0f32; 7]);
+
+    let err = call_reduce_strided(
+        &device,
+        command_buffer,
+        &kernels,
+        "fast_sum_f32_strided",
+        &[7],
+        &[1],
+        2,
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        MetalKernelError::IndivisibleLength {
+            length: 7,
+            divisor: 2,
+            ..
+        }
+    ));
+
+    let err = call_last_softmax(
+        &device,
+        command_buffer,
+        &kernels,
+        "softmax_f32",
+        7,
+        3,
+        &input,
+        0,
+        &output,
+        None,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        MetalKernelError::IndivisibleLength {
+            length: 7,
+            divisor: 3,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn deterministic_sum() {
+    let device = device();
+    let kernels = Kernels::new();
+    let v: Vec<f32> = (0..4 * 25_000)
+        .map(|i| (i as f32 * 0.7).sin() * 1e3)
+        .collect();
+    let input = new_buffer(&device, &v);
+    let command_queue = device.new_command_queue();
+
+    let run = |deterministic: bool| -> Vec<f32> {
+        let command_buffer = command_queue.new_command_buffer();
+        let output = new_buffer(&device, &[0f32; 4]);
+        call_sum_strided(
+            &device,
+            command_buffer,
+            &kernels,
+            DType::F32,
+            &[v.len()],
+            &[1],
+            4,
+            BufferOffset::zero_offset(&input),
+            &output,
+            deterministic,
+        )
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+        read_to_vec(&output, 4)
+    };
+
+    let expected = run(true);
+    for _ in 0..50 {
+        let results = run(true);
+        let bits = |v: &[f32]| v.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&results), bits(&expected));
+    }
+    let cpu: Vec<f32> = v.chunks(25_000).map(|c| c.iter().sum()).collect();
+    for ((d, f), c) in expected.iter().zip(run(false)).zip(cpu) {
+        assert!((d - c).abs() < 5.0, "{d} {c}");
+        assert!((f - c).abs() < 5.0, "{f} {c}");
+    }
+}
+
+#[test]
+fn softmax() {
+    let v = vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0];
+    let last_dim = 6;
+    let results = run_softmax(&v, last_dim, "softmax_f32");
+    assert_eq!(
+        approx(results, 4),
+        vec![0.0043, 0.0116, 0.0315, 0.0858, 0.2331, 0.6337]
+    );
+
+    let last_dim = 4096;
+    let n = 200;
+    let mut v = vec![0.0; n * last_dim];
+    for i in 0..n {
+        v[i * last_dim] = 20.0;
+    }
+    let results = run_softmax(&v, last_dim, "softmax_f32");
+    let results = approx(results, 4);
+    assert_eq!(
+        results.iter().map(|&s| s.round() as usize).sum::<usize>(),
+        n
+    );
+    assert_eq!(results[0], 1.0);
+    assert_eq!(results[1], 0.0);
     assert_eq!(results[last_dim], 1.0);
     assert_eq!(results[2 * last_dim], 1.0);
 
@@ -935,6 +2064,99 @@ fn softmax() {
     );
 }
 
+#[test]
+fn softmax_temperature() {
+    let v: Vec<f32> = (0..24).map(|i| ((i * 7) % 11) as f32 - 5.).collect();
+    let last_dim = 6;
+    let results = run_softmax_with_temperature(&v, last_dim, "softmax_f32", Some(0.5));
+    let scaled = run_affine(&v, 2., 0.);
+    let expected = run_softmax(&scaled, last_dim, "softmax_f32");
+    assert_eq!(approx(results, 5), approx(expected, 5));
+
+    let results = run_softmax_with_temperature(&v, last_dim, "softmax_f32", Some(1.));
+    assert_eq!(results, run_softmax(&v, last_dim, "softmax_f32"));
+
+    let device = device();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, &v);
+    for temperature in [0., -1., f32::NAN] {
+        let err = call_last_softmax(
+            &device,
+            command_buffer,
+            &Kernels::new(),
+            "softmax_f32",
+            v.len(),
+            last_dim,
+            &input,
+            0,
+            &input,
+            Some(temperature),
+        )
+        .unwrap_err();
+        assert!(matches!(err, MetalKernelError::InvalidTemperature(_)));
+    }
+}
+
+fn run_logsumexp<T: Clone>(v: &[T], last_dim: usize, name: &'static str) -> Vec<T> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, &v[..v.len() / last_dim]);
+    call_logsumexp(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        v.len(),
+        last_dim,
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len() / last_dim)
+}
+
+#[test]
+fn logsumexp() {
+    // exp overflows f32 for the first row so the naive formulation gives inf.
+    let v: Vec<f32> = vec![
+        1000., 999., 998., 1000., -1000., -1001., -1002., -1000., 0.5, -1.5, 2., 0.,
+    ];
+    let last_dim = 4;
+    let naive: f32 = v[..4].iter().map(|x| x.exp()).sum::<f32>().ln();
+    assert!(naive.is_infinite());
+    let expected = reference::logsumexp(&v, last_dim);
+    let results = run_logsumexp(&v, last_dim, "logsumexp_f32");
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r - e).abs() <= 1e-5 * e.abs().max(1.), "{r} {e}");
+    }
+
+    let v_f16: Vec<f16> = v.iter().map(|&x| f16::from_f32(x)).collect();
+    let results = run_logsumexp(&v_f16, last_dim, "logsumexp_f16");
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r.to_f32() - e).abs() <= 1e-3 * e.abs().max(1.), "{r} {e}");
+    }
+    let v_bf16: Vec<bf16> = v.iter().map(|&x| bf16::from_f32(x)).collect();
+    let results = run_logsumexp(&v_bf16, last_dim, "logsumexp_bf16");
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r.to_f32() - e).abs() <= 1e-2 * e.abs().max(1.), "{r} {e}");
+    }
+
+    // Rows longer than a threadgroup.
+    let last_dim = 3000;
+    let v: Vec<f32> = (0..2 * last_dim).map(|i| (i % 17) as f32 * 10.).collect();
+    let results = run_logsumexp(&v, last_dim, "logsumexp_f32");
+    let expected = reference::logsumexp(&v, last_dim);
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r - e).abs() <= 1e-5 * e.abs(), "{r} {e}");
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn run_where_cond<I: Clone, T: Clone>(
     shape: &[usize],
@@ -1024,6 +2246,82 @@ fn where_cond() {
     );
     assert_eq!(approx(results, 4), vec![-1.0f32, 2.0, -3.0, -4.0, 5.0, 6.0]);
 }
+#[test]
+fn where_cond_contiguous() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+
+    let shape = [2, 3];
+    let cond = [0u8, 1, 0, 0, 1, 1];
+    let left = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0];
+    let right = [-1.0f32, -2.0, -3.0, -4.0, -5.0, -6.0];
+    let cond_buffer = new_buffer(&device, &cond);
+    let left_buffer = new_buffer(&device, &left);
+    let right_buffer = new_buffer(&device, &right);
+    let strided_output = new_buffer(&device, &[0f32; 6]);
+    // The strided output is written after two padding elements.
+    let offset_output = new_buffer(&device, &[0f32; 8]);
+    let contiguous_output = new_buffer(&device, &[0f32; 6]);
+
+    call_where_cond_strided(
+        &device,
+        command_buffer,
+        &kernels,
+        "where_u8_f32",
+        &shape,
+        BufferOffset::zero_offset(&cond_buffer),
+        &[3, 1],
+        BufferOffset::zero_offset(&left_buffer),
+        &[3, 1],
+        BufferOffset::zero_offset(&right_buffer),
+        &[3, 1],
+        &strided_output,
+    )
+    .unwrap();
+    call_where_cond_strided_with_offset(
+        &device,
+        command_buffer,
+        &kernels,
+        "where_u8_f32",
+        &shape,
+        BufferOffset::zero_offset(&cond_buffer),
+        &[3, 1],
+        BufferOffset::zero_offset(&left_buffer),
+        &[3, 1],
+        BufferOffset::zero_offset(&right_buffer),
+        &[3, 1],
+        BufferOffset {
+            buffer: &offset_output,
+            offset_in_bytes: 2 * std::mem::size_of::<f32>(),
+        },
+    )
+    .unwrap();
+    call_where_cond_contiguous(
+        &device,
+        command_buffer,
+        &kernels,
+        "where_u8_f32_contiguous",
+        cond.len(),
+        BufferOffset::zero_offset(&cond_buffer),
+        BufferOffset::zero_offset(&left_buffer),
+        BufferOffset::zero_offset(&right_buffer),
+        &contiguous_output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let strided: Vec<f32> = read_to_vec(&strided_output, 6);
+    let with_offset: Vec<f32> = read_to_vec(&offset_output, 8);
+    let contiguous: Vec<f32> = read_to_vec(&contiguous_output, 6);
+    assert_eq!(strided, [-1.0, 2.0, -3.0, -4.0, 5.0, 6.0]);
+    assert_eq!(contiguous, strided);
+    assert_eq!(with_offset[..2], [0.0, 0.0]);
+    assert_eq!(with_offset[2..], strided);
+}
+
 #[test]
 fn where_cond_u32_f32() {
     let shape = vec![6];
@@ -1046,16 +2344,13 @@ fn where_cond_u32_f32() {
     assert_eq!(approx(results, 4), vec![-1.0f32, 2.0, -3.0, -4.0, 5.0, 6.0]);
 }
 
-#[allow(clippy::too_many_arguments)]
-fn run_gemm<T: Clone>(
+fn run_masked_fill<T: Clone>(
+    shape: &[usize],
+    input: &[T],
+    mask: &[u8],
+    mask_stride: &[usize],
+    value: f32,
     name: &'static str,
-    (b, m, n, k): (usize, usize, usize, usize),
-    lhs: &[T],
-    lhs_stride: &[usize],
-    lhs_offset: usize,
-    rhs: &[T],
-    rhs_stride: &[usize],
-    rhs_offset: usize,
 ) -> Vec<T> {
     let device = device();
     let kernels = Kernels::new();
@@ -1063,30 +2358,24 @@ fn run_gemm<T: Clone>(
     let command_buffer = command_queue.new_command_buffer();
     let options = MTLResourceOptions::StorageModeManaged;
 
-    let lhs = device.new_buffer_with_data(
-        lhs.as_ptr() as *const core::ffi::c_void,
-        std::mem::size_of_val(lhs) as u64,
-        options,
-    );
-    let rhs = device.new_buffer_with_data(
-        rhs.as_ptr() as *const core::ffi::c_void,
-        std::mem::size_of_val(rhs) as u64,
-        options,
-    );
-    let length = b * m * n;
+    let length: usize = shape.iter().product();
+    let input_stride = (0..shape.len())
+        .map(|i| shape[i + 1..].iter().product())
+        .collect::<Vec<usize>>();
+    let input = new_buffer(&device, input);
+    let mask = new_buffer(&device, mask);
     let output = device.new_buffer((length * core::mem::size_of::<T>()) as u64, options);
-    call_gemm(
+    call_masked_fill_strided(
         &device,
         command_buffer,
         &kernels,
         name,
-        (b, m, n, k),
-        lhs_stride,
-        lhs_offset,
-        &lhs,
-        rhs_stride,
-        rhs_offset,
-        &rhs,
+        shape,
+        BufferOffset::zero_offset(&input),
+        &input_stride,
+        BufferOffset::zero_offset(&mask),
+        mask_stride,
+        value,
         &output,
     )
     .unwrap();
@@ -1097,15 +2386,243 @@ fn run_gemm<T: Clone>(
 }
 
 #[test]
-fn gemm() {
-    let (b, m, n, k) = (1, 2, 4, 3);
-    let lhs_stride = vec![m * k, k, 1];
-    let lhs: Vec<f32> = (0..b * m * k).map(|f| f as f32).collect();
-    let rhs_stride = vec![n * k, n, 1];
-    let rhs: Vec<f32> = (0..b * n * k).map(|f| f as f32).collect();
-    let results = run_gemm(
-        "sgemm",
-        (b, m, n, k),
+fn masked_fill() {
+    let inf = f32::NEG_INFINITY;
+    let input: Vec<f32> = (0..16).map(|v| v as f32).collect();
+    let mask: Vec<u8> = (0..16).map(|i| ((i % 4) > (i / 4)) as u8).collect();
+
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input_buffer = new_buffer(&device, &input);
+    let mask_buffer = new_buffer(&device, &mask);
+    let output = new_buffer(&device, &input);
+    call_masked_fill(
+        &device,
+        command_buffer,
+        &kernels,
+        "masked_fill_f32",
+        input.len(),
+        BufferOffset::zero_offset(&input_buffer),
+        BufferOffset::zero_offset(&mask_buffer),
+        inf,
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<f32> = read_to_vec(&output, input.len());
+    let expected = vec![
+        0.0, inf, inf, inf, //
+        4.0, 5.0, inf, inf, //
+        8.0, 9.0, 10.0, inf, //
+        12.0, 13.0, 14.0, 15.0,
+    ];
+    assert_eq!(results, expected);
+
+    // Broadcast the [4, 4] mask over a leading dimension of size 2.
+    let input: Vec<f32> = (0..32).map(|v| v as f32).collect();
+    let results = run_masked_fill(
+        &[2, 4, 4],
+        &input,
+        &mask,
+        &[0, 4, 1],
+        inf,
+        "masked_fill_f32",
+    );
+    let expected: Vec<f32> = (0..32)
+        .map(|i| if mask[i % 16] == 1 { inf } else { i as f32 })
+        .collect();
+    assert_eq!(results, expected);
+
+    let input: Vec<f16> = (0..16).map(|v| f16::from_f32(v as f32)).collect();
+    let results = run_masked_fill(&[4, 4], &input, &mask, &[4, 1], inf, "masked_fill_f16");
+    let expected: Vec<f16> = (0..16)
+        .map(|i| {
+            if mask[i] == 1 {
+                f16::NEG_INFINITY
+            } else {
+                f16::from_f32(i as f32)
+            }
+        })
+        .collect();
+    assert_eq!(results, expected);
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg(feature = "metal-flash-attention")]
+fn run_gemm<T: Clone>(
+    name: &'static str,
+    (b, m, n, k): (usize, usize, usize, usize),
+    lhs: &[T],
+    lhs_stride: &[usize],
+    lhs_offset: usize,
+    rhs: &[T],
+    rhs_stride: &[usize],
+    rhs_offset: usize,
+) -> Vec<T> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let options = MTLResourceOptions::StorageModeManaged;
+
+    let lhs = device.new_buffer_with_data(
+        lhs.as_ptr() as *const core::ffi::c_void,
+        std::mem::size_of_val(lhs) as u64,
+        options,
+    );
+    let rhs = device.new_buffer_with_data(
+        rhs.as_ptr() as *const core::ffi::c_void,
+        std::mem::size_of_val(rhs) as u64,
+        options,
+    );
+    let length = b * m * n;
+    let output = device.new_buffer((length * core::mem::size_of::<T>()) as u64, options);
+    call_gemm(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        (b, m, n, k),
+        lhs_stride,
+        lhs_offset,
+        &lhs,
+        rhs_stride,
+        rhs_offset,
+        &rhs,
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    read_to_vec(&output, length)
+}
+
+#[test]
+#[cfg(feature = "metal-flash-attention")]
+fn gemm_alpha_beta() {
+    let (b, m, n, k) = (2, 3, 4, 5);
+    let lhs: Vec<f32> = (0..b * m * k).map(|f| f as f32 / 8.).collect();
+    let rhs: Vec<f32> = (0..b * k * n).map(|f| (f as f32 - 20.) / 8.).collect();
+    let c: Vec<f32> = (0..b * m * n).map(|f| f as f32 * 3. - 7.).collect();
+    let product = run_gemm(
+        "sgemm",
+        (b, m, n, k),
+        &lhs,
+        &[m * k, k, 1],
+        0,
+        &rhs,
+        &[k * n, n, 1],
+        0,
+    );
+
+    let device = device();
+    let kernels = Kernels::new();
+    let lhs_buffer = new_buffer(&device, &lhs);
+    let rhs_buffer = new_buffer(&device, &rhs);
+    for (alpha, beta) in [(1f32, 1f32), (0.5, -2.)] {
+        let output = new_buffer(&device, &c);
+        let command_queue = device.new_command_queue();
+        let command_buffer = command_queue.new_command_buffer();
+        call_gemm_with_alpha_beta(
+            &device,
+            command_buffer,
+            &kernels,
+            "sgemm",
+            (b, m, n, k),
+            &[m * k, k, 1],
+            0,
+            &lhs_buffer,
+            &[k * n, n, 1],
+            0,
+            &rhs_buffer,
+            (alpha, beta),
+            &output,
+        )
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+
+        let expected: Vec<f32> = product
+            .iter()
+            .zip(c.iter())
+            .map(|(p, c)| alpha * p + beta * c)
+            .collect();
+        let results = read_to_vec::<f32>(&output, b * m * n);
+        assert_close(&results, &expected, 1e-5, "gemm_alpha_beta");
+    }
+}
+
+#[test]
+#[cfg(feature = "metal-flash-attention")]
+fn gemm_autotune() {
+    let (m, n, k) = (33, 70, 17);
+    let lhs: Vec<f32> = (0..m * k).map(|f| (f % 13) as f32 - 6.).collect();
+    let rhs: Vec<f32> = (0..k * n).map(|f| (f % 7) as f32 / 4.).collect();
+    let expected = run_gemm(
+        "sgemm",
+        (1, m, n, k),
+        &lhs,
+        &[m * k, k, 1],
+        0,
+        &rhs,
+        &[k * n, n, 1],
+        0,
+    );
+
+    let device = device();
+    let kernels = Kernels::new().with_gemm_autotune();
+    assert_eq!(kernels.gemm_tile(&device, "sgemm", (m, n, k)), None);
+    let lhs_buffer = new_buffer(&device, &lhs);
+    let rhs_buffer = new_buffer(&device, &rhs);
+    for _ in 0..2 {
+        let options = MTLResourceOptions::StorageModeManaged;
+        let output = device.new_buffer((m * n * std::mem::size_of::<f32>()) as u64, options);
+        let command_queue = device.new_command_queue();
+        let command_buffer = command_queue.new_command_buffer();
+        call_gemm(
+            &device,
+            command_buffer,
+            &kernels,
+            "sgemm",
+            (1, m, n, k),
+            &[m * k, k, 1],
+            0,
+            &lhs_buffer,
+            &[k * n, n, 1],
+            0,
+            &rhs_buffer,
+            &output,
+        )
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+        let results = read_to_vec::<f32>(&output, m * n);
+        assert_close(&results, &expected, 1e-5, "gemm_autotune");
+    }
+
+    let tile = kernels.gemm_tile(&device, "sgemm", (m, n, k)).unwrap();
+    assert!(GemmTile::CANDIDATES.contains(&tile));
+    let tuned = kernels.autotune_gemm(&device, "sgemm", (m, n, k)).unwrap();
+    assert_eq!(tuned, tile);
+    // Other problem sizes are tuned separately.
+    assert_eq!(kernels.gemm_tile(&device, "sgemm", (m, n, k + 1)), None);
+}
+
+#[test]
+#[cfg(feature = "metal-flash-attention")]
+fn gemm() {
+    let (b, m, n, k) = (1, 2, 4, 3);
+    let lhs_stride = vec![m * k, k, 1];
+    let lhs: Vec<f32> = (0..b * m * k).map(|f| f as f32).collect();
+    let rhs_stride = vec![n * k, n, 1];
+    let rhs: Vec<f32> = (0..b * n * k).map(|f| f as f32).collect();
+    let results = run_gemm(
+        "sgemm",
+        (b, m, n, k),
         &lhs,
         &lhs_stride,
         0,
@@ -1258,6 +2775,7 @@ fn run_mlx_gemm<T: Clone>(
     read_to_vec(&output, length)
 }
 
+#[cfg(feature = "metal-flash-attention")]
 fn mlx_vs_mfa_one(b: usize, m: usize, n: usize, k: usize, dtype: GemmDType) {
     use rand::SeedableRng;
     use rand_distr::Distribution;
@@ -1294,6 +2812,7 @@ fn mlx_vs_mfa_one(b: usize, m: usize, n: usize, k: usize, dtype: GemmDType) {
 }
 
 #[test]
+#[cfg(feature = "metal-flash-attention")]
 fn mlx_vs_mfa() {
     mlx_vs_mfa_one(1, 32, 32, 25, GemmDType::F32);
     mlx_vs_mfa_one(1, 128, 128, 100, GemmDType::F32);
@@ -1743,6 +3262,37 @@ fn index_add() {
     }
 }
 
+#[test]
+fn index_add_deterministic() {
+    let (ids_dim_size, right_size, dst_dim_size) = (512, 4, 8);
+    let shape = [ids_dim_size, right_size];
+    let mut rng = rand::thread_rng();
+    let indices: Vec<u32> = (0..ids_dim_size)
+        .map(|_| rng.gen_range(0..dst_dim_size as u32))
+        .collect();
+    let right: Vec<f32> = (0..ids_dim_size * right_size)
+        .map(|_| rng.gen_range(-1e3..1e3))
+        .collect();
+    let left = vec![0f32; ids_dim_size * right_size];
+
+    let results = run_index_add(&left, &right, &indices, &shape, 0, "ia_u32_f32");
+    for _ in 0..4 {
+        let again = run_index_add(&left, &right, &indices, &shape, 0, "ia_u32_f32");
+        let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&again), bits(&results));
+    }
+
+    let mut expected = left.clone();
+    for (j, &idx) in indices.iter().enumerate() {
+        for r in 0..right_size {
+            expected[idx as usize * right_size + r] += right[j * right_size + r];
+        }
+    }
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r - e).abs() <= 1e-3 * e.abs().max(1.), "{r} {e}");
+    }
+}
+
 fn run_pool2d<T: Clone>(
     v: &[T],
     (w_k, h_k): (usize, usize),
@@ -2093,55 +3643,118 @@ fn avg_pool2d_u32() {
 }
 
 #[allow(clippy::too_many_arguments)]
-fn run_conv_transpose1d<T: Clone>(
+fn cpu_conv2d(
+    input: &[f32],
+    input_shape: &[usize],
+    kernel: &[f32],
+    kernel_shape: &[usize],
+    bias: Option<&[f32]>,
+    stride: usize,
+    padding: usize,
+    dilation: usize,
+) -> Vec<f32> {
+    let (b_size, c_in, h_in, w_in) = (
+        input_shape[0],
+        input_shape[1],
+        input_shape[2],
+        input_shape[3],
+    );
+    let (c_out, h_k, w_k) = (kernel_shape[0], kernel_shape[2], kernel_shape[3]);
+    let h_out = (h_in + 2 * padding - dilation * (h_k - 1) - 1) / stride + 1;
+    let w_out = (w_in + 2 * padding - dilation * (w_k - 1) - 1) / stride + 1;
+    let mut dst = vec![0f32; b_size * c_out * h_out * w_out];
+    for b in 0..b_size {
+        for o in 0..c_out {
+            for y in 0..h_out {
+                for x in 0..w_out {
+                    let mut d = bias.map_or(0., |bias| bias[o]);
+                    for c in 0..c_in {
+                        for k_y in 0..h_k {
+                            for k_x in 0..w_k {
+                                let inp_y = (y * stride + k_y * dilation) as i64 - padding as i64;
+                                let inp_x = (x * stride + k_x * dilation) as i64 - padding as i64;
+                                if inp_y < 0
+                                    || inp_x < 0
+                                    || inp_y >= h_in as i64
+                                    || inp_x >= w_in as i64
+                                {
+                                    continue;
+                                }
+                                let (inp_y, inp_x) = (inp_y as usize, inp_x as usize);
+                                d += input[((b * c_in + c) * h_in + inp_y) * w_in + inp_x]
+                                    * kernel[((o * c_in + c) * h_k + k_y) * w_k + k_x];
+                            }
+                        }
+                    }
+                    dst[((b * c_out + o) * h_out + y) * w_out + x] = d;
+                }
+            }
+        }
+    }
+    dst
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_conv2d_direct<T: Clone>(
     input: &[T],
     input_shape: &[usize],
-    input_stride: &[usize],
     kernel: &[T],
     kernel_shape: &[usize],
-    kernel_stride: &[usize],
-    dilation: usize,
+    bias: Option<&[T]>,
     stride: usize,
     padding: usize,
-    out_padding: usize,
+    dilation: usize,
     name: &'static str,
 ) -> Vec<T> {
     let device = device();
     let command_queue = device.new_command_queue();
     let command_buffer = command_queue.new_command_buffer();
 
-    let c_out = kernel_shape[1];
-    let k_size = kernel_shape[2];
-    let b_size = input_shape[0];
-    let l_in = input_shape[2];
-    let l_out = (l_in - 1) * stride - 2 * padding + dilation * (k_size - 1) + out_padding + 1;
-    let dst_el = c_out * l_out * b_size;
+    let (h_k, w_k) = (kernel_shape[2], kernel_shape[3]);
+    let out_h = (input_shape[2] + 2 * padding - dilation * (h_k - 1) - 1) / stride + 1;
+    let out_w = (input_shape[3] + 2 * padding - dilation * (w_k - 1) - 1) / stride + 1;
+    let dst_el = input_shape[0] * kernel_shape[0] * out_h * out_w;
+    let input_stride = [
+        input_shape[1] * input_shape[2] * input_shape[3],
+        input_shape[2] * input_shape[3],
+        input_shape[3],
+        1,
+    ];
+    let kernel_stride = [
+        kernel_shape[1] * kernel_shape[2] * kernel_shape[3],
+        kernel_shape[2] * kernel_shape[3],
+        kernel_shape[3],
+        1,
+    ];
 
     let input = new_buffer(&device, input);
     let kernel = new_buffer(&device, kernel);
+    let bias = bias.map(|bias| new_buffer(&device, bias));
     let output = new_buffer(&device, &vec![0.0f32; dst_el]);
     let kernels = Kernels::new();
 
-    call_conv_transpose1d(
+    call_conv2d_direct(
         &device,
         command_buffer,
         &kernels,
         name,
-        dilation,
-        stride,
-        padding,
-        out_padding,
-        c_out,
-        l_out,
-        b_size,
-        input_shape,
-        input_stride,
-        kernel_shape,
-        kernel_stride,
+        CallConv2dDirectCfg {
+            stride,
+            padding,
+            dilation,
+            out_w,
+            out_h,
+            input_dims: input_shape,
+            input_stride: &input_stride,
+            kernel_dims: kernel_shape,
+            kernel_stride: &kernel_stride,
+            input_offset: 0,
+            kernel_offset: 0,
+            channels_last: false,
+        },
         &input,
-        0,
         &kernel,
-        0,
+        bias.as_ref(),
         &output,
     )
     .unwrap();
@@ -2152,55 +3765,385 @@ fn run_conv_transpose1d<T: Clone>(
 }
 
 #[test]
-fn conv_transpose1d_f32() {
-    let input = vec![1.0f32, 2.0, 3.0, 4.0];
-    let input_shape = &[1, 1, 4];
-    let input_stride = &[4, 4, 1];
+fn conv2d_direct() {
+    let input_shape = [1, 3, 8, 8];
+    let kernel_shape = [4, 3, 3, 3];
+    let input: Vec<f32> = (0..3 * 8 * 8).map(|i| (i as f32 * 0.37).sin()).collect();
+    let kernel: Vec<f32> = (0..4 * 3 * 3 * 3)
+        .map(|i| (i as f32 * 0.11).cos() * 0.5)
+        .collect();
+    let bias = [0.1f32, -0.2, 0.3, 0.0];
 
-    let kernel = vec![1.0f32, 2.0, 3.0, 4.0];
-    let kernel_shape = &[1, 1, 4];
-    let kernel_stride = &[4, 4, 1];
+    let expected = cpu_conv2d(&input, &input_shape, &kernel, &kernel_shape, None, 1, 1, 1);
+    let results = run_conv2d_direct(
+        &input,
+        &input_shape,
+        &kernel,
+        &kernel_shape,
+        None,
+        1,
+        1,
+        1,
+        "conv2d_direct_f32",
+    );
+    assert_eq!(results.len(), 4 * 8 * 8);
+    assert_eq!(approx(results, 4), approx(expected, 4));
 
-    let results = run_conv_transpose1d(
+    let expected = cpu_conv2d(
         &input,
-        input_shape,
-        input_stride,
+        &input_shape,
         &kernel,
-        kernel_shape,
-        kernel_stride,
+        &kernel_shape,
+        Some(&bias),
+        2,
         1,
         1,
-        0,
-        0,
-        "conv_transpose1d_f32",
     );
+    let results = run_conv2d_direct(
+        &input,
+        &input_shape,
+        &kernel,
+        &kernel_shape,
+        Some(&bias),
+        2,
+        1,
+        1,
+        "conv2d_direct_f32",
+    );
+    assert_eq!(results.len(), 4 * 4 * 4);
+    assert_eq!(approx(results, 4), approx(expected, 4));
 
-    let expected = vec![1., 4., 10., 20., 25., 24., 16.];
-    assert_eq!(results, expected);
+    let expected = cpu_conv2d(
+        &input,
+        &input_shape,
+        &kernel,
+        &kernel_shape,
+        Some(&bias),
+        1,
+        1,
+        1,
+    );
+    let to_f16 = |v: &[f32]| v.iter().map(|v| f16::from_f32(*v)).collect::<Vec<_>>();
+    let results = run_conv2d_direct(
+        &to_f16(&input),
+        &input_shape,
+        &to_f16(&kernel),
+        &kernel_shape,
+        Some(&to_f16(&bias)),
+        1,
+        1,
+        1,
+        "conv2d_direct_f16",
+    );
+    for (r, e) in results.iter().zip(expected.iter()) {
+        assert!((r.to_f32() - e).abs() < 2e-2, "{r} {e}");
+    }
 }
 
 #[test]
-fn conv_transpose1d_f16() {
-    let input: Vec<f16> = [1.0, 2.0, 3.0, 4.0]
-        .iter()
-        .map(|v| f16::from_f32(*v))
+fn conv2d_direct_channels_last() {
+    let (b, c_in, h, w, c_out) = (2, 3, 5, 6, 4);
+    let input_shape = [b, c_in, h, w];
+    let kernel_shape = [c_out, c_in, 3, 3];
+    let input: Vec<f32> = (0..b * c_in * h * w)
+        .map(|i| (i as f32 * 0.37).sin())
         .collect();
-    let input_shape = &[1, 1, 4];
-    let input_stride = &[4, 4, 1];
-
-    let kernel: Vec<f16> = [1.0, 2.0, 3.0, 4.0]
-        .iter()
-        .map(|v| f16::from_f32(*v))
+    let kernel: Vec<f32> = (0..c_out * c_in * 9)
+        .map(|i| (i as f32 * 0.11).cos() * 0.5)
         .collect();
-    let kernel_shape = &[1, 1, 4];
-    let kernel_stride = &[4, 4, 1];
-
-    let results = run_conv_transpose1d(
+    let bias = [0.1f32, -0.2, 0.3, 0.0];
+    let expected = cpu_conv2d(
         &input,
-        input_shape,
-        input_stride,
+        &input_shape,
         &kernel,
-        kernel_shape,
+        &kernel_shape,
+        Some(&bias),
+        1,
+        1,
+        1,
+    );
+
+    // The same input stored as (b, h, w, c_in).
+    let mut input_nhwc = vec![0f32; input.len()];
+    for (i, v) in input.iter().enumerate() {
+        let (bi, ci, yi, xi) = (i / (c_in * h * w), (i / (h * w)) % c_in, (i / w) % h, i % w);
+        input_nhwc[((bi * h + yi) * w + xi) * c_in + ci] = *v;
+    }
+    let input_stride = [h * w * c_in, 1, w * c_in, c_in];
+    let kernel_stride = [c_in * 9, 9, 3, 1];
+
+    let device = device();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, &input_nhwc);
+    let kernel = new_buffer(&device, &kernel);
+    let bias = new_buffer(&device, &bias);
+    let output = new_buffer(&device, &vec![0.0f32; expected.len()]);
+    let kernels = Kernels::new();
+    call_conv2d_direct(
+        &device,
+        command_buffer,
+        &kernels,
+        "conv2d_direct_f32",
+        CallConv2dDirectCfg {
+            stride: 1,
+            padding: 1,
+            dilation: 1,
+            out_w: w,
+            out_h: h,
+            input_dims: &input_shape,
+            input_stride: &input_stride,
+            kernel_dims: &kernel_shape,
+            kernel_stride: &kernel_stride,
+            input_offset: 0,
+            kernel_offset: 0,
+            channels_last: true,
+        },
+        &input,
+        &kernel,
+        Some(&bias),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<f32> = read_to_vec(&output, expected.len());
+
+    for (i, e) in expected.iter().enumerate() {
+        let (bi, ci, yi, xi) = (
+            i / (c_out * h * w),
+            (i / (h * w)) % c_out,
+            (i / w) % h,
+            i % w,
+        );
+        let r = results[((bi * h + yi) * w + xi) * c_out + ci];
+        assert!((r - e).abs() < 1e-4, "{i} {r} {e}");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn cpu_conv_transpose2d(
+    input: &[f32],
+    input_shape: &[usize],
+    kernel: &[f32],
+    kernel_shape: &[usize],
+    stride: usize,
+    padding: usize,
+    output_padding: usize,
+) -> (Vec<f32>, usize, usize) {
+    let (b_size, c_in, h_in, w_in) = (
+        input_shape[0],
+        input_shape[1],
+        input_shape[2],
+        input_shape[3],
+    );
+    let (c_out, h_k, w_k) = (kernel_shape[1], kernel_shape[2], kernel_shape[3]);
+    let h_out = (h_in - 1) * stride - 2 * padding + h_k + output_padding;
+    let w_out = (w_in - 1) * stride - 2 * padding + w_k + output_padding;
+    let mut dst = vec![0f32; b_size * c_out * h_out * w_out];
+    for b in 0..b_size {
+        for c in 0..c_in {
+            for y in 0..h_in {
+                for x in 0..w_in {
+                    let v = input[((b * c_in + c) * h_in + y) * w_in + x];
+                    for o in 0..c_out {
+                        for k_y in 0..h_k {
+                            for k_x in 0..w_k {
+                                let out_y = (y * stride + k_y) as i64 - padding as i64;
+                                let out_x = (x * stride + k_x) as i64 - padding as i64;
+                                if out_y < 0
+                                    || out_x < 0
+                                    || out_y >= h_out as i64
+                                    || out_x >= w_out as i64
+                                {
+                                    continue;
+                                }
+                                let (out_y, out_x) = (out_y as usize, out_x as usize);
+                                dst[((b * c_out + o) * h_out + out_y) * w_out + out_x] +=
+                                    v * kernel[((c * c_out + o) * h_k + k_y) * w_k + k_x];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    (dst, h_out, w_out)
+}
+
+#[test]
+fn conv_transpose2d() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+
+    let input_shape = [1, 2, 3, 3];
+    let input_stride = [18, 9, 3, 1];
+    let kernel_shape = [2, 3, 3, 3];
+    let kernel_stride = [27, 9, 3, 1];
+    let input: Vec<f32> = (0..18).map(|i| (i as f32 * 0.37).sin()).collect();
+    let kernel: Vec<f32> = (0..54).map(|i| (i as f32 * 0.11).cos()).collect();
+    let (stride, padding, output_padding) = (2, 1, 1);
+
+    let (expected, out_h, out_w) = cpu_conv_transpose2d(
+        &input,
+        &input_shape,
+        &kernel,
+        &kernel_shape,
+        stride,
+        padding,
+        output_padding,
+    );
+    // (h - 1) * stride - 2 * pad + h_k + output_pad
+    assert_eq!((out_h, out_w), (6, 6));
+    let dst_el = 3 * out_h * out_w;
+
+    let input = new_buffer(&device, &input);
+    let kernel = new_buffer(&device, &kernel);
+    let output = new_buffer(&device, &vec![0.0f32; dst_el]);
+    call_conv_transpose2d(
+        &device,
+        command_buffer,
+        &kernels,
+        "conv_transpose2d_f32",
+        CallConvTranspose2dCfg {
+            dilation: 1,
+            stride,
+            padding,
+            output_padding,
+            c_out: 3,
+            out_w,
+            out_h,
+            b_size: 1,
+            input_dims: &input_shape,
+            input_stride: &input_stride,
+            kernel_dims: &kernel_shape,
+            kernel_stride: &kernel_stride,
+            input_offset: 0,
+            kernel_offset: 0,
+        },
+        &input,
+        &kernel,
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let results: Vec<f32> = read_to_vec(&output, dst_el);
+    assert_eq!(approx(results, 4), approx(expected, 4));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_conv_transpose1d<T: Clone>(
+    input: &[T],
+    input_shape: &[usize],
+    input_stride: &[usize],
+    kernel: &[T],
+    kernel_shape: &[usize],
+    kernel_stride: &[usize],
+    dilation: usize,
+    stride: usize,
+    padding: usize,
+    out_padding: usize,
+    name: &'static str,
+) -> Vec<T> {
+    let device = device();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+
+    let c_out = kernel_shape[1];
+    let k_size = kernel_shape[2];
+    let b_size = input_shape[0];
+    let l_in = input_shape[2];
+    let l_out = (l_in - 1) * stride - 2 * padding + dilation * (k_size - 1) + out_padding + 1;
+    let dst_el = c_out * l_out * b_size;
+
+    let input = new_buffer(&device, input);
+    let kernel = new_buffer(&device, kernel);
+    let output = new_buffer(&device, &vec![0.0f32; dst_el]);
+    let kernels = Kernels::new();
+
+    call_conv_transpose1d(
+        &device,
+        command_buffer,
+        &kernels,
+        name,
+        dilation,
+        stride,
+        padding,
+        out_padding,
+        c_out,
+        l_out,
+        b_size,
+        input_shape,
+        input_stride,
+        kernel_shape,
+        kernel_stride,
+        &input,
+        0,
+        &kernel,
+        0,
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    read_to_vec(&output, dst_el)
+}
+
+#[test]
+fn conv_transpose1d_f32() {
+    let input = vec![1.0f32, 2.0, 3.0, 4.0];
+    let input_shape = &[1, 1, 4];
+    let input_stride = &[4, 4, 1];
+
+    let kernel = vec![1.0f32, 2.0, 3.0, 4.0];
+    let kernel_shape = &[1, 1, 4];
+    let kernel_stride = &[4, 4, 1];
+
+    let results = run_conv_transpose1d(
+        &input,
+        input_shape,
+        input_stride,
+        &kernel,
+        kernel_shape,
+        kernel_stride,
+        1,
+        1,
+        0,
+        0,
+        "conv_transpose1d_f32",
+    );
+
+    let expected = vec![1., 4., 10., 20., 25., 24., 16.];
+    assert_eq!(results, expected);
+}
+
+#[test]
+fn conv_transpose1d_f16() {
+    let input: Vec<f16> = [1.0, 2.0, 3.0, 4.0]
+        .iter()
+        .map(|v| f16::from_f32(*v))
+        .collect();
+    let input_shape = &[1, 1, 4];
+    let input_stride = &[4, 4, 1];
+
+    let kernel: Vec<f16> = [1.0, 2.0, 3.0, 4.0]
+        .iter()
+        .map(|v| f16::from_f32(*v))
+        .collect();
+    let kernel_shape = &[1, 1, 4];
+    let kernel_stride = &[4, 4, 1];
+
+    let results = run_conv_transpose1d(
+        &input,
+        input_shape,
+        input_stride,
+        &kernel,
+        kernel_shape,
         kernel_stride,
         1,
         1,
@@ -2338,3 +4281,1095 @@ fn const_fill() {
     test::<bf16, _>("fill_bf16", bf16::from_f32);
     test::<f32, _>("fill_f32", |v| v);
 }
+
+#[test]
+fn warmup() {
+    let device = device();
+    let kernels = Kernels::new();
+    let names = [
+        (Source::Unary, "cos_f32"),
+        (Source::Unary, "exp_f16"),
+        (Source::Binary, "add_f32"),
+    ];
+    kernels.warmup(&device, &names).unwrap();
+    {
+        let pipelines = kernels.pipelines.read().unwrap();
+        assert_eq!(pipelines.len(), names.len());
+        for (source, name) in names {
+            assert!(pipelines.contains_key(&(device.registry_id(), source, name, None)));
+        }
+        let libraries = kernels.libraries.read().unwrap();
+        assert_eq!(libraries.len(), 2);
+    }
+
+    // Warming up cached kernels again is a no-op.
+    kernels.warmup(&device, &names[..1]).unwrap();
+    assert_eq!(kernels.pipelines.read().unwrap().len(), names.len());
+
+    kernels.warmup(&device, STABLE_DIFFUSION_KERNELS).unwrap();
+}
+
+#[test]
+fn load_pipeline_multithreaded() {
+    let device = device();
+    let kernels = Kernels::new();
+    let v: Vec<f32> = (0..1024).map(|v| v as f32).collect();
+    let expected: Vec<f32> = v.iter().map(|v| v.cos()).collect();
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..32)
+            .map(|_| {
+                s.spawn(|| {
+                    let command_queue = device.new_command_queue();
+                    let mut results = vec![];
+                    for _ in 0..8 {
+                        let command_buffer = command_queue.new_command_buffer();
+                        let input = new_buffer(&device, &v);
+                        let output = new_buffer(&device, &v);
+                        call_unary_contiguous(
+                            &device,
+                            command_buffer,
+                            &kernels,
+                            unary::contiguous::cos::FLOAT,
+                            v.len(),
+                            BufferOffset::zero_offset(&input),
+                            &output,
+                        )
+                        .unwrap();
+                        command_buffer.commit();
+                        command_buffer.wait_until_completed();
+                        results.push(read_to_vec::<f32>(&output, v.len()));
+                    }
+                    results
+                })
+            })
+            .collect();
+        for handle in handles {
+            for results in handle.join().unwrap() {
+                assert_eq!(approx(results, 4), approx(expected.clone(), 4));
+            }
+        }
+    });
+    assert_eq!(kernels.pipelines.read().unwrap().len(), 1);
+    assert_eq!(kernels.libraries.read().unwrap().len(), 1);
+}
+
+#[test]
+fn kernels_per_device() {
+    let kernels = Kernels::new();
+    let devices = Device::all();
+    for device in devices.iter() {
+        for _ in 0..2 {
+            kernels
+                .load_pipeline(device, Source::Affine, "affine_f32")
+                .unwrap();
+        }
+    }
+    // Each device gets its own library and pipeline.
+    assert_eq!(kernels.loaded_sources().unwrap(), vec![Source::Affine]);
+    assert_eq!(kernels.compiled_pipelines().unwrap().len(), devices.len());
+    assert_eq!(kernels.libraries.read().unwrap().len(), devices.len());
+}
+
+#[test]
+fn compile_options() {
+    let device = device();
+    let fast_math = LibraryCompileOptions::default();
+    let precise = LibraryCompileOptions {
+        fast_math: false,
+        ..Default::default()
+    };
+    let kernels = Kernels::new_with_compile_options(fast_math)
+        .with_source_compile_options(Source::Reduce, precise);
+    assert_eq!(kernels.compile_options(Source::Unary), fast_math);
+    assert_eq!(kernels.compile_options(Source::Reduce), precise);
+
+    kernels
+        .load_library_with_options(&device, Source::Unary, fast_math)
+        .unwrap();
+    kernels
+        .load_library_with_options(&device, Source::Unary, precise)
+        .unwrap();
+    // The default options for the source hit the cache.
+    kernels.load_library(&device, Source::Unary).unwrap();
+    {
+        let libraries = kernels.libraries.read().unwrap();
+        assert_eq!(libraries.len(), 2);
+        let id = device.registry_id();
+        assert!(libraries.contains_key(&(id, Source::Unary, fast_math)));
+        assert!(libraries.contains_key(&(id, Source::Unary, precise)));
+    }
+
+    kernels.load_library(&device, Source::Reduce).unwrap();
+    let libraries = kernels.libraries.read().unwrap();
+    let id = device.registry_id();
+    assert!(libraries.contains_key(&(id, Source::Reduce, precise)));
+    assert!(!libraries.contains_key(&(id, Source::Reduce, fast_math)));
+}
+
+fn run_sdpa(
+    q: &[f32],
+    k: &[f32],
+    v: &[f32],
+    shape: (usize, usize, usize, usize, usize),
+    scale: f32,
+    causal: bool,
+) -> Result<Vec<f32>, MetalKernelError> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let q_buffer = new_buffer(&device, q);
+    let k_buffer = new_buffer(&device, k);
+    let v_buffer = new_buffer(&device, v);
+    let output = new_buffer(&device, q);
+    call_sdpa(
+        &device,
+        command_buffer,
+        &kernels,
+        GemmDType::F32,
+        shape,
+        BufferOffset::zero_offset(&q_buffer),
+        BufferOffset::zero_offset(&k_buffer),
+        BufferOffset::zero_offset(&v_buffer),
+        &output,
+        scale,
+        causal,
+    )?;
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    Ok(read_to_vec(&output, q.len()))
+}
+
+#[test]
+fn sdpa() {
+    let mut rng = rand::thread_rng();
+    let mut randn = |n: usize| (0..n).map(|_| rng.gen_range(-1f32..1.)).collect::<Vec<_>>();
+    // 40 is not one of the fused head dims and goes through the unfused fallback.
+    for (q_len, kv_len, head_dim) in [(16, 16, 32), (5, 16, 32), (16, 16, 40), (5, 16, 40)] {
+        let (b, h) = (1, 2);
+        let q = randn(b * h * q_len * head_dim);
+        let k = randn(b * h * kv_len * head_dim);
+        let v = randn(b * h * kv_len * head_dim);
+        let scale = 1. / (head_dim as f32).sqrt();
+        for causal in [false, true] {
+            let shape = (b, h, q_len, kv_len, head_dim);
+            let results = run_sdpa(&q, &k, &v, shape, scale, causal).unwrap();
+            let expected = reference::sdpa(&q, &k, &v, (q_len, kv_len, head_dim), scale, causal);
+            for (r, e) in results.iter().zip(expected.iter()) {
+                assert!((r - e).abs() < 1e-4, "{shape:?} {causal} {r} {e}");
+            }
+        }
+    }
+
+    // The first query of a causal attention without cache only sees the first value.
+    let (q_len, head_dim) = (16, 32);
+    let q = randn(q_len * head_dim);
+    let k = randn(q_len * head_dim);
+    let v = randn(q_len * head_dim);
+    let shape = (1, 1, q_len, q_len, head_dim);
+    let results = run_sdpa(&q, &k, &v, shape, 1., true).unwrap();
+    assert_eq!(
+        approx(results[..head_dim].to_vec(), 4),
+        approx(v[..head_dim].to_vec(), 4)
+    );
+
+    let shape = (1, 1, q_len, 4, head_dim);
+    let err = run_sdpa(&q, &k[..4 * head_dim], &v[..4 * head_dim], shape, 1., true);
+    assert!(matches!(
+        err,
+        Err(MetalKernelError::InvalidCausalAttention { .. })
+    ));
+    let shape = (1, 1, q_len, q_len, head_dim);
+    let err = run_sdpa(&q, &k, &v, shape, 0., false);
+    assert!(matches!(
+        err,
+        Err(MetalKernelError::InvalidAttentionScale(_))
+    ));
+}
+
+#[test]
+fn all_sources() {
+    let sources = Source::all();
+    // Fails to build when a variant is added, so that it gets added to `Source::all` too.
+    let mut indices: Vec<usize> = sources
+        .iter()
+        .map(|source| match source {
+            Source::Affine => 0,
+            Source::Binary => 1,
+            Source::Cast => 2,
+            Source::Conv => 3,
+            Source::Fill => 4,
+            Source::Gemm => 5,
+            Source::Indexing => 6,
+            Source::Quantized => 7,
+            Source::Random => 8,
+            Source::Reduce => 9,
+            Source::Sampling => 10,
+            Source::Sdpa => 11,
+            Source::Sort => 12,
+            Source::Ternary => 13,
+            Source::Unary => 14,
+            #[cfg(feature = "metal-flash-attention")]
+            Source::Mfa => 15,
+        })
+        .collect();
+    indices.sort();
+    let expected = 15 + usize::from(cfg!(feature = "metal-flash-attention"));
+    assert_eq!(indices, (0..expected).collect::<Vec<_>>());
+
+    // All the sources but the precompiled `Mfa` one have some MSL code.
+    #[cfg(feature = "metal-flash-attention")]
+    let msl_sources = sources.iter().filter(|&&s| s != Source::Mfa);
+    #[cfg(not(feature = "metal-flash-attention"))]
+    let msl_sources = sources.iter();
+    let kernels = Kernels::new();
+    let msl_sources: Vec<_> = msl_sources
+        .map(|&s| kernels.get_library_source(s))
+        .collect();
+    assert_eq!(msl_sources.len(), 15);
+    assert!(msl_sources
+        .iter()
+        .all(|src| src.contains("#include <metal_stdlib>")));
+}
+
+#[test]
+fn encoder_labels() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    {
+        let encoder = command_buffer.encoder_with_label("affine_f32");
+        assert_eq!(encoder.as_ref().label(), "affine_f32");
+    }
+
+    // An encoder provided by the caller keeps its own label.
+    let input = new_buffer(&device, &[1.0f32, 2.0, 3.0]);
+    let output = new_buffer(&device, &[0.0f32; 3]);
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_label("denoising step");
+    call_affine(
+        &device,
+        encoder,
+        &kernels,
+        "affine_f32",
+        3,
+        BufferOffset::zero_offset(&input),
+        &output,
+        2.,
+        1.,
+    )
+    .unwrap();
+    assert_eq!(encoder.label(), "denoising step");
+    encoder.end_encoding();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    assert_eq!(read_to_vec::<f32>(&output, 3), [3., 5., 7.]);
+}
+
+#[test]
+fn bfloat_unsupported() {
+    let device = device();
+    let kernels = Kernels::new();
+    if kernels.supports_bfloat(&device) {
+        kernels
+            .load_pipeline(&device, Source::Unary, "copy_bf16")
+            .unwrap();
+    }
+
+    // Simulate a device without bfloat support.
+    let kernels = Kernels::new();
+    kernels
+        .bfloat_supported
+        .write()
+        .unwrap()
+        .insert(device.registry_id(), false);
+    let err = kernels
+        .load_pipeline(&device, Source::Unary, "copy_bf16")
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        MetalKernelError::DTypeUnsupported(DType::BF16)
+    ));
+    let msg = err.to_string();
+    assert!(msg.contains("BF16") && msg.contains("f16 or f32"), "{msg}");
+    // Other dtypes are unaffected.
+    kernels
+        .load_pipeline(&device, Source::Unary, "copy_f16")
+        .unwrap();
+}
+
+fn assert_close(results: &[f32], expected: &[f32], tol: f32, name: &str) {
+    assert_eq!(results.len(), expected.len(), "{name}");
+    for (i, (r, e)) in results.iter().zip(expected.iter()).enumerate() {
+        assert!(
+            (r - e).abs() <= tol * e.abs().max(1.),
+            "{name}: {r} != {e} at {i}"
+        )
+    }
+}
+
+#[test]
+fn reference_unary() {
+    let v: Vec<f32> = (0..64).map(|i| (i as f32 - 32.) / 8.).collect();
+    let positive: Vec<f32> = v.iter().map(|x| x.abs() + 0.1).collect();
+    let kernels = [
+        unary::contiguous::cos::FLOAT,
+        unary::contiguous::sin::FLOAT,
+        unary::contiguous::exp::FLOAT,
+        unary::contiguous::sqr::FLOAT,
+        unary::contiguous::sqrt::FLOAT,
+        unary::contiguous::neg::FLOAT,
+        unary::contiguous::log::FLOAT,
+        unary::contiguous::gelu::FLOAT,
+        unary::contiguous::gelu_erf::FLOAT,
+        unary::contiguous::erf::FLOAT,
+        unary::contiguous::abs::FLOAT,
+        unary::contiguous::ceil::FLOAT,
+        unary::contiguous::floor::FLOAT,
+        unary::contiguous::round::FLOAT,
+        unary::contiguous::relu::FLOAT,
+        unary::contiguous::tanh::FLOAT,
+        unary::contiguous::recip::FLOAT,
+        unary::contiguous::silu::FLOAT,
+        unary::contiguous::sigmoid::FLOAT,
+        unary::contiguous::sign::FLOAT,
+    ];
+    for kernel in kernels {
+        let name = kernel.0.strip_suffix("_f32").unwrap();
+        let input = match name {
+            "sqrt" | "log" | "recip" => &positive,
+            _ => &v,
+        };
+        let expected = reference::unary(name, input).unwrap();
+        let results = run(input, kernel);
+        assert_close(&results, &expected, 1e-4, name);
+    }
+}
+
+#[test]
+fn reference_binary() {
+    let lhs: Vec<f32> = (0..64).map(|i| (i as f32 - 32.) / 8.).collect();
+    let rhs: Vec<f32> = (0..64)
+        .map(|i| ((i * 37) % 64) as f32 / 16. + 0.5)
+        .collect();
+    let kernels = [
+        binary::contiguous::add::FLOAT,
+        binary::contiguous::sub::FLOAT,
+        binary::contiguous::mul::FLOAT,
+        binary::contiguous::div::FLOAT,
+        binary::contiguous::min::FLOAT,
+        binary::contiguous::max::FLOAT,
+    ];
+    for kernel in kernels {
+        let name = kernel.0.strip_suffix("_f32").unwrap();
+        let expected = reference::binary(name, &lhs, &rhs).unwrap();
+        let results = run_binary(&lhs, &rhs, kernel);
+        assert_close(&results, &expected, 1e-6, name);
+    }
+}
+
+#[test]
+fn reference_reduce() {
+    // Distinct values so that argmin/argmax have no ties.
+    let v: Vec<f32> = (0..96).map(|i| ((i * 37) % 96) as f32 - 48.).collect();
+    for out_length in [1, 3, 12] {
+        for name in ["sum", "min", "max"] {
+            let expected = reference::reduce(name, &v, out_length).unwrap();
+            let kernel = match name {
+                "sum" => "fast_sum_f32_strided",
+                "min" => "fast_min_f32_strided",
+                _ => "fast_max_f32_strided",
+            };
+            let results = run_reduce(&v, out_length, kernel);
+            assert_close(&results, &expected, 1e-6, name);
+        }
+        for (name, kernel) in [
+            ("argmin", "fast_argmin_f32_strided"),
+            ("argmax", "fast_argmax_f32_strided"),
+        ] {
+            let expected = reference::arg_reduce(name, &v, out_length).unwrap();
+            let device = device();
+            let kernels = Kernels::new();
+            let command_queue = device.new_command_queue();
+            let command_buffer = command_queue.new_command_buffer();
+            let input = new_buffer(&device, &v);
+            let output = new_buffer(&device, &vec![0u32; out_length]);
+            call_reduce_strided(
+                &device,
+                command_buffer,
+                &kernels,
+                kernel,
+                &[v.len()],
+                &[1],
+                out_length,
+                BufferOffset::zero_offset(&input),
+                &output,
+            )
+            .unwrap();
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+            let results: Vec<u32> = read_to_vec(&output, out_length);
+            assert_eq!(results, expected, "{name}");
+        }
+    }
+}
+
+#[test]
+fn reference_softmax() {
+    let v: Vec<f32> = (0..96).map(|i| ((i * 37) % 96) as f32 / 16.).collect();
+    for last_dim in [1, 8, 32, 96] {
+        let expected = reference::softmax(&v, last_dim);
+        let results = run_softmax(&v, last_dim, "softmax_f32");
+        assert_close(&results, &expected, 1e-5, "softmax");
+    }
+}
+
+#[test]
+fn reference_affine() {
+    let v: Vec<f32> = (0..64).map(|i| (i as f32 - 32.) / 8.).collect();
+    let expected = reference::affine(&v, 1.5, -0.25);
+    let results = run_affine(&v, 1.5, -0.25);
+    assert_close(&results, &expected, 1e-6, "affine");
+}
+
+#[test]
+fn reference_where_cond() {
+    let shape = vec![8];
+    let cond = vec![0u8, 1, 2, 0, 1, 0, 0, 255];
+    let on_true: Vec<f32> = (0..8).map(|i| i as f32).collect();
+    let on_false: Vec<f32> = (0..8).map(|i| -(i as f32)).collect();
+    let expected = reference::where_cond(&cond, &on_true, &on_false);
+    let results = run_where_cond(
+        &shape,
+        &cond,
+        (vec![1], 0),
+        &on_true,
+        (vec![1], 0),
+        &on_false,
+        (vec![1], 0),
+        "where_u8_f32",
+    );
+    assert_eq!(results, expected);
+}
+
+#[test]
+fn reference_index_select() {
+    let v: Vec<f32> = (0..24).map(|i| i as f32).collect();
+    let shape = [2, 3, 4];
+    let ids = [2u32, 0, 2, 1];
+    for dim in 0..shape.len() {
+        let ids: Vec<u32> = ids.iter().map(|&i| i % shape[dim] as u32).collect();
+        let expected = reference::index_select(&v, &shape, dim, &ids);
+        let results = run_index_select(&v, &shape, &[12, 4, 1], &ids, dim, "is_u32_f32");
+        assert_eq!(results, expected, "dim {dim}");
+    }
+
+    // The transposed view of the (2, 3, 4) tensor.
+    let view = reference::strided(&v, &[4, 3, 2], &[1, 4, 12], 0);
+    assert_eq!(&view[..4], [0., 12., 4., 16.]);
+}
+
+#[test]
+fn unary_repeated() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let v = vec![1.5f32, -0.5, 2.0, -3.0, 0.25];
+
+    // Squaring in place 3 times is x^8, the input is also the output buffer.
+    let buffer = new_buffer(&device, &v);
+    let command_buffer = command_queue.new_command_buffer();
+    call_unary_contiguous_repeated(
+        &device,
+        command_buffer,
+        &kernels,
+        unary::contiguous::sqr::FLOAT,
+        v.len(),
+        BufferOffset::zero_offset(&buffer),
+        BufferOffset::zero_offset(&buffer),
+        3,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let expected: Vec<f32> = v.iter().map(|x| x.powi(8)).collect();
+    assert_eq!(
+        approx(read_to_vec(&buffer, v.len()), 4),
+        approx(expected, 4)
+    );
+
+    // The generic version, negating 5 times is a single negation.
+    let buffer = new_buffer(&device, &v);
+    let command_buffer = command_queue.new_command_buffer();
+    call_repeated(command_buffer, 5, |encoder| {
+        call_unary_contiguous_with_offset(
+            &device,
+            encoder,
+            &kernels,
+            unary::contiguous::neg::FLOAT,
+            v.len(),
+            BufferOffset::zero_offset(&buffer),
+            BufferOffset::zero_offset(&buffer),
+        )
+    })
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let expected: Vec<f32> = v.iter().map(|x| -x).collect();
+    assert_eq!(read_to_vec::<f32>(&buffer, v.len()), expected);
+}
+
+#[test]
+fn binary_affine_arena_offsets() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let f32_size = std::mem::size_of::<f32>();
+    // Two arenas holding several tensors, the operands start at different offsets.
+    let arena0: Vec<f32> = (0..16).map(|i| i as f32).collect();
+    let arena1: Vec<f32> = (0..16).map(|i| 100. * i as f32).collect();
+    let arena0 = new_buffer(&device, &arena0);
+    let arena1 = new_buffer(&device, &arena1);
+    let output = new_buffer(&device, &[0f32; 16]);
+
+    let command_buffer = command_queue.new_command_buffer();
+    call_binary_contiguous_with_offset(
+        &device,
+        command_buffer,
+        &kernels,
+        binary::contiguous::add::FLOAT,
+        4,
+        BufferOffset {
+            buffer: &arena0,
+            offset_in_bytes: 2 * f32_size,
+        },
+        BufferOffset {
+            buffer: &arena1,
+            offset_in_bytes: 8 * f32_size,
+        },
+        BufferOffset {
+            buffer: &output,
+            offset_in_bytes: 4 * f32_size,
+        },
+    )
+    .unwrap();
+    call_affine_with_offset(
+        &device,
+        command_buffer,
+        &kernels,
+        "affine_f32",
+        4,
+        BufferOffset {
+            buffer: &arena1,
+            offset_in_bytes: 12 * f32_size,
+        },
+        BufferOffset {
+            buffer: &output,
+            offset_in_bytes: 10 * f32_size,
+        },
+        0.5,
+        1.,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let results: Vec<f32> = read_to_vec(&output, 16);
+    assert_eq!(
+        results,
+        [0., 0., 0., 0., 802., 903., 1004., 1105., 0., 0., 601., 651., 701., 751., 0., 0.]
+    );
+}
+
+#[test]
+fn kernels_diagnostics() {
+    let device = device();
+    let kernels = Kernels::new();
+    assert!(kernels.loaded_sources().unwrap().is_empty());
+    assert!(kernels.compiled_pipelines().unwrap().is_empty());
+
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let v = [1f32, 2., 3., 4.];
+    let input = new_buffer(&device, &v);
+    let output = new_buffer(&device, &v);
+    call_unary_contiguous(
+        &device,
+        command_buffer,
+        &kernels,
+        unary::contiguous::cos::FLOAT,
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    call_unary_contiguous(
+        &device,
+        command_buffer,
+        &kernels,
+        unary::contiguous::exp::FLOAT,
+        v.len(),
+        BufferOffset::zero_offset(&output),
+        &input,
+    )
+    .unwrap();
+    call_affine(
+        &device,
+        command_buffer,
+        &kernels,
+        "affine_f32",
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+        2.,
+        1.,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+
+    let sources = kernels.loaded_sources().unwrap();
+    assert_eq!(sources.len(), 2);
+    assert!(sources.contains(&Source::Unary));
+    assert!(sources.contains(&Source::Affine));
+    assert_eq!(
+        kernels.compiled_pipelines().unwrap(),
+        [
+            (Source::Affine, "affine_f32".to_string()),
+            (Source::Unary, "cos_f32".to_string()),
+            (Source::Unary, "exp_f32".to_string()),
+        ]
+    );
+}
+
+fn run_sampling_filter(v: &[f32], ncols: usize, name: &'static str, k: usize, p: f32) -> Vec<f32> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, v);
+    let nrows = v.len() / ncols;
+    let input = BufferOffset::zero_offset(&input);
+    if name.starts_with("top_k") {
+        call_top_k(
+            &device,
+            command_buffer,
+            &kernels,
+            name,
+            nrows,
+            ncols,
+            k,
+            input,
+            &output,
+        )
+        .unwrap();
+    } else {
+        call_top_p(
+            &device,
+            command_buffer,
+            &kernels,
+            name,
+            nrows,
+            ncols,
+            p,
+            input,
+            &output,
+        )
+        .unwrap();
+    }
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len())
+}
+
+#[test]
+fn top_k_filter() {
+    let logits = [1.0f32, 3.0, 0.5, 2.0, -1.0, -2.0, 4.0, 0.0, 5.0, 1.0];
+    let results = run_sampling_filter(&logits, 5, "top_k_f32", 2, 0.);
+    let inf = f32::NEG_INFINITY;
+    assert_eq!(results, [inf, 3.0, inf, 2.0, inf, inf, 4.0, inf, 5.0, inf]);
+
+    // A row longer than a threadgroup.
+    let logits: Vec<f32> = (0..4096).map(|i| ((i * 37) % 4096) as f32).collect();
+    let results = run_sampling_filter(&logits, 4096, "top_k_f32", 10, 0.);
+    let kept: Vec<f32> = results.iter().copied().filter(|v| v.is_finite()).collect();
+    assert_eq!(kept.len(), 10);
+    assert!(kept.iter().all(|&v| v >= 4086.));
+}
+
+#[test]
+fn top_p_filter() {
+    // The probabilities of the first row are 0.62, 0.23, 0.08, 0.05, 0.01 in decreasing order,
+    // the three first ones are needed to reach 0.9.
+    let logits = [1.0f32, 3.0, 0.5, 2.0, -1.0, -2.0, 4.0, 0.0, 5.0, 1.0];
+    let results = run_sampling_filter(&logits, 5, "top_p_f32", 0, 0.9);
+    let inf = f32::NEG_INFINITY;
+    assert_eq!(results, [1.0, 3.0, inf, 2.0, inf, inf, 4.0, inf, 5.0, inf]);
+
+    let probs = reference::softmax(&logits, 5);
+    let kept_mass: f32 = (0..5)
+        .filter(|&i| results[i].is_finite())
+        .map(|i| probs[i])
+        .sum();
+    assert!(kept_mass >= 0.9);
+
+    // The largest logit is kept even for a zero p.
+    let results = run_sampling_filter(&logits, 5, "top_p_f32", 0, 0.);
+    assert_eq!(results, [inf, 3.0, inf, inf, inf, inf, inf, inf, 5.0, inf]);
+}
+
+fn run_multinomial(probs: &[f32], row_len: usize, seed: u64) -> Vec<u32> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, probs);
+    let num_rows = probs.len() / row_len;
+    let options = MTLResourceOptions::StorageModeManaged;
+    let output = device.new_buffer((num_rows * std::mem::size_of::<u32>()) as u64, options);
+    call_multinomial(
+        &device,
+        command_buffer,
+        &kernels,
+        "multinomial_f32",
+        num_rows,
+        row_len,
+        seed,
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, num_rows)
+}
+
+#[test]
+fn multinomial() {
+    // One-hot rows always return the hot index.
+    let row_len = 8;
+    let hot = [3usize, 0, 7, 5];
+    let mut probs = vec![0f32; hot.len() * row_len];
+    for (row, &h) in hot.iter().enumerate() {
+        probs[row * row_len + h] = 1.0;
+    }
+    for seed in [0, 1, 299792458] {
+        let results = run_multinomial(&probs, row_len, seed);
+        assert_eq!(results, [3, 0, 7, 5]);
+    }
+
+    // Uniform rows, the probabilities do not have to be normalized.
+    let (num_rows, row_len) = (8000, 4);
+    let probs = vec![0.5f32; num_rows * row_len];
+    let results = run_multinomial(&probs, row_len, 42);
+    let mut histogram = [0usize; 4];
+    for &r in results.iter() {
+        histogram[r as usize] += 1
+    }
+    for &count in histogram.iter() {
+        assert!((1800..2200).contains(&count), "{histogram:?}");
+    }
+
+    // Deterministic given the seed.
+    assert_eq!(run_multinomial(&probs, row_len, 42), results);
+    assert_ne!(run_multinomial(&probs, row_len, 43), results);
+}
+
+#[test]
+fn kernels_source_dump() {
+    let device = device();
+    let dir = std::env::temp_dir().join(format!("candle-metal-dump-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let kernels = Kernels::new().with_source_dump(&dir);
+
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let v = [1f32, 2., 3., 4.];
+    let input = new_buffer(&device, &v);
+    let output = new_buffer(&device, &v);
+    call_affine(
+        &device,
+        command_buffer,
+        &kernels,
+        "affine_f32",
+        v.len(),
+        BufferOffset::zero_offset(&input),
+        &output,
+        2.,
+        1.,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    assert_eq!(read_to_vec::<f32>(&output, v.len()), [3., 5., 7., 9.]);
+    let dumped = std::fs::read_to_string(dir.join("affine.metal")).unwrap();
+    assert_eq!(dumped, include_str!("affine.metal"));
+
+    // The precompiled library only gets a note, whether it can be loaded or not.
+    #[cfg(feature = "metal-flash-attention")]
+    {
+        let _ = kernels.load_library(&device, Source::Mfa);
+        let note = std::fs::read_to_string(dir.join("mfa.txt")).unwrap();
+        assert!(note.contains("precompiled"));
+        assert!(!dir.join("mfa.metal").exists());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reduce_absmax() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    // The extreme is negative in the first and last rows, both signs tie in the second one.
+    let v = [
+        1.0f32, -7.0, 3.0, 2.0, -0.5, 0.25, 0.5, -0.125, 4.0, -4.0, -9.0, 8.5,
+    ];
+    let input = new_buffer(&device, &v);
+    let output = new_buffer(&device, &[0f32; 3]);
+    call_reduce_absmax(
+        &device,
+        command_buffer,
+        &kernels,
+        "fast_absmax_f32_strided",
+        &[3, 4],
+        &[4, 1],
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<f32> = read_to_vec(&output, 3);
+    assert_eq!(results, reference::reduce("absmax", &v, 3).unwrap());
+    assert_eq!(results, [7.0, 0.5, 9.0]);
+
+    // A single row spanning multiple threads.
+    let v: Vec<f32> = (0..4096).map(|i| (i % 97) as f32 - 50.).collect();
+    let results = run_reduce(&v, 1, "fast_absmax_f32_strided");
+    assert_eq!(results, [50.0]);
+}
+
+#[test]
+fn latent_to_rgb8() {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let (channels, height, width) = (3, 2, 4);
+    // Includes values outside of [-1, 1] to exercise the clamping.
+    let v: Vec<f32> = (0..channels * height * width)
+        .map(|i| (i as f32 - 8.) / 8.)
+        .collect();
+    let input = new_buffer(&device, &v);
+    let options = MTLResourceOptions::StorageModeManaged;
+    let output = device.new_buffer(v.len() as u64, options);
+    call_latent_to_rgb8(
+        &device,
+        command_buffer,
+        &kernels,
+        "latent_to_rgb8_f32",
+        (channels, height, width),
+        BufferOffset::zero_offset(&input),
+        &output,
+    )
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    let results: Vec<u8> = read_to_vec(&output, v.len());
+    let expected = reference::latent_to_rgb8(&v, channels, height, width);
+    assert_eq!(results, expected);
+    // The first pixel gathers the first element of each channel.
+    assert_eq!(results[..3], [0, 127, 255]);
+}
+
+#[test]
+fn unary_reversed_view() {
+    let device = device();
+    let kernels = Kernels::new();
+    let v: Vec<f32> = (1..=8).map(|v| v as f32).collect();
+    let input = new_buffer(&device, &v);
+    let run = |kernel: unary::strided::Kernel, strides: &[i64], first: usize| {
+        let command_queue = device.new_command_queue();
+        let command_buffer = command_queue.new_command_buffer();
+        let output = new_buffer(&device, &v);
+        call_unary_strided_signed(
+            &device,
+            command_buffer,
+            &kernels,
+            kernel,
+            &[2, 4],
+            BufferOffset {
+                buffer: &input,
+                offset_in_bytes: first * std::mem::size_of::<f32>(),
+            },
+            strides,
+            BufferOffset::zero_offset(&output),
+        )
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+        read_to_vec::<f32>(&output, v.len())
+    };
+
+    // Flip of the last dimension of a (2, 4) tensor, the view starts at element 3.
+    let results = run(unary::strided::neg::FLOAT, &[4, -1], 3);
+    assert_eq!(results, [-4., -3., -2., -1., -8., -7., -6., -5.]);
+    // Both dimensions flipped, the view starts at the last element.
+    let results = run(unary::strided::copy::FLOAT, &[-4, -1], 7);
+    assert_eq!(results, [8., 7., 6., 5., 4., 3., 2., 1.]);
+    // Non-negative strides behave as with call_unary_strided.
+    let results = run(unary::strided::copy::FLOAT, &[1, 2], 0);
+    assert_eq!(results, [1., 3., 5., 7., 2., 4., 6., 8.]);
+}
+
+/// Returns the `n` bytes that a kernel receives for the parameter `data`.
+fn param_bytes<P: utils::EncoderParam>(data: P, n: usize) -> Vec<u8> {
+    const SOURCE: &str = "
+kernel void copy_bytes(
+    constant uchar *bytes [[buffer(0)]],
+    constant size_t &n [[buffer(1)]],
+    device uchar *output [[buffer(2)]]
+) {
+    for (size_t i = 0; i < n; i++) {
+        output[i] = bytes[i];
+    }
+}";
+    let device = device();
+    let library = device
+        .new_library_with_source(SOURCE, &CompileOptions::new())
+        .unwrap();
+    let function = library.get_function("copy_bytes", None).unwrap();
+    let pipeline = device
+        .new_compute_pipeline_state_with_function(&function)
+        .unwrap();
+    let output = device.new_buffer(n as u64, MTLResourceOptions::StorageModeManaged);
+
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (data, n, &output));
+    let one = MTLSize {
+        width: 1,
+        height: 1,
+        depth: 1,
+    };
+    encoder.dispatch_thread_groups(one, one);
+    encoder.end_encoding();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, n)
+}
+
+#[test]
+fn encoder_param_layout() {
+    assert_eq!(param_bytes(-2i32, 4), (-2i32).to_ne_bytes());
+    assert_eq!(param_bytes(i32::MIN, 4), i32::MIN.to_ne_bytes());
+    assert_eq!(param_bytes(-3i64, 8), (-3i64).to_ne_bytes());
+    assert_eq!(param_bytes(1.5f64, 8), 1.5f64.to_ne_bytes());
+    assert_eq!(param_bytes(0.1f32, 4), 0.1f32.to_ne_bytes());
+    let values: &[i32] = &[-1, 2, -3];
+    let expected: Vec<u8> = values.iter().flat_map(|v| v.to_ne_bytes()).collect();
+    assert_eq!(param_bytes(values, 12), expected);
+}
+
+#[test]
+fn mean_var() {
+    let device = device();
+    let kernels = Kernels::new();
+    let mut rng = rand::thread_rng();
+    // A non-zero mean to catch the cancellation of the naive sum of squares formula.
+    let v: Vec<f32> = (0..4 * 128).map(|_| rng.gen::<f32>() * 4. + 10.).collect();
+    let input = new_buffer(&device, &v);
+    for unbiased in [false, true] {
+        let command_queue = device.new_command_queue();
+        let command_buffer = command_queue.new_command_buffer();
+        let options = MTLResourceOptions::StorageModeManaged;
+        let mean = device.new_buffer(4 * std::mem::size_of::<f32>() as u64, options);
+        let var = device.new_buffer(4 * std::mem::size_of::<f32>() as u64, options);
+        call_mean_var(
+            &device,
+            command_buffer,
+            &kernels,
+            "mean_var_f32",
+            v.len(),
+            128,
+            unbiased,
+            BufferOffset::zero_offset(&input),
+            &mean,
+            &var,
+        )
+        .unwrap();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+
+        let (expected_mean, expected_var) = reference::mean_var(&v, 128, unbiased);
+        assert_close(&read_to_vec::<f32>(&mean, 4), &expected_mean, 1e-4, "mean");
+        assert_close(&read_to_vec::<f32>(&var, 4), &expected_var, 1e-4, "var");
+    }
+}
+
+fn run_pixel_rearrange(v: &[f32], shape: &[usize], r: usize, shuffle: bool) -> Vec<f32> {
+    let device = device();
+    let kernels = Kernels::new();
+    let command_queue = device.new_command_queue();
+    let command_buffer = command_queue.new_command_buffer();
+    let input = new_buffer(&device, v);
+    let output = new_buffer(&device, v);
+    let input = BufferOffset::zero_offset(&input);
+    if shuffle {
+        call_pixel_shuffle(
+            &device,
+            command_buffer,
+            &kernels,
+            "pixel_shuffle_f32",
+            shape,
+            r,
+            input,
+            &output,
+        )
+    } else {
+        call_pixel_unshuffle(
+            &device,
+            command_buffer,
+            &kernels,
+            "pixel_unshuffle_f32",
+            shape,
+            r,
+            input,
+            &output,
+        )
+    }
+    .unwrap();
+    command_buffer.commit();
+    command_buffer.wait_until_completed();
+    read_to_vec(&output, v.len())
+}
+
+#[test]
+fn pixel_shuffle() {
+    let v: Vec<f32> = (0..16).map(|v| v as f32).collect();
+    let results = run_pixel_rearrange(&v, &[1, 4, 2, 2], 2, true);
+    // Each output 2x2 block takes one pixel from each of the 4 input channels.
+    #[rustfmt::skip]
+    let expected = [
+        0., 4., 1., 5.,
+        8., 12., 9., 13.,
+        2., 6., 3., 7.,
+        10., 14., 11., 15.,
+    ];
+    assert_eq!(results, expected);
+    assert_eq!(results, reference::pixel_shuffle(&v, &[1, 4, 2, 2], 2));
+    assert_eq!(run_pixel_rearrange(&results, &[1, 1, 4, 4], 2, false), v);
+
+    let v: Vec<f32> = (0..2 * 18 * 3 * 2).map(|v| v as f32).collect();
+    let results = run_pixel_rearrange(&v, &[2, 18, 3, 2], 3, true);
+    assert_eq!(results, reference::pixel_shuffle(&v, &[2, 18, 3, 2], 3));
+    let back = run_pixel_rearrange(&results, &[2, 2, 9, 6], 3, false);
+    assert_eq!(back, reference::pixel_unshuffle(&results, &[2, 2, 9, 6], 3));
+    assert_eq!(back, v);
+}
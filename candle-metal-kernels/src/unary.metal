@@ -3,16 +3,19 @@
 #
 using namespace metal;
 
-METAL_FUNC uint get_strided_index(
+// The strides are signed so that reversed views can be read without a copy, the input then
+// points at the first element of the view rather than at its lowest address. Non-negative
+// strides passed as size_t have the same representation.
+METAL_FUNC int64_t get_strided_index(
     uint idx,
     constant size_t &num_dims,
     constant size_t *dims,
-    constant size_t *strides
+    constant int64_t *strides
 ) {
-    uint strided_i = 0;
+    int64_t strided_i = 0;
     for (uint d = 0; d < num_dims; d++) {
         uint dim_idx = num_dims - 1 - d;
-        strided_i += (idx % dims[dim_idx]) * strides[dim_idx];
+        strided_i += int64_t(idx % dims[dim_idx]) * strides[dim_idx];
         idx /= dims[dim_idx];
     }
     return strided_i;
@@ -45,9 +48,11 @@ template <typename T> METAL_FUNC T erf(T in){
     return T(sign*y);
 }
 template <typename T> METAL_FUNC T id(T in) { return in; }
+// Exact GELU: 0.5 * x * (1 + erf(x / sqrt(2))).
 template <typename T> METAL_FUNC T gelu_erf(T x) {
     return T(x * (1 + erf(x * M_SQRT1_2_F)) / 2);
 }
+// Tanh approximation of GELU: 0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3))).
 template <typename T> METAL_FUNC T gelu(T x) {
     if (x > 5) {
         return x;
@@ -70,6 +75,57 @@ template <typename T> METAL_FUNC T silu(T in){
 template <typename T> METAL_FUNC T sigmoid(T in) {
     return recip(static_cast<T>(1) + exp(-in));
 }
+// Variances computed as E[x^2] - E[x]^2 can end up slightly negative because of rounding, these
+// values are clamped to 0 rather than producing NaN.
+template <typename T> METAL_FUNC T sqrt_clamp(T in) {
+    return T(sqrt(max(float(in), 0.0f)));
+}
+
+// Selects the fast path of the `*_precision` kernels below, the accurate path is used when the
+// constant is not set.
+constant bool erf_fast_ [[function_constant(0)]];
+constant bool erf_fast = is_function_constant_defined(erf_fast_) && erf_fast_;
+
+// A&S formula 7.1.27, evaluated in the input type without going through float. The formula has a
+// max error of 5e-4, rounding in half or bfloat arithmetic adds to it.
+template <typename T> METAL_FUNC T erf_fast_poly(T in) {
+    const T x = in < T(0) ? -in : in;
+    T t = T(0.000972) + x * T(0.078108);
+    t = T(0.230389) + x * t;
+    t = T(0.278393) + x * t;
+    t = T(1) + x * t;
+    t = t * t;
+    t = t * t;
+    const T y = T(1) - T(1) / t;
+    return in < T(0) ? -y : y;
+}
+
+template <typename T> METAL_FUNC T erf_precision(T in) {
+    if (erf_fast) {
+        return erf_fast_poly(in);
+    }
+    return T(erf(float(in)));
+}
+
+template <typename T> METAL_FUNC T gelu_erf_precision(T x) {
+    if (erf_fast) {
+        return x * (T(1) + erf_fast_poly(T(x * T(M_SQRT1_2_F)))) * T(0.5);
+    }
+    return T(gelu_erf(float(x)));
+}
+
+#define ERF_PRECISION(FN, TYPENAME, FN_NAME) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    device const TYPENAME *input,  \
+    device TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = FN<TYPENAME>(input[tid]); \
+}
 
 #define TILE_SIZE 2
 
@@ -89,7 +145,7 @@ kernel void FN_NAME##_##strided( \
     constant size_t &dim, \
     constant size_t &num_dims, \
     constant size_t *dims, \
-    constant size_t *strides, \
+    constant int64_t *strides, \
     device const TYPENAME *input,  \
     device TYPENAME *output, \
     uint tid [[ thread_position_in_grid ]] \
@@ -111,6 +167,30 @@ kernel void FN_NAME##_##tiled( \
     } \
 }
 
+// Reads IN_TYPENAME values and writes OUT_TYPENAME ones, the op is computed in float in between
+// like for the kernels above. This fuses a cast before or after the op in mixed precision
+// pointwise chains, saving a dispatch and a round trip through memory.
+#define UNARY_CAST(FN, IN_TYPENAME, OUT_TYPENAME, FN_NAME) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    device const IN_TYPENAME *input,  \
+    device OUT_TYPENAME *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = OUT_TYPENAME(FN(float(input[tid]))); \
+}
+
+#define UNARY_CAST_OP(NAME) \
+UNARY_CAST(NAME, half, float, NAME##_f16_f32); \
+UNARY_CAST(NAME, float, half, NAME##_f32_f16);
+
+#define BFLOAT_UNARY_CAST_OP(NAME) \
+UNARY_CAST(NAME, bfloat, float, NAME##_bf16_f32); \
+UNARY_CAST(NAME, float, bfloat, NAME##_f32_bf16);
+
 #define UNARY_OP(NAME) \
 UNARY(NAME, float, NAME##_f32, NAME##_f32_strided); \
 UNARY(NAME, half, NAME##_f16, NAME##_f16_strided);
@@ -118,6 +198,19 @@ UNARY(NAME, half, NAME##_f16, NAME##_f16_strided);
 #define BFLOAT_UNARY_OP(NAME) \
 UNARY(NAME, bfloat, NAME##_bf16, NAME##_bf16_strided);
 
+#define PREDICATE(FN, TYPENAME, FN_NAME) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    device const TYPENAME *input,  \
+    device uint8_t *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = FN(float(input[tid])) ? 1 : 0; \
+}
+
 #define COPY2D(FN_NAME, TYPENAME) \
 kernel void FN_NAME( \
     constant int64_t &d1, \
@@ -158,6 +251,13 @@ UNARY_OP(recip)
 UNARY_OP(relu)
 UNARY_OP(sign)
 UNARY_OP(sigmoid)
+// The fused 1 / sqrt(x) of the metal standard library, rather than a sqrt and a recip.
+UNARY_OP(rsqrt)
+UNARY_OP(sqrt_clamp)
+PREDICATE(isnan, float, isnan_f32)
+PREDICATE(isnan, half, isnan_f16)
+PREDICATE(isfinite, float, isfinite_f32)
+PREDICATE(isfinite, half, isfinite_f16)
 UNARY(id, float, copy_f32, copy_f32_strided)
 UNARY(id, half, copy_f16, copy_f16_strided)
 UNARY(id, uint8_t, copy_u8, copy_u8_strided)
@@ -168,6 +268,33 @@ UNARY(id, uint32_t, copy_u32, copy_u32_strided)
 UNARY(precise::tanh, float, tanh_f32, tanh_f32_strided);
 UNARY(precise::tanh, half, tanh_f16, tanh_f16_strided);
 
+UNARY_CAST_OP(cos)
+UNARY_CAST_OP(sin)
+UNARY_CAST_OP(sqr)
+UNARY_CAST_OP(sqrt)
+UNARY_CAST_OP(neg)
+UNARY_CAST_OP(exp)
+UNARY_CAST_OP(log)
+UNARY_CAST_OP(gelu)
+UNARY_CAST_OP(silu)
+UNARY_CAST_OP(abs)
+UNARY_CAST_OP(ceil)
+UNARY_CAST_OP(floor)
+UNARY_CAST_OP(round)
+UNARY_CAST_OP(gelu_erf)
+UNARY_CAST_OP(erf)
+UNARY_CAST_OP(recip)
+UNARY_CAST_OP(relu)
+UNARY_CAST_OP(sign)
+UNARY_CAST_OP(sigmoid)
+UNARY_CAST_OP(rsqrt)
+UNARY_CAST_OP(sqrt_clamp)
+UNARY_CAST(precise::tanh, half, float, tanh_f16_f32);
+UNARY_CAST(precise::tanh, float, half, tanh_f32_f16);
+
+ERF_PRECISION(erf_precision, half, erf_precision_f16)
+ERF_PRECISION(gelu_erf_precision, half, gelu_erf_precision_f16)
+
 #if __METAL_VERSION__ >= 220
 UNARY(id, int64_t, copy_i64, copy_i64_strided)
 COPY2D(copy2d_i64, int64_t)
@@ -193,10 +320,42 @@ BFLOAT_UNARY_OP(recip)
 BFLOAT_UNARY_OP(relu)
 BFLOAT_UNARY_OP(sign)
 BFLOAT_UNARY_OP(sigmoid)
+BFLOAT_UNARY_OP(rsqrt)
+BFLOAT_UNARY_OP(sqrt_clamp)
+
+PREDICATE(isnan, bfloat, isnan_bf16)
+PREDICATE(isfinite, bfloat, isfinite_bf16)
 
 UNARY(id, bfloat, copy_bf16, copy_bf16_strided)
 
 UNARY(precise::tanh, bfloat, tanh_bf16, tanh_bf16_strided);
 
+BFLOAT_UNARY_CAST_OP(cos)
+BFLOAT_UNARY_CAST_OP(sin)
+BFLOAT_UNARY_CAST_OP(sqr)
+BFLOAT_UNARY_CAST_OP(sqrt)
+BFLOAT_UNARY_CAST_OP(neg)
+BFLOAT_UNARY_CAST_OP(exp)
+BFLOAT_UNARY_CAST_OP(log)
+BFLOAT_UNARY_CAST_OP(gelu)
+BFLOAT_UNARY_CAST_OP(silu)
+BFLOAT_UNARY_CAST_OP(abs)
+BFLOAT_UNARY_CAST_OP(ceil)
+BFLOAT_UNARY_CAST_OP(floor)
+BFLOAT_UNARY_CAST_OP(round)
+BFLOAT_UNARY_CAST_OP(gelu_erf)
+BFLOAT_UNARY_CAST_OP(erf)
+BFLOAT_UNARY_CAST_OP(recip)
+BFLOAT_UNARY_CAST_OP(relu)
+BFLOAT_UNARY_CAST_OP(sign)
+BFLOAT_UNARY_CAST_OP(sigmoid)
+BFLOAT_UNARY_CAST_OP(rsqrt)
+BFLOAT_UNARY_CAST_OP(sqrt_clamp)
+UNARY_CAST(precise::tanh, bfloat, float, tanh_bf16_f32);
+UNARY_CAST(precise::tanh, float, bfloat, tanh_f32_bf16);
+
+ERF_PRECISION(erf_precision, bfloat, erf_precision_bf16)
+ERF_PRECISION(gelu_erf_precision, bfloat, gelu_erf_precision_bf16)
+
 COPY2D(copy2d_bf16, bfloat)
 #endif
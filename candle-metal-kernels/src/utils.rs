@@ -95,6 +95,8 @@ primitive!(i64);
 primitive!(u32);
 primitive!(u64);
 primitive!(f32);
+// There is no double type in the metal shading language, kernels can read the bits as a `ulong`.
+primitive!(f64);
 
 pub struct BufferOffset<'a> {
     pub buffer: &'a Buffer,
@@ -110,6 +112,38 @@ impl<'a> BufferOffset<'a> {
     }
 }
 
+/// A strided view into a buffer, element 0 of the view starts at `offset_in_bytes`.
+#[derive(Clone, Copy)]
+pub struct BufferView<'a> {
+    pub buffer: &'a Buffer,
+    pub strides: &'a [usize],
+    pub offset_in_bytes: usize,
+}
+
+impl<'a> BufferView<'a> {
+    pub fn new(buffer: &'a Buffer, strides: &'a [usize]) -> Self {
+        Self {
+            buffer,
+            strides,
+            offset_in_bytes: 0,
+        }
+    }
+
+    pub fn with_offset(self, offset_in_bytes: usize) -> Self {
+        Self {
+            offset_in_bytes,
+            ..self
+        }
+    }
+
+    pub fn buffer_offset(&self) -> BufferOffset<'a> {
+        BufferOffset {
+            buffer: self.buffer,
+            offset_in_bytes: self.offset_in_bytes,
+        }
+    }
+}
+
 impl<T> EncoderParam for &[T] {
     fn set_param(encoder: &ComputeCommandEncoderRef, position: u64, data: Self) {
         encoder.set_bytes(
@@ -166,6 +200,13 @@ pub trait EncoderProvider {
     where
         Self: 'a;
     fn encoder(&self) -> Self::Encoder<'_>;
+
+    /// Same as [`EncoderProvider::encoder`] with a label shown in GPU frame captures, the
+    /// `call_*` functions use the kernel name. A new encoder gets the label, an existing one
+    /// keeps its own label, set by the caller, and gets a debug signpost instead.
+    fn encoder_with_label(&self, _label: &str) -> Self::Encoder<'_> {
+        self.encoder()
+    }
 }
 
 pub struct WrappedEncoder<'a> {
@@ -197,6 +238,12 @@ impl EncoderProvider for &metal::CommandBuffer {
             end_encoding_on_drop: true,
         }
     }
+
+    fn encoder_with_label(&self, label: &str) -> Self::Encoder<'_> {
+        let encoder = self.encoder();
+        encoder.inner.set_label(label);
+        encoder
+    }
 }
 
 impl EncoderProvider for &metal::CommandBufferRef {
@@ -209,6 +256,12 @@ impl EncoderProvider for &metal::CommandBufferRef {
             end_encoding_on_drop: true,
         }
     }
+
+    fn encoder_with_label(&self, label: &str) -> Self::Encoder<'_> {
+        let encoder = self.encoder();
+        encoder.inner.set_label(label);
+        encoder
+    }
 }
 
 impl EncoderProvider for &ComputeCommandEncoderRef {
@@ -221,4 +274,9 @@ impl EncoderProvider for &ComputeCommandEncoderRef {
             end_encoding_on_drop: false,
         }
     }
+
+    fn encoder_with_label(&self, label: &str) -> Self::Encoder<'_> {
+        self.insert_debug_signpost(label);
+        self.encoder()
+    }
 }
@@ -188,6 +188,51 @@ impl Conv2d {
         self.bias.as_ref()
     }
 
+    /// Applies the convolution to a channels-last input of shape `(b, h, w, c_in)`, the output
+    /// is channels-last too. 1x1 convolutions are a matmul over the channels, on metal the other
+    /// ones use the direct conv kernel and elsewhere they go through the channels-first conv2d.
+    pub fn forward_channels_last(&self, x: &Tensor) -> Result<Tensor> {
+        let (c_out, c_in, h_k, w_k) = self.weight.dims4()?;
+        let cfg = &self.config;
+        let x = if (h_k, w_k) == (1, 1) && cfg.padding == 0 && cfg.stride == 1 && cfg.groups == 1 {
+            let (b, h, w, _) = x.dims4()?;
+            let weight = self.weight.reshape((c_out, c_in))?;
+            x.reshape((b * h * w, c_in))?
+                .matmul(&weight.t()?)?
+                .reshape((b, h, w, c_out))?
+        } else {
+            self.conv_channels_last(x)?
+        };
+        match &self.bias {
+            None => Ok(x),
+            Some(bias) => x.broadcast_add(bias),
+        }
+    }
+
+    fn conv_channels_last(&self, x: &Tensor) -> Result<Tensor> {
+        let cfg = &self.config;
+        #[cfg(feature = "metal")]
+        if x.device().is_metal() && cfg.groups == 1 {
+            return crate::metal_kernels::conv2d_channels_last(
+                x,
+                &self.weight,
+                cfg.padding,
+                cfg.stride,
+                cfg.dilation,
+            );
+        }
+        x.permute((0, 3, 1, 2))?
+            .conv2d(
+                &self.weight,
+                cfg.padding,
+                cfg.stride,
+                cfg.dilation,
+                cfg.groups,
+            )?
+            .permute((0, 2, 3, 1))?
+            .contiguous()
+    }
+
     pub fn absorb_bn(&self, bn: &BatchNorm) -> Result<Self> {
         if let Some((w_bn, b_bn)) = bn.weight_and_bias() {
             let std_ = w_bn.div(&((bn.running_var() + bn.eps())?.sqrt()?))?;
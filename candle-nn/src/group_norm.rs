@@ -34,6 +34,41 @@ impl GroupNorm {
             num_groups,
         })
     }
+
+    /// Normalizes a channels-last input of shape `(b, ..., c)`, e.g. `(b, h, w, c)`, the output
+    /// is the channels-last version of the output of `forward` on the channels-first input.
+    pub fn forward_channels_last(&self, x: &Tensor) -> Result<Tensor> {
+        let x_shape = x.dims();
+        if x_shape.len() <= 2 {
+            candle::bail!("input rank for GroupNorm should be at least 3");
+        }
+        let (b_sz, n_channels) = (x_shape[0], x_shape[x_shape.len() - 1]);
+        if n_channels != self.num_channels {
+            candle::bail!(
+                "unexpected num-channels in GroupNorm ({n_channels} <> {}",
+                self.num_channels
+            )
+        }
+        let group_size = n_channels / self.num_groups;
+        let spatial_size = x_shape[1..x_shape.len() - 1].iter().product::<usize>();
+        let hidden_size = spatial_size * group_size;
+        let x_dtype = x.dtype();
+        let internal_dtype = match x_dtype {
+            DType::F16 | DType::BF16 => DType::F32,
+            d => d,
+        };
+        let x = x.reshape((b_sz, spatial_size, self.num_groups, group_size))?;
+        let x = x.to_dtype(internal_dtype)?;
+        let mean_x = (x.sum_keepdim(3)?.sum_keepdim(1)? / hidden_size as f64)?;
+        let x = x.broadcast_sub(&mean_x)?;
+        let norm_x = (x.sqr()?.sum_keepdim(3)?.sum_keepdim(1)? / hidden_size as f64)?;
+        let x_normed = x.broadcast_div(&(norm_x + self.eps)?.sqrt()?)?;
+        x_normed
+            .to_dtype(x_dtype)?
+            .reshape(x_shape)?
+            .broadcast_mul(&self.weight)?
+            .broadcast_add(&self.bias)
+    }
 }
 
 impl crate::Module for GroupNorm {
@@ -10,6 +10,8 @@ pub mod kv_cache;
 pub mod layer_norm;
 pub mod linear;
 pub mod loss;
+#[cfg(feature = "metal")]
+pub mod metal_kernels;
 pub mod ops;
 pub mod optim;
 pub mod rnn;
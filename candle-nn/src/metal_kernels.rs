@@ -0,0 +1,414 @@
+//! Tensor level wrappers around the candle-metal-kernels functions.
+//!
+//! The kernels operate on metal buffers, these functions take tensors that are already on a
+//! metal device, pass their buffers, strides and offsets to the matching kernel and return the
+//! result as a new tensor. This gives direct access to the kernels, e.g. to compare them with the
+//! tensor ops or to try kernel variants, without going through the metal backend.
+use candle::backend::BackendStorage;
+use candle::{
+    CpuStorage, CustomOp1, CustomOp2, DType, Layout, MetalError, MetalStorage, Result, Shape,
+    Tensor,
+};
+
+macro_rules! float_kernel {
+    ($kernels:ident, $op:ident, $dtype:expr, $name:expr) => {
+        match $dtype {
+            DType::F32 => $kernels::$op::FLOAT,
+            DType::F16 => $kernels::$op::HALF,
+            DType::BF16 => $kernels::$op::BFLOAT,
+            dtype => candle::bail!("metal {} is not implemented for {dtype:?}", $name),
+        }
+    };
+}
+
+macro_rules! kernel {
+    ($kernels:ident, $op:ident, $dtype:expr, $name:expr) => {
+        match $dtype {
+            DType::F32 => $kernels::$op::FLOAT,
+            DType::F16 => $kernels::$op::HALF,
+            DType::BF16 => $kernels::$op::BFLOAT,
+            DType::I64 => $kernels::$op::I64,
+            DType::U32 => $kernels::$op::U32,
+            DType::U8 => $kernels::$op::U8,
+            dtype => candle::bail!("metal {} is not implemented for {dtype:?}", $name),
+        }
+    };
+}
+
+macro_rules! ops {
+    ($op_enum:ident, $kind:ident, $kernel:ident, $($variant:ident => $op:ident),+) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $op_enum {
+            $($variant),+
+        }
+
+        impl $op_enum {
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => stringify!($op)),+
+                }
+            }
+
+            fn strided_kernel(
+                &self,
+                dtype: DType,
+            ) -> Result<candle_metal_kernels::$kind::strided::Kernel> {
+                use candle_metal_kernels::$kind::strided;
+                let kernel = match self {
+                    $(Self::$variant => $kernel!(strided, $op, dtype, self.name())),+
+                };
+                Ok(kernel)
+            }
+
+            // `call_unary` picks the contiguous unary kernels from the strided ones.
+            #[allow(dead_code)]
+            fn contiguous_kernel(
+                &self,
+                dtype: DType,
+            ) -> Result<candle_metal_kernels::$kind::contiguous::Kernel> {
+                use candle_metal_kernels::$kind::contiguous;
+                let kernel = match self {
+                    $(Self::$variant => $kernel!(contiguous, $op, dtype, self.name())),+
+                };
+                Ok(kernel)
+            }
+        }
+    };
+}
+
+ops!(
+    UnaryOp, unary, float_kernel,
+    Abs => abs, Ceil => ceil, Cos => cos, Erf => erf, Exp => exp, Floor => floor,
+    Gelu => gelu, GeluErf => gelu_erf, Log => log, Neg => neg, Recip => recip, Relu => relu,
+    Round => round, Sigmoid => sigmoid, Sign => sign, Silu => silu, Sin => sin, Sqr => sqr,
+    Sqrt => sqrt, Tanh => tanh, Rsqrt => rsqrt, SqrtClamp => sqrt_clamp
+);
+
+ops!(
+    BinaryOp, binary, kernel,
+    Add => add, Sub => sub, Mul => mul, Div => div, Minimum => min, Maximum => max
+);
+
+fn on_metal(t: &Tensor, op: &'static str) -> Result<()> {
+    if !t.device().is_metal() {
+        candle::bail!("metal_kernels::{op} expects tensors on a metal device")
+    }
+    Ok(())
+}
+
+fn buffer_offset<'a>(
+    storage: &'a MetalStorage,
+    layout: &Layout,
+) -> candle_metal_kernels::BufferOffset<'a> {
+    candle_metal_kernels::BufferOffset {
+        buffer: storage.buffer(),
+        offset_in_bytes: layout.start_offset() * storage.dtype().size_in_bytes(),
+    }
+}
+
+impl CustomOp1 for UnaryOp {
+    fn name(&self) -> &'static str {
+        UnaryOp::name(self)
+    }
+
+    fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("metal_kernels::unary {} only runs on metal", self.name())
+    }
+
+    fn metal_fwd(&self, storage: &MetalStorage, layout: &Layout) -> Result<(MetalStorage, Shape)> {
+        let device = storage.device();
+        let dtype = storage.dtype();
+        let el_count = layout.shape().elem_count();
+        let buffer = device.new_buffer(el_count, dtype, self.name())?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label(self.name());
+        candle_metal_kernels::call_unary(
+            device.metal_device(),
+            &command_buffer,
+            device.kernels(),
+            self.strided_kernel(dtype)?,
+            layout.dims(),
+            buffer_offset(storage, layout),
+            layout.stride(),
+            candle_metal_kernels::BufferOffset::zero_offset(&buffer),
+        )
+        .map_err(MetalError::from)?;
+        let storage = MetalStorage::new(buffer, device.clone(), el_count, dtype);
+        Ok((storage, layout.shape().clone()))
+    }
+}
+
+impl CustomOp2 for BinaryOp {
+    fn name(&self) -> &'static str {
+        BinaryOp::name(self)
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("metal_kernels::binary {} only runs on metal", self.name())
+    }
+
+    fn metal_fwd(
+        &self,
+        lhs: &MetalStorage,
+        lhs_l: &Layout,
+        rhs: &MetalStorage,
+        rhs_l: &Layout,
+    ) -> Result<(MetalStorage, Shape)> {
+        let device = lhs.device();
+        let dtype = lhs.dtype();
+        if rhs.dtype() != dtype {
+            candle::bail!(
+                "metal_kernels::binary {} dtype mismatch {dtype:?} {:?}",
+                self.name(),
+                rhs.dtype()
+            )
+        }
+        let el_count = lhs_l.shape().elem_count();
+        let buffer = device.new_buffer(el_count, dtype, self.name())?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label(self.name());
+        if lhs_l.is_contiguous() && rhs_l.is_contiguous() {
+            candle_metal_kernels::call_binary_contiguous(
+                device.metal_device(),
+                &command_buffer,
+                device.kernels(),
+                self.contiguous_kernel(dtype)?,
+                el_count,
+                buffer_offset(lhs, lhs_l),
+                buffer_offset(rhs, rhs_l),
+                &buffer,
+            )
+            .map_err(MetalError::from)?;
+        } else {
+            candle_metal_kernels::call_binary_strided(
+                device.metal_device(),
+                &command_buffer,
+                device.kernels(),
+                self.strided_kernel(dtype)?,
+                lhs_l.dims(),
+                buffer_offset(lhs, lhs_l),
+                lhs_l.stride(),
+                buffer_offset(rhs, rhs_l),
+                rhs_l.stride(),
+                &buffer,
+            )
+            .map_err(MetalError::from)?;
+        }
+        let storage = MetalStorage::new(buffer, device.clone(), el_count, dtype);
+        Ok((storage, lhs_l.shape().clone()))
+    }
+}
+
+/// A conv2d with a channels-last input and output, see [`conv2d_channels_last`].
+struct Conv2dChannelsLast {
+    padding: usize,
+    stride: usize,
+    dilation: usize,
+}
+
+impl CustomOp2 for Conv2dChannelsLast {
+    fn name(&self) -> &'static str {
+        "conv2d-channels-last"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("metal_kernels::conv2d_channels_last only runs on metal")
+    }
+
+    fn metal_fwd(
+        &self,
+        xs: &MetalStorage,
+        xs_l: &Layout,
+        kernel: &MetalStorage,
+        kernel_l: &Layout,
+    ) -> Result<(MetalStorage, Shape)> {
+        let device = xs.device();
+        let dtype = xs.dtype();
+        if kernel.dtype() != dtype {
+            candle::bail!(
+                "metal_kernels::conv2d_channels_last dtype mismatch {dtype:?} {:?}",
+                kernel.dtype()
+            )
+        }
+        let name = match dtype {
+            DType::F32 => "conv2d_direct_f32",
+            DType::F16 => "conv2d_direct_f16",
+            DType::BF16 => "conv2d_direct_bf16",
+            dtype => candle::bail!("metal conv2d_channels_last is not implemented for {dtype:?}"),
+        };
+        let (b, h, w, c_in) = xs_l.shape().dims4()?;
+        let (c_out, _, h_k, w_k) = kernel_l.shape().dims4()?;
+        let out_h = (h + 2 * self.padding - self.dilation * (h_k - 1) - 1) / self.stride + 1;
+        let out_w = (w + 2 * self.padding - self.dilation * (w_k - 1) - 1) / self.stride + 1;
+        // The kernel takes the channels-first dims, the input strides give the actual layout.
+        let stride = xs_l.stride();
+        let input_stride = [stride[0], stride[3], stride[1], stride[2]];
+        let el_count = b * out_h * out_w * c_out;
+        let buffer = device.new_buffer(el_count, dtype, self.name())?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label(self.name());
+        candle_metal_kernels::call_conv2d_direct(
+            device.metal_device(),
+            &command_buffer,
+            device.kernels(),
+            name,
+            candle_metal_kernels::CallConv2dDirectCfg {
+                stride: self.stride,
+                padding: self.padding,
+                dilation: self.dilation,
+                out_w,
+                out_h,
+                input_dims: &[b, c_in, h, w],
+                input_stride: &input_stride,
+                kernel_dims: kernel_l.dims(),
+                kernel_stride: kernel_l.stride(),
+                input_offset: xs_l.start_offset() * dtype.size_in_bytes(),
+                kernel_offset: kernel_l.start_offset() * dtype.size_in_bytes(),
+                channels_last: true,
+            },
+            xs.buffer(),
+            kernel.buffer(),
+            None,
+            &buffer,
+        )
+        .map_err(MetalError::from)?;
+        let storage = MetalStorage::new(buffer, device.clone(), el_count, dtype);
+        Ok((storage, Shape::from((b, out_h, out_w, c_out))))
+    }
+}
+
+struct DequantizeInt8 {
+    dtype: DType,
+}
+
+impl CustomOp2 for DequantizeInt8 {
+    fn name(&self) -> &'static str {
+        "dequantize-int8"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("metal_kernels::dequantize_int8 only runs on metal")
+    }
+
+    fn metal_fwd(
+        &self,
+        weight: &MetalStorage,
+        weight_l: &Layout,
+        scale: &MetalStorage,
+        scale_l: &Layout,
+    ) -> Result<(MetalStorage, Shape)> {
+        if weight.dtype() != DType::U8 || scale.dtype() != DType::F32 {
+            candle::bail!(
+                "metal_kernels::dequantize_int8 expects u8 weights and f32 scales, got {:?} {:?}",
+                weight.dtype(),
+                scale.dtype()
+            )
+        }
+        if !weight_l.is_contiguous() || !scale_l.is_contiguous() {
+            candle::bail!("metal_kernels::dequantize_int8 expects contiguous inputs")
+        }
+        let name = match self.dtype {
+            DType::F32 => "dequantize_int8_f32",
+            DType::F16 => "dequantize_int8_f16",
+            DType::BF16 => "dequantize_int8_bf16",
+            dtype => candle::bail!("metal dequantize_int8 is not implemented for {dtype:?}"),
+        };
+        let device = weight.device();
+        let shape = weight_l.shape();
+        let el_count = shape.elem_count();
+        let inner = el_count / shape.dims()[0];
+        let buffer = device.new_buffer(el_count, self.dtype, self.name())?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label(self.name());
+        candle_metal_kernels::call_dequantize_int8(
+            device.metal_device(),
+            &command_buffer,
+            device.kernels(),
+            name,
+            el_count,
+            inner,
+            buffer_offset(weight, weight_l),
+            buffer_offset(scale, scale_l),
+            &buffer,
+        )
+        .map_err(MetalError::from)?;
+        let storage = MetalStorage::new(buffer, device.clone(), el_count, self.dtype);
+        Ok((storage, shape.clone()))
+    }
+}
+
+/// Applies the unary kernel for `op` to a tensor on a metal device, the contiguous kernel is used
+/// when the tensor is contiguous and the strided one otherwise. There is no backward pass.
+pub fn unary(xs: &Tensor, op: UnaryOp) -> Result<Tensor> {
+    on_metal(xs, "unary")?;
+    xs.apply_op1_no_bwd(&op)
+}
+
+/// Applies the binary kernel for `op` to two tensors on a metal device, the operands are
+/// broadcast to a common shape. There is no backward pass.
+pub fn binary(lhs: &Tensor, rhs: &Tensor, op: BinaryOp) -> Result<Tensor> {
+    on_metal(lhs, "binary")?;
+    let shape = lhs
+        .shape()
+        .broadcast_shape_binary_op(rhs.shape(), op.name())?;
+    let lhs = lhs.broadcast_as(&shape)?;
+    let rhs = rhs.broadcast_as(&shape)?;
+    lhs.apply_op2_no_bwd(&rhs, &op)
+}
+
+/// Applies a conv2d to a channels-last input of shape `(b, h, w, c_in)` on a metal device using
+/// the direct conv kernel, `kernel` has the usual `(c_out, c_in, h_k, w_k)` shape and the output
+/// is `(b, h_out, w_out, c_out)`. The input can be strided. There is no backward pass.
+pub fn conv2d_channels_last(
+    xs: &Tensor,
+    kernel: &Tensor,
+    padding: usize,
+    stride: usize,
+    dilation: usize,
+) -> Result<Tensor> {
+    on_metal(xs, "conv2d_channels_last")?;
+    let (_, _, _, c_in) = xs.dims4()?;
+    let (_, c_in_k, _, _) = kernel.dims4()?;
+    if c_in != c_in_k {
+        candle::bail!("metal_kernels::conv2d_channels_last expects {c_in_k} channels, got {c_in}")
+    }
+    let op = Conv2dChannelsLast {
+        padding,
+        stride,
+        dilation,
+    };
+    xs.apply_op2_no_bwd(kernel, &op)
+}
+
+/// Dequantizes int8 weights stored as `u8` values offset by 128 to `dtype` on a metal device,
+/// `scale` holds one `f32` scale per entry of the first dimension of `weight`. Both tensors have
+/// to be contiguous. There is no backward pass.
+pub fn dequantize_int8(weight: &Tensor, scale: &Tensor, dtype: DType) -> Result<Tensor> {
+    on_metal(weight, "dequantize_int8")?;
+    let out_dim = weight.dim(0)?;
+    if scale.elem_count() != out_dim {
+        candle::bail!(
+            "metal_kernels::dequantize_int8 expects {out_dim} scales, got {:?}",
+            scale.shape()
+        )
+    }
+    weight.apply_op2_no_bwd(scale, &DequantizeInt8 { dtype })
+}
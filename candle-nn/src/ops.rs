@@ -419,6 +419,7 @@ impl candle::CustomOp1 for SoftmaxLastDim {
             storage.buffer(),
             layout.start_offset() * storage.dtype().size_in_bytes(),
             &output,
+            None,
         )
         .map_err(candle::Error::wrap)?;
         let newstorage =
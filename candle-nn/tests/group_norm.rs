@@ -101,5 +101,14 @@ fn group_norm() -> Result<()> {
         ]
     );
 
+    // A channels-last input gives the channels-last output.
+    let ys = gn3.forward_channels_last(&input.transpose(1, 2)?.contiguous()?)?;
+    let diff = (ys.transpose(1, 2)? - gn3.forward(&input)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
     Ok(())
 }
@@ -0,0 +1,81 @@
+#![cfg(feature = "metal")]
+
+use candle::test_utils::to_vec2_round;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::metal_kernels::{
+    binary, conv2d_channels_last, dequantize_int8, unary, BinaryOp, UnaryOp,
+};
+
+#[test]
+fn unary_exp() -> Result<()> {
+    let device = Device::new_metal(0)?;
+    let xs = Tensor::new(&[[-1f32, 0., 1.], [2., -2., 0.5]], &device)?;
+    let ys = unary(&xs, UnaryOp::Exp)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&xs.exp()?, 4)?);
+    // The transposed input goes through the strided kernel.
+    let ys = unary(&xs.t()?, UnaryOp::Exp)?;
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&xs.t()?.exp()?, 4)?);
+    Ok(())
+}
+
+#[test]
+fn binary_add() -> Result<()> {
+    let device = Device::new_metal(0)?;
+    let lhs = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &device)?;
+    let rhs = Tensor::new(&[[0.5f32, -1., 2.], [3., 0., -4.]], &device)?;
+    let ys = binary(&lhs, &rhs, BinaryOp::Add)?;
+    assert_eq!(ys.to_vec2::<f32>()?, (&lhs + &rhs)?.to_vec2::<f32>()?);
+    let ys = binary(&lhs.t()?, &rhs.t()?, BinaryOp::Add)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        (lhs.t()? + rhs.t()?)?.to_vec2::<f32>()?
+    );
+    // Broadcasting a row.
+    let row = Tensor::new(&[10f32, 20., 30.], &device)?;
+    let ys = binary(&lhs, &row, BinaryOp::Add)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[11., 22., 33.], [14., 25., 36.]]);
+    Ok(())
+}
+
+#[test]
+fn conv2d_nhwc() -> Result<()> {
+    let device = Device::new_metal(0)?;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 7, 5), &device)?;
+    let kernel = Tensor::randn(0f32, 1., (4, 3, 3, 3), &device)?;
+    let expected = xs.conv2d(&kernel, 1, 2, 1, 1)?;
+    let ys = conv2d_channels_last(&xs.permute((0, 2, 3, 1))?.contiguous()?, &kernel, 1, 2, 1)?;
+    assert_eq!(ys.dims(), &[2, 4, 3, 4]);
+    let diff = (ys.permute((0, 3, 1, 2))? - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn dequantize_int8_weights() -> Result<()> {
+    let device = Device::new_metal(0)?;
+    let weight = Tensor::new(&[[0u8, 128, 255], [100, 130, 200]], &device)?;
+    let scale = Tensor::new(&[0.5f32, 0.25], &device)?;
+    let ys = dequantize_int8(&weight, &scale, DType::F32)?;
+    let expected = ((weight.to_dtype(DType::F32)? - 128.)?.broadcast_mul(&scale.unsqueeze(1)?))?;
+    assert_eq!(ys.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    let ys = dequantize_int8(&weight, &scale, DType::F16)?;
+    assert_eq!(ys.dtype(), DType::F16);
+    assert_eq!(
+        ys.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        expected.to_vec2::<f32>()?
+    );
+    assert!(dequantize_int8(&weight, &scale.narrow(0, 0, 1)?, DType::F32).is_err());
+    Ok(())
+}
+
+#[test]
+fn requires_metal() -> Result<()> {
+    let xs = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+    assert!(unary(&xs, UnaryOp::Exp).is_err());
+    assert!(binary(&xs, &xs, BinaryOp::Add).is_err());
+    Ok(())
+}
@@ -1,17 +1,18 @@
 //! Attention Based Building Blocks
-use candle::{DType, IndexOp, Result, Tensor, D};
+use super::int8;
+use candle::{DType, Result, Tensor, D};
 use candle_nn as nn;
 use candle_nn::Module;
 
 #[derive(Debug)]
 struct GeGlu {
-    proj: nn::Linear,
+    proj: int8::Linear,
     span: tracing::Span,
 }
 
 impl GeGlu {
     fn new(vs: nn::VarBuilder, dim_in: usize, dim_out: usize) -> Result<Self> {
-        let proj = nn::linear(dim_in, dim_out * 2, vs.pp("proj"))?;
+        let proj = int8::linear(dim_in, dim_out * 2, vs.pp("proj"))?;
         let span = tracing::span!(tracing::Level::TRACE, "geglu");
         Ok(Self { proj, span })
     }
@@ -29,7 +30,7 @@ impl Module for GeGlu {
 #[derive(Debug)]
 struct FeedForward {
     project_in: GeGlu,
-    linear: nn::Linear,
+    linear: int8::Linear,
     span: tracing::Span,
 }
 
@@ -43,7 +44,7 @@ impl FeedForward {
         let dim_out = dim_out.unwrap_or(dim);
         let vs = vs.pp("net");
         let project_in = GeGlu::new(vs.pp("0"), dim, inner_dim)?;
-        let linear = nn::linear(inner_dim, dim_out, vs.pp("2"))?;
+        let linear = int8::linear(inner_dim, dim_out, vs.pp("2"))?;
         let span = tracing::span!(tracing::Level::TRACE, "ff");
         Ok(Self {
             project_in,
@@ -79,11 +80,12 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
 
 #[derive(Debug)]
 pub struct CrossAttention {
-    to_q: nn::Linear,
-    to_k: nn::Linear,
-    to_v: nn::Linear,
-    to_out: nn::Linear,
+    to_q: int8::Linear,
+    to_k: int8::Linear,
+    to_v: int8::Linear,
+    to_out: int8::Linear,
     heads: usize,
+    kv_heads: usize,
     scale: f64,
     slice_size: Option<usize>,
     span: tracing::Span,
@@ -103,13 +105,42 @@ impl CrossAttention {
         slice_size: Option<usize>,
         use_flash_attn: bool,
     ) -> Result<Self> {
+        Self::new_with_kv_heads(
+            vs,
+            query_dim,
+            context_dim,
+            heads,
+            heads,
+            dim_head,
+            slice_size,
+            use_flash_attn,
+        )
+    }
+
+    /// Grouped-query attention, the keys and values have `kv_heads` heads and each of them is
+    /// shared by `heads / kv_heads` query heads. Multi-query attention uses a single kv head.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_kv_heads(
+        vs: nn::VarBuilder,
+        query_dim: usize,
+        context_dim: Option<usize>,
+        heads: usize,
+        kv_heads: usize,
+        dim_head: usize,
+        slice_size: Option<usize>,
+        use_flash_attn: bool,
+    ) -> Result<Self> {
+        if kv_heads == 0 || !heads.is_multiple_of(kv_heads) {
+            candle::bail!("the {heads} attention heads cannot be grouped in {kv_heads} kv heads")
+        }
         let inner_dim = dim_head * heads;
+        let kv_dim = dim_head * kv_heads;
         let context_dim = context_dim.unwrap_or(query_dim);
         let scale = 1.0 / f64::sqrt(dim_head as f64);
-        let to_q = nn::linear_no_bias(query_dim, inner_dim, vs.pp("to_q"))?;
-        let to_k = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_k"))?;
-        let to_v = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_v"))?;
-        let to_out = nn::linear(inner_dim, query_dim, vs.pp("to_out.0"))?;
+        let to_q = int8::linear_no_bias(query_dim, inner_dim, vs.pp("to_q"))?;
+        let to_k = int8::linear_no_bias(context_dim, kv_dim, vs.pp("to_k"))?;
+        let to_v = int8::linear_no_bias(context_dim, kv_dim, vs.pp("to_v"))?;
+        let to_out = int8::linear(inner_dim, query_dim, vs.pp("to_out.0"))?;
         let span = tracing::span!(tracing::Level::TRACE, "xa");
         let span_attn = tracing::span!(tracing::Level::TRACE, "xa-attn");
         let span_softmax = tracing::span!(tracing::Level::TRACE, "xa-softmax");
@@ -119,6 +150,7 @@ impl CrossAttention {
             to_v,
             to_out,
             heads,
+            kv_heads,
             scale,
             slice_size,
             span,
@@ -135,44 +167,118 @@ impl CrossAttention {
             .reshape((batch_size * self.heads, seq_len, dim / self.heads))
     }
 
+    /// Same as [`Self::reshape_heads_to_batch_dim`] for keys and values, the kv heads are
+    /// repeated so that query head `i` attends with kv head `i / (heads / kv_heads)`.
+    fn reshape_kv_heads_to_batch_dim(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, dim) = xs.dims3()?;
+        let dim_head = dim / self.kv_heads;
+        let xs = xs
+            .reshape((batch_size, seq_len, self.kv_heads, dim_head))?
+            .transpose(1, 2)?;
+        crate::utils::repeat_kv(xs, self.heads / self.kv_heads)?.reshape((
+            batch_size * self.heads,
+            seq_len,
+            dim_head,
+        ))
+    }
+
     fn reshape_batch_dim_to_heads(&self, xs: &Tensor) -> Result<Tensor> {
         let (batch_size, seq_len, dim) = xs.dims3()?;
-        xs.reshape((batch_size / self.heads, self.heads, seq_len, dim))?
-            .transpose(1, 2)?
-            .reshape((batch_size / self.heads, seq_len, dim * self.heads))
+        xs.reshape((batch_size / self.heads, self.heads, seq_len, dim))
+    }
+
+    /// Merges the heads of an attention output of shape `(batch, heads, seq_len, dim_head)` and
+    /// applies the output projection.
+    ///
+    /// The merge is only fused into the projection on the `flash-attn` path, which is cuda only.
+    /// The flash attention output is laid out as `(seq_len, batch, heads, dim_head)` in memory so
+    /// the merge is a reshape of that layout, and the result is a transposed view of the
+    /// projected output that later ops read as strided. The regular attention used on the cpu and
+    /// metal returns the `(batch * heads, seq_len, dim_head)` layout of its batched matmul, the
+    /// merged heads are then materialized before the projection.
+    pub fn project_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, heads, seq_len, dim_head) = xs.dims4()?;
+        let seq_major = xs.permute((2, 0, 1, 3))?;
+        if seq_major.is_contiguous() {
+            let xs = seq_major.reshape((seq_len, batch_size, heads * dim_head))?;
+            self.to_out.forward(&xs)?.transpose(0, 1)
+        } else {
+            let xs = xs
+                .transpose(1, 2)?
+                .reshape((batch_size, seq_len, heads * dim_head))?;
+            self.to_out.forward(&xs)
+        }
     }
 
+    /// Expands an additive bias broadcastable to `(batch, heads, q_len, k_len)` to the
+    /// `(batch * heads, q_len, k_len)` layout of the attention scores.
+    fn bias_to_batch_dim(&self, bias: &Tensor, query: &Tensor, key: &Tensor) -> Result<Tensor> {
+        let (batch_size_attention, q_len, _) = query.dims3()?;
+        let k_len = key.dim(1)?;
+        let batch_size = batch_size_attention / self.heads;
+        bias.to_dtype(DType::F32)?
+            .broadcast_as((batch_size, self.heads, q_len, k_len))?
+            .reshape((batch_size_attention, q_len, k_len))
+    }
+
+    /// Computes the attention in chunks of `slice_size` queries, the scores of each chunk are
+    /// `(batch * heads, slice_size, k_len)` so the full `(q_len, k_len)` score matrix is never
+    /// materialized. The last chunk is shorter when `slice_size` does not divide `q_len`.
     fn sliced_attention(
         &self,
         query: &Tensor,
         key: &Tensor,
         value: &Tensor,
+        attn_bias: Option<&Tensor>,
         slice_size: usize,
     ) -> Result<Tensor> {
-        let batch_size_attention = query.dim(0)?;
-        let mut hidden_states = Vec::with_capacity(batch_size_attention / slice_size);
+        let (batch_size_attention, q_len, _) = query.dims3()?;
+        let k_len = key.dim(1)?;
+        let batch_size = batch_size_attention / self.heads;
+        // The bias is only broadcast here, each chunk then materializes its own rows.
+        let attn_bias = match attn_bias {
+            None => None,
+            Some(bias) => Some(
+                bias.to_dtype(DType::F32)?
+                    .broadcast_as((batch_size, self.heads, q_len, k_len))?,
+            ),
+        };
         let in_dtype = query.dtype();
         let query = query.to_dtype(DType::F32)?;
-        let key = key.to_dtype(DType::F32)?;
+        let key = (key.to_dtype(DType::F32)?.t()? * self.scale)?;
         let value = value.to_dtype(DType::F32)?;
 
-        for i in 0..batch_size_attention / slice_size {
-            let start_idx = i * slice_size;
-            let end_idx = (i + 1) * slice_size;
-
-            let xs = query
-                .i(start_idx..end_idx)?
-                .matmul(&(key.i(start_idx..end_idx)?.t()? * self.scale)?)?;
-            let xs = nn::ops::softmax(&xs, D::Minus1)?.matmul(&value.i(start_idx..end_idx)?)?;
-            hidden_states.push(xs)
+        let mut hidden_states = Vec::with_capacity(q_len.div_ceil(slice_size));
+        for start_idx in (0..q_len).step_by(slice_size) {
+            let len = usize::min(slice_size, q_len - start_idx);
+            let xs = query.narrow(1, start_idx, len)?.matmul(&key)?;
+            let xs = match &attn_bias {
+                None => xs,
+                Some(bias) => {
+                    let bias = bias.narrow(2, start_idx, len)?;
+                    (xs + bias.reshape((batch_size_attention, len, k_len))?)?
+                }
+            };
+            let xs = {
+                let _enter = self.span_softmax.enter();
+                nn::ops::softmax_last_dim(&xs)?
+            };
+            hidden_states.push(xs.matmul(&value)?)
         }
-        let hidden_states = Tensor::stack(&hidden_states, 0)?.to_dtype(in_dtype)?;
+        let hidden_states = Tensor::cat(&hidden_states, 1)?.to_dtype(in_dtype)?;
         self.reshape_batch_dim_to_heads(&hidden_states)
     }
 
-    fn attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Result<Tensor> {
+    fn attention(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        attn_bias: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let _enter = self.span_attn.enter();
-        let xs = if self.use_flash_attn {
+        // The flash attention kernels do not take a bias, the regular path is used in this case.
+        let xs = if self.use_flash_attn && attn_bias.is_none() {
             let init_dtype = query.dtype();
             let q = query
                 .to_dtype(candle::DType::F16)?
@@ -186,47 +292,66 @@ impl CrossAttention {
                 .to_dtype(candle::DType::F16)?
                 .unsqueeze(0)?
                 .transpose(1, 2)?;
+            // The output has the `(1, seq_len, batch * heads, dim_head)` layout of `q`, it is
+            // only permuted so that `project_heads` can merge the heads without a copy.
+            let (batch_size_attention, seq_len, dim_head) = query.dims3()?;
             flash_attn(&q, &k, &v, self.scale as f32, false)?
-                .transpose(1, 2)?
-                .squeeze(0)?
                 .to_dtype(init_dtype)?
+                .reshape((
+                    seq_len,
+                    batch_size_attention / self.heads,
+                    self.heads,
+                    dim_head,
+                ))?
+                .permute((1, 2, 0, 3))?
         } else {
             let in_dtype = query.dtype();
             let query = query.to_dtype(DType::F32)?;
             let key = key.to_dtype(DType::F32)?;
             let value = value.to_dtype(DType::F32)?;
             let xs = query.matmul(&(key.t()? * self.scale)?)?;
+            let xs = match attn_bias {
+                None => xs,
+                Some(bias) => (xs + self.bias_to_batch_dim(bias, &query, &key)?)?,
+            };
             let xs = {
                 let _enter = self.span_softmax.enter();
                 nn::ops::softmax_last_dim(&xs)?
             };
-            xs.matmul(&value)?.to_dtype(in_dtype)?
+            self.reshape_batch_dim_to_heads(&xs.matmul(&value)?.to_dtype(in_dtype)?)?
         };
-        self.reshape_batch_dim_to_heads(&xs)
+        Ok(xs)
     }
 
     pub fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Result<Tensor> {
+        self.forward_with_bias(xs, context, None)
+    }
+
+    /// Same as [`Self::forward`] with an additive bias applied to the attention scores before
+    /// the softmax, e.g. `-inf` on the keys that some queries should not attend to. The bias has
+    /// to be broadcastable to `(batch, heads, q_len, k_len)`.
+    pub fn forward_with_bias(
+        &self,
+        xs: &Tensor,
+        context: Option<&Tensor>,
+        attn_bias: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let query = self.to_q.forward(xs)?;
         let context = context.unwrap_or(xs).contiguous()?;
         let key = self.to_k.forward(&context)?;
         let value = self.to_v.forward(&context)?;
         let query = self.reshape_heads_to_batch_dim(&query)?;
-        let key = self.reshape_heads_to_batch_dim(&key)?;
-        let value = self.reshape_heads_to_batch_dim(&value)?;
-        let dim0 = query.dim(0)?;
-        let slice_size = self.slice_size.and_then(|slice_size| {
-            if dim0 < slice_size {
-                None
-            } else {
-                Some(slice_size)
+        let key = self.reshape_kv_heads_to_batch_dim(&key)?;
+        let value = self.reshape_kv_heads_to_batch_dim(&value)?;
+        let q_len = query.dim(1)?;
+        let xs = match self.slice_size {
+            Some(slice_size) if slice_size > 0 && slice_size < q_len => {
+                self.sliced_attention(&query, &key, &value, attn_bias, slice_size)?
             }
-        });
-        let xs = match slice_size {
-            None => self.attention(&query, &key, &value)?,
-            Some(slice_size) => self.sliced_attention(&query, &key, &value, slice_size)?,
+            _ => self.attention(&query, &key, &value, attn_bias)?,
         };
-        self.to_out.forward(&xs)
+        self.project_heads(&xs)
     }
 }
 
@@ -317,8 +442,8 @@ impl Default for SpatialTransformerConfig {
 
 #[derive(Debug)]
 enum Proj {
-    Conv2d(nn::Conv2d),
-    Linear(nn::Linear),
+    Conv2d(int8::Conv2d),
+    Linear(int8::Linear),
 }
 
 // Aka Transformer2DModel
@@ -344,9 +469,9 @@ impl SpatialTransformer {
         let inner_dim = n_heads * d_head;
         let norm = nn::group_norm(config.num_groups, in_channels, 1e-6, vs.pp("norm"))?;
         let proj_in = if config.use_linear_projection {
-            Proj::Linear(nn::linear(in_channels, inner_dim, vs.pp("proj_in"))?)
+            Proj::Linear(int8::linear(in_channels, inner_dim, vs.pp("proj_in"))?)
         } else {
-            Proj::Conv2d(nn::conv2d(
+            Proj::Conv2d(int8::conv2d(
                 in_channels,
                 inner_dim,
                 1,
@@ -369,9 +494,9 @@ impl SpatialTransformer {
             transformer_blocks.push(tb)
         }
         let proj_out = if config.use_linear_projection {
-            Proj::Linear(nn::linear(in_channels, inner_dim, vs.pp("proj_out"))?)
+            Proj::Linear(int8::linear(in_channels, inner_dim, vs.pp("proj_out"))?)
         } else {
-            Proj::Conv2d(nn::conv2d(
+            Proj::Conv2d(int8::conv2d(
                 inner_dim,
                 in_channels,
                 1,
@@ -441,6 +566,9 @@ pub struct AttentionBlockConfig {
     pub num_groups: usize,
     pub rescale_output_factor: f64,
     pub eps: f64,
+    /// When set, the attention is computed over chunks of this many queries at a time which
+    /// bounds the size of the attention matrix, 0 runs the regular attention.
+    pub sliced_attention_size: Option<usize>,
 }
 
 impl Default for AttentionBlockConfig {
@@ -450,6 +578,7 @@ impl Default for AttentionBlockConfig {
             num_groups: 32,
             rescale_output_factor: 1.,
             eps: 1e-5,
+            sliced_attention_size: None,
         }
     }
 }
@@ -530,12 +659,28 @@ impl Module for AttentionBlock {
         // scale is applied twice, hence the -0.25 here rather than -0.5.
         // https://github.com/huggingface/diffusers/blob/d3d22ce5a894becb951eec03e663951b28d45135/src/diffusers/models/attention.py#L87
         let scale = f64::powf(self.channels as f64 / self.num_heads as f64, -0.25);
-        let attention_scores = (query_states * scale)?.matmul(&(key_states.t()? * scale)?)?;
-        let attention_probs = nn::ops::softmax(&attention_scores, D::Minus1)?;
+        let query_states = (query_states * scale)?;
+        let key_states = (key_states.t()? * scale)?;
+        let attention = |query_states: &Tensor| {
+            let attention_scores = query_states.matmul(&key_states)?;
+            let attention_probs = nn::ops::softmax(&attention_scores, D::Minus1)?;
+            attention_probs.matmul(&value_states)
+        };
 
         // TODO: revert the call to force_contiguous once the three matmul kernels have been
         // adapted to handle layout with some dims set to 1.
-        let xs = attention_probs.matmul(&value_states)?;
+        let seq_len = height * width;
+        let xs = match self.config.sliced_attention_size {
+            Some(slice_size) if slice_size > 0 && slice_size < seq_len => {
+                let mut hidden_states = vec![];
+                for start_idx in (0..seq_len).step_by(slice_size) {
+                    let len = usize::min(slice_size, seq_len - start_idx);
+                    hidden_states.push(attention(&query_states.narrow(2, start_idx, len)?)?)
+                }
+                Tensor::cat(&hidden_states, 2)?
+            }
+            _ => attention(&query_states)?,
+        };
         let xs = xs.to_dtype(in_dtype)?;
         let xs = xs.transpose(1, 2)?.contiguous()?;
         let xs = xs.flatten_from(D::Minus2)?;
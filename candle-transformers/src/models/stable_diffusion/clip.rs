@@ -8,7 +8,9 @@ use candle::{DType, Device, Result, Tensor, D};
 use candle_nn as nn;
 use candle_nn::Module;
 
-#[derive(Debug, Clone, Copy)]
+// The tanh approximation is used for `gelu` as in the configurations below.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Activation {
     QuickGelu,
     Gelu,
@@ -25,18 +27,21 @@ impl Module for Activation {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct Config {
     vocab_size: usize,
-    embed_dim: usize,       // aka config.hidden_size
-    activation: Activation, // aka config.hidden_act
+    #[serde(rename = "hidden_size")]
+    embed_dim: usize,
+    #[serde(rename = "hidden_act")]
+    activation: Activation,
     intermediate_size: usize,
     pub max_position_embeddings: usize,
-    // The character to use for padding, use EOS when not set.
+    // The character to use for padding, use EOS when not set. This is not part of the text
+    // encoder config.json but of the tokenizer one.
+    #[serde(default)]
     pub pad_with: Option<String>,
     num_hidden_layers: usize,
     num_attention_heads: usize,
-    #[allow(dead_code)]
     projection_dim: usize,
 }
 
@@ -139,6 +144,12 @@ impl Config {
             activation: Activation::GeluErf,
         }
     }
+
+    /// Sets the number of transformer layers.
+    pub fn with_num_hidden_layers(mut self, num_hidden_layers: usize) -> Self {
+        self.num_hidden_layers = num_hidden_layers;
+        self
+    }
 }
 
 // CLIP Text Model
@@ -343,10 +354,20 @@ pub struct ClipTextTransformer {
     embeddings: ClipTextEmbeddings,
     encoder: ClipEncoder,
     final_layer_norm: candle_nn::LayerNorm,
+    text_projection: Option<candle_nn::Linear>,
 }
 
 impl ClipTextTransformer {
+    /// The `text_projection` weights are optional, they are only included in the checkpoints
+    /// of models with a projection such as the second text encoder of SDXL.
     pub fn new(vs: candle_nn::VarBuilder, c: &Config) -> Result<Self> {
+        let text_projection = if vs.contains_tensor("text_projection.weight") {
+            let proj =
+                candle_nn::linear_no_bias(c.embed_dim, c.projection_dim, vs.pp("text_projection"))?;
+            Some(proj)
+        } else {
+            None
+        };
         let vs = vs.pp("text_model");
         let embeddings = ClipTextEmbeddings::new(vs.pp("embeddings"), c)?;
         let encoder = ClipEncoder::new(vs.pp("encoder"), c)?;
@@ -355,6 +376,7 @@ impl ClipTextTransformer {
             embeddings,
             encoder,
             final_layer_norm,
+            text_projection,
         })
     }
 
@@ -377,7 +399,7 @@ impl ClipTextTransformer {
             })
             .collect();
         let mask = Tensor::from_slice(&mask, (seq_len, seq_len), device)?;
-        mask.broadcast_as((bsz, seq_len, seq_len))
+        mask.broadcast_as((bsz, 1, seq_len, seq_len))
     }
 
     pub fn forward_with_mask(&self, xs: &Tensor, mask_after: usize) -> Result<Tensor> {
@@ -388,6 +410,42 @@ impl ClipTextTransformer {
         let xs = self.encoder.forward(&xs, &causal_attention_mask)?;
         self.final_layer_norm.forward(&xs)
     }
+
+    /// Returns the last hidden states and the pooled output. The pooled output is the hidden
+    /// state of the EOS token, the token with the largest id, followed by the text projection
+    /// when the model has one.
+    pub fn forward_with_pooled(&self, input_ids: &Tensor) -> Result<(Tensor, Tensor)> {
+        let xs = self.forward(input_ids)?;
+        let eos_ids = input_ids.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let pooled = eos_ids
+            .iter()
+            .enumerate()
+            .map(|(b, &eos_id)| xs.get(b)?.get(eos_id as usize))
+            .collect::<Result<Vec<_>>>()?;
+        let pooled = Tensor::stack(&pooled, 0)?;
+        let pooled = match &self.text_projection {
+            Some(proj) => proj.forward(&pooled)?,
+            None => pooled,
+        };
+        Ok((xs, pooled))
+    }
+
+    /// Same as `forward` but encodes the prompts by sub-batches of at most `chunk_size` prompts
+    /// and concatenates the results, this bounds the size of the activations for large batches.
+    pub fn forward_chunked(&self, input_ids: &Tensor, chunk_size: usize) -> Result<Tensor> {
+        if chunk_size == 0 {
+            candle::bail!("the chunk size has to be positive")
+        }
+        let bsz = input_ids.dim(0)?;
+        let chunks = (0..bsz)
+            .step_by(chunk_size)
+            .map(|start| {
+                let len = chunk_size.min(bsz - start);
+                self.forward(&input_ids.narrow(0, start, len)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&chunks, 0)
+    }
 }
 
 impl Module for ClipTextTransformer {
@@ -0,0 +1,255 @@
+//! ControlNet
+//!
+//! A ControlNet is a trainable copy of the unet down and mid blocks that takes an additional
+//! conditioning image (edges, depth map, pose...). It returns residuals that are added to the
+//! unet skip connections, see [`UNet2DConditionModel::forward_with_additional_residuals`].
+//!
+//! - [Paper](https://arxiv.org/abs/2302.05543)
+//! - [Diffusers implementation](https://github.com/huggingface/diffusers/blob/main/src/diffusers/models/controlnet.py)
+//!
+//! [`UNet2DConditionModel::forward_with_additional_residuals`]: super::unet_2d::UNet2DConditionModel::forward_with_additional_residuals
+use super::embeddings::{TimestepEmbedding, Timesteps};
+use super::resnet::MemoryFormat;
+use super::unet_2d::{
+    build_down_blocks, build_mid_block, UNet2DConditionModelConfig, UNetDownBlock,
+};
+use super::unet_2d_blocks::UNetMidBlock2DCrossAttn;
+use crate::models::with_tracing::{conv2d, Conv2d};
+use candle::{Result, Tensor};
+use candle_nn as nn;
+use candle_nn::Module;
+
+#[derive(Debug, Clone)]
+pub struct ControlNetConfig {
+    /// The configuration of the unet that the ControlNet conditions, only the down and mid blocks
+    /// are used.
+    pub unet: UNet2DConditionModelConfig,
+    pub conditioning_channels: usize,
+    pub conditioning_embedding_out_channels: Vec<usize>,
+}
+
+impl ControlNetConfig {
+    pub fn new(unet: UNet2DConditionModelConfig) -> Self {
+        Self {
+            unet,
+            conditioning_channels: 3,
+            conditioning_embedding_out_channels: vec![16, 32, 96, 256],
+        }
+    }
+}
+
+/// Embeds the conditioning image down to the latent resolution, each block after the first one
+/// halves the spatial dimensions.
+#[derive(Debug)]
+struct ControlNetConditioningEmbedding {
+    conv_in: Conv2d,
+    blocks: Vec<Conv2d>,
+    conv_out: Conv2d,
+}
+
+impl ControlNetConditioningEmbedding {
+    fn new(
+        vs: nn::VarBuilder,
+        conditioning_embedding_channels: usize,
+        conditioning_channels: usize,
+        block_out_channels: &[usize],
+    ) -> Result<Self> {
+        let conv_cfg = nn::Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let down_cfg = nn::Conv2dConfig {
+            padding: 1,
+            stride: 2,
+            ..Default::default()
+        };
+        let conv_in = conv2d(
+            conditioning_channels,
+            block_out_channels[0],
+            3,
+            conv_cfg,
+            vs.pp("conv_in"),
+        )?;
+        let vs_b = vs.pp("blocks");
+        let mut blocks = vec![];
+        for (i, channels) in block_out_channels.windows(2).enumerate() {
+            let (c_in, c_out) = (channels[0], channels[1]);
+            blocks.push(conv2d(
+                c_in,
+                c_in,
+                3,
+                conv_cfg,
+                vs_b.pp((2 * i).to_string()),
+            )?);
+            blocks.push(conv2d(
+                c_in,
+                c_out,
+                3,
+                down_cfg,
+                vs_b.pp((2 * i + 1).to_string()),
+            )?);
+        }
+        let conv_out = conv2d(
+            *block_out_channels.last().unwrap(),
+            conditioning_embedding_channels,
+            3,
+            conv_cfg,
+            vs.pp("conv_out"),
+        )?;
+        Ok(Self {
+            conv_in,
+            blocks,
+            conv_out,
+        })
+    }
+}
+
+impl Module for ControlNetConditioningEmbedding {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = nn::ops::silu(&self.conv_in.forward(xs)?)?;
+        for block in self.blocks.iter() {
+            xs = nn::ops::silu(&block.forward(&xs)?)?
+        }
+        self.conv_out.forward(&xs)
+    }
+}
+
+#[derive(Debug)]
+pub struct ControlNet {
+    conv_in: Conv2d,
+    time_proj: Timesteps,
+    time_embedding: TimestepEmbedding,
+    controlnet_cond_embedding: ControlNetConditioningEmbedding,
+    down_blocks: Vec<UNetDownBlock>,
+    controlnet_down_blocks: Vec<Conv2d>,
+    mid_block: UNetMidBlock2DCrossAttn,
+    controlnet_mid_block: Conv2d,
+    span: tracing::Span,
+    pub config: ControlNetConfig,
+}
+
+impl ControlNet {
+    pub fn new(
+        vs: nn::VarBuilder,
+        in_channels: usize,
+        use_flash_attn: bool,
+        config: ControlNetConfig,
+    ) -> Result<Self> {
+        let unet = &config.unet;
+        let n_blocks = unet.blocks.len();
+        let b_channels = unet.blocks[0].out_channels;
+        let bl_channels = unet.blocks.last().unwrap().out_channels;
+        let time_embed_dim = b_channels * 4;
+        let conv_cfg = nn::Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let conv_in = conv2d(in_channels, b_channels, 3, conv_cfg, vs.pp("conv_in"))?;
+
+        let time_proj = Timesteps::new(b_channels, unet.flip_sin_to_cos, unet.freq_shift);
+        let time_embedding =
+            TimestepEmbedding::new(vs.pp("time_embedding"), b_channels, time_embed_dim)?;
+        let controlnet_cond_embedding = ControlNetConditioningEmbedding::new(
+            vs.pp("controlnet_cond_embedding"),
+            b_channels,
+            config.conditioning_channels,
+            &config.conditioning_embedding_out_channels,
+        )?;
+
+        // The ControlNet keeps the default layout.
+        let memory_format = MemoryFormat::ChannelsFirst;
+        let down_blocks = build_down_blocks(
+            vs.pp("down_blocks"),
+            unet,
+            time_embed_dim,
+            use_flash_attn,
+            memory_format,
+        )?;
+        let mid_block = build_mid_block(
+            vs.pp("mid_block"),
+            unet,
+            time_embed_dim,
+            use_flash_attn,
+            memory_format,
+        )?;
+
+        // One 1x1 conv, zero initialized when training, per unet skip connection: the conv_in
+        // output, then the output of each resnet and of each downsampler.
+        let mut res_channels = vec![b_channels];
+        for (i, block) in unet.blocks.iter().enumerate() {
+            let n_res = unet.layers_per_block + usize::from(i < n_blocks - 1);
+            res_channels.extend(std::iter::repeat_n(block.out_channels, n_res))
+        }
+        let vs_cdb = vs.pp("controlnet_down_blocks");
+        let controlnet_down_blocks = res_channels
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| conv2d(c, c, 1, Default::default(), vs_cdb.pp(i.to_string())))
+            .collect::<Result<Vec<_>>>()?;
+        let controlnet_mid_block = conv2d(
+            bl_channels,
+            bl_channels,
+            1,
+            Default::default(),
+            vs.pp("controlnet_mid_block"),
+        )?;
+        let span = tracing::span!(tracing::Level::TRACE, "controlnet");
+        Ok(Self {
+            conv_in,
+            time_proj,
+            time_embedding,
+            controlnet_cond_embedding,
+            down_blocks,
+            controlnet_down_blocks,
+            mid_block,
+            controlnet_mid_block,
+            span,
+            config,
+        })
+    }
+
+    /// Returns the down block residuals and the mid block residual, these are meant to be passed
+    /// to [`UNet2DConditionModel::forward_with_additional_residuals`]. `controlnet_cond` is the
+    /// conditioning image, it has to be at the image resolution rather than the latent one.
+    ///
+    /// [`UNet2DConditionModel::forward_with_additional_residuals`]: super::unet_2d::UNet2DConditionModel::forward_with_additional_residuals
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        controlnet_cond: &Tensor,
+        conditioning_scale: f64,
+    ) -> Result<(Vec<Tensor>, Tensor)> {
+        let _enter = self.span.enter();
+        let bsize = xs.dim(0)?;
+        // 1. time
+        let emb = (Tensor::ones(bsize, xs.dtype(), xs.device())? * timestep)?;
+        let emb = self.time_proj.forward(&emb)?;
+        let emb = self.time_embedding.forward(&emb)?;
+        // 2. pre-process
+        let xs = self.conv_in.forward(xs)?;
+        let cond = self.controlnet_cond_embedding.forward(controlnet_cond)?;
+        let xs = (xs + cond)?;
+        // 3. down
+        let mut down_block_res_xs = vec![xs.clone()];
+        let mut xs = xs;
+        for down_block in self.down_blocks.iter() {
+            let (_xs, res_xs) = down_block.forward(&xs, &emb, encoder_hidden_states)?;
+            down_block_res_xs.extend(res_xs);
+            xs = _xs;
+        }
+        // 4. mid
+        let xs = self
+            .mid_block
+            .forward(&xs, Some(&emb), Some(encoder_hidden_states))?;
+        // 5. controlnet blocks
+        let down_block_res_xs = down_block_res_xs
+            .iter()
+            .zip(self.controlnet_down_blocks.iter())
+            .map(|(xs, block)| block.forward(xs)? * conditioning_scale)
+            .collect::<Result<Vec<_>>>()?;
+        let mid_block_res_xs = (self.controlnet_mid_block.forward(&xs)? * conditioning_scale)?;
+        Ok((down_block_res_xs, mid_block_res_xs))
+    }
+}
@@ -8,7 +8,8 @@
 //! Denoising Diffusion Implicit Models, J. Song et al, 2020.
 //! https://arxiv.org/abs/2010.02502
 use super::schedulers::{
-    betas_for_alpha_bar, BetaSchedule, PredictionType, Scheduler, SchedulerConfig, TimestepSpacing,
+    check_timesteps, next_timestep, BetaSchedule, PredictionType, Scheduler, SchedulerConfig,
+    TimestepSpacing,
 };
 use candle::{Result, Tensor};
 
@@ -54,6 +55,10 @@ impl SchedulerConfig for DDIMSchedulerConfig {
     fn build(&self, inference_steps: usize) -> Result<Box<dyn Scheduler>> {
         Ok(Box::new(DDIMScheduler::new(inference_steps, *self)?))
     }
+
+    fn prediction_type(&self) -> PredictionType {
+        self.prediction_type
+    }
 }
 
 /// The DDIM scheduler.
@@ -62,6 +67,9 @@ pub struct DDIMScheduler {
     timesteps: Vec<usize>,
     alphas_cumprod: Vec<f64>,
     step_ratio: usize,
+    /// Set when the timesteps come from [`Scheduler::set_timesteps`], the steps then go to the
+    /// next timestep rather than `step_ratio` timesteps back.
+    custom_timesteps: bool,
     init_noise_sigma: f64,
     pub config: DDIMSchedulerConfig,
 }
@@ -97,28 +105,16 @@ impl DDIMScheduler {
             }
         };
 
-        let betas = match config.beta_schedule {
-            BetaSchedule::ScaledLinear => super::utils::linspace(
-                config.beta_start.sqrt(),
-                config.beta_end.sqrt(),
-                config.train_timesteps,
-            )?
-            .sqr()?,
-            BetaSchedule::Linear => {
-                super::utils::linspace(config.beta_start, config.beta_end, config.train_timesteps)?
-            }
-            BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(config.train_timesteps, 0.999)?,
-        };
-        let betas = betas.to_vec1::<f64>()?;
-        let mut alphas_cumprod = Vec::with_capacity(betas.len());
-        for &beta in betas.iter() {
-            let alpha = 1.0 - beta;
-            alphas_cumprod.push(alpha * *alphas_cumprod.last().unwrap_or(&1f64))
-        }
+        let alphas_cumprod = config.beta_schedule.alphas_cumprod(
+            config.beta_start,
+            config.beta_end,
+            config.train_timesteps,
+        )?;
         Ok(Self {
             alphas_cumprod,
             timesteps,
             step_ratio,
+            custom_timesteps: false,
             init_noise_sigma: 1.,
             config,
         })
@@ -128,13 +124,16 @@ impl DDIMScheduler {
 impl Scheduler for DDIMScheduler {
     /// Performs a backward step during inference.
     fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let model_output = &model_output.to_dtype(sample.dtype())?;
         let timestep = if timestep >= self.alphas_cumprod.len() {
             timestep - 1
         } else {
             timestep
         };
         // https://github.com/huggingface/diffusers/blob/6e099e2c8ce4c4f5c7318e970a8c093dc5c7046e/src/diffusers/schedulers/scheduling_ddim.py#L195
-        let prev_timestep = if timestep > self.step_ratio {
+        let prev_timestep = if self.custom_timesteps {
+            next_timestep(&self.timesteps, timestep).unwrap_or(0)
+        } else if timestep > self.step_ratio {
             timestep - self.step_ratio
         } else {
             0
@@ -174,13 +173,7 @@ impl Scheduler for DDIMScheduler {
         let prev_sample =
             ((pred_original_sample * alpha_prod_t_prev.sqrt())? + pred_sample_direction)?;
         if self.config.eta > 0. {
-            &prev_sample
-                + Tensor::randn(
-                    0f32,
-                    std_dev_t as f32,
-                    prev_sample.shape(),
-                    prev_sample.device(),
-                )?
+            &prev_sample + prev_sample.randn_like(0., std_dev_t)?
         } else {
             Ok(prev_sample)
         }
@@ -196,7 +189,7 @@ impl Scheduler for DDIMScheduler {
         self.timesteps.as_slice()
     }
 
-    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, timestep: usize) -> Result<Tensor> {
         let timestep = if timestep >= self.alphas_cumprod.len() {
             timestep - 1
         } else {
@@ -204,10 +197,23 @@ impl Scheduler for DDIMScheduler {
         };
         let sqrt_alpha_prod = self.alphas_cumprod[timestep].sqrt();
         let sqrt_one_minus_alpha_prod = (1.0 - self.alphas_cumprod[timestep]).sqrt();
+        let noise = noise.to_dtype(original.dtype())?;
         (original * sqrt_alpha_prod)? + (noise * sqrt_one_minus_alpha_prod)?
     }
 
     fn init_noise_sigma(&self) -> f64 {
         self.init_noise_sigma
     }
+
+    fn set_timesteps(&mut self, timesteps: &[usize]) -> Result<()> {
+        check_timesteps(timesteps, self.config.train_timesteps)?;
+        self.timesteps = timesteps.to_vec();
+        self.custom_timesteps = true;
+        Ok(())
+    }
+
+    fn set_prediction_type(&mut self, prediction_type: PredictionType) -> Result<()> {
+        self.config.prediction_type = prediction_type;
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
-use super::schedulers::{betas_for_alpha_bar, BetaSchedule, PredictionType};
-use candle::{Result, Tensor};
+use super::schedulers::{
+    check_timesteps, next_timestep, BetaSchedule, PredictionType, Scheduler, SchedulerConfig,
+};
+use candle::{bail, Result, Tensor};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DDPMVarianceType {
@@ -7,7 +9,11 @@ pub enum DDPMVarianceType {
     FixedSmallLog,
     FixedLarge,
     FixedLargeLog,
+    /// The model predicts the variance, its output has twice the sample channels.
     Learned,
+    /// The model predicts an interpolation factor in `[-1, 1]` between the log of the
+    /// `FixedSmall` and `FixedLarge` variances, its output has twice the sample channels.
+    LearnedRange,
 }
 
 impl Default for DDPMVarianceType {
@@ -48,35 +54,34 @@ impl Default for DDPMSchedulerConfig {
     }
 }
 
+impl SchedulerConfig for DDPMSchedulerConfig {
+    fn build(&self, inference_steps: usize) -> Result<Box<dyn Scheduler>> {
+        Ok(Box::new(DDPMScheduler::new(inference_steps, self.clone())?))
+    }
+
+    fn prediction_type(&self) -> PredictionType {
+        self.prediction_type
+    }
+}
+
 pub struct DDPMScheduler {
     alphas_cumprod: Vec<f64>,
     init_noise_sigma: f64,
     timesteps: Vec<usize>,
     step_ratio: usize,
+    /// Set when the timesteps come from [`Scheduler::set_timesteps`], the steps then go to the
+    /// next timestep rather than `step_ratio` timesteps back.
+    custom_timesteps: bool,
     pub config: DDPMSchedulerConfig,
 }
 
 impl DDPMScheduler {
     pub fn new(inference_steps: usize, config: DDPMSchedulerConfig) -> Result<Self> {
-        let betas = match config.beta_schedule {
-            BetaSchedule::ScaledLinear => super::utils::linspace(
-                config.beta_start.sqrt(),
-                config.beta_end.sqrt(),
-                config.train_timesteps,
-            )?
-            .sqr()?,
-            BetaSchedule::Linear => {
-                super::utils::linspace(config.beta_start, config.beta_end, config.train_timesteps)?
-            }
-            BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(config.train_timesteps, 0.999)?,
-        };
-
-        let betas = betas.to_vec1::<f64>()?;
-        let mut alphas_cumprod = Vec::with_capacity(betas.len());
-        for &beta in betas.iter() {
-            let alpha = 1.0 - beta;
-            alphas_cumprod.push(alpha * *alphas_cumprod.last().unwrap_or(&1f64))
-        }
+        let alphas_cumprod = config.beta_schedule.alphas_cumprod(
+            config.beta_start,
+            config.beta_end,
+            config.train_timesteps,
+        )?;
 
         // min(train_timesteps, inference_steps)
         // https://github.com/huggingface/diffusers/blob/8331da46837be40f96fbd24de6a6fb2da28acd11/src/diffusers/schedulers/scheduling_ddpm.py#L187
@@ -90,12 +95,23 @@ impl DDPMScheduler {
             init_noise_sigma: 1.0,
             timesteps,
             step_ratio,
+            custom_timesteps: false,
             config,
         })
     }
 
-    fn get_variance(&self, timestep: usize) -> f64 {
-        let prev_t = timestep as isize - self.step_ratio as isize;
+    /// The timestep reached by a step from `timestep`, negative after the last step.
+    fn prev_timestep(&self, timestep: usize) -> isize {
+        if self.custom_timesteps {
+            next_timestep(&self.timesteps, timestep).map_or(-1, |t| t as isize)
+        } else {
+            timestep as isize - self.step_ratio as isize
+        }
+    }
+
+    /// Returns the posterior variance and beta for the given timestep.
+    fn posterior_variance(&self, timestep: usize) -> (f64, f64) {
+        let prev_t = self.prev_timestep(timestep);
         let alpha_prod_t = self.alphas_cumprod[timestep];
         let alpha_prod_t_prev = if prev_t >= 0 {
             self.alphas_cumprod[prev_t as usize]
@@ -108,6 +124,11 @@ impl DDPMScheduler {
         // and sample from it to get previous sample
         // x_{t-1} ~ N(pred_prev_sample, variance) == add variance to pred_sample
         let variance = (1. - alpha_prod_t_prev) / (1. - alpha_prod_t) * current_beta_t;
+        (variance, current_beta_t)
+    }
+
+    fn get_variance(&self, timestep: usize) -> f64 {
+        let (variance, current_beta_t) = self.posterior_variance(timestep);
 
         // retrieve variance
         match self.config.variance_type {
@@ -119,22 +140,66 @@ impl DDPMScheduler {
             }
             DDPMVarianceType::FixedLarge => current_beta_t,
             DDPMVarianceType::FixedLargeLog => current_beta_t.ln(),
-            DDPMVarianceType::Learned => variance,
+            DDPMVarianceType::Learned | DDPMVarianceType::LearnedRange => variance,
+        }
+    }
+
+    /// The standard deviation of the noise added at `timestep` when the variance is predicted by
+    /// the model.
+    fn get_learned_std(&self, timestep: usize, predicted_variance: &Tensor) -> Result<Tensor> {
+        match self.config.variance_type {
+            DDPMVarianceType::LearnedRange => {
+                let (variance, current_beta_t) = self.posterior_variance(timestep);
+                let min_log = variance.max(1e-20).ln();
+                let max_log = current_beta_t.ln();
+                let frac = ((predicted_variance + 1.)? / 2.)?;
+                let log_variance = ((frac * (max_log - min_log))? + min_log)?;
+                (log_variance * 0.5)?.exp()
+            }
+            _ => predicted_variance.sqrt(),
         }
     }
+}
 
-    pub fn timesteps(&self) -> &[usize] {
+impl Scheduler for DDPMScheduler {
+    fn timesteps(&self) -> &[usize] {
         self.timesteps.as_slice()
     }
 
     ///  Ensures interchangeability with schedulers that need to scale the denoising model input
     /// depending on the current timestep.
-    pub fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Tensor {
-        sample
+    fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Result<Tensor> {
+        Ok(sample)
     }
 
-    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
-        let prev_t = timestep as isize - self.step_ratio as isize;
+    fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let model_output = &model_output.to_dtype(sample.dtype())?;
+        let prev_t = self.prev_timestep(timestep);
+
+        // When learning the variance, the model outputs the noise prediction followed by the
+        // predicted variance along the channel dimension.
+        let channels = sample.dim(1)?;
+        let learned_variance = matches!(
+            self.config.variance_type,
+            DDPMVarianceType::Learned | DDPMVarianceType::LearnedRange
+        );
+        let (model_output, predicted_variance) =
+            if learned_variance && model_output.dim(1)? == 2 * channels {
+                let predicted_variance = model_output.narrow(1, channels, channels)?;
+                (
+                    model_output.narrow(1, 0, channels)?,
+                    Some(predicted_variance),
+                )
+            } else if self.config.variance_type == DDPMVarianceType::LearnedRange {
+                bail!(
+                    "learned range variance requires a model output with {} channels, got {:?}",
+                    2 * channels,
+                    model_output.shape()
+                )
+            } else {
+                (model_output.clone(), None)
+            };
+        let model_output = &model_output;
 
         // https://github.com/huggingface/diffusers/blob/df2b548e893ccb8a888467c2508756680df22821/src/diffusers/schedulers/scheduling_ddpm.py#L272
         // 1. compute alphas, betas
@@ -180,7 +245,10 @@ impl DDPMScheduler {
         let mut variance = model_output.zeros_like()?;
         if timestep > 0 {
             let variance_noise = model_output.randn_like(0., 1.)?;
-            if self.config.variance_type == DDPMVarianceType::FixedSmallLog {
+            if let Some(predicted_variance) = &predicted_variance {
+                let std = self.get_learned_std(timestep, predicted_variance)?;
+                variance = (variance_noise * std)?;
+            } else if self.config.variance_type == DDPMVarianceType::FixedSmallLog {
                 variance = (variance_noise * self.get_variance(timestep))?;
             } else {
                 variance = (variance_noise * self.get_variance(timestep).sqrt())?;
@@ -189,17 +257,30 @@ impl DDPMScheduler {
         &pred_prev_sample + variance
     }
 
-    pub fn add_noise(
+    fn add_noise(
         &self,
         original_samples: &Tensor,
-        noise: Tensor,
+        noise: &Tensor,
         timestep: usize,
     ) -> Result<Tensor> {
+        let noise = noise.to_dtype(original_samples.dtype())?;
         (original_samples * self.alphas_cumprod[timestep].sqrt())?
             + noise * (1. - self.alphas_cumprod[timestep]).sqrt()
     }
 
-    pub fn init_noise_sigma(&self) -> f64 {
+    fn init_noise_sigma(&self) -> f64 {
         self.init_noise_sigma
     }
+
+    fn set_timesteps(&mut self, timesteps: &[usize]) -> Result<()> {
+        check_timesteps(timesteps, self.config.train_timesteps)?;
+        self.timesteps = timesteps.to_vec();
+        self.custom_timesteps = true;
+        Ok(())
+    }
+
+    fn set_prediction_type(&mut self, prediction_type: PredictionType) -> Result<()> {
+        self.config.prediction_type = prediction_type;
+        Ok(())
+    }
 }
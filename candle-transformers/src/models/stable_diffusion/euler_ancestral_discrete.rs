@@ -9,8 +9,7 @@
 /// [kd]: https://github.com/crowsonkb/k-diffusion/blob/481677d114f6ea445aa009cf5bd7a9cdee909e47/k_diffusion/sampling.py#L72
 use super::{
     schedulers::{
-        betas_for_alpha_bar, BetaSchedule, PredictionType, Scheduler, SchedulerConfig,
-        TimestepSpacing,
+        check_timesteps, BetaSchedule, PredictionType, Scheduler, SchedulerConfig, TimestepSpacing,
     },
     utils::interp,
 };
@@ -35,6 +34,10 @@ pub struct EulerAncestralDiscreteSchedulerConfig {
     pub train_timesteps: usize,
     /// time step spacing for the diffusion process
     pub timestep_spacing: TimestepSpacing,
+    /// Replaces the sigmas with the ones of Karras et al. 2022, spaced between the sigmas of the
+    /// first and last timesteps of the schedule, the timesteps are then the ones matching these
+    /// sigmas. This lowers the noise levels of the last steps.
+    pub use_karras_sigmas: bool,
 }
 
 impl Default for EulerAncestralDiscreteSchedulerConfig {
@@ -47,6 +50,7 @@ impl Default for EulerAncestralDiscreteSchedulerConfig {
             prediction_type: PredictionType::Epsilon,
             train_timesteps: 1000,
             timestep_spacing: TimestepSpacing::Leading,
+            use_karras_sigmas: false,
         }
     }
 }
@@ -58,6 +62,10 @@ impl SchedulerConfig for EulerAncestralDiscreteSchedulerConfig {
             *self,
         )?))
     }
+
+    fn prediction_type(&self) -> PredictionType {
+        self.prediction_type
+    }
 }
 
 /// The EulerAncestral Discrete scheduler.
@@ -103,24 +111,30 @@ impl EulerAncestralDiscreteScheduler {
             }
         };
 
-        let betas = match config.beta_schedule {
-            BetaSchedule::ScaledLinear => super::utils::linspace(
-                config.beta_start.sqrt(),
-                config.beta_end.sqrt(),
-                config.train_timesteps,
-            )?
-            .sqr()?,
-            BetaSchedule::Linear => {
-                super::utils::linspace(config.beta_start, config.beta_end, config.train_timesteps)?
-            }
-            BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(config.train_timesteps, 0.999)?,
-        };
-        let betas = betas.to_vec1::<f64>()?;
-        let mut alphas_cumprod = Vec::with_capacity(betas.len());
-        for &beta in betas.iter() {
-            let alpha = 1.0 - beta;
-            alphas_cumprod.push(alpha * *alphas_cumprod.last().unwrap_or(&1f64))
-        }
+        let (timesteps, sigmas, init_noise_sigma) = Self::sigmas(&timesteps, &config)?;
+        Ok(Self {
+            sigmas,
+            timesteps,
+            init_noise_sigma,
+            config,
+        })
+    }
+
+    /// `rho` in the Karras et al. 2022 noise schedule.
+    const KARRAS_RHO: f64 = 7.;
+
+    /// Returns the timesteps, the sigmas at these timesteps followed by a zero one, and the
+    /// standard deviation of the initial noise. The timesteps are the given ones unless Karras
+    /// sigmas are used.
+    fn sigmas(
+        timesteps: &[usize],
+        config: &EulerAncestralDiscreteSchedulerConfig,
+    ) -> Result<(Vec<usize>, Vec<f64>, f64)> {
+        let alphas_cumprod = config.beta_schedule.alphas_cumprod(
+            config.beta_start,
+            config.beta_end,
+            config.train_timesteps,
+        )?;
         let sigmas: Vec<f64> = alphas_cumprod
             .iter()
             .map(|&f| ((1. - f) / f).sqrt())
@@ -133,6 +147,10 @@ impl EulerAncestralDiscreteScheduler {
             &sigmas_xa,
             &sigmas,
         );
+        let mut timesteps = timesteps.to_vec();
+        if config.use_karras_sigmas {
+            (timesteps, sigmas_int) = Self::karras(&sigmas_int, &sigmas)?;
+        }
         sigmas_int.push(0.0);
 
         // standard deviation of the initial noise distribution
@@ -142,13 +160,44 @@ impl EulerAncestralDiscreteScheduler {
             .chain(std::iter::once(&0.0))
             .reduce(|a, b| if a > b { a } else { b })
             .expect("init_noise_sigma could not be reduced from sigmas - this should never happen");
+        Ok((timesteps, sigmas_int, init_noise_sigma))
+    }
 
-        Ok(Self {
-            sigmas: sigmas_int,
-            timesteps,
-            init_noise_sigma,
-            config,
-        })
+    /// Spaces `sigmas_int`, the sigmas of the schedule, with the Karras et al. 2022 schedule and
+    /// maps the resulting sigmas back to the closest timesteps by interpolating the log of
+    /// `train_sigmas`, the sigmas of all the training timesteps.
+    fn karras(sigmas_int: &[f64], train_sigmas: &[f64]) -> Result<(Vec<usize>, Vec<f64>)> {
+        let (sigma_max, sigma_min) = match (sigmas_int.first(), sigmas_int.last()) {
+            (Some(&max), Some(&min)) => (max, min),
+            _ => return Ok((vec![], vec![])),
+        };
+        let max_inv_rho = sigma_max.powf(1. / Self::KARRAS_RHO);
+        let min_inv_rho = sigma_min.powf(1. / Self::KARRAS_RHO);
+        let sigmas: Vec<f64> = super::utils::linspace(0., 1., sigmas_int.len())?
+            .to_vec1::<f64>()?
+            .iter()
+            .map(|ramp| (max_inv_rho + ramp * (min_inv_rho - max_inv_rho)).powf(Self::KARRAS_RHO))
+            .collect();
+
+        let log_sigmas: Vec<f64> = train_sigmas.iter().map(|s| s.ln()).collect();
+        let (log_min, log_max) = (log_sigmas[0], log_sigmas[log_sigmas.len() - 1]);
+        let log_sigmas_int: Vec<f64> = sigmas
+            .iter()
+            .map(|s| s.ln().clamp(log_min, log_max))
+            .collect();
+        let ts: Vec<_> = (0..log_sigmas.len()).map(|i| i as f64).collect();
+        let timesteps: Vec<usize> = interp(&log_sigmas_int, &log_sigmas, &ts)
+            .iter()
+            .map(|t| t.round() as usize)
+            .collect();
+        // The steps are looked up by timestep so two sigmas cannot map to the same one.
+        if timesteps.windows(2).any(|w| w[0] == w[1]) {
+            bail!(
+                "{} steps are too many for karras sigmas, some get the same timestep",
+                timesteps.len()
+            )
+        }
+        Ok((timesteps, sigmas))
     }
 }
 
@@ -177,6 +226,7 @@ impl Scheduler for EulerAncestralDiscreteScheduler {
 
     /// Performs a backward step during inference.
     fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let model_output = &model_output.to_dtype(sample.dtype())?;
         let step_index = self
             .timesteps
             .iter()
@@ -211,7 +261,7 @@ impl Scheduler for EulerAncestralDiscreteScheduler {
         prev_sample + noise * sigma_up
     }
 
-    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, timestep: usize) -> Result<Tensor> {
         let step_index = self
             .timesteps
             .iter()
@@ -223,7 +273,7 @@ impl Scheduler for EulerAncestralDiscreteScheduler {
             .get(step_index)
             .expect("step_index out of sigma bounds - this shouldn't happen");
 
-        original + (noise * *sigma)?
+        original + (noise.to_dtype(original.dtype())? * *sigma)?
     }
 
     fn init_noise_sigma(&self) -> f64 {
@@ -232,4 +282,21 @@ impl Scheduler for EulerAncestralDiscreteScheduler {
             TimestepSpacing::Leading => (self.init_noise_sigma.powi(2) + 1.0).sqrt(),
         }
     }
+
+    fn set_timesteps(&mut self, timesteps: &[usize]) -> Result<()> {
+        check_timesteps(timesteps, self.config.train_timesteps)?;
+        let (timesteps, sigmas, init_noise_sigma) = Self::sigmas(timesteps, &self.config)?;
+        self.timesteps = timesteps;
+        self.sigmas = sigmas;
+        self.init_noise_sigma = init_noise_sigma;
+        Ok(())
+    }
+
+    fn set_prediction_type(&mut self, prediction_type: PredictionType) -> Result<()> {
+        if matches!(prediction_type, PredictionType::Sample) {
+            bail!("prediction_type not implemented yet: sample")
+        }
+        self.config.prediction_type = prediction_type;
+        Ok(())
+    }
 }
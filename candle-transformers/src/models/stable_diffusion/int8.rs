@@ -0,0 +1,192 @@
+//! Int8 weights with per output channel scales.
+//!
+//! A quantized linear or conv layer stores its weight as `weight`, a `u8` tensor holding the int8
+//! values offset by 128, and `weight_scale`, a `f32` tensor with one scale per output channel. The
+//! weight is kept quantized in memory and dequantized to the activations dtype on each forward
+//! pass, on metal this uses a dedicated kernel. The layers check for `weight_scale` so quantized
+//! and non-quantized checkpoints can be loaded with the same model code.
+use crate::models::with_tracing;
+use candle::{DType, Module, Result, Tensor};
+use candle_nn as nn;
+
+const ZERO_POINT: f64 = 128.;
+
+/// The scales broadcast over the dimensions of `weight` following the output channels.
+fn channel_scale(scale: &Tensor, weight: &Tensor, dtype: DType) -> Result<Tensor> {
+    let mut dims = vec![1; weight.rank()];
+    dims[0] = weight.dim(0)?;
+    scale.to_dtype(dtype)?.reshape(dims)
+}
+
+/// Quantizes a `(out_dim, in_dim)` linear or `(out_dim, in_dim, h, w)` conv weight symmetrically
+/// using one scale per output channel, returns the `u8` weight and the `f32` scales in the format
+/// expected by [`linear`] and [`conv2d`].
+pub fn quantize(weight: &Tensor) -> Result<(Tensor, Tensor)> {
+    let weight = weight.to_dtype(DType::F32)?;
+    let scale = (weight.flatten_from(1)?.abs()?.max(1)? / 127.)?.maximum(1e-12)?;
+    let quantized = (weight
+        .broadcast_div(&channel_scale(&scale, &weight, DType::F32)?)?
+        .round()?
+        + ZERO_POINT)?
+        .clamp(ZERO_POINT - 127., ZERO_POINT + 127.)?
+        .to_dtype(DType::U8)?;
+    Ok((quantized, scale))
+}
+
+/// Dequantizes a weight returned by [`quantize`] to the given dtype.
+pub fn dequantize(weight: &Tensor, scale: &Tensor, dtype: DType) -> Result<Tensor> {
+    #[cfg(feature = "metal")]
+    if weight.device().is_metal() && dtype.is_float() && dtype != DType::F64 {
+        return candle_nn::metal_kernels::dequantize_int8(
+            &weight.contiguous()?,
+            &scale.to_dtype(DType::F32)?.contiguous()?,
+            dtype,
+        );
+    }
+    let scale = channel_scale(scale, weight, dtype)?;
+    (weight.to_dtype(dtype)? - ZERO_POINT)?.broadcast_mul(&scale)
+}
+
+#[derive(Debug, Clone)]
+pub struct Int8Linear {
+    weight: Tensor,
+    scale: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl Module for Int8Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let weight = dequantize(&self.weight, &self.scale, xs.dtype())?;
+        nn::Linear::new(weight, self.bias.clone()).forward(xs)
+    }
+}
+
+/// A linear layer which weight is either in the checkpoint dtype or int8 quantized.
+#[derive(Debug, Clone)]
+pub enum Linear {
+    Float(nn::Linear),
+    Int8(Int8Linear),
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Float(l) => l.forward(xs),
+            Self::Int8(l) => l.forward(xs),
+        }
+    }
+}
+
+pub fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vs: nn::VarBuilder) -> Result<Linear> {
+    if !vs.contains_tensor("weight_scale") {
+        return Ok(Linear::Float(nn::linear_b(in_dim, out_dim, bias, vs)?));
+    }
+    let init = nn::Init::Const(0.);
+    let weight = vs.get_with_hints_dtype((out_dim, in_dim), "weight", init, DType::U8)?;
+    let scale = vs.get_with_hints_dtype(out_dim, "weight_scale", init, DType::F32)?;
+    let bias = if bias {
+        Some(vs.get(out_dim, "bias")?)
+    } else {
+        None
+    };
+    Ok(Linear::Int8(Int8Linear {
+        weight,
+        scale,
+        bias,
+    }))
+}
+
+pub fn linear(in_dim: usize, out_dim: usize, vs: nn::VarBuilder) -> Result<Linear> {
+    linear_b(in_dim, out_dim, true, vs)
+}
+
+pub fn linear_no_bias(in_dim: usize, out_dim: usize, vs: nn::VarBuilder) -> Result<Linear> {
+    linear_b(in_dim, out_dim, false, vs)
+}
+
+#[derive(Debug, Clone)]
+pub struct Int8Conv2d {
+    weight: Tensor,
+    scale: Tensor,
+    bias: Option<Tensor>,
+    config: nn::Conv2dConfig,
+    span: tracing::Span,
+}
+
+impl Int8Conv2d {
+    fn conv(&self, dtype: DType) -> Result<nn::Conv2d> {
+        let weight = dequantize(&self.weight, &self.scale, dtype)?;
+        Ok(nn::Conv2d::new(weight, self.bias.clone(), self.config))
+    }
+}
+
+impl Module for Int8Conv2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        self.conv(xs.dtype())?.forward(xs)
+    }
+}
+
+/// A conv layer which weight is either in the checkpoint dtype or int8 quantized.
+#[derive(Debug, Clone)]
+pub enum Conv2d {
+    Float(with_tracing::Conv2d),
+    Int8(Int8Conv2d),
+}
+
+impl Conv2d {
+    /// See [`candle_nn::Conv2d::forward_channels_last`].
+    pub fn forward_channels_last(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Float(c) => c.forward_channels_last(xs),
+            Self::Int8(c) => {
+                let _enter = c.span.enter();
+                c.conv(xs.dtype())?.forward_channels_last(xs)
+            }
+        }
+    }
+}
+
+impl Module for Conv2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Float(c) => c.forward(xs),
+            Self::Int8(c) => c.forward(xs),
+        }
+    }
+}
+
+pub fn conv2d(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    config: nn::Conv2dConfig,
+    vs: nn::VarBuilder,
+) -> Result<Conv2d> {
+    if !vs.contains_tensor("weight_scale") {
+        return Ok(Conv2d::Float(with_tracing::conv2d(
+            in_channels,
+            out_channels,
+            kernel_size,
+            config,
+            vs,
+        )?));
+    }
+    let init = nn::Init::Const(0.);
+    let shape = (
+        out_channels,
+        in_channels / config.groups,
+        kernel_size,
+        kernel_size,
+    );
+    let weight = vs.get_with_hints_dtype(shape, "weight", init, DType::U8)?;
+    let scale = vs.get_with_hints_dtype(out_channels, "weight_scale", init, DType::F32)?;
+    let bias = vs.get(out_channels, "bias")?;
+    Ok(Conv2d::Int8(Int8Conv2d {
+        weight,
+        scale,
+        bias: Some(bias),
+        config,
+        span: tracing::span!(tracing::Level::TRACE, "conv2d-int8"),
+    }))
+}
@@ -0,0 +1,185 @@
+//! # Latent Consistency Models
+//!
+//! The LCM scheduler is meant to be used with a latent consistency distilled
+//! checkpoint, such models map any point of the diffusion trajectory directly to
+//! its origin so that a handful of steps (usually 2 to 8) are enough to generate
+//! an image. Each step predicts the denoised sample using the consistency model
+//! boundary condition and noises it back to the next timestep.
+//!
+//! Latent Consistency Models, S. Luo et al, 2023.
+//! https://arxiv.org/abs/2310.04378
+use super::schedulers::{
+    check_timesteps, next_timestep, BetaSchedule, PredictionType, Scheduler, SchedulerConfig,
+};
+use candle::{Result, Tensor};
+
+/// The configuration for the LCM scheduler.
+#[derive(Debug, Clone, Copy)]
+pub struct LCMSchedulerConfig {
+    /// The value of beta at the beginning of training.
+    pub beta_start: f64,
+    /// The value of beta at the end of training.
+    pub beta_end: f64,
+    /// How beta evolved during training.
+    pub beta_schedule: BetaSchedule,
+    /// prediction type of the scheduler function, one of `epsilon` (predicting
+    /// the noise of the diffusion process), `sample` (directly predicting the noisy sample`)
+    /// or `v_prediction` (see section 2.4 https://imagen.research.google/video/paper.pdf)
+    pub prediction_type: PredictionType,
+    /// number of diffusion steps used to train the model
+    pub train_timesteps: usize,
+    /// The number of steps of the schedule used when distilling the model, the
+    /// inference timesteps are a subset of these.
+    pub original_inference_steps: usize,
+    /// The factor applied to the timestep when computing the boundary condition
+    /// scalings `c_skip` and `c_out`.
+    pub timestep_scaling: f64,
+}
+
+impl Default for LCMSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            beta_start: 0.00085f64,
+            beta_end: 0.012f64,
+            beta_schedule: BetaSchedule::ScaledLinear,
+            prediction_type: PredictionType::Epsilon,
+            train_timesteps: 1000,
+            original_inference_steps: 50,
+            timestep_scaling: 10.,
+        }
+    }
+}
+
+impl SchedulerConfig for LCMSchedulerConfig {
+    fn build(&self, inference_steps: usize) -> Result<Box<dyn Scheduler>> {
+        Ok(Box::new(LCMScheduler::new(inference_steps, *self)?))
+    }
+
+    fn prediction_type(&self) -> PredictionType {
+        self.prediction_type
+    }
+}
+
+/// The LCM scheduler.
+#[derive(Debug, Clone)]
+pub struct LCMScheduler {
+    timesteps: Vec<usize>,
+    alphas_cumprod: Vec<f64>,
+    init_noise_sigma: f64,
+    pub config: LCMSchedulerConfig,
+}
+
+impl LCMScheduler {
+    /// `sigma_data`, the standard deviation of the data distribution, as used in the
+    /// consistency models boundary condition.
+    const SIGMA_DATA: f64 = 0.5;
+
+    /// Creates a new LCM scheduler given the number of steps to be used for
+    /// inference, this cannot be more than `config.original_inference_steps`.
+    pub fn new(inference_steps: usize, config: LCMSchedulerConfig) -> Result<Self> {
+        if inference_steps == 0 || inference_steps > config.original_inference_steps {
+            candle::bail!(
+                "the number of inference steps {inference_steps} has to be between 1 and {}",
+                config.original_inference_steps
+            )
+        }
+        // The schedule used during distillation, the inference timesteps are picked
+        // evenly from it starting with the noisiest one.
+        let k = config.train_timesteps / config.original_inference_steps;
+        let skipping_step = config.original_inference_steps / inference_steps;
+        let timesteps: Vec<usize> = (1..=config.original_inference_steps)
+            .rev()
+            .map(|i| i * k - 1)
+            .step_by(skipping_step)
+            .take(inference_steps)
+            .collect();
+
+        let alphas_cumprod = config.beta_schedule.alphas_cumprod(
+            config.beta_start,
+            config.beta_end,
+            config.train_timesteps,
+        )?;
+        Ok(Self {
+            alphas_cumprod,
+            timesteps,
+            init_noise_sigma: 1.,
+            config,
+        })
+    }
+
+    /// Returns the `(c_skip, c_out)` scalings of the boundary condition for the given
+    /// timestep, the denoised sample is `c_skip * sample + c_out * pred_original_sample`.
+    pub fn get_scalings_for_boundary_condition(&self, timestep: usize) -> (f64, f64) {
+        let t = timestep as f64 * self.config.timestep_scaling;
+        let sigma_data2 = Self::SIGMA_DATA * Self::SIGMA_DATA;
+        let c_skip = sigma_data2 / (t * t + sigma_data2);
+        let c_out = t / (t * t + sigma_data2).sqrt();
+        (c_skip, c_out)
+    }
+}
+
+impl Scheduler for LCMScheduler {
+    /// Performs a backward step during inference, no noise is added back on the
+    /// last step.
+    fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let model_output = &model_output.to_dtype(sample.dtype())?;
+        let prev_timestep = next_timestep(&self.timesteps, timestep);
+        let timestep = timestep.min(self.alphas_cumprod.len() - 1);
+
+        let alpha_prod_t = self.alphas_cumprod[timestep];
+        let beta_prod_t = 1. - alpha_prod_t;
+        let (c_skip, c_out) = self.get_scalings_for_boundary_condition(timestep);
+
+        let pred_original_sample = match self.config.prediction_type {
+            PredictionType::Epsilon => {
+                ((sample - (model_output * beta_prod_t.sqrt())?)? * (1. / alpha_prod_t.sqrt()))?
+            }
+            PredictionType::VPrediction => {
+                ((sample * alpha_prod_t.sqrt())? - (model_output * beta_prod_t.sqrt())?)?
+            }
+            PredictionType::Sample => model_output.clone(),
+        };
+        let denoised = ((pred_original_sample * c_out)? + (sample * c_skip)?)?;
+
+        match prev_timestep {
+            Some(prev_timestep) => {
+                let noise = denoised.randn_like(0., 1.)?;
+                self.add_noise(&denoised, &noise, prev_timestep)
+            }
+            None => Ok(denoised),
+        }
+    }
+
+    ///  Ensures interchangeability with schedulers that need to scale the denoising model input
+    /// depending on the current timestep.
+    fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Result<Tensor> {
+        Ok(sample)
+    }
+
+    fn timesteps(&self) -> &[usize] {
+        self.timesteps.as_slice()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, timestep: usize) -> Result<Tensor> {
+        let timestep = timestep.min(self.alphas_cumprod.len() - 1);
+        let sqrt_alpha_prod = self.alphas_cumprod[timestep].sqrt();
+        let sqrt_one_minus_alpha_prod = (1.0 - self.alphas_cumprod[timestep]).sqrt();
+        let noise = noise.to_dtype(original.dtype())?;
+        (original * sqrt_alpha_prod)? + (noise * sqrt_one_minus_alpha_prod)?
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        self.init_noise_sigma
+    }
+
+    fn set_timesteps(&mut self, timesteps: &[usize]) -> Result<()> {
+        check_timesteps(timesteps, self.config.train_timesteps)?;
+        self.timesteps = timesteps.to_vec();
+        Ok(())
+    }
+
+    fn set_prediction_type(&mut self, prediction_type: PredictionType) -> Result<()> {
+        self.config.prediction_type = prediction_type;
+        Ok(())
+    }
+}
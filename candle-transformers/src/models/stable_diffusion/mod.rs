@@ -1,9 +1,15 @@
 pub mod attention;
 pub mod clip;
+pub mod controlnet;
 pub mod ddim;
 pub mod ddpm;
 pub mod embeddings;
 pub mod euler_ancestral_discrete;
+pub mod int8;
+pub mod lcm;
+mod model_index;
+pub mod multidiffusion;
+pub mod placement;
 pub mod resnet;
 pub mod schedulers;
 pub mod unet_2d;
@@ -13,15 +19,49 @@ pub mod vae;
 
 use std::sync::Arc;
 
-use candle::{DType, Device, Result};
+use candle::{DType, Device, Module, Result, Tensor, D};
 use candle_nn as nn;
 
 use self::schedulers::{Scheduler, SchedulerConfig};
 
+/// Rounds a requested image size up to a multiple of 8, the VAE downsampling factor, returns
+/// the requested and the padded sizes.
+fn padded_size(requested: Option<usize>, default: usize) -> (usize, usize) {
+    let requested = requested.unwrap_or(default);
+    let padded = requested.div_ceil(8) * 8;
+    if padded != requested {
+        tracing::trace!(requested, padded, "image size padded to a multiple of 8")
+    }
+    (requested, padded)
+}
+
+/// The options of [`StableDiffusionConfig::sdxl_with`], the defaults give the SDXL base
+/// configuration with a DDIM scheduler.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SdxlOptions {
+    pub height: Option<usize>,
+    pub width: Option<usize>,
+    /// The scheduler, see [`StableDiffusionConfig::with_sampler`]. DDIM when not set.
+    pub sampler: Option<schedulers::Sampler>,
+    /// The DDIM `eta`, see [`StableDiffusionConfig::with_eta`]. This is only used with the DDIM
+    /// sampler.
+    pub eta: Option<f64>,
+    /// Uses Karras sigmas, this is only supported by the Euler ancestral sampler.
+    pub use_karras_sigmas: bool,
+    /// The number of queries per cross-attention slice, see
+    /// [`unet_2d::UNet2DConditionModelConfig::sliced_attention_size`].
+    pub sliced_attention_size: Option<usize>,
+}
+
 #[derive(Clone, Debug)]
 pub struct StableDiffusionConfig {
+    /// The size of the generated images, a multiple of 8.
     pub width: usize,
     pub height: usize,
+    /// The size that was asked for, images can be cropped to it with
+    /// [`StableDiffusionConfig::crop_to_requested`].
+    pub requested_width: usize,
+    pub requested_height: usize,
     pub clip: clip::Config,
     pub clip2: Option<clip::Config>,
     autoencoder: vae::AutoEncoderKLConfig,
@@ -30,6 +70,32 @@ pub struct StableDiffusionConfig {
 }
 
 impl StableDiffusionConfig {
+    /// Assembles a configuration, the requested `(height, width)` default to `default_size` and
+    /// get padded to multiples of 8.
+    fn from_parts(
+        (height, width): (Option<usize>, Option<usize>),
+        default_size: usize,
+        clip: clip::Config,
+        clip2: Option<clip::Config>,
+        autoencoder: vae::AutoEncoderKLConfig,
+        unet: unet_2d::UNet2DConditionModelConfig,
+        scheduler: Arc<dyn SchedulerConfig>,
+    ) -> Self {
+        let (requested_height, height) = padded_size(height, default_size);
+        let (requested_width, width) = padded_size(width, default_size);
+        Self {
+            width,
+            height,
+            requested_width,
+            requested_height,
+            clip,
+            clip2,
+            autoencoder,
+            unet,
+            scheduler,
+        }
+    }
+
     pub fn v1_5(
         sliced_attention_size: Option<usize>,
         height: Option<usize>,
@@ -65,35 +131,22 @@ impl StableDiffusionConfig {
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         };
-        let height = if let Some(height) = height {
-            assert_eq!(height % 8, 0, "height has to be divisible by 8");
-            height
-        } else {
-            512
-        };
-
-        let width = if let Some(width) = width {
-            assert_eq!(width % 8, 0, "width has to be divisible by 8");
-            width
-        } else {
-            512
-        };
-
         let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
             prediction_type: schedulers::PredictionType::Epsilon,
             ..Default::default()
         });
 
-        StableDiffusionConfig {
-            width,
-            height,
-            clip: clip::Config::v1_5(),
-            clip2: None,
+        Self::from_parts(
+            (height, width),
+            512,
+            clip::Config::v1_5(),
+            None,
             autoencoder,
-            scheduler,
             unet,
-        }
+            scheduler,
+        )
     }
 
     fn v2_1_(
@@ -133,35 +186,22 @@ impl StableDiffusionConfig {
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         };
         let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
             prediction_type,
             ..Default::default()
         });
 
-        let height = if let Some(height) = height {
-            assert_eq!(height % 8, 0, "height has to be divisible by 8");
-            height
-        } else {
-            768
-        };
-
-        let width = if let Some(width) = width {
-            assert_eq!(width % 8, 0, "width has to be divisible by 8");
-            width
-        } else {
-            768
-        };
-
-        StableDiffusionConfig {
-            width,
-            height,
-            clip: clip::Config::v2_1(),
-            clip2: None,
+        Self::from_parts(
+            (height, width),
+            768,
+            clip::Config::v2_1(),
+            None,
             autoencoder,
-            scheduler,
             unet,
-        }
+            scheduler,
+        )
     }
 
     pub fn v2_1(
@@ -214,35 +254,22 @@ impl StableDiffusionConfig {
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         };
         let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
             prediction_type,
             ..Default::default()
         });
 
-        let height = if let Some(height) = height {
-            assert_eq!(height % 8, 0, "height has to be divisible by 8");
-            height
-        } else {
-            1024
-        };
-
-        let width = if let Some(width) = width {
-            assert_eq!(width % 8, 0, "width has to be divisible by 8");
-            width
-        } else {
-            1024
-        };
-
-        StableDiffusionConfig {
-            width,
-            height,
-            clip: clip::Config::sdxl(),
-            clip2: Some(clip::Config::sdxl2()),
+        Self::from_parts(
+            (height, width),
+            1024,
+            clip::Config::sdxl(),
+            Some(clip::Config::sdxl2()),
             autoencoder,
-            scheduler,
             unet,
-        }
+            scheduler,
+        )
     }
 
     fn sdxl_turbo_(
@@ -281,6 +308,7 @@ impl StableDiffusionConfig {
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         };
         let scheduler = Arc::new(
             euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig {
@@ -290,43 +318,68 @@ impl StableDiffusionConfig {
             },
         );
 
-        let height = if let Some(height) = height {
-            assert_eq!(height % 8, 0, "height has to be divisible by 8");
-            height
-        } else {
-            512
-        };
-
-        let width = if let Some(width) = width {
-            assert_eq!(width % 8, 0, "width has to be divisible by 8");
-            width
-        } else {
-            512
-        };
-
-        Self {
-            width,
-            height,
-            clip: clip::Config::sdxl(),
-            clip2: Some(clip::Config::sdxl2()),
+        Self::from_parts(
+            (height, width),
+            512,
+            clip::Config::sdxl(),
+            Some(clip::Config::sdxl2()),
             autoencoder,
-            scheduler,
             unet,
-        }
+            scheduler,
+        )
     }
 
+    #[deprecated(note = "use `sdxl_with` with `SdxlOptions` instead")]
     pub fn sdxl(
         sliced_attention_size: Option<usize>,
         height: Option<usize>,
         width: Option<usize>,
     ) -> Self {
-        Self::sdxl_(
-            sliced_attention_size,
+        Self::sdxl_with(SdxlOptions {
             height,
             width,
+            sliced_attention_size,
+            ..Default::default()
+        })
+    }
+
+    /// The SDXL base configuration, options that do not apply to the selected sampler are
+    /// ignored with a warning.
+    pub fn sdxl_with(options: SdxlOptions) -> Self {
+        use schedulers::Sampler;
+
+        let mut config = Self::sdxl_(
+            options.sliced_attention_size,
+            options.height,
+            options.width,
             // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/scheduler/scheduler_config.json
             schedulers::PredictionType::Epsilon,
-        )
+        );
+        let sampler = options.sampler.unwrap_or(Sampler::Ddim);
+        if options.sampler.is_some() {
+            config = config.with_sampler(sampler);
+        }
+        if let Some(eta) = options.eta {
+            if sampler == Sampler::Ddim {
+                config = config.with_eta(eta)
+            } else {
+                tracing::warn!(?sampler, eta, "eta is only used by DDIM, ignoring it")
+            }
+        }
+        if options.use_karras_sigmas {
+            if sampler == Sampler::EulerAncestral {
+                config.scheduler = Arc::new(
+                    euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig {
+                        prediction_type: config.scheduler.prediction_type(),
+                        use_karras_sigmas: true,
+                        ..Default::default()
+                    },
+                )
+            } else {
+                tracing::warn!(?sampler, "karras sigmas are not supported, ignoring them")
+            }
+        }
+        config
     }
 
     pub fn sdxl_turbo(
@@ -378,81 +431,161 @@ impl StableDiffusionConfig {
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         };
         let scheduler = Arc::new(ddim::DDIMSchedulerConfig {
             ..Default::default()
         });
 
-        let height = if let Some(height) = height {
-            assert_eq!(height % 8, 0, "height has to be divisible by 8");
-            height
-        } else {
-            1024
-        };
-
-        let width = if let Some(width) = width {
-            assert_eq!(width % 8, 0, "width has to be divisible by 8");
-            width
-        } else {
-            1024
-        };
-
-        Self {
-            width,
-            height,
-            clip: clip::Config::ssd1b(),
-            clip2: Some(clip::Config::ssd1b2()),
+        Self::from_parts(
+            (height, width),
+            1024,
+            clip::Config::ssd1b(),
+            Some(clip::Config::ssd1b2()),
             autoencoder,
-            scheduler,
             unet,
-        }
+            scheduler,
+        )
     }
 
+    /// Loads the configuration of a model directory in the diffusers format, from its
+    /// `model_index.json` file and the configs of the unet, vae, text encoders, tokenizers and
+    /// scheduler. This is what the hardcoded constructors above are based on, unsupported
+    /// schedulers are replaced with DDIM.
+    pub fn from_model_dir<P: AsRef<std::path::Path>>(
+        dir: P,
+        sliced_attention_size: Option<usize>,
+        height: Option<usize>,
+        width: Option<usize>,
+    ) -> Result<Self> {
+        model_index::load(dir.as_ref(), sliced_attention_size, height, width)
+    }
+
+    /// Computes the VAE self-attention in chunks of `sliced_attention_size` queries, this lowers
+    /// the peak memory usage when decoding high resolution images.
+    pub fn with_vae_sliced_attention_size(mut self, sliced_attention_size: Option<usize>) -> Self {
+        self.autoencoder.sliced_attention_size = sliced_attention_size;
+        self
+    }
+
+    /// Replaces the unet configuration, e.g. for fine-tuned checkpoints with a different
+    /// architecture.
+    pub fn with_unet_config(mut self, unet: unet_2d::UNet2DConditionModelConfig) -> Self {
+        self.unet = unet;
+        self
+    }
+
+    /// Builds the VAE, the weights can be split in multiple safetensors files.
     pub fn build_vae<P: AsRef<std::path::Path>>(
         &self,
-        vae_weights: P,
+        vae_weights: &[P],
         device: &Device,
         dtype: DType,
     ) -> Result<vae::AutoEncoderKL> {
-        let vs_ae =
-            unsafe { nn::VarBuilder::from_mmaped_safetensors(&[vae_weights], dtype, device)? };
+        let vs_ae = unsafe { nn::VarBuilder::from_mmaped_safetensors(vae_weights, dtype, device)? };
         // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
         let autoencoder = vae::AutoEncoderKL::new(vs_ae, 3, 3, self.autoencoder.clone())?;
         Ok(autoencoder)
     }
 
+    /// Builds the unet, the weights can be split in multiple safetensors files as is the case for
+    /// sharded SDXL checkpoints. The linear and conv layers can be int8 quantized, see [`int8`].
+    /// `memory_format` is a hint for the layout of the resnet blocks activations, channels-last
+    /// uses the direct conv kernel on metal.
     pub fn build_unet<P: AsRef<std::path::Path>>(
         &self,
-        unet_weights: P,
+        unet_weights: &[P],
         device: &Device,
         in_channels: usize,
         use_flash_attn: bool,
+        memory_format: resnet::MemoryFormat,
         dtype: DType,
     ) -> Result<unet_2d::UNet2DConditionModel> {
         let vs_unet =
-            unsafe { nn::VarBuilder::from_mmaped_safetensors(&[unet_weights], dtype, device)? };
+            unsafe { nn::VarBuilder::from_mmaped_safetensors(unet_weights, dtype, device)? };
         let unet = unet_2d::UNet2DConditionModel::new(
             vs_unet,
             in_channels,
             4,
             use_flash_attn,
+            memory_format,
             self.unet.clone(),
         )?;
         Ok(unet)
     }
 
+    /// Builds a ControlNet for the unet of this configuration, `in_channels` has to match the
+    /// unet input channels.
+    pub fn build_controlnet<P: AsRef<std::path::Path>>(
+        &self,
+        controlnet_weights: &[P],
+        device: &Device,
+        in_channels: usize,
+        use_flash_attn: bool,
+        dtype: DType,
+    ) -> Result<controlnet::ControlNet> {
+        let vs =
+            unsafe { nn::VarBuilder::from_mmaped_safetensors(controlnet_weights, dtype, device)? };
+        let config = controlnet::ControlNetConfig::new(self.unet.clone());
+        controlnet::ControlNet::new(vs, in_channels, use_flash_attn, config)
+    }
+
+    /// Replaces the scheduler with the default configuration of `sampler`, the prediction type of
+    /// the model is kept.
+    pub fn with_sampler(mut self, sampler: schedulers::Sampler) -> Self {
+        self.scheduler = sampler.config(self.scheduler.prediction_type());
+        tracing::trace!(?sampler, scheduler = ?self.scheduler, "scheduler selected");
+        self
+    }
+
+    /// Crops images of the generated size, with the height and width as the last two dimensions,
+    /// to the requested size. This removes the padding added when the requested size is not a
+    /// multiple of 8.
+    pub fn crop_to_requested(&self, image: &Tensor) -> Result<Tensor> {
+        let image = image.narrow(D::Minus2, 0, self.requested_height)?;
+        image.narrow(D::Minus1, 0, self.requested_width)
+    }
+
+    /// Uses a DDIM scheduler with the given `eta`, the amount of noise added back on each step:
+    /// 0 is the deterministic DDIM and 1 is close to DDPM. Only DDIM has this parameter so other
+    /// schedulers, e.g. the Euler one of SDXL-Turbo, get replaced. The prediction type of the
+    /// model is kept.
+    pub fn with_eta(mut self, eta: f64) -> Self {
+        self.scheduler = Arc::new(ddim::DDIMSchedulerConfig {
+            eta,
+            prediction_type: self.scheduler.prediction_type(),
+            ..Default::default()
+        });
+        self
+    }
+
     pub fn build_scheduler(&self, n_steps: usize) -> Result<Box<dyn Scheduler>> {
+        tracing::trace!(scheduler = ?self.scheduler, n_steps, "building the scheduler");
         self.scheduler.build(n_steps)
     }
 }
 
 pub fn build_clip_transformer<P: AsRef<std::path::Path>>(
     clip: &clip::Config,
-    clip_weights: P,
+    clip_weights: &[P],
     device: &Device,
     dtype: DType,
 ) -> Result<clip::ClipTextTransformer> {
-    let vs = unsafe { nn::VarBuilder::from_mmaped_safetensors(&[clip_weights], dtype, device)? };
+    let vs = unsafe { nn::VarBuilder::from_mmaped_safetensors(clip_weights, dtype, device)? };
     let text_model = clip::ClipTextTransformer::new(vs, clip)?;
     Ok(text_model)
 }
+
+/// Encodes a prompt with the two text encoders of SDXL. Returns the cross-attention
+/// conditioning, the CLIP-L hidden states followed by the CLIP-G ones along the feature
+/// dimension (768 + 1280 = 2048 features), and the pooled output of CLIP-G.
+pub fn sdxl_encode_prompt(
+    clip_l: &clip::ClipTextTransformer,
+    clip_g: &clip::ClipTextTransformer,
+    tokens: &Tensor,
+) -> Result<(Tensor, Tensor)> {
+    let hidden_l = clip_l.forward(tokens)?;
+    let (hidden_g, pooled) = clip_g.forward_with_pooled(tokens)?;
+    let hidden = Tensor::cat(&[hidden_l, hidden_g], D::Minus1)?;
+    Ok((hidden, pooled))
+}
@@ -0,0 +1,300 @@
+//! Loads a [`StableDiffusionConfig`] from a model directory in the diffusers format.
+//!
+//! The directory holds a `model_index.json` file listing the pipeline components and one
+//! sub-directory per component with its own `config.json`, e.g.
+//! https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/tree/main
+use std::path::Path;
+use std::sync::Arc;
+
+use candle::Result;
+
+use super::schedulers::{BetaSchedule, PredictionType, SchedulerConfig, TimestepSpacing};
+use super::StableDiffusionConfig;
+use super::{clip, ddim, ddpm, euler_ancestral_discrete, lcm, unet_2d, vae};
+
+/// Some per block values are written as a single value shared by all the blocks.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+enum PerBlock {
+    Shared(usize),
+    Each(Vec<usize>),
+}
+
+impl PerBlock {
+    fn get(&self, i: usize) -> Result<usize> {
+        match self {
+            Self::Shared(v) => Ok(*v),
+            Self::Each(vs) => match vs.get(i) {
+                Some(v) => Ok(*v),
+                None => candle::bail!("no value for block {i} in {vs:?}"),
+            },
+        }
+    }
+}
+
+fn one() -> PerBlock {
+    PerBlock::Shared(1)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/unet/config.json
+#[derive(Debug, Clone, serde::Deserialize)]
+struct UNetFile {
+    block_out_channels: Vec<usize>,
+    down_block_types: Vec<String>,
+    attention_head_dim: PerBlock,
+    #[serde(default = "one")]
+    transformer_layers_per_block: PerBlock,
+    #[serde(default)]
+    center_input_sample: bool,
+    cross_attention_dim: usize,
+    downsample_padding: usize,
+    #[serde(default = "default_true")]
+    flip_sin_to_cos: bool,
+    #[serde(default)]
+    freq_shift: f64,
+    layers_per_block: usize,
+    mid_block_scale_factor: f64,
+    norm_eps: f64,
+    norm_num_groups: usize,
+    #[serde(default)]
+    use_linear_projection: bool,
+    sample_size: Option<usize>,
+}
+
+impl UNetFile {
+    fn config(
+        &self,
+        sliced_attention_size: Option<usize>,
+    ) -> Result<unet_2d::UNet2DConditionModelConfig> {
+        if self.down_block_types.len() != self.block_out_channels.len() {
+            candle::bail!(
+                "unet config has {} down blocks but {} output channels",
+                self.down_block_types.len(),
+                self.block_out_channels.len()
+            )
+        }
+        let mut blocks = Vec::with_capacity(self.block_out_channels.len());
+        for (i, (&out_channels, block_type)) in self
+            .block_out_channels
+            .iter()
+            .zip(self.down_block_types.iter())
+            .enumerate()
+        {
+            let use_cross_attn = if block_type.starts_with("CrossAttn") {
+                Some(self.transformer_layers_per_block.get(i)?)
+            } else {
+                None
+            };
+            blocks.push(unet_2d::BlockConfig {
+                out_channels,
+                use_cross_attn,
+                attention_head_dim: self.attention_head_dim.get(i)?,
+            })
+        }
+        Ok(unet_2d::UNet2DConditionModelConfig {
+            blocks,
+            center_input_sample: self.center_input_sample,
+            cross_attention_dim: self.cross_attention_dim,
+            downsample_padding: self.downsample_padding,
+            flip_sin_to_cos: self.flip_sin_to_cos,
+            freq_shift: self.freq_shift,
+            layers_per_block: self.layers_per_block,
+            mid_block_scale_factor: self.mid_block_scale_factor,
+            norm_eps: self.norm_eps,
+            norm_num_groups: self.norm_num_groups,
+            sliced_attention_size,
+            use_linear_projection: self.use_linear_projection,
+        })
+    }
+}
+
+// https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/vae/config.json
+#[derive(Debug, Clone, serde::Deserialize)]
+struct VaeFile {
+    block_out_channels: Vec<usize>,
+    layers_per_block: usize,
+    latent_channels: usize,
+    norm_num_groups: usize,
+    sample_size: Option<usize>,
+}
+
+// https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/scheduler/scheduler_config.json
+// The fields that are not set keep the defaults of the scheduler configs.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct SchedulerFile {
+    #[serde(rename = "_class_name")]
+    class_name: String,
+    beta_start: Option<f64>,
+    beta_end: Option<f64>,
+    beta_schedule: Option<BetaSchedule>,
+    prediction_type: Option<PredictionType>,
+    #[serde(rename = "num_train_timesteps")]
+    train_timesteps: Option<usize>,
+    steps_offset: Option<usize>,
+    timestep_spacing: Option<TimestepSpacing>,
+    original_inference_steps: Option<usize>,
+    timestep_scaling: Option<f64>,
+}
+
+/// Overrides the fields shared by all the scheduler configs with the ones set in the file.
+macro_rules! set_common {
+    ($config:ident, $file:ident) => {
+        if let Some(v) = $file.beta_start {
+            $config.beta_start = v
+        }
+        if let Some(v) = $file.beta_end {
+            $config.beta_end = v
+        }
+        if let Some(v) = $file.beta_schedule {
+            $config.beta_schedule = v
+        }
+        if let Some(v) = $file.prediction_type {
+            $config.prediction_type = v
+        }
+        if let Some(v) = $file.train_timesteps {
+            $config.train_timesteps = v
+        }
+    };
+}
+
+impl SchedulerFile {
+    /// Schedulers that are not available fall back to DDIM with the same training parameters,
+    /// as done by the hardcoded configurations.
+    fn config(&self) -> Arc<dyn SchedulerConfig> {
+        let file = self;
+        match self.class_name.as_str() {
+            "DDPMScheduler" => {
+                let mut config = ddpm::DDPMSchedulerConfig::default();
+                set_common!(config, file);
+                Arc::new(config)
+            }
+            "EulerAncestralDiscreteScheduler" => {
+                let mut config =
+                    euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig::default();
+                set_common!(config, file);
+                if let Some(v) = file.steps_offset {
+                    config.steps_offset = v
+                }
+                if let Some(v) = file.timestep_spacing {
+                    config.timestep_spacing = v
+                }
+                Arc::new(config)
+            }
+            "LCMScheduler" => {
+                let mut config = lcm::LCMSchedulerConfig::default();
+                set_common!(config, file);
+                if let Some(v) = file.original_inference_steps {
+                    config.original_inference_steps = v
+                }
+                if let Some(v) = file.timestep_scaling {
+                    config.timestep_scaling = v
+                }
+                Arc::new(config)
+            }
+            class_name => {
+                if class_name != "DDIMScheduler" {
+                    tracing::warn!(class_name, "unsupported scheduler, using DDIM instead")
+                }
+                let mut config = ddim::DDIMSchedulerConfig::default();
+                set_common!(config, file);
+                if let Some(v) = file.steps_offset {
+                    config.steps_offset = v
+                }
+                if let Some(v) = file.timestep_spacing {
+                    config.timestep_spacing = v
+                }
+                Arc::new(config)
+            }
+        }
+    }
+}
+
+// https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/tokenizer_2/special_tokens_map.json
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+enum Token {
+    Content(String),
+    AddedToken { content: String },
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct SpecialTokensFile {
+    pad_token: Option<Token>,
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = std::fs::File::open(path).map_err(|e| candle::Error::from(e).with_path(path))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| candle::Error::wrap(e).with_path(path))
+}
+
+/// The text encoder config with the padding token of the matching tokenizer, padding uses EOS
+/// when the tokenizer pads with it or has no special tokens file.
+fn clip_config(dir: &Path, text_encoder: &str, tokenizer: &str) -> Result<clip::Config> {
+    let mut config: clip::Config = read_json(&dir.join(text_encoder).join("config.json"))?;
+    let special_tokens = dir.join(tokenizer).join("special_tokens_map.json");
+    if special_tokens.exists() {
+        let special_tokens: SpecialTokensFile = read_json(&special_tokens)?;
+        config.pad_with = match special_tokens.pad_token {
+            Some(Token::Content(content)) | Some(Token::AddedToken { content }) => {
+                Some(content).filter(|c| c != "<|endoftext|>")
+            }
+            None => None,
+        };
+    }
+    Ok(config)
+}
+
+pub(crate) fn load(
+    dir: &Path,
+    sliced_attention_size: Option<usize>,
+    height: Option<usize>,
+    width: Option<usize>,
+) -> Result<StableDiffusionConfig> {
+    let model_index: serde_json::Map<String, serde_json::Value> =
+        read_json(&dir.join("model_index.json"))?;
+    // Missing components are either absent or listed as `[null, null]`.
+    let has_component = |name: &str| match model_index.get(name) {
+        Some(serde_json::Value::Array(vs)) => vs.iter().all(|v| !v.is_null()),
+        Some(v) => !v.is_null(),
+        None => false,
+    };
+
+    let unet: UNetFile = read_json(&dir.join("unet").join("config.json"))?;
+    let vae: VaeFile = read_json(&dir.join("vae").join("config.json"))?;
+    let scheduler: SchedulerFile = read_json(&dir.join("scheduler").join("scheduler_config.json"))?;
+    let clip = clip_config(dir, "text_encoder", "tokenizer")?;
+    let clip2 = if has_component("text_encoder_2") {
+        Some(clip_config(dir, "text_encoder_2", "tokenizer_2")?)
+    } else {
+        None
+    };
+    let default_size = match (vae.sample_size, unet.sample_size) {
+        (Some(size), _) => size,
+        (None, Some(size)) => size * 8,
+        (None, None) => 512,
+    };
+    let autoencoder = vae::AutoEncoderKLConfig {
+        block_out_channels: vae.block_out_channels,
+        layers_per_block: vae.layers_per_block,
+        latent_channels: vae.latent_channels,
+        norm_num_groups: vae.norm_num_groups,
+        sliced_attention_size: None,
+    };
+    let scheduler = scheduler.config();
+    tracing::trace!(?dir, ?scheduler, "loaded the model index");
+
+    Ok(StableDiffusionConfig::from_parts(
+        (height, width),
+        default_size,
+        clip,
+        clip2,
+        autoencoder,
+        unet.config(sliced_attention_size)?,
+        scheduler,
+    ))
+}
@@ -0,0 +1,93 @@
+//! # MultiDiffusion
+//!
+//! Running the unet on latents larger than the ones it was trained on results in
+//! repeated objects and broken compositions. MultiDiffusion instead denoises
+//! overlapping windows of the training resolution and averages the overlapping
+//! regions after each denoising step so that the windows agree with each other.
+//!
+//! MultiDiffusion: Fusing Diffusion Paths for Controlled Image Generation, O. Bar-Tal et al, 2023.
+//! https://arxiv.org/abs/2302.08113
+use super::schedulers::Scheduler;
+use candle::{IndexOp, Result, Tensor};
+
+/// Splits the spatial dimensions of the latents in square overlapping windows.
+#[derive(Debug, Clone, Copy)]
+pub struct MultiDiffusion {
+    /// The side of the windows in latent pixels, e.g. 64 for a model trained on 512x512 images.
+    pub tile_size: usize,
+    /// The offset between two consecutive windows, the overlap is `tile_size - stride`.
+    pub stride: usize,
+}
+
+impl MultiDiffusion {
+    pub fn new(tile_size: usize, stride: usize) -> Result<Self> {
+        if stride == 0 || stride > tile_size {
+            candle::bail!("multidiffusion stride {stride} has to be between 1 and {tile_size}")
+        }
+        Ok(Self { tile_size, stride })
+    }
+
+    /// The start of each window along a dimension of the given size, the last window is
+    /// aligned with the end so that every position is covered.
+    fn window_starts(&self, size: usize) -> Vec<usize> {
+        if size <= self.tile_size {
+            return vec![0];
+        }
+        let last = size - self.tile_size;
+        let mut starts: Vec<usize> = (0..=last).step_by(self.stride).collect();
+        if starts.last() != Some(&last) {
+            starts.push(last)
+        }
+        starts
+    }
+
+    /// Applies `f` to each window of `latents`, a `(batch, channels, height, width)` tensor, and
+    /// averages the results where windows overlap. `f` has to return a tensor with the same shape
+    /// as its input.
+    pub fn forward<F>(&self, latents: &Tensor, mut f: F) -> Result<Tensor>
+    where
+        F: FnMut(&Tensor) -> Result<Tensor>,
+    {
+        let (b, c, h, w) = latents.dims4()?;
+        let tile_h = self.tile_size.min(h);
+        let tile_w = self.tile_size.min(w);
+        let mut sum = latents.zeros_like()?;
+        let mut counts = vec![0u32; h * w];
+        for &h0 in self.window_starts(h).iter() {
+            for &w0 in self.window_starts(w).iter() {
+                let (h1, w1) = (h0 + tile_h, w0 + tile_w);
+                let window = latents.i((.., .., h0..h1, w0..w1))?;
+                let ys = f(&window)?;
+                let acc = (sum.i((.., .., h0..h1, w0..w1))? + ys)?;
+                sum = sum.slice_assign(&[0..b, 0..c, h0..h1, w0..w1], &acc)?;
+                for i in h0..h1 {
+                    for count in counts[i * w + w0..i * w + w1].iter_mut() {
+                        *count += 1
+                    }
+                }
+            }
+        }
+        let counts =
+            Tensor::from_vec(counts, (h, w), latents.device())?.to_dtype(latents.dtype())?;
+        sum.broadcast_div(&counts)
+    }
+
+    /// Performs a denoising step: `unet` returns the noise prediction for a window of latents,
+    /// the scheduler step is applied per window and the resulting latents are averaged. Input
+    /// scaling and classifier free guidance should be handled in `unet`.
+    pub fn step<F>(
+        &self,
+        scheduler: &dyn Scheduler,
+        latents: &Tensor,
+        timestep: usize,
+        mut unet: F,
+    ) -> Result<Tensor>
+    where
+        F: FnMut(&Tensor) -> Result<Tensor>,
+    {
+        self.forward(latents, |window| {
+            let noise_pred = unet(window)?;
+            scheduler.step(&noise_pred, timestep, window)
+        })
+    }
+}
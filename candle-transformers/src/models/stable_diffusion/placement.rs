@@ -0,0 +1,54 @@
+//! # Device placement
+//!
+//! The vae only runs once to encode the initial image and once to decode the
+//! final latents whereas the unet runs on every denoising step, so on memory
+//! constrained setups the vae can be kept on the cpu with only the unet on the
+//! gpu. [`DevicePlacement`] moves the latents between the two devices around
+//! the denoising loop.
+use candle::{Device, Result, Tensor};
+
+/// The devices on which the vae and the unet weights are loaded.
+#[derive(Debug, Clone)]
+pub struct DevicePlacement {
+    pub vae: Device,
+    pub unet: Device,
+}
+
+impl DevicePlacement {
+    pub fn new(vae: Device, unet: Device) -> Self {
+        Self { vae, unet }
+    }
+
+    /// Runs both models on the same device, no transfers happen in this case.
+    pub fn single(device: &Device) -> Self {
+        Self::new(device.clone(), device.clone())
+    }
+
+    /// Moves `image` to the vae device, encodes it with `encode` and returns the resulting
+    /// latents on the unet device.
+    pub fn encode<F>(&self, image: &Tensor, encode: F) -> Result<Tensor>
+    where
+        F: FnOnce(&Tensor) -> Result<Tensor>,
+    {
+        let latents = encode(&image.to_device(&self.vae)?)?;
+        latents.to_device(&self.unet)
+    }
+
+    /// Moves `latents` to the unet device and runs the denoising loop `denoise` on them, the
+    /// returned latents stay on the unet device.
+    pub fn denoise<F>(&self, latents: &Tensor, denoise: F) -> Result<Tensor>
+    where
+        F: FnOnce(Tensor) -> Result<Tensor>,
+    {
+        denoise(latents.to_device(&self.unet)?)
+    }
+
+    /// Moves `latents` to the vae device and decodes them with `decode`, the returned images
+    /// are on the vae device.
+    pub fn decode<F>(&self, latents: &Tensor, decode: F) -> Result<Tensor>
+    where
+        F: FnOnce(&Tensor) -> Result<Tensor>,
+    {
+        decode(&latents.to_device(&self.vae)?)
+    }
+}
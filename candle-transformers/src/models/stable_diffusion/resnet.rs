@@ -4,11 +4,22 @@
 //!
 //! Denoising Diffusion Implicit Models, K. He and al, 2015.
 //! https://arxiv.org/abs/1512.03385
-use crate::models::with_tracing::{conv2d, Conv2d};
+use super::int8::{self, conv2d, Conv2d};
 use candle::{Result, Tensor, D};
 use candle_nn as nn;
 use candle_nn::Module;
 
+/// The memory layout of the activations inside the conv and norm layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryFormat {
+    /// `(b, c, h, w)`, the candle default.
+    #[default]
+    ChannelsFirst,
+    /// `(b, h, w, c)`, convolutions can be faster in this layout on some backends, e.g. with the
+    /// direct conv kernel on metal.
+    ChannelsLast,
+}
+
 /// Configuration for a ResNet block.
 #[derive(Debug, Clone, Copy)]
 pub struct ResnetBlock2DConfig {
@@ -27,6 +38,8 @@ pub struct ResnetBlock2DConfig {
     // non_linearity: silu
     /// The final output is scaled by dividing by this value.
     pub output_scale_factor: f64,
+    /// The layout used within the block, the input and output are channels-first either way.
+    pub memory_format: MemoryFormat,
 }
 
 impl Default for ResnetBlock2DConfig {
@@ -39,6 +52,7 @@ impl Default for ResnetBlock2DConfig {
             eps: 1e-6,
             use_in_shortcut: None,
             output_scale_factor: 1.,
+            memory_format: MemoryFormat::ChannelsFirst,
         }
     }
 }
@@ -49,7 +63,7 @@ pub struct ResnetBlock2D {
     conv1: Conv2d,
     norm2: nn::GroupNorm,
     conv2: Conv2d,
-    time_emb_proj: Option<nn::Linear>,
+    time_emb_proj: Option<int8::Linear>,
     conv_shortcut: Option<Conv2d>,
     span: tracing::Span,
     config: ResnetBlock2DConfig,
@@ -95,7 +109,7 @@ impl ResnetBlock2D {
         };
         let time_emb_proj = match config.temb_channels {
             None => None,
-            Some(temb_channels) => Some(nn::linear(
+            Some(temb_channels) => Some(int8::linear(
                 temb_channels,
                 out_channels,
                 vs.pp("time_emb_proj"),
@@ -116,6 +130,9 @@ impl ResnetBlock2D {
 
     pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
+        if self.config.memory_format == MemoryFormat::ChannelsLast {
+            return self.forward_channels_last(xs, temb);
+        }
         let shortcut_xs = match &self.conv_shortcut {
             Some(conv_shortcut) => conv_shortcut.forward(xs)?,
             None => xs.clone(),
@@ -135,4 +152,27 @@ impl ResnetBlock2D {
             .forward(&nn::ops::silu(&self.norm2.forward(&xs)?)?)?;
         (shortcut_xs + xs)? / self.config.output_scale_factor
     }
+
+    /// The input is converted to `(b, h, w, c)` once and all the layers of the block run in this
+    /// layout, the output is a channels-first view of the channels-last result.
+    fn forward_channels_last(&self, xs: &Tensor, temb: Option<&Tensor>) -> Result<Tensor> {
+        let xs = xs.permute((0, 2, 3, 1))?.contiguous()?;
+        let shortcut_xs = match &self.conv_shortcut {
+            Some(conv_shortcut) => conv_shortcut.forward_channels_last(&xs)?,
+            None => xs.clone(),
+        };
+        let xs = self.norm1.forward_channels_last(&xs)?;
+        let xs = self.conv1.forward_channels_last(&nn::ops::silu(&xs)?)?;
+        let xs = match (temb, &self.time_emb_proj) {
+            (Some(temb), Some(time_emb_proj)) => time_emb_proj
+                .forward(&nn::ops::silu(temb)?)?
+                .unsqueeze(1)?
+                .unsqueeze(1)?
+                .broadcast_add(&xs)?,
+            _ => xs,
+        };
+        let xs = nn::ops::silu(&self.norm2.forward_channels_last(&xs)?)?;
+        let xs = self.conv2.forward_channels_last(&xs)?;
+        ((shortcut_xs + xs)? / self.config.output_scale_factor)?.permute((0, 3, 1, 2))
+    }
 }
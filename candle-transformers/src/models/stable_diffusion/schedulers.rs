@@ -3,28 +3,285 @@
 //!
 //! Noise schedulers can be used to set the trade-off between
 //! inference speed and quality.
-use candle::{Result, Tensor};
+use candle::{DType, Result, Tensor};
+use std::sync::Arc;
 
 pub trait SchedulerConfig: std::fmt::Debug + Send + Sync {
     fn build(&self, inference_steps: usize) -> Result<Box<dyn Scheduler>>;
+
+    /// What the model output predicts, this is a property of the model rather than of the
+    /// scheduler so it is kept when switching schedulers.
+    fn prediction_type(&self) -> PredictionType;
+}
+
+/// The schedulers that can be selected by name, e.g. from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampler {
+    Ddim,
+    Ddpm,
+    EulerAncestral,
+    Lcm,
+}
+
+impl Sampler {
+    /// The scheduler configuration with the default parameters of this sampler.
+    pub fn config(&self, prediction_type: PredictionType) -> Arc<dyn SchedulerConfig> {
+        match self {
+            Self::Ddim => Arc::new(super::ddim::DDIMSchedulerConfig {
+                prediction_type,
+                ..Default::default()
+            }),
+            Self::Ddpm => Arc::new(super::ddpm::DDPMSchedulerConfig {
+                prediction_type,
+                ..Default::default()
+            }),
+            Self::EulerAncestral => Arc::new(
+                super::euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig {
+                    prediction_type,
+                    ..Default::default()
+                },
+            ),
+            Self::Lcm => Arc::new(super::lcm::LCMSchedulerConfig {
+                prediction_type,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+impl std::str::FromStr for Sampler {
+    type Err = candle::Error;
+
+    /// Parses `DDIM`, `DDPM`, `EULER_ANCESTRAL` or `LCM`, ignoring the case.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "DDIM" => Ok(Self::Ddim),
+            "DDPM" => Ok(Self::Ddpm),
+            "EULER_ANCESTRAL" => Ok(Self::EulerAncestral),
+            "LCM" => Ok(Self::Lcm),
+            _ => candle::bail!(
+                "unknown sampler {s}, expected one of DDIM, DDPM, EULER_ANCESTRAL or LCM"
+            ),
+        }
+    }
 }
 
 /// This trait represents a scheduler for the diffusion process.
+///
+/// The schedule constants are kept in `f64` and the tensor operations run in the dtype of the
+/// latents, the model output and the noise are converted to it so that e.g. f16 latents can be
+/// used with a model running in f32.
 pub trait Scheduler {
     fn timesteps(&self) -> &[usize];
 
-    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor>;
+    /// Noises the clean latents `original` to the level of `timestep`, e.g. to start img2img or
+    /// inpainting from an encoded image rather than from pure noise. This is
+    /// `sqrt(alpha_prod) * original + sqrt(1 - alpha_prod) * noise` with the cumulative alpha
+    /// product of `timestep`, Euler schedulers instead add `sigma * noise` at one of their
+    /// timesteps.
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, timestep: usize) -> Result<Tensor>;
 
+    /// The standard deviation of the initial noise, the initial latents have to be scaled by
+    /// this factor, e.g. Euler schedulers use sigmas rather than unit variance noise.
     fn init_noise_sigma(&self) -> f64;
 
+    /// Scales the denoising model input for the given timestep, this has to be applied on each
+    /// step before calling the model, e.g. Euler schedulers divide by `sqrt(sigma^2 + 1)`.
     fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Result<Tensor>;
 
     fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor>;
+
+    /// Replaces the timesteps derived from the number of inference steps with custom ones, e.g.
+    /// Karras spaced or hand-tuned timesteps. These have to be strictly decreasing and below the
+    /// number of training steps, each step then denoises from a timestep to the next one.
+    fn set_timesteps(&mut self, timesteps: &[usize]) -> Result<()> {
+        candle::bail!("custom timesteps {timesteps:?} are not supported by this scheduler")
+    }
+
+    /// Changes how the model outputs passed to the next steps are interpreted. This is only
+    /// needed when switching models mid-trajectory, e.g. handing off from an epsilon base model
+    /// to a v-prediction refiner, the prediction type of the scheduler config should be used
+    /// otherwise.
+    fn set_prediction_type(&mut self, prediction_type: PredictionType) -> Result<()> {
+        candle::bail!("switching to {prediction_type:?} is not supported by this scheduler")
+    }
+}
+
+/// Checks that custom timesteps are strictly decreasing and below `train_timesteps`.
+pub(crate) fn check_timesteps(timesteps: &[usize], train_timesteps: usize) -> Result<()> {
+    if timesteps.is_empty() {
+        candle::bail!("the custom timesteps cannot be empty")
+    }
+    if timesteps.windows(2).any(|w| w[0] <= w[1]) {
+        candle::bail!("the custom timesteps {timesteps:?} have to be strictly decreasing")
+    }
+    if timesteps[0] >= train_timesteps {
+        candle::bail!(
+            "the custom timestep {} has to be below the {train_timesteps} training steps",
+            timesteps[0]
+        )
+    }
+    Ok(())
+}
+
+/// The timestep following `timestep` in `timesteps`, `None` on the last step.
+pub(crate) fn next_timestep(timesteps: &[usize], timestep: usize) -> Option<usize> {
+    timesteps
+        .iter()
+        .position(|&t| t == timestep)
+        .and_then(|i| timesteps.get(i + 1))
+        .copied()
+}
+
+/// Runs the denoising loop over all the scheduler timesteps starting from `latents`, which should
+/// already be scaled by [`Scheduler::init_noise_sigma`]. `model` is called with the scaled model
+/// input and the timestep and returns the noise prediction.
+///
+/// Returns the final latents, and when `keep_intermediates` is set the latents before the first
+/// step and after each step, so `timesteps().len() + 1` tensors, e.g. for latent interpolation.
+/// These are not kept by default as they hold onto the memory of every step.
+///
+/// The last `final_fp32_steps` steps upcast the latents to f32 so that the scheduler math runs in
+/// full precision, which reduces the error accumulated when denoising in f16. The model is still
+/// called with inputs in the dtype of `latents` and the returned latents are then in f32.
+pub fn denoise<F>(
+    scheduler: &dyn Scheduler,
+    latents: Tensor,
+    keep_intermediates: bool,
+    final_fp32_steps: usize,
+    model: F,
+) -> Result<(Tensor, Vec<Tensor>)>
+where
+    F: FnMut(&Tensor, usize) -> Result<Tensor>,
+{
+    denoise_(
+        scheduler,
+        latents,
+        None,
+        keep_intermediates,
+        final_fp32_steps,
+        model,
+    )
+}
+
+/// A model input buffer allocated once and overwritten by each denoising step, this replaces
+/// the per step concatenation of the latents for classifier-free guidance. Only the model input
+/// is reused, the scaling of the model input, the model itself, the guidance and the scheduler
+/// step still return new tensors.
+#[derive(Debug, Clone)]
+pub struct ModelInputBuffer {
+    model_input: Tensor,
+    copies: usize,
+}
+
+impl ModelInputBuffer {
+    /// Allocates the model input for `latents` repeated `copies` times along the batch dimension,
+    /// e.g. 2 with classifier-free guidance where the model is run on the unconditional and the
+    /// text conditioned inputs at once.
+    pub fn new(latents: &Tensor, copies: usize) -> Result<Self> {
+        if copies == 0 {
+            candle::bail!("the number of copies of the latents in the model input cannot be 0")
+        }
+        let model_input = Self::alloc(latents, latents.dtype(), copies)?;
+        Ok(Self {
+            model_input,
+            copies,
+        })
+    }
+
+    fn alloc(latents: &Tensor, dtype: DType, copies: usize) -> Result<Tensor> {
+        let mut dims = latents.dims().to_vec();
+        dims[0] *= copies;
+        Tensor::zeros(dims, dtype, latents.device())
+    }
+
+    /// Copies `latents` in each slot of the buffer and returns the buffer, this is the same as
+    /// concatenating `copies` times `latents` along the batch dimension. The buffer is only
+    /// reallocated when the latents dtype changes, e.g. once when the final f32 steps start.
+    ///
+    /// The returned tensor is overwritten by the next call, use [`Tensor::copy`] to keep it.
+    pub fn fill(&mut self, latents: &Tensor) -> Result<&Tensor> {
+        if self.model_input.dtype() != latents.dtype() {
+            self.model_input = Self::alloc(latents, latents.dtype(), self.copies)?
+        }
+        let latents = latents.contiguous()?;
+        let b_size = latents.dim(0)?;
+        for copy in 0..self.copies {
+            self.model_input.slice_set(&latents, 0, copy * b_size)?
+        }
+        Ok(&self.model_input)
+    }
+}
+
+/// Same as [`denoise`] but the model input is written in `input_buffer` rather than allocated on
+/// every step, see [`ModelInputBuffer`]. When the buffer holds several copies of the latents,
+/// `model` gets them concatenated along the batch dimension and should combine its predictions
+/// back into a single noise prediction with the batch size of `latents`, e.g. for
+/// classifier-free guidance.
+///
+/// The input of `model` is overwritten by the next step, use [`Tensor::copy`] to keep it.
+pub fn denoise_with_input_buffer<F>(
+    scheduler: &dyn Scheduler,
+    latents: Tensor,
+    input_buffer: &mut ModelInputBuffer,
+    keep_intermediates: bool,
+    final_fp32_steps: usize,
+    model: F,
+) -> Result<(Tensor, Vec<Tensor>)>
+where
+    F: FnMut(&Tensor, usize) -> Result<Tensor>,
+{
+    denoise_(
+        scheduler,
+        latents,
+        Some(input_buffer),
+        keep_intermediates,
+        final_fp32_steps,
+        model,
+    )
+}
+
+fn denoise_<F>(
+    scheduler: &dyn Scheduler,
+    latents: Tensor,
+    mut input_buffer: Option<&mut ModelInputBuffer>,
+    keep_intermediates: bool,
+    final_fp32_steps: usize,
+    mut model: F,
+) -> Result<(Tensor, Vec<Tensor>)>
+where
+    F: FnMut(&Tensor, usize) -> Result<Tensor>,
+{
+    let model_dtype = latents.dtype();
+    let timesteps = scheduler.timesteps();
+    let fp32_from = timesteps.len().saturating_sub(final_fp32_steps);
+    let mut intermediates = vec![];
+    let mut latents = latents;
+    for (index, &timestep) in timesteps.iter().enumerate() {
+        if index == fp32_from {
+            latents = latents.to_dtype(DType::F32)?
+        }
+        if keep_intermediates {
+            intermediates.push(latents.clone())
+        }
+        let model_input = match input_buffer.as_deref_mut() {
+            None => latents.clone(),
+            Some(input_buffer) => input_buffer.fill(&latents)?.clone(),
+        };
+        let model_input = scheduler.scale_model_input(model_input, timestep)?;
+        let noise_pred = model(&model_input.to_dtype(model_dtype)?, timestep)?;
+        latents = scheduler.step(&noise_pred, timestep, &latents)?;
+    }
+    if keep_intermediates {
+        intermediates.push(latents.clone())
+    }
+    Ok((latents, intermediates))
 }
 
 /// This represents how beta ranges from its minimum value to the maximum
 /// during training.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BetaSchedule {
     /// Linear interpolation.
     Linear,
@@ -34,7 +291,40 @@ pub enum BetaSchedule {
     SquaredcosCapV2,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl BetaSchedule {
+    /// Returns the betas used during training for each of the `train_timesteps` steps.
+    pub fn betas(&self, beta_start: f64, beta_end: f64, train_timesteps: usize) -> Result<Tensor> {
+        match self {
+            Self::ScaledLinear => {
+                super::utils::linspace(beta_start.sqrt(), beta_end.sqrt(), train_timesteps)?.sqr()
+            }
+            Self::Linear => super::utils::linspace(beta_start, beta_end, train_timesteps),
+            Self::SquaredcosCapV2 => betas_for_alpha_bar(train_timesteps, 0.999),
+        }
+    }
+
+    /// Returns the cumulative product of `(1-beta)` for each of the training steps, this is
+    /// shared by all the schedulers.
+    pub fn alphas_cumprod(
+        &self,
+        beta_start: f64,
+        beta_end: f64,
+        train_timesteps: usize,
+    ) -> Result<Vec<f64>> {
+        let betas = self
+            .betas(beta_start, beta_end, train_timesteps)?
+            .to_vec1::<f64>()?;
+        let mut alphas_cumprod = Vec::with_capacity(betas.len());
+        for &beta in betas.iter() {
+            let alpha = 1.0 - beta;
+            alphas_cumprod.push(alpha * *alphas_cumprod.last().unwrap_or(&1f64))
+        }
+        Ok(alphas_cumprod)
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PredictionType {
     Epsilon,
     VPrediction,
@@ -44,7 +334,8 @@ pub enum PredictionType {
 /// Time step spacing for the diffusion process.
 ///
 /// "linspace", "leading", "trailing" corresponds to annotation of Table 2. of https://arxiv.org/abs/2305.08891
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TimestepSpacing {
     Leading,
     Linspace,
@@ -63,13 +354,11 @@ impl Default for TimestepSpacing {
 /// Contains a function `alpha_bar` that takes an argument `t` and transforms it to the cumulative product of `(1-beta)`
 /// up to that part of the diffusion process.
 pub(crate) fn betas_for_alpha_bar(num_diffusion_timesteps: usize, max_beta: f64) -> Result<Tensor> {
-    let alpha_bar = |time_step: usize| {
-        f64::cos((time_step as f64 + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).powi(2)
-    };
+    let alpha_bar = |t: f64| f64::cos((t + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).powi(2);
     let mut betas = Vec::with_capacity(num_diffusion_timesteps);
     for i in 0..num_diffusion_timesteps {
-        let t1 = i / num_diffusion_timesteps;
-        let t2 = (i + 1) / num_diffusion_timesteps;
+        let t1 = i as f64 / num_diffusion_timesteps as f64;
+        let t2 = (i + 1) as f64 / num_diffusion_timesteps as f64;
         betas.push((1.0 - alpha_bar(t2) / alpha_bar(t1)).min(max_beta));
     }
     let betas_len = betas.len();
@@ -3,8 +3,9 @@
 //! The 2D Unet models take as input a noisy sample and the current diffusion
 //! timestep and return a denoised version of the input.
 use super::embeddings::{TimestepEmbedding, Timesteps};
+use super::int8::{conv2d, Conv2d};
+use super::resnet::MemoryFormat;
 use super::unet_2d_blocks::*;
-use crate::models::with_tracing::{conv2d, Conv2d};
 use candle::{Result, Tensor};
 use candle_nn as nn;
 use candle_nn::Module;
@@ -30,6 +31,10 @@ pub struct UNet2DConditionModelConfig {
     pub norm_num_groups: usize,
     pub norm_eps: f64,
     pub cross_attention_dim: usize,
+    /// When set, the cross-attention layers compute the scores over chunks of this many queries
+    /// at a time rather than for the whole sequence at once. This is a number of queries, not a
+    /// number of `batch * heads` slices as in diffusers. 0 enables automatic slicing, each block
+    /// then uses slices of half its `attention_head_dim` queries.
     pub sliced_attention_size: Option<usize>,
     pub use_linear_projection: bool,
 }
@@ -80,6 +85,21 @@ pub(crate) enum UNetDownBlock {
     CrossAttn(CrossAttnDownBlock2D),
 }
 
+impl UNetDownBlock {
+    /// Returns the block output and the residuals for the skip connections.
+    pub(crate) fn forward(
+        &self,
+        xs: &Tensor,
+        emb: &Tensor,
+        encoder_hidden_states: &Tensor,
+    ) -> Result<(Tensor, Vec<Tensor>)> {
+        match self {
+            Self::Basic(b) => b.forward(xs, Some(emb)),
+            Self::CrossAttn(b) => b.forward(xs, Some(emb), Some(encoder_hidden_states)),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum UNetUpBlock {
     Basic(UpBlock2D),
@@ -101,17 +121,19 @@ pub struct UNet2DConditionModel {
 }
 
 impl UNet2DConditionModel {
+    /// `memory_format` is the layout used by the resnet blocks, see [`MemoryFormat`], the inputs
+    /// and outputs are channels-first either way.
     pub fn new(
         vs: nn::VarBuilder,
         in_channels: usize,
         out_channels: usize,
         use_flash_attn: bool,
+        memory_format: MemoryFormat,
         config: UNet2DConditionModelConfig,
     ) -> Result<Self> {
         let n_blocks = config.blocks.len();
         let b_channels = config.blocks[0].out_channels;
         let bl_channels = config.blocks.last().unwrap().out_channels;
-        let bl_attention_head_dim = config.blocks.last().unwrap().attention_head_dim;
         let time_embed_dim = b_channels * 4;
         let conv_cfg = nn::Conv2dConfig {
             padding: 1,
@@ -123,87 +145,19 @@ impl UNet2DConditionModel {
         let time_embedding =
             TimestepEmbedding::new(vs.pp("time_embedding"), b_channels, time_embed_dim)?;
 
-        let vs_db = vs.pp("down_blocks");
-        let down_blocks = (0..n_blocks)
-            .map(|i| {
-                let BlockConfig {
-                    out_channels,
-                    use_cross_attn,
-                    attention_head_dim,
-                } = config.blocks[i];
-
-                // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
-                let sliced_attention_size = match config.sliced_attention_size {
-                    Some(0) => Some(attention_head_dim / 2),
-                    _ => config.sliced_attention_size,
-                };
-
-                let in_channels = if i > 0 {
-                    config.blocks[i - 1].out_channels
-                } else {
-                    b_channels
-                };
-                let db_cfg = DownBlock2DConfig {
-                    num_layers: config.layers_per_block,
-                    resnet_eps: config.norm_eps,
-                    resnet_groups: config.norm_num_groups,
-                    add_downsample: i < n_blocks - 1,
-                    downsample_padding: config.downsample_padding,
-                    ..Default::default()
-                };
-                if let Some(transformer_layers_per_block) = use_cross_attn {
-                    let config = CrossAttnDownBlock2DConfig {
-                        downblock: db_cfg,
-                        attn_num_head_channels: attention_head_dim,
-                        cross_attention_dim: config.cross_attention_dim,
-                        sliced_attention_size,
-                        use_linear_projection: config.use_linear_projection,
-                        transformer_layers_per_block,
-                    };
-                    let block = CrossAttnDownBlock2D::new(
-                        vs_db.pp(i.to_string()),
-                        in_channels,
-                        out_channels,
-                        Some(time_embed_dim),
-                        use_flash_attn,
-                        config,
-                    )?;
-                    Ok(UNetDownBlock::CrossAttn(block))
-                } else {
-                    let block = DownBlock2D::new(
-                        vs_db.pp(i.to_string()),
-                        in_channels,
-                        out_channels,
-                        Some(time_embed_dim),
-                        db_cfg,
-                    )?;
-                    Ok(UNetDownBlock::Basic(block))
-                }
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        // https://github.com/huggingface/diffusers/blob/a76f2ad538e73b34d5fe7be08c8eb8ab38c7e90c/src/diffusers/models/unet_2d_condition.py#L462
-        let mid_transformer_layers_per_block = match config.blocks.last() {
-            None => 1,
-            Some(block) => block.use_cross_attn.unwrap_or(1),
-        };
-        let mid_cfg = UNetMidBlock2DCrossAttnConfig {
-            resnet_eps: config.norm_eps,
-            output_scale_factor: config.mid_block_scale_factor,
-            cross_attn_dim: config.cross_attention_dim,
-            attn_num_head_channels: bl_attention_head_dim,
-            resnet_groups: Some(config.norm_num_groups),
-            use_linear_projection: config.use_linear_projection,
-            transformer_layers_per_block: mid_transformer_layers_per_block,
-            ..Default::default()
-        };
-
-        let mid_block = UNetMidBlock2DCrossAttn::new(
+        let down_blocks = build_down_blocks(
+            vs.pp("down_blocks"),
+            &config,
+            time_embed_dim,
+            use_flash_attn,
+            memory_format,
+        )?;
+        let mid_block = build_mid_block(
             vs.pp("mid_block"),
-            bl_channels,
-            Some(time_embed_dim),
+            &config,
+            time_embed_dim,
             use_flash_attn,
-            mid_cfg,
+            memory_format,
         )?;
 
         let vs_ub = vs.pp("up_blocks");
@@ -217,7 +171,7 @@ impl UNet2DConditionModel {
 
                 // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
                 let sliced_attention_size = match config.sliced_attention_size {
-                    Some(0) => Some(attention_head_dim / 2),
+                    Some(0) => Some(usize::max(attention_head_dim / 2, 1)),
                     _ => config.sliced_attention_size,
                 };
 
@@ -239,6 +193,7 @@ impl UNet2DConditionModel {
                     resnet_eps: config.norm_eps,
                     resnet_groups: config.norm_num_groups,
                     add_upsample: i < n_blocks - 1,
+                    memory_format,
                     ..Default::default()
                 };
                 if let Some(transformer_layers_per_block) = use_cross_attn {
@@ -337,18 +292,20 @@ impl UNet2DConditionModel {
         let mut down_block_res_xs = vec![xs.clone()];
         let mut xs = xs;
         for down_block in self.down_blocks.iter() {
-            let (_xs, res_xs) = match down_block {
-                UNetDownBlock::Basic(b) => b.forward(&xs, Some(&emb))?,
-                UNetDownBlock::CrossAttn(b) => {
-                    b.forward(&xs, Some(&emb), Some(encoder_hidden_states))?
-                }
-            };
+            let (_xs, res_xs) = down_block.forward(&xs, &emb, encoder_hidden_states)?;
             down_block_res_xs.extend(res_xs);
             xs = _xs;
         }
 
         let new_down_block_res_xs =
             if let Some(down_block_additional_residuals) = down_block_additional_residuals {
+                if down_block_additional_residuals.len() != down_block_res_xs.len() {
+                    candle::bail!(
+                        "expected {} down block residuals, got {}",
+                        down_block_res_xs.len(),
+                        down_block_additional_residuals.len()
+                    )
+                }
                 let mut v = vec![];
                 // A previous version of this code had a bug because of the addition being made
                 // in place via += hence modifying the input of the mid block.
@@ -399,3 +356,109 @@ impl UNet2DConditionModel {
         self.conv_out.forward(&xs)
     }
 }
+
+/// Builds the down blocks shared by the unet and the ControlNet.
+pub(crate) fn build_down_blocks(
+    vs_db: nn::VarBuilder,
+    config: &UNet2DConditionModelConfig,
+    time_embed_dim: usize,
+    use_flash_attn: bool,
+    memory_format: MemoryFormat,
+) -> Result<Vec<UNetDownBlock>> {
+    let n_blocks = config.blocks.len();
+    let b_channels = config.blocks[0].out_channels;
+    (0..n_blocks)
+        .map(|i| {
+            let BlockConfig {
+                out_channels,
+                use_cross_attn,
+                attention_head_dim,
+            } = config.blocks[i];
+
+            // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
+            let sliced_attention_size = match config.sliced_attention_size {
+                Some(0) => Some(usize::max(attention_head_dim / 2, 1)),
+                _ => config.sliced_attention_size,
+            };
+
+            let in_channels = if i > 0 {
+                config.blocks[i - 1].out_channels
+            } else {
+                b_channels
+            };
+            let db_cfg = DownBlock2DConfig {
+                num_layers: config.layers_per_block,
+                resnet_eps: config.norm_eps,
+                resnet_groups: config.norm_num_groups,
+                add_downsample: i < n_blocks - 1,
+                downsample_padding: config.downsample_padding,
+                memory_format,
+                ..Default::default()
+            };
+            if let Some(transformer_layers_per_block) = use_cross_attn {
+                let config = CrossAttnDownBlock2DConfig {
+                    downblock: db_cfg,
+                    attn_num_head_channels: attention_head_dim,
+                    cross_attention_dim: config.cross_attention_dim,
+                    sliced_attention_size,
+                    use_linear_projection: config.use_linear_projection,
+                    transformer_layers_per_block,
+                };
+                let block = CrossAttnDownBlock2D::new(
+                    vs_db.pp(i.to_string()),
+                    in_channels,
+                    out_channels,
+                    Some(time_embed_dim),
+                    use_flash_attn,
+                    config,
+                )?;
+                Ok(UNetDownBlock::CrossAttn(block))
+            } else {
+                let block = DownBlock2D::new(
+                    vs_db.pp(i.to_string()),
+                    in_channels,
+                    out_channels,
+                    Some(time_embed_dim),
+                    db_cfg,
+                )?;
+                Ok(UNetDownBlock::Basic(block))
+            }
+        })
+        .collect::<Result<Vec<_>>>()
+}
+
+/// Builds the mid block shared by the unet and the ControlNet.
+pub(crate) fn build_mid_block(
+    vs: nn::VarBuilder,
+    config: &UNet2DConditionModelConfig,
+    time_embed_dim: usize,
+    use_flash_attn: bool,
+    memory_format: MemoryFormat,
+) -> Result<UNetMidBlock2DCrossAttn> {
+    let bl_channels = config.blocks.last().unwrap().out_channels;
+    let bl_attention_head_dim = config.blocks.last().unwrap().attention_head_dim;
+    // https://github.com/huggingface/diffusers/blob/a76f2ad538e73b34d5fe7be08c8eb8ab38c7e90c/src/diffusers/models/unet_2d_condition.py#L462
+    let mid_transformer_layers_per_block = match config.blocks.last() {
+        None => 1,
+        Some(block) => block.use_cross_attn.unwrap_or(1),
+    };
+    let mid_cfg = UNetMidBlock2DCrossAttnConfig {
+        resnet_eps: config.norm_eps,
+        output_scale_factor: config.mid_block_scale_factor,
+        cross_attn_dim: config.cross_attention_dim,
+        attn_num_head_channels: bl_attention_head_dim,
+        resnet_groups: Some(config.norm_num_groups),
+        use_linear_projection: config.use_linear_projection,
+        transformer_layers_per_block: mid_transformer_layers_per_block,
+        memory_format,
+        ..Default::default()
+    };
+
+    UNetMidBlock2DCrossAttn::new(
+        vs,
+        bl_channels,
+        Some(time_embed_dim),
+        use_flash_attn,
+        mid_cfg,
+    )
+}
@@ -3,8 +3,8 @@
 use super::attention::{
     AttentionBlock, AttentionBlockConfig, SpatialTransformer, SpatialTransformerConfig,
 };
-use super::resnet::{ResnetBlock2D, ResnetBlock2DConfig};
-use crate::models::with_tracing::{conv2d, Conv2d};
+use super::int8::{conv2d, Conv2d};
+use super::resnet::{MemoryFormat, ResnetBlock2D, ResnetBlock2DConfig};
 use candle::{Module, Result, Tensor, D};
 use candle_nn as nn;
 
@@ -278,6 +278,7 @@ pub struct UNetMidBlock2DConfig {
     pub attn_num_head_channels: Option<usize>,
     // attention_type "default"
     pub output_scale_factor: f64,
+    pub sliced_attention_size: Option<usize>,
 }
 
 impl Default for UNetMidBlock2DConfig {
@@ -288,6 +289,7 @@ impl Default for UNetMidBlock2DConfig {
             resnet_groups: Some(32),
             attn_num_head_channels: Some(1),
             output_scale_factor: 1.,
+            sliced_attention_size: None,
         }
     }
 }
@@ -325,6 +327,7 @@ impl UNetMidBlock2D {
             num_groups: resnet_groups,
             rescale_output_factor: config.output_scale_factor,
             eps: config.resnet_eps,
+            sliced_attention_size: config.sliced_attention_size,
         };
         let mut attn_resnets = vec![];
         for index in 0..config.num_layers {
@@ -367,6 +370,7 @@ pub struct UNetMidBlock2DCrossAttnConfig {
     pub sliced_attention_size: Option<usize>,
     pub use_linear_projection: bool,
     pub transformer_layers_per_block: usize,
+    pub memory_format: MemoryFormat,
 }
 
 impl Default for UNetMidBlock2DCrossAttnConfig {
@@ -381,6 +385,7 @@ impl Default for UNetMidBlock2DCrossAttnConfig {
             sliced_attention_size: None, // Sliced attention disabled
             use_linear_projection: false,
             transformer_layers_per_block: 1,
+            memory_format: MemoryFormat::ChannelsFirst,
         }
     }
 }
@@ -411,6 +416,7 @@ impl UNetMidBlock2DCrossAttn {
             groups: resnet_groups,
             output_scale_factor: config.output_scale_factor,
             temb_channels,
+            memory_format: config.memory_format,
             ..Default::default()
         };
         let resnet = ResnetBlock2D::new(vs_resnets.pp("0"), in_channels, resnet_cfg)?;
@@ -473,6 +479,7 @@ pub struct DownBlock2DConfig {
     pub output_scale_factor: f64,
     pub add_downsample: bool,
     pub downsample_padding: usize,
+    pub memory_format: MemoryFormat,
 }
 
 impl Default for DownBlock2DConfig {
@@ -484,6 +491,7 @@ impl Default for DownBlock2DConfig {
             output_scale_factor: 1.,
             add_downsample: true,
             downsample_padding: 1,
+            memory_format: MemoryFormat::ChannelsFirst,
         }
     }
 }
@@ -508,8 +516,10 @@ impl DownBlock2D {
         let resnet_cfg = ResnetBlock2DConfig {
             out_channels: Some(out_channels),
             eps: config.resnet_eps,
+            groups: config.resnet_groups,
             output_scale_factor: config.output_scale_factor,
             temb_channels,
+            memory_format: config.memory_format,
             ..Default::default()
         };
         let resnets = (0..config.num_layers)
@@ -672,6 +682,7 @@ pub struct UpBlock2DConfig {
     pub resnet_groups: usize,
     pub output_scale_factor: f64,
     pub add_upsample: bool,
+    pub memory_format: MemoryFormat,
 }
 
 impl Default for UpBlock2DConfig {
@@ -682,6 +693,7 @@ impl Default for UpBlock2DConfig {
             resnet_groups: 32,
             output_scale_factor: 1.,
             add_upsample: true,
+            memory_format: MemoryFormat::ChannelsFirst,
         }
     }
 }
@@ -708,7 +720,9 @@ impl UpBlock2D {
             out_channels: Some(out_channels),
             temb_channels,
             eps: config.resnet_eps,
+            groups: config.resnet_groups,
             output_scale_factor: config.output_scale_factor,
+            memory_format: config.memory_format,
             ..Default::default()
         };
         let resnets = (0..config.num_layers)
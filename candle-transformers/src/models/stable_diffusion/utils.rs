@@ -1,4 +1,4 @@
-use candle::{Device, Result, Tensor};
+use candle::{DType, Device, Result, Tensor};
 
 pub fn linspace(start: f64, stop: f64, steps: usize) -> Result<Tensor> {
     if steps == 0 {
@@ -59,3 +59,23 @@ pub fn interp(x: &[f64], xp: &[f64], fp: &[f64]) -> Vec<f64> {
     let mut interpolator = LinearInterpolator { xp, fp, cache: 0 };
     x.iter().map(|&x| interpolator.eval(x)).collect()
 }
+
+/// Spherical linear interpolation between `a` and `b`, treating the latents as flattened vectors.
+/// Unlike a linear interpolation this preserves the norm of gaussian noise and so avoids the
+/// washed out midpoints. Falls back to a linear interpolation when `a` and `b` are almost
+/// collinear.
+pub fn slerp(a: &Tensor, b: &Tensor, t: f64) -> Result<Tensor> {
+    const DOT_THRESHOLD: f64 = 0.9995;
+    let to_f64 = |xs: Tensor| xs.to_dtype(DType::F64)?.to_scalar::<f64>();
+    let norm_a = to_f64(a.sqr()?.sum_all()?.sqrt()?)?;
+    let norm_b = to_f64(b.sqr()?.sum_all()?.sqrt()?)?;
+    let dot = to_f64((a * b)?.sum_all()?)? / (norm_a * norm_b);
+    if dot.abs() > DOT_THRESHOLD {
+        return (a * (1. - t))? + (b * t)?;
+    }
+    let theta = dot.acos();
+    let sin_theta = theta.sin();
+    let s_a = ((1. - t) * theta).sin() / sin_theta;
+    let s_b = (t * theta).sin() / sin_theta;
+    (a * s_a)? + (b * s_b)?
+}
@@ -19,6 +19,7 @@ struct EncoderConfig {
     layers_per_block: usize,
     norm_num_groups: usize,
     double_z: bool,
+    sliced_attention_size: Option<usize>,
 }
 
 impl Default for EncoderConfig {
@@ -28,6 +29,7 @@ impl Default for EncoderConfig {
             layers_per_block: 2,
             norm_num_groups: 32,
             double_z: true,
+            sliced_attention_size: None,
         }
     }
 }
@@ -93,6 +95,7 @@ impl Encoder {
             output_scale_factor: 1.,
             attn_num_head_channels: None,
             resnet_groups: Some(config.norm_num_groups),
+            sliced_attention_size: config.sliced_attention_size,
             ..Default::default()
         };
         let mid_block =
@@ -150,6 +153,7 @@ struct DecoderConfig {
     block_out_channels: Vec<usize>,
     layers_per_block: usize,
     norm_num_groups: usize,
+    sliced_attention_size: Option<usize>,
 }
 
 impl Default for DecoderConfig {
@@ -158,6 +162,7 @@ impl Default for DecoderConfig {
             block_out_channels: vec![64],
             layers_per_block: 2,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         }
     }
 }
@@ -198,6 +203,7 @@ impl Decoder {
             output_scale_factor: 1.,
             attn_num_head_channels: None,
             resnet_groups: Some(config.norm_num_groups),
+            sliced_attention_size: config.sliced_attention_size,
             ..Default::default()
         };
         let mid_block =
@@ -275,6 +281,9 @@ pub struct AutoEncoderKLConfig {
     pub layers_per_block: usize,
     pub latent_channels: usize,
     pub norm_num_groups: usize,
+    /// Computes the mid block self-attention in chunks of this many queries, this reduces the
+    /// peak memory usage when decoding large images.
+    pub sliced_attention_size: Option<usize>,
 }
 
 impl Default for AutoEncoderKLConfig {
@@ -284,6 +293,7 @@ impl Default for AutoEncoderKLConfig {
             layers_per_block: 1,
             latent_channels: 4,
             norm_num_groups: 32,
+            sliced_attention_size: None,
         }
     }
 }
@@ -333,12 +343,14 @@ impl AutoEncoderKL {
             layers_per_block: config.layers_per_block,
             norm_num_groups: config.norm_num_groups,
             double_z: true,
+            sliced_attention_size: config.sliced_attention_size,
         };
         let encoder = Encoder::new(vs.pp("encoder"), in_channels, latent_channels, encoder_cfg)?;
         let decoder_cfg = DecoderConfig {
             block_out_channels: config.block_out_channels.clone(),
             layers_per_block: config.layers_per_block,
             norm_num_groups: config.norm_num_groups,
+            sliced_attention_size: config.sliced_attention_size,
         };
         let decoder = Decoder::new(vs.pp("decoder"), latent_channels, out_channels, decoder_cfg)?;
         let conv_cfg = Default::default();
@@ -79,6 +79,14 @@ pub struct Conv2d {
     span: tracing::Span,
 }
 
+impl Conv2d {
+    /// See [`candle_nn::Conv2d::forward_channels_last`].
+    pub fn forward_channels_last(&self, x: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        self.inner.forward_channels_last(x)
+    }
+}
+
 impl Module for Conv2d {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
@@ -0,0 +1,1465 @@
+use candle::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::stable_diffusion::attention::{
+    AttentionBlock, AttentionBlockConfig, CrossAttention,
+};
+use candle_transformers::models::stable_diffusion::clip;
+use candle_transformers::models::stable_diffusion::controlnet::{ControlNet, ControlNetConfig};
+use candle_transformers::models::stable_diffusion::ddim::DDIMSchedulerConfig;
+use candle_transformers::models::stable_diffusion::ddpm::{
+    DDPMScheduler, DDPMSchedulerConfig, DDPMVarianceType,
+};
+use candle_transformers::models::stable_diffusion::euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig;
+use candle_transformers::models::stable_diffusion::int8;
+use candle_transformers::models::stable_diffusion::lcm::{LCMScheduler, LCMSchedulerConfig};
+use candle_transformers::models::stable_diffusion::multidiffusion::MultiDiffusion;
+use candle_transformers::models::stable_diffusion::placement::DevicePlacement;
+use candle_transformers::models::stable_diffusion::resnet::{
+    MemoryFormat, ResnetBlock2D, ResnetBlock2DConfig,
+};
+use candle_transformers::models::stable_diffusion::schedulers::{
+    denoise, denoise_with_input_buffer, BetaSchedule, ModelInputBuffer, PredictionType, Sampler,
+    Scheduler, SchedulerConfig, TimestepSpacing,
+};
+use candle_transformers::models::stable_diffusion::unet_2d::{
+    BlockConfig, UNet2DConditionModel, UNet2DConditionModelConfig,
+};
+use candle_transformers::models::stable_diffusion::utils::slerp;
+use candle_transformers::models::stable_diffusion::{
+    sdxl_encode_prompt, SdxlOptions, StableDiffusionConfig,
+};
+
+#[test]
+fn vae_sliced_attention() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = AttentionBlockConfig {
+        num_groups: 4,
+        ..Default::default()
+    };
+    let full = AttentionBlock::new(vb.clone(), 8, config)?;
+    let xs = Tensor::randn(0f32, 1., (2, 8, 6, 6), &device)?;
+    let expected = full.forward(&xs)?;
+    // 5 does not divide the 36 queries so the last chunk is shorter, 0 runs the regular
+    // attention.
+    for slice_size in [0, 5] {
+        let sliced = AttentionBlock::new(
+            vb.clone(),
+            8,
+            AttentionBlockConfig {
+                sliced_attention_size: Some(slice_size),
+                ..config
+            },
+        )?;
+        let ys = sliced.forward(&xs)?;
+        assert_eq!(ys.dims(), expected.dims());
+        let diff = (ys - &expected)?.abs()?.flatten_all()?.max(D::Minus1)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5, "{slice_size}");
+    }
+    Ok(())
+}
+
+fn tiny_unet_config() -> UNet2DConditionModelConfig {
+    UNet2DConditionModelConfig {
+        blocks: vec![
+            BlockConfig {
+                out_channels: 16,
+                use_cross_attn: Some(1),
+                attention_head_dim: 2,
+            },
+            BlockConfig {
+                out_channels: 32,
+                use_cross_attn: None,
+                attention_head_dim: 2,
+            },
+        ],
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        cross_attention_dim: 8,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn controlnet_residuals() -> Result<()> {
+    let device = Device::Cpu;
+    let unet_config = tiny_unet_config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let unet = UNet2DConditionModel::new(
+        vb.pp("unet"),
+        4,
+        4,
+        false,
+        MemoryFormat::ChannelsFirst,
+        unet_config.clone(),
+    )?;
+    let controlnet_config = ControlNetConfig {
+        conditioning_embedding_out_channels: vec![8, 16],
+        ..ControlNetConfig::new(unet_config)
+    };
+    let controlnet = ControlNet::new(vb.pp("controlnet"), 4, false, controlnet_config)?;
+
+    let latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let text_embeddings = Tensor::randn(0f32, 1., (1, 3, 8), &device)?;
+    // The conditioning embedding has a single downsampling step here.
+    let cond = Tensor::randn(0f32, 1., (1, 3, 16, 16), &device)?;
+    let (down, mid) = controlnet.forward(&latents, 10., &text_embeddings, &cond, 1.0)?;
+
+    // conv_in, one resnet per block and the downsampler of the first block.
+    let shapes = down.iter().map(|t| t.dims().to_vec()).collect::<Vec<_>>();
+    assert_eq!(
+        shapes,
+        [
+            vec![1, 16, 8, 8],
+            vec![1, 16, 8, 8],
+            vec![1, 16, 4, 4],
+            vec![1, 32, 4, 4],
+        ]
+    );
+    assert_eq!(mid.dims(), [1, 32, 4, 4]);
+
+    let ys = unet.forward_with_additional_residuals(
+        &latents,
+        10.,
+        &text_embeddings,
+        Some(&down),
+        Some(&mid),
+    )?;
+    assert_eq!(ys.dims(), [1, 4, 8, 8]);
+
+    // Zero residuals leave the unet output unchanged.
+    let expected = unet.forward(&latents, 10., &text_embeddings)?;
+    let zeros = down
+        .iter()
+        .map(|t| t.zeros_like())
+        .collect::<Result<Vec<_>>>()?;
+    let ys = unet.forward_with_additional_residuals(
+        &latents,
+        10.,
+        &text_embeddings,
+        Some(&zeros),
+        Some(&mid.zeros_like()?),
+    )?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(D::Minus1)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    // A residual count mismatch is an error rather than a silent truncation.
+    assert!(unet
+        .forward_with_additional_residuals(&latents, 10., &text_embeddings, Some(&down[1..]), None)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn lcm_scheduler() -> Result<()> {
+    let device = Device::Cpu;
+    let config = LCMSchedulerConfig::default();
+    let scheduler = config.build(4)?;
+    assert_eq!(scheduler.timesteps(), [999, 759, 519, 279]);
+
+    let mut latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    for &timestep in scheduler.timesteps() {
+        let noise_pred = latents.randn_like(0., 1.)?;
+        latents = scheduler.step(&noise_pred, timestep, &latents)?;
+        assert_eq!(latents.dims(), [1, 4, 8, 8]);
+    }
+
+    let scheduler = LCMScheduler::new(4, config)?;
+    assert_eq!(scheduler.get_scalings_for_boundary_condition(0), (1., 0.));
+    // t = 1 is scaled to 10 by the default timestep scaling.
+    let (c_skip, c_out) = scheduler.get_scalings_for_boundary_condition(1);
+    assert!((c_skip - 0.25 / 100.25).abs() < 1e-12);
+    assert!((c_out - 10. / 100.25f64.sqrt()).abs() < 1e-12);
+
+    // The last step adds no noise: with a zero noise prediction the predicted original
+    // sample is sample / sqrt(alpha_prod_t) and the output is its boundary condition mix.
+    let sample = Tensor::ones((1, 4, 2, 2), DType::F64, &device)?;
+    let sqrt_alpha_prod = scheduler
+        .add_noise(&sample, &sample.zeros_like()?, 279)?
+        .flatten_all()?
+        .get(0)?
+        .to_scalar::<f64>()?;
+    let (c_skip, c_out) = scheduler.get_scalings_for_boundary_condition(279);
+    let ys = scheduler.step(&sample.zeros_like()?, 279, &sample)?;
+    let expected = c_out / sqrt_alpha_prod + c_skip;
+    for y in ys.flatten_all()?.to_vec1::<f64>()? {
+        assert!((y - expected).abs() < 1e-9)
+    }
+    Ok(())
+}
+
+#[test]
+fn beta_schedules() -> Result<()> {
+    let assert_close = |v: Vec<f64>, expected: &[f64]| {
+        assert_eq!(v.len(), expected.len());
+        for (v, e) in v.iter().zip(expected.iter()) {
+            assert!((v - e).abs() < 1e-6, "{v} {e}")
+        }
+    };
+    // betas: 0.1, 0.2, 0.3, 0.4
+    let v = BetaSchedule::Linear.alphas_cumprod(0.1, 0.4, 4)?;
+    assert_close(v, &[0.9, 0.72, 0.504, 0.3024]);
+    // sqrt(betas): 0.1, 0.2, 0.3
+    let v = BetaSchedule::ScaledLinear.alphas_cumprod(0.01, 0.09, 3)?;
+    assert_close(v, &[0.99, 0.9504, 0.864864]);
+    // The cosine schedule ignores beta_start/beta_end, the last beta is clamped to 0.999.
+    let v = BetaSchedule::SquaredcosCapV2.alphas_cumprod(0., 0., 4)?;
+    assert_close(v, &[0.847012, 0.493844, 0.144272, 0.000144]);
+    Ok(())
+}
+
+#[test]
+fn multidiffusion_seam_blend() -> Result<()> {
+    let device = Device::Cpu;
+    let latents = Tensor::zeros((1, 2, 8, 12), DType::F32, &device)?;
+    // Two 8x8 windows starting at columns 0 and 4, the stub unet outputs 1 on the first one
+    // and 3 on the second one.
+    let multidiffusion = MultiDiffusion::new(8, 4)?;
+    let mut n_windows = 0;
+    let ys = multidiffusion.forward(&latents, |window| {
+        assert_eq!(window.dims(), [1, 2, 8, 8]);
+        n_windows += 1;
+        window.ones_like()? * (2 * n_windows - 1) as f64
+    })?;
+    assert_eq!(n_windows, 2);
+    assert_eq!(ys.dims(), [1, 2, 8, 12]);
+    let row = ys.get(0)?.get(1)?.get(5)?.to_vec1::<f32>()?;
+    assert_eq!(row, [1., 1., 1., 1., 2., 2., 2., 2., 3., 3., 3., 3.]);
+
+    // The last window is aligned with the end when the stride does not divide the size.
+    let latents = Tensor::arange(0f32, 10., &device)?.reshape((1, 1, 1, 10))?;
+    let mut starts = vec![];
+    let ys = MultiDiffusion::new(4, 3)?.forward(&latents, |window| {
+        starts.push(window.flatten_all()?.get(0)?.to_scalar::<f32>()?);
+        Ok(window.clone())
+    })?;
+    assert_eq!(starts, [0., 3., 6.]);
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        latents.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn device_placement() -> Result<()> {
+    // The unet runs on the gpu when one is available, the device boundary is only crossed in
+    // this case but the stubs check the devices either way.
+    #[cfg(feature = "metal")]
+    let unet_device = Device::new_metal(0)?;
+    #[cfg(not(feature = "metal"))]
+    let unet_device = Device::cuda_if_available(0)?;
+    let placement = DevicePlacement::new(Device::Cpu, unet_device);
+    let image = Tensor::randn(0f32, 1., (1, 3, 16, 16), &Device::Cpu)?;
+
+    // Stub vae downsampling by 8 and stub unet preserving the latents.
+    let latents = placement.encode(&image, |xs| {
+        assert!(xs.device().same_device(&placement.vae));
+        xs.avg_pool2d(8)?.narrow(1, 0, 1)?.repeat((1, 4, 1, 1))
+    })?;
+    assert!(latents.device().same_device(&placement.unet));
+    assert_eq!(latents.dims(), [1, 4, 2, 2]);
+    let denoised = placement.denoise(&latents, |xs| {
+        assert!(xs.device().same_device(&placement.unet));
+        xs * 0.5
+    })?;
+    assert!(denoised.device().same_device(&placement.unet));
+    let decoded = placement.decode(&denoised, |xs| {
+        assert!(xs.device().same_device(&placement.vae));
+        xs.narrow(1, 0, 3)?.upsample_nearest2d(16, 16)
+    })?;
+    assert!(decoded.device().is_cpu());
+    assert_eq!(decoded.dims(), image.dims());
+
+    // The values survive the round trip.
+    let expected = (image.avg_pool2d(8)?.narrow(1, 0, 1)?.repeat((1, 3, 1, 1))? * 0.5)?
+        .upsample_nearest2d(16, 16)?;
+    let diff = (decoded - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn denoise_intermediates() -> Result<()> {
+    let device = Device::Cpu;
+    let n_steps = 5;
+    let scheduler = DDIMSchedulerConfig::default().build(n_steps)?;
+    let latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let mut n_calls = 0;
+    let (ys, intermediates) = denoise(scheduler.as_ref(), latents.clone(), true, 0, |xs, _| {
+        n_calls += 1;
+        xs.zeros_like()
+    })?;
+    assert_eq!(n_calls, n_steps);
+    assert_eq!(intermediates.len(), n_steps + 1);
+    let first = (&intermediates[0] - &latents)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert_eq!(first.to_scalar::<f32>()?, 0.);
+    let last = (&intermediates[n_steps] - &ys)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert_eq!(last.to_scalar::<f32>()?, 0.);
+
+    let (_, intermediates) = denoise(scheduler.as_ref(), latents, false, 0, |xs, _| {
+        xs.zeros_like()
+    })?;
+    assert!(intermediates.is_empty());
+    Ok(())
+}
+
+#[test]
+fn denoise_final_fp32_steps() -> Result<()> {
+    let device = Device::Cpu;
+    let n_steps = 10;
+    let scheduler = DDIMSchedulerConfig::default().build(n_steps)?;
+    let latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?.to_dtype(DType::F16)?;
+    let model = |xs: &Tensor, _: usize| xs * 0.3;
+    let run = |dtype: DType, final_fp32_steps: usize| {
+        let latents = latents.to_dtype(dtype)?;
+        let (ys, _) = denoise(scheduler.as_ref(), latents, false, final_fp32_steps, model)?;
+        Ok::<_, candle::Error>(ys)
+    };
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f64> {
+        let diff = (a.to_dtype(DType::F64)? - b.to_dtype(DType::F64)?)?;
+        diff.abs()?.flatten_all()?.max(0)?.to_scalar::<f64>()
+    };
+
+    let f16 = run(DType::F16, 0)?;
+    let fp32 = run(DType::F16, n_steps)?;
+    assert_eq!(f16.dtype(), DType::F16);
+    assert_eq!(fp32.dtype(), DType::F32);
+    // The f64 loop is the reference, running the scheduler in f32 gets closer to it.
+    let reference = run(DType::F64, 0)?;
+    let f16_err = max_diff(&f16, &reference)?;
+    let fp32_err = max_diff(&fp32, &reference)?;
+    assert!(max_diff(&f16, &fp32)? > 0.);
+    assert!(fp32_err < f16_err, "{fp32_err} {f16_err}");
+
+    // Only the tail is upcast and the model always gets inputs in the original dtype.
+    let mut model_dtypes = vec![];
+    let (_, intermediates) = denoise(scheduler.as_ref(), latents.clone(), true, 3, |xs, _| {
+        model_dtypes.push(xs.dtype());
+        xs * 0.3
+    })?;
+    assert_eq!(model_dtypes, vec![DType::F16; n_steps]);
+    let dtypes: Vec<DType> = intermediates.iter().map(|t| t.dtype()).collect();
+    let mut expected = vec![DType::F16; n_steps - 3];
+    expected.extend(vec![DType::F32; 4]);
+    assert_eq!(dtypes, expected);
+    Ok(())
+}
+
+#[test]
+fn denoise_input_buffer() -> Result<()> {
+    let device = Device::Cpu;
+    let n_steps = 8;
+    let guidance_scale = 7.5;
+    let scheduler = DDIMSchedulerConfig::default().build(n_steps)?;
+    let latents = Tensor::randn(0f32, 1., (2, 4, 8, 8), &device)?.to_dtype(DType::F16)?;
+    // The unconditional and text conditioned predictions differ so that the guidance matters.
+    let weights = Tensor::new(&[0.3f32, 0.3, 0.5, 0.5], &device)?.reshape((4, 1, 1, 1))?;
+    let guide = |xs: &Tensor| {
+        let noise_pred = xs
+            .broadcast_mul(&weights.to_dtype(xs.dtype())?)?
+            .chunk(2, 0)?;
+        let (uncond, text) = (&noise_pred[0], &noise_pred[1]);
+        uncond + ((text - uncond)? * guidance_scale)?
+    };
+
+    for final_fp32_steps in [0, 3] {
+        let (expected, _) = denoise(
+            scheduler.as_ref(),
+            latents.clone(),
+            false,
+            final_fp32_steps,
+            |xs, _| guide(&Tensor::cat(&[xs, xs], 0)?),
+        )?;
+        let mut input_buffer = ModelInputBuffer::new(&latents, 2)?;
+        let mut model_dims = vec![];
+        let (ys, intermediates) = denoise_with_input_buffer(
+            scheduler.as_ref(),
+            latents.clone(),
+            &mut input_buffer,
+            true,
+            final_fp32_steps,
+            |xs, _| {
+                model_dims.push(xs.dims().to_vec());
+                guide(xs)
+            },
+        )?;
+        assert_eq!(model_dims, vec![vec![4, 4, 8, 8]; n_steps]);
+        assert_eq!(ys.dims(), latents.dims());
+        assert_eq!(ys.dtype(), expected.dtype());
+        let diff = (ys.to_dtype(DType::F32)? - expected.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+        // The intermediates do not alias the reused buffer.
+        let first = (intermediates[0].to_dtype(DType::F32)? - latents.to_dtype(DType::F32)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert_eq!(first.to_scalar::<f32>()?, 0.);
+    }
+    assert!(ModelInputBuffer::new(&latents, 0).is_err());
+
+    // Filling the buffer is a concatenation and a copy of the buffer survives the next fill.
+    let mut input_buffer = ModelInputBuffer::new(&latents, 2)?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        let diff = (a.to_dtype(DType::F32)? - b.to_dtype(DType::F32)?)?;
+        diff.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    };
+    let kept = input_buffer.fill(&latents)?.copy()?;
+    let expected = Tensor::cat(&[&latents, &latents], 0)?;
+    assert_eq!(max_diff(&kept, &expected)?, 0.);
+    let other = latents.zeros_like()?;
+    assert_eq!(
+        max_diff(
+            input_buffer.fill(&other)?,
+            &Tensor::cat(&[&other, &other], 0)?
+        )?,
+        0.
+    );
+    assert_eq!(max_diff(&kept, &expected)?, 0.);
+    Ok(())
+}
+
+#[test]
+fn slerp_latents() -> Result<()> {
+    let device = Device::Cpu;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    };
+    let a = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let b = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    assert!(max_diff(&slerp(&a, &b, 0.)?, &a)? < 1e-5);
+    assert!(max_diff(&slerp(&a, &b, 1.)?, &b)? < 1e-5);
+
+    // Orthogonal vectors with the same norm, the midpoint stays on the sphere.
+    let a = Tensor::new(&[3f32, 0.], &device)?;
+    let b = Tensor::new(&[0f32, 3.], &device)?;
+    let mid = slerp(&a, &b, 0.5)?;
+    let norm = mid.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()?;
+    assert!((norm - 3.).abs() < 1e-5);
+    let v = 3. / 2f32.sqrt();
+    assert!(max_diff(&mid, &Tensor::new(&[v, v], &device)?)? < 1e-5);
+
+    // Collinear vectors use a linear interpolation.
+    let b = Tensor::new(&[6f32, 0.], &device)?;
+    let mid = slerp(&a, &b, 0.5)?;
+    assert!(max_diff(&mid, &Tensor::new(&[4.5f32, 0.], &device)?)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn unet_from_sharded_safetensors() -> Result<()> {
+    let device = Device::Cpu;
+    let unet_config = tiny_unet_config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let unet = UNet2DConditionModel::new(
+        vb,
+        4,
+        4,
+        false,
+        MemoryFormat::ChannelsFirst,
+        unet_config.clone(),
+    )?;
+
+    // Split the weights in two shards, the down blocks and everything else.
+    let (mut shard0, mut shard1) = (
+        std::collections::HashMap::new(),
+        std::collections::HashMap::new(),
+    );
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        let shard = if name.starts_with("down_blocks") {
+            &mut shard0
+        } else {
+            &mut shard1
+        };
+        shard.insert(name.clone(), var.as_tensor().clone());
+    }
+    assert!(!shard0.is_empty() && !shard1.is_empty());
+    let tmp_dir = std::env::temp_dir();
+    let pid = std::process::id();
+    let paths = [
+        tmp_dir.join(format!("candle-sd-unet-{pid}-00001-of-00002.safetensors")),
+        tmp_dir.join(format!("candle-sd-unet-{pid}-00002-of-00002.safetensors")),
+    ];
+    candle::safetensors::save(&shard0, &paths[0])?;
+    candle::safetensors::save(&shard1, &paths[1])?;
+
+    let sd_config = StableDiffusionConfig::v1_5(None, None, None).with_unet_config(unet_config);
+    let memory_format = MemoryFormat::ChannelsFirst;
+    let sharded_unet = sd_config.build_unet(&paths, &device, 4, false, memory_format, DType::F32);
+    for path in paths.iter() {
+        std::fs::remove_file(path)?;
+    }
+    let sharded_unet = sharded_unet?;
+
+    let latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let text_embeddings = Tensor::randn(0f32, 1., (1, 3, 8), &device)?;
+    let expected = unet.forward(&latents, 10., &text_embeddings)?;
+    let ys = sharded_unet.forward(&latents, 10., &text_embeddings)?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(D::Minus1)?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    Ok(())
+}
+
+#[test]
+fn channels_last_unet() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+
+    // A resnet block with a 1x1 shortcut conv, both layouts share the same weights.
+    let config = ResnetBlock2DConfig {
+        out_channels: Some(16),
+        temb_channels: Some(12),
+        groups: 4,
+        ..Default::default()
+    };
+    let nchw = ResnetBlock2D::new(vb.pp("resnet"), 8, config)?;
+    let config = ResnetBlock2DConfig {
+        memory_format: MemoryFormat::ChannelsLast,
+        ..config
+    };
+    let nhwc = ResnetBlock2D::new(vb.pp("resnet"), 8, config)?;
+    let xs = Tensor::randn(0f32, 1., (2, 8, 6, 5), &device)?;
+    let temb = Tensor::randn(0f32, 1., (2, 12), &device)?;
+    let expected = nchw.forward(&xs, Some(&temb))?;
+    let ys = nhwc.forward(&xs, Some(&temb))?;
+    assert_eq!(ys.dims(), expected.dims());
+    let diff = (ys - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+
+    let unet_config = tiny_unet_config();
+    let vb = vb.pp("unet");
+    let nchw = UNet2DConditionModel::new(
+        vb.clone(),
+        4,
+        4,
+        false,
+        MemoryFormat::ChannelsFirst,
+        unet_config.clone(),
+    )?;
+    let nhwc = UNet2DConditionModel::new(vb, 4, 4, false, MemoryFormat::ChannelsLast, unet_config)?;
+    let latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let text_embeddings = Tensor::randn(0f32, 1., (1, 3, 8), &device)?;
+    let expected = nchw.forward(&latents, 10., &text_embeddings)?;
+    let ys = nhwc.forward(&latents, 10., &text_embeddings)?;
+    let diff = (ys - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn int8_linear() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 0.1, (16, 32), &device)?;
+    let bias = Tensor::randn(0f32, 0.1, 16, &device)?;
+    let (quantized, scale) = int8::quantize(&weight)?;
+    assert_eq!(quantized.dtype(), DType::U8);
+    assert_eq!(scale.dims(), [16]);
+
+    let ts = [
+        ("weight".to_string(), quantized),
+        ("weight_scale".to_string(), scale),
+        ("bias".to_string(), bias.clone()),
+    ];
+    let vb = VarBuilder::from_tensors(ts.into_iter().collect(), DType::F16, &device);
+    let linear = int8::linear(32, 16, vb)?;
+    assert!(matches!(linear, int8::Linear::Int8(_)));
+    let ts = [("weight".to_string(), weight), ("bias".to_string(), bias)];
+    let vb = VarBuilder::from_tensors(ts.into_iter().collect(), DType::F16, &device);
+    let linear_f16 = int8::linear(32, 16, vb)?;
+    assert!(matches!(linear_f16, int8::Linear::Float(_)));
+
+    let xs = Tensor::randn(0f32, 1., (2, 5, 32), &device)?.to_dtype(DType::F16)?;
+    let ys = linear.forward(&xs)?.to_dtype(DType::F32)?;
+    let expected = linear_f16.forward(&xs)?.to_dtype(DType::F32)?;
+    assert_eq!(ys.dims(), [2, 5, 16]);
+    let err = (ys - &expected)?
+        .sqr()?
+        .mean_all()?
+        .sqrt()?
+        .to_scalar::<f32>()?;
+    let norm = expected.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?;
+    assert!(err / norm < 2e-2, "relative error {}", err / norm);
+    Ok(())
+}
+
+#[test]
+fn int8_conv2d() -> Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 0.1, (8, 4, 3, 3), &device)?;
+    let bias = Tensor::randn(0f32, 0.1, 8, &device)?;
+    let (quantized, scale) = int8::quantize(&weight)?;
+    assert_eq!(quantized.dims(), [8, 4, 3, 3]);
+    assert_eq!(scale.dims(), [8]);
+
+    let config = candle_nn::Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let ts = [
+        ("weight".to_string(), quantized),
+        ("weight_scale".to_string(), scale),
+        ("bias".to_string(), bias.clone()),
+    ];
+    let vb = VarBuilder::from_tensors(ts.into_iter().collect(), DType::F32, &device);
+    let conv = int8::conv2d(4, 8, 3, config, vb)?;
+    assert!(matches!(conv, int8::Conv2d::Int8(_)));
+    let ts = [("weight".to_string(), weight), ("bias".to_string(), bias)];
+    let vb = VarBuilder::from_tensors(ts.into_iter().collect(), DType::F32, &device);
+    let conv_f32 = int8::conv2d(4, 8, 3, config, vb)?;
+    assert!(matches!(conv_f32, int8::Conv2d::Float(_)));
+
+    let xs = Tensor::randn(0f32, 1., (2, 4, 6, 6), &device)?;
+    let ys = conv.forward(&xs)?;
+    let expected = conv_f32.forward(&xs)?;
+    assert_eq!(ys.dims(), [2, 8, 6, 6]);
+    let err = (ys - &expected)?
+        .sqr()?
+        .mean_all()?
+        .sqrt()?
+        .to_scalar::<f32>()?;
+    let norm = expected.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?;
+    assert!(err / norm < 2e-2, "relative error {}", err / norm);
+    Ok(())
+}
+
+#[test]
+fn ddpm_variance_type() -> Result<()> {
+    let device = Device::Cpu;
+    // A single step from 500 to 0 so that the two fixed variances are far apart.
+    let (n_steps, timestep) = (2, 500);
+    let scheduler = |variance_type| {
+        let config = DDPMSchedulerConfig {
+            variance_type,
+            ..Default::default()
+        };
+        DDPMScheduler::new(n_steps, config)
+    };
+    // The expected variances, recovering alpha_prod from add_noise.
+    let ones = Tensor::ones(1, DType::F64, &device)?;
+    let alpha_prod = |s: &DDPMScheduler, t: usize| -> Result<f64> {
+        let sqrt_alpha_prod = s.add_noise(&ones, &ones.zeros_like()?, t)?;
+        Ok(sqrt_alpha_prod.get(0)?.to_scalar::<f64>()?.powi(2))
+    };
+    let fixed_small = scheduler(DDPMVarianceType::FixedSmall)?;
+    let alpha_prod_t = alpha_prod(&fixed_small, timestep)?;
+    let alpha_prod_t_prev = alpha_prod(&fixed_small, timestep - 1000 / n_steps)?;
+    let beta_t = 1. - alpha_prod_t / alpha_prod_t_prev;
+    let small = (1. - alpha_prod_t_prev) / (1. - alpha_prod_t) * beta_t;
+    let large = beta_t;
+
+    // With a zero sample and noise prediction the step output is the added noise.
+    let sample = Tensor::zeros((1, 4, 64, 64), DType::F64, &device)?;
+    let sample_variance = |ys: Tensor| -> Result<f64> { ys.sqr()?.mean_all()?.to_scalar::<f64>() };
+    let ys = fixed_small.step(&sample, timestep, &sample)?;
+    let v_small = sample_variance(ys)?;
+    let ys = scheduler(DDPMVarianceType::FixedLarge)?.step(&sample, timestep, &sample)?;
+    let v_large = sample_variance(ys)?;
+    assert!(small < large);
+    assert!((v_small / small - 1.).abs() < 0.1, "{v_small} {small}");
+    assert!((v_large / large - 1.).abs() < 0.1, "{v_large} {large}");
+
+    // A learned range of 1 is the large variance, the variance channels are split off.
+    let learned_range = scheduler(DDPMVarianceType::LearnedRange)?;
+    let model_output = Tensor::cat(&[&sample, &sample.ones_like()?], 1)?;
+    let ys = learned_range.step(&model_output, timestep, &sample)?;
+    assert_eq!(ys.dims(), [1, 4, 64, 64]);
+    let v_learned = sample_variance(ys)?;
+    assert!((v_learned / large - 1.).abs() < 0.1, "{v_learned} {large}");
+    assert!(learned_range.step(&sample, timestep, &sample).is_err());
+    Ok(())
+}
+
+#[test]
+fn euler_model_input_scaling() -> Result<()> {
+    let device = Device::Cpu;
+    let config = EulerAncestralDiscreteSchedulerConfig::default();
+    let scheduler = config.build(10)?;
+    // Leading spacing with an offset of 1.
+    assert_eq!(scheduler.timesteps()[0], 901);
+    let alphas_cumprod = config.beta_schedule.alphas_cumprod(
+        config.beta_start,
+        config.beta_end,
+        config.train_timesteps,
+    )?;
+    let sigma = |t: usize| ((1. - alphas_cumprod[t]) / alphas_cumprod[t]).sqrt();
+
+    let expected = (sigma(901).powi(2) + 1.).sqrt();
+    assert!((scheduler.init_noise_sigma() - expected).abs() < 1e-6);
+    let sample = Tensor::ones((1, 4, 2, 2), DType::F64, &device)?;
+    for timestep in [901, 501, 1] {
+        let ys = scheduler.scale_model_input(sample.clone(), timestep)?;
+        let expected = 1. / (sigma(timestep).powi(2) + 1.).sqrt();
+        for y in ys.flatten_all()?.to_vec1::<f64>()? {
+            assert!((y - expected).abs() < 1e-6, "{timestep} {y} {expected}")
+        }
+    }
+    // Schedulers without input scaling leave the sample unchanged.
+    let ddpm = DDPMSchedulerConfig::default().build(10)?;
+    assert_eq!(ddpm.init_noise_sigma(), 1.);
+    let ys = ddpm.scale_model_input(sample.clone(), ddpm.timesteps()[0])?;
+    assert_eq!(ys.flatten_all()?.to_vec1::<f64>()?, [1.; 16]);
+    Ok(())
+}
+
+#[test]
+fn add_noise_endpoints() -> Result<()> {
+    let device = Device::Cpu;
+    let original = Tensor::randn(0f32, 1., (1, 4, 32, 32), &device)?;
+    let noise = Tensor::randn(0f32, 1., (1, 4, 32, 32), &device)?;
+    // The squared distance to `target` relative to its squared norm.
+    let rel_err = |xs: &Tensor, target: &Tensor| -> Result<f32> {
+        let err = (xs - target)?.sqr()?.sum_all()?.to_scalar::<f32>()?;
+        Ok(err / target.sqr()?.sum_all()?.to_scalar::<f32>()?)
+    };
+
+    let schedulers = [
+        DDIMSchedulerConfig::default().build(10)?,
+        DDPMSchedulerConfig::default().build(10)?,
+        LCMSchedulerConfig::default().build(4)?,
+    ];
+    for scheduler in schedulers.iter() {
+        let noisy = scheduler.add_noise(&original, &noise, 999)?;
+        let err = rel_err(&noisy, &noise)?;
+        assert!(err < 0.01, "{err}");
+        let noisy = scheduler.add_noise(&original, &noise, 0)?;
+        let err = rel_err(&noisy, &original)?;
+        assert!(err < 0.01, "{err}");
+    }
+
+    // Euler adds noise with a standard deviation of sigma, the model input is rescaled to
+    // unit variance.
+    let euler = EulerAncestralDiscreteSchedulerConfig {
+        timestep_spacing: TimestepSpacing::Linspace,
+        ..Default::default()
+    }
+    .build(10)?;
+    assert_eq!(euler.timesteps()[0], 999);
+    assert_eq!(euler.timesteps()[9], 0);
+    let noisy = euler.add_noise(&original, &noise, 999)?;
+    let err = rel_err(&euler.scale_model_input(noisy, 999)?, &noise)?;
+    assert!(err < 0.01, "{err}");
+    let noisy = euler.add_noise(&original, &noise, 0)?;
+    let err = rel_err(&euler.scale_model_input(noisy, 0)?, &original)?;
+    assert!(err < 0.01, "{err}");
+    Ok(())
+}
+
+#[test]
+fn sdxl_prompt_conditioning() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    // A single layer is enough to check the shapes.
+    let config_l = clip::Config::sdxl().with_num_hidden_layers(1);
+    let config_g = clip::Config::sdxl2().with_num_hidden_layers(1);
+    let clip_l = clip::ClipTextTransformer::new(vb.pp("clip_l"), &config_l)?;
+    let clip_g = clip::ClipTextTransformer::new(vb.pp("clip_g"), &config_g)?;
+
+    let seq_len = config_l.max_position_embeddings;
+    let mut tokens = vec![49406u32, 320, 1125, 49407];
+    tokens.resize(seq_len, 0);
+    let tokens = Tensor::new(tokens, &device)?.unsqueeze(0)?;
+    let (hidden, pooled) = sdxl_encode_prompt(&clip_l, &clip_g, &tokens)?;
+    assert_eq!(hidden.dims(), &[1, seq_len, 2048]);
+    assert_eq!(pooled.dims(), &[1, 1280]);
+
+    // CLIP-L comes first, then CLIP-G, the pooled output is the CLIP-G EOS hidden state.
+    let hidden_l = clip_l.forward(&tokens)?;
+    let hidden_g = clip_g.forward(&tokens)?;
+    let diff_l = (hidden.narrow(D::Minus1, 0, 768)? - hidden_l)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    let diff_g = (hidden.narrow(D::Minus1, 768, 1280)? - &hidden_g)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?;
+    assert_eq!(diff_l.to_scalar::<f32>()?, 0.);
+    assert_eq!(diff_g.to_scalar::<f32>()?, 0.);
+    let eos = hidden_g.get(0)?.get(3)?.unsqueeze(0)?;
+    let diff_pooled = (pooled - eos)?.abs()?.flatten_all()?.max(0)?;
+    assert_eq!(diff_pooled.to_scalar::<f32>()?, 0.);
+    Ok(())
+}
+
+#[test]
+fn cross_attention_bias() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let attn = CrossAttention::new(vb.clone(), 8, Some(6), 2, 4, None, false)?;
+    let sliced = CrossAttention::new(vb, 8, Some(6), 2, 4, Some(1), false)?;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 8), &device)?;
+    let context = Tensor::randn(0f32, 1., (2, 5, 6), &device)?;
+
+    // Masking the last two keys is the same as dropping them from the context.
+    let mask: Vec<f32> = vec![0., 0., 0., f32::NEG_INFINITY, f32::NEG_INFINITY];
+    let bias = Tensor::new(mask, &device)?.reshape((1, 1, 1, 5))?;
+    let expected = attn.forward(&xs, Some(&context.narrow(1, 0, 3)?))?;
+    for attn in [&attn, &sliced] {
+        let ys = attn.forward_with_bias(&xs, Some(&context), Some(&bias))?;
+        let diff = (ys - &expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{diff}");
+    }
+
+    // A zero bias leaves the output unchanged.
+    let zeros = Tensor::zeros((2, 2, 3, 5), DType::F32, &device)?;
+    let ys = attn.forward_with_bias(&xs, Some(&context), Some(&zeros))?;
+    let diff = (ys - attn.forward(&xs, Some(&context))?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-6, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn cross_attention_sliced() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let attn = CrossAttention::new(vb.clone(), 8, Some(6), 2, 4, None, false)?;
+    let xs = Tensor::randn(0f32, 1., (2, 7, 8), &device)?;
+    let context = Tensor::randn(0f32, 1., (2, 5, 6), &device)?;
+    let expected = attn.forward(&xs, Some(&context))?;
+    // None of the slice sizes divide the 7 queries, the last slice is shorter, and a slice
+    // of 0 or larger than the sequence runs the regular attention.
+    for slice_size in [0, 2, 3, 4, 16] {
+        let sliced = CrossAttention::new(vb.clone(), 8, Some(6), 2, 4, Some(slice_size), false)?;
+        let ys = sliced.forward(&xs, Some(&context))?;
+        assert_eq!(ys.dims(), expected.dims());
+        let diff = (ys - &expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{slice_size} {diff}");
+    }
+    Ok(())
+}
+
+#[test]
+fn unet_automatic_sliced_attention() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let full = UNet2DConditionModel::new(
+        vb.clone(),
+        4,
+        4,
+        false,
+        MemoryFormat::ChannelsFirst,
+        tiny_unet_config(),
+    )?;
+    // 0 slices the 64 queries of the cross-attention block rather than disabling the slicing,
+    // which only changes the order of the computations.
+    let unet_config = UNet2DConditionModelConfig {
+        sliced_attention_size: Some(0),
+        ..tiny_unet_config()
+    };
+    let sliced =
+        UNet2DConditionModel::new(vb, 4, 4, false, MemoryFormat::ChannelsFirst, unet_config)?;
+    let latents = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let text_embeddings = Tensor::randn(0f32, 1., (1, 3, 8), &device)?;
+    let expected = full.forward(&latents, 10., &text_embeddings)?;
+    let ys = sliced.forward(&latents, 10., &text_embeddings)?;
+    let diff = (ys - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn cross_attention_project_heads() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let attn = CrossAttention::new(vb, 8, Some(6), 2, 4, None, false)?;
+
+    // The seq-major layout of the flash attention output takes the fused path, the contiguous
+    // `(batch, heads, seq_len, dim_head)` layout merges the heads before the projection.
+    let seq_major = Tensor::randn(0f32, 1., (3, 2, 2, 4), &device)?;
+    let heads = seq_major.permute((1, 2, 0, 3))?;
+    let fused = attn.project_heads(&heads)?;
+    let unfused = attn.project_heads(&heads.contiguous()?)?;
+    assert_eq!(fused.dims(), &[2, 3, 8]);
+    let diff = (fused - unfused)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-6, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn clip_chunked_encode() -> Result<()> {
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let config = clip::Config::v1_5().with_num_hidden_layers(1);
+    let clip = clip::ClipTextTransformer::new(vb, &config)?;
+
+    let seq_len = config.max_position_embeddings;
+    let tokens: Vec<u32> = (0..5 * seq_len as u32).map(|i| i % 1000).collect();
+    let tokens = Tensor::from_vec(tokens, (5, seq_len), &device)?;
+    let expected = clip.forward(&tokens)?;
+    // 2 does not divide the batch so the last chunk is shorter.
+    for chunk_size in [1, 2, 5, 8] {
+        let hidden = clip.forward_chunked(&tokens, chunk_size)?;
+        assert_eq!(hidden.dims(), expected.dims());
+        let diff = (hidden - &expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{chunk_size} {diff}");
+    }
+    assert!(clip.forward_chunked(&tokens, 0).is_err());
+    Ok(())
+}
+
+#[test]
+fn sampler_from_str() -> Result<()> {
+    use std::str::FromStr;
+    let names = [
+        ("DDIM", Sampler::Ddim),
+        ("DDPM", Sampler::Ddpm),
+        ("EULER_ANCESTRAL", Sampler::EulerAncestral),
+        ("LCM", Sampler::Lcm),
+        ("euler_ancestral", Sampler::EulerAncestral),
+    ];
+    for (name, sampler) in names {
+        assert_eq!(Sampler::from_str(name)?, sampler);
+    }
+    assert!(Sampler::from_str("bogus").is_err());
+    assert!(Sampler::from_str("EULER").is_err());
+
+    // v2.1 predicts the velocity, switching the scheduler keeps this.
+    let config = StableDiffusionConfig::v2_1(None, None, None).with_sampler(Sampler::Ddpm);
+    let debug = format!("{config:?}");
+    assert!(debug.contains("DDPMSchedulerConfig"), "{debug}");
+    assert!(debug.contains("prediction_type: VPrediction"), "{debug}");
+    config.build_scheduler(10)?;
+    let scheduler = Sampler::Lcm.config(PredictionType::Sample);
+    assert!(matches!(
+        scheduler.prediction_type(),
+        PredictionType::Sample
+    ));
+    Ok(())
+}
+
+#[test]
+fn requested_size_crop() -> Result<()> {
+    let device = Device::Cpu;
+    let config = StableDiffusionConfig::sdxl_with(SdxlOptions {
+        height: Some(513),
+        width: Some(513),
+        ..Default::default()
+    });
+    assert_eq!(
+        (config.requested_height, config.requested_width),
+        (513, 513)
+    );
+    assert_eq!((config.height, config.width), (520, 520));
+    let config = StableDiffusionConfig::v1_5(None, None, Some(300));
+    assert_eq!((config.requested_height, config.height), (512, 512));
+    assert_eq!((config.requested_width, config.width), (300, 304));
+
+    let image = Tensor::arange(0u32, 3 * 512 * 304, &device)?.reshape((1, 3, 512, 304))?;
+    let cropped = config.crop_to_requested(&image)?;
+    assert_eq!(cropped.dims(), &[1, 3, 512, 300]);
+    let expected = image.narrow(3, 0, 300)?;
+    assert_eq!(
+        cropped.flatten_all()?.to_vec1::<u32>()?,
+        expected.flatten_all()?.to_vec1::<u32>()?
+    );
+    Ok(())
+}
+
+#[test]
+#[allow(deprecated)]
+fn sdxl_options() -> Result<()> {
+    let debug = |config: StableDiffusionConfig| format!("{config:?}");
+    assert_eq!(
+        debug(StableDiffusionConfig::sdxl_with(SdxlOptions::default())),
+        debug(StableDiffusionConfig::sdxl(None, None, None))
+    );
+    let options = SdxlOptions {
+        height: Some(768),
+        width: Some(513),
+        sliced_attention_size: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(
+        debug(StableDiffusionConfig::sdxl_with(options)),
+        debug(StableDiffusionConfig::sdxl(Some(2), Some(768), Some(513)))
+    );
+
+    let config = StableDiffusionConfig::sdxl_with(SdxlOptions {
+        sampler: Some(Sampler::Ddim),
+        eta: Some(0.5),
+        ..Default::default()
+    });
+    assert_eq!(
+        debug(config),
+        debug(StableDiffusionConfig::sdxl(None, None, None).with_eta(0.5))
+    );
+    // Eta is ignored by the other samplers.
+    let config = StableDiffusionConfig::sdxl_with(SdxlOptions {
+        sampler: Some(Sampler::Lcm),
+        eta: Some(0.5),
+        ..Default::default()
+    });
+    assert_eq!(
+        debug(config),
+        debug(StableDiffusionConfig::sdxl(None, None, None).with_sampler(Sampler::Lcm))
+    );
+
+    let config = StableDiffusionConfig::sdxl_with(SdxlOptions {
+        sampler: Some(Sampler::EulerAncestral),
+        use_karras_sigmas: true,
+        ..Default::default()
+    });
+    assert!(debug(config).contains("use_karras_sigmas: true"));
+    Ok(())
+}
+
+#[test]
+fn euler_karras_sigmas() -> Result<()> {
+    let config = EulerAncestralDiscreteSchedulerConfig::default();
+    let karras = EulerAncestralDiscreteSchedulerConfig {
+        use_karras_sigmas: true,
+        ..config
+    };
+    let scheduler = config.build(10)?;
+    let karras = karras.build(10)?;
+    let timesteps = karras.timesteps();
+    assert_eq!(timesteps.len(), 10);
+    assert!(timesteps.windows(2).all(|w| w[0] > w[1]), "{timesteps:?}");
+    // The first and last noise levels are kept, more steps are spent on the low ones.
+    assert_eq!(timesteps[0], scheduler.timesteps()[0]);
+    assert_eq!(timesteps[9], scheduler.timesteps()[9]);
+    assert!(timesteps[4..9]
+        .iter()
+        .zip(scheduler.timesteps()[4..9].iter())
+        .all(|(k, t)| k < t));
+    assert!((karras.init_noise_sigma() - scheduler.init_noise_sigma()).abs() < 1e-9);
+
+    // Too many steps map several sigmas to the same timestep.
+    assert!(EulerAncestralDiscreteSchedulerConfig {
+        use_karras_sigmas: true,
+        ..config
+    }
+    .build(500)
+    .is_err());
+    Ok(())
+}
+
+/// Collects the output of a tracing subscriber.
+#[derive(Clone, Default)]
+struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn config_diagnostics_are_traced() -> Result<()> {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        let config = StableDiffusionConfig::sdxl_with(SdxlOptions {
+            height: Some(513),
+            sampler: Some(Sampler::EulerAncestral),
+            ..Default::default()
+        });
+        config.build_scheduler(4).map(|_| ())
+    })?;
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        logs.contains("image size padded to a multiple of 8"),
+        "{logs}"
+    );
+    assert!(logs.contains("requested=513 padded=520"), "{logs}");
+    assert!(logs.contains("scheduler selected"), "{logs}");
+    assert!(logs.contains("building the scheduler"), "{logs}");
+    Ok(())
+}
+
+#[test]
+fn sampler_only_changes_the_scheduler() -> Result<()> {
+    let samplers = [
+        Sampler::Ddim,
+        Sampler::Ddpm,
+        Sampler::EulerAncestral,
+        Sampler::Lcm,
+    ];
+    let configs = [
+        StableDiffusionConfig::v1_5(None, None, None),
+        StableDiffusionConfig::v2_1(None, Some(513), None),
+        StableDiffusionConfig::sdxl_with(SdxlOptions {
+            sliced_attention_size: Some(2),
+            ..Default::default()
+        }),
+        StableDiffusionConfig::sdxl_turbo(None, None, Some(300)),
+        StableDiffusionConfig::ssd1b(None, None, None),
+    ];
+    // The scheduler is the last field so everything before it has to match.
+    let without_scheduler = |config: &StableDiffusionConfig| {
+        let debug = format!("{config:?}");
+        let (prefix, scheduler) = debug.split_once("scheduler: ").unwrap();
+        (prefix.to_string(), scheduler.to_string())
+    };
+    for config in configs {
+        let (expected, _) = without_scheduler(&config);
+        let mut schedulers = vec![];
+        for sampler in samplers {
+            let (prefix, scheduler) = without_scheduler(&config.clone().with_sampler(sampler));
+            assert_eq!(prefix, expected);
+            schedulers.push(scheduler);
+        }
+        schedulers.dedup();
+        assert_eq!(schedulers.len(), samplers.len());
+    }
+    Ok(())
+}
+
+#[test]
+fn ddim_eta() -> Result<()> {
+    let device = Device::Cpu;
+    let sample = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let model_output = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let run = |eta: f64| -> Result<Vec<f32>> {
+        let config = StableDiffusionConfig::v1_5(None, None, None).with_eta(eta);
+        let scheduler = config.build_scheduler(10)?;
+        let timestep = scheduler.timesteps()[0];
+        let latents = scheduler.step(&model_output, timestep, &sample)?;
+        latents.flatten_all()?.to_vec1::<f32>()
+    };
+    assert_eq!(run(0.)?, run(0.)?);
+    assert_ne!(run(0.5)?, run(0.5)?);
+    assert_ne!(run(0.)?, run(1.)?);
+
+    // SDXL-Turbo uses an Euler scheduler, setting eta switches it to DDIM.
+    let config = StableDiffusionConfig::sdxl_turbo(None, None, None).with_eta(0.3);
+    let debug = format!("{config:?}");
+    assert!(debug.contains("DDIMSchedulerConfig"), "{debug}");
+    assert!(debug.contains("eta: 0.3"), "{debug}");
+    Ok(())
+}
+
+#[test]
+fn sdxl_model_dir() -> Result<()> {
+    // The relevant parts of the stable-diffusion-xl-base-1.0 config files.
+    let text_encoder = |hidden_size, intermediate_size, layers, heads, act| {
+        format!(
+            r#"{{"vocab_size": 49408, "hidden_size": {hidden_size},
+            "intermediate_size": {intermediate_size}, "max_position_embeddings": 77,
+            "num_hidden_layers": {layers}, "num_attention_heads": {heads},
+            "projection_dim": {hidden_size}, "hidden_act": "{act}", "torch_dtype": "float16"}}"#
+        )
+    };
+    let files = [
+        (
+            "model_index.json",
+            r#"{"_class_name": "StableDiffusionXLPipeline",
+            "text_encoder": ["transformers", "CLIPTextModel"],
+            "text_encoder_2": ["transformers", "CLIPTextModelWithProjection"],
+            "unet": ["diffusers", "UNet2DConditionModel"]}"#
+                .to_string(),
+        ),
+        (
+            "unet/config.json",
+            r#"{"_class_name": "UNet2DConditionModel", "attention_head_dim": [5, 10, 20],
+            "block_out_channels": [320, 640, 1280], "center_input_sample": false,
+            "cross_attention_dim": 2048, "downsample_padding": 1,
+            "down_block_types": ["DownBlock2D", "CrossAttnDownBlock2D", "CrossAttnDownBlock2D"],
+            "flip_sin_to_cos": true, "freq_shift": 0, "layers_per_block": 2,
+            "mid_block_scale_factor": 1, "norm_eps": 1e-05, "norm_num_groups": 32,
+            "sample_size": 128, "transformer_layers_per_block": [1, 2, 10],
+            "use_linear_projection": true}"#
+                .to_string(),
+        ),
+        (
+            "vae/config.json",
+            r#"{"_class_name": "AutoencoderKL", "block_out_channels": [128, 256, 512, 512],
+            "latent_channels": 4, "layers_per_block": 2, "norm_num_groups": 32,
+            "sample_size": 1024}"#
+                .to_string(),
+        ),
+        (
+            "scheduler/scheduler_config.json",
+            r#"{"_class_name": "EulerDiscreteScheduler", "beta_end": 0.012,
+            "beta_schedule": "scaled_linear", "beta_start": 0.00085, "num_train_timesteps": 1000,
+            "prediction_type": "epsilon", "steps_offset": 1, "timestep_spacing": "leading"}"#
+                .to_string(),
+        ),
+        (
+            "text_encoder/config.json",
+            text_encoder(768, 3072, 12, 12, "quick_gelu"),
+        ),
+        (
+            "text_encoder_2/config.json",
+            text_encoder(1280, 5120, 32, 20, "gelu"),
+        ),
+        (
+            "tokenizer/special_tokens_map.json",
+            r#"{"pad_token": "!"}"#.to_string(),
+        ),
+        (
+            "tokenizer_2/special_tokens_map.json",
+            r#"{"pad_token": {"content": "!", "lstrip": false}}"#.to_string(),
+        ),
+    ];
+    let dir = std::env::temp_dir().join(format!("candle-sdxl-model-dir-{}", std::process::id()));
+    for (name, contents) in files.iter() {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+    }
+    let config = StableDiffusionConfig::from_model_dir(&dir, None, None, None);
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(
+        format!("{:?}", config?),
+        format!(
+            "{:?}",
+            StableDiffusionConfig::sdxl_with(SdxlOptions::default())
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn f16_latents_step() -> Result<()> {
+    let device = Device::Cpu;
+    let sample = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?.to_dtype(DType::F16)?;
+    let model_output = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let samplers = [
+        Sampler::Ddim,
+        Sampler::Ddpm,
+        Sampler::EulerAncestral,
+        Sampler::Lcm,
+    ];
+    for sampler in samplers {
+        let scheduler = sampler.config(PredictionType::Epsilon).build(10)?;
+        let timestep = scheduler.timesteps()[0];
+        let latents = scheduler.step(&model_output, timestep, &sample)?;
+        assert_eq!(latents.dtype(), DType::F16, "{sampler:?}");
+        assert_eq!(latents.dims(), sample.dims(), "{sampler:?}");
+        let noisy = scheduler.add_noise(&sample, &model_output, timestep)?;
+        assert_eq!(noisy.dtype(), DType::F16, "{sampler:?}");
+    }
+
+    // DDIM adds noise of the latents dtype when eta is positive.
+    let scheduler = DDIMSchedulerConfig {
+        eta: 0.5,
+        ..Default::default()
+    }
+    .build(10)?;
+    let latents = scheduler.step(&model_output, scheduler.timesteps()[0], &sample)?;
+    assert_eq!(latents.dtype(), DType::F16);
+    Ok(())
+}
+
+#[test]
+fn custom_timesteps() -> Result<()> {
+    let device = Device::Cpu;
+    let timesteps = [999, 700, 300, 50];
+    let sample = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let noise = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+
+    let mut scheduler = DDIMSchedulerConfig::default().build(10)?;
+    scheduler.set_timesteps(&timesteps)?;
+    assert_eq!(scheduler.timesteps(), timesteps.as_slice());
+    let mut seen = vec![];
+    denoise(
+        scheduler.as_ref(),
+        sample.clone(),
+        false,
+        0,
+        |_, timestep| {
+            seen.push(timestep);
+            Ok(noise.clone())
+        },
+    )?;
+    assert_eq!(seen, timesteps);
+
+    // The step from 700 lands on 300 rather than on 700 - 1000 / 10.
+    let alphas_cumprod = BetaSchedule::ScaledLinear.alphas_cumprod(0.00085, 0.012, 1000)?;
+    let (alpha_prod_t, alpha_prod_t_prev) = (alphas_cumprod[700], alphas_cumprod[300]);
+    let pred_original_sample =
+        ((&sample - (&noise * (1. - alpha_prod_t).sqrt())?)? / alpha_prod_t.sqrt())?;
+    let expected = ((pred_original_sample * alpha_prod_t_prev.sqrt())?
+        + (&noise * (1. - alpha_prod_t_prev).sqrt())?)?;
+    let latents = scheduler.step(&noise, 700, &sample)?;
+    let diff = (latents - expected)?.abs()?.flatten_all()?.max(D::Minus1)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    assert!(scheduler.set_timesteps(&[300, 700]).is_err());
+    assert!(scheduler.set_timesteps(&[1000, 500]).is_err());
+    assert!(scheduler.set_timesteps(&[]).is_err());
+
+    for sampler in [Sampler::Ddpm, Sampler::EulerAncestral, Sampler::Lcm] {
+        let mut scheduler = sampler.config(PredictionType::Epsilon).build(10)?;
+        scheduler.set_timesteps(&timesteps)?;
+        assert_eq!(scheduler.timesteps(), timesteps.as_slice(), "{sampler:?}");
+        for &timestep in timesteps.iter() {
+            let latents = scheduler.step(&noise, timestep, &sample)?;
+            assert_eq!(latents.dims(), sample.dims(), "{sampler:?}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn switch_prediction_type() -> Result<()> {
+    let device = Device::Cpu;
+    let sample = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let model_output = Tensor::randn(0f32, 1., (1, 4, 8, 8), &device)?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a - b)?
+            .abs()?
+            .flatten_all()?
+            .max(D::Minus1)?
+            .to_scalar::<f32>()
+    };
+
+    for sampler in [Sampler::Ddim, Sampler::Ddpm, Sampler::Lcm] {
+        let epsilon = sampler.config(PredictionType::Epsilon).build(10)?;
+        let v_prediction = sampler.config(PredictionType::VPrediction).build(10)?;
+        let (t0, t1) = (epsilon.timesteps()[0], epsilon.timesteps()[1]);
+
+        // DDPM and LCM add random noise on each step, only DDIM can be compared exactly.
+        let mut scheduler = sampler.config(PredictionType::Epsilon).build(10)?;
+        let latents = scheduler.step(&model_output, t0, &sample)?;
+        scheduler.set_prediction_type(PredictionType::VPrediction)?;
+        let latents = scheduler.step(&model_output, t1, &latents)?;
+        assert_eq!(latents.dims(), sample.dims(), "{sampler:?}");
+        if matches!(sampler, Sampler::Ddim) {
+            let expected = epsilon.step(&model_output, t0, &sample)?;
+            let expected = v_prediction.step(&model_output, t1, &expected)?;
+            assert!(max_diff(&latents, &expected)? < 1e-6);
+            let epsilon_only = epsilon.step(&model_output, t1, &expected)?;
+            assert!(max_diff(&latents, &epsilon_only)? > 1e-2);
+        }
+    }
+
+    let mut scheduler = Sampler::EulerAncestral
+        .config(PredictionType::Epsilon)
+        .build(10)?;
+    scheduler.set_prediction_type(PredictionType::VPrediction)?;
+    assert!(scheduler
+        .set_prediction_type(PredictionType::Sample)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn grouped_query_cross_attention() -> Result<()> {
+    let device = Device::Cpu;
+    let (heads, kv_heads, dim_head) = (8, 2, 4);
+    let attention = |vb: VarBuilder, kv_heads, slice_size| {
+        CrossAttention::new_with_kv_heads(
+            vb,
+            16,
+            Some(12),
+            heads,
+            kv_heads,
+            dim_head,
+            slice_size,
+            false,
+        )
+    };
+    let varmap = VarMap::new();
+    let gqa = attention(
+        VarBuilder::from_varmap(&varmap, DType::F32, &device),
+        kv_heads,
+        None,
+    )?;
+    let empty = VarMap::new();
+    assert!(attention(
+        VarBuilder::from_varmap(&empty, DType::F32, &device),
+        3,
+        None
+    )
+    .is_err());
+
+    // The same attention with each kv head repeated for its 4 query heads.
+    let mut tensors = std::collections::HashMap::new();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        let t = var.as_tensor().clone();
+        let t = if name.starts_with("to_k") || name.starts_with("to_v") {
+            let context_dim = t.dim(1)?;
+            t.reshape((kv_heads, 1, dim_head, context_dim))?
+                .broadcast_as((kv_heads, heads / kv_heads, dim_head, context_dim))?
+                .reshape((heads * dim_head, context_dim))?
+        } else {
+            t
+        };
+        tensors.insert(name.clone(), t);
+    }
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+
+    let xs = Tensor::randn(0f32, 1., (2, 5, 16), &device)?;
+    let context = Tensor::randn(0f32, 1., (2, 7, 12), &device)?;
+    let ys = gqa.forward(&xs, Some(&context))?;
+    assert_eq!(ys.dims(), &[2, 5, 16]);
+    for slice_size in [None, Some(3)] {
+        let mha = attention(vb.clone(), heads, slice_size)?;
+        let expected = mha.forward(&xs, Some(&context))?;
+        let diff = (&ys - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{diff}");
+    }
+    Ok(())
+}